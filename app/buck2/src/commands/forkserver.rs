@@ -29,6 +29,10 @@ pub(crate) struct ForkserverCommand {
 
     #[clap(long)]
     state_dir: PathBuf,
+
+    /// Run each command in its own cgroup under this one.
+    #[clap(long)]
+    cgroup_dir: Option<PathBuf>,
 }
 
 impl ForkserverCommand {
@@ -42,6 +46,8 @@ impl ForkserverCommand {
 
         fs_util::create_dir_all(&state_dir)?;
 
+        let cgroup_dir = self.cgroup_dir.map(AbsNormPathBuf::try_from).transpose()?;
+
         #[cfg(unix)]
         {
             // For us to get this FD it must be non-CLOEXEC but we don't want our children to
@@ -56,12 +62,13 @@ impl ForkserverCommand {
                 self.fd,
                 log_reload_handle,
                 state_dir,
+                cgroup_dir,
             ))
         }
 
         #[cfg(not(unix))]
         {
-            let _ignored = log_reload_handle;
+            let _ignored = (log_reload_handle, cgroup_dir);
             Err(anyhow::anyhow!("The forkserver is only available on UNIX"))
         }
     }
@@ -0,0 +1,570 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+//! Create a tar or zip archive from artifacts, without running a command. Archives are
+//! reproducible: entries are sorted by path, and timestamps, owners and permissions are fixed, so
+//! the same inputs always produce the same bytes.
+
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Seek;
+use std::io::Write;
+
+use allocative::Allocative;
+use anyhow::Context as _;
+use async_trait::async_trait;
+use buck2_build_api::actions::artifact::build_artifact::BuildArtifact;
+use buck2_build_api::actions::box_slice_set::BoxSliceSet;
+use buck2_build_api::actions::execute::action_executor::ActionExecutionKind;
+use buck2_build_api::actions::execute::action_executor::ActionExecutionMetadata;
+use buck2_build_api::actions::execute::action_executor::ActionOutputs;
+use buck2_build_api::actions::Action;
+use buck2_build_api::actions::ActionExecutable;
+use buck2_build_api::actions::ActionExecutionCtx;
+use buck2_build_api::actions::IncrementalActionExecutable;
+use buck2_build_api::actions::UnregisteredAction;
+use buck2_build_api::artifact_groups::ArtifactGroup;
+use buck2_common::file_ops::FileDigest;
+use buck2_common::file_ops::FileMetadata;
+use buck2_common::file_ops::TrackedFileDigest;
+use buck2_core::category::Category;
+use buck2_core::directory::Directory;
+use buck2_core::directory::DirectoryEntry;
+use buck2_core::directory::DirectoryIterator;
+use buck2_core::fs::fs_util;
+use buck2_core::fs::paths::abs_norm_path::AbsNormPath;
+use buck2_core::fs::paths::forward_rel_path::ForwardRelativePath;
+use buck2_core::fs::paths::forward_rel_path::ForwardRelativePathBuf;
+use buck2_core::fs::project::ProjectRoot;
+use buck2_core::fs::project_rel_path::ProjectRelativePathBuf;
+use buck2_execute::artifact::artifact_dyn::ArtifactDyn;
+use buck2_execute::artifact_value::ArtifactValue;
+use buck2_execute::directory::ActionDirectoryMember;
+use buck2_execute::execute::command_executor::ActionExecutionTimingData;
+use dupe::Dupe;
+use gazebo::prelude::*;
+use indexmap::IndexMap;
+use indexmap::IndexSet;
+use once_cell::sync::Lazy;
+use starlark::values::OwnedFrozenValue;
+use thiserror::Error;
+
+use crate::actions::impls::extract_archive::ArchiveFormat;
+
+/// The modification time of every tar entry: the Unix epoch. Zip archives can't represent
+/// anything before 1980, so they use 1980-01-01 instead.
+const TAR_MTIME: u64 = 0;
+
+#[derive(Debug, Error)]
+enum CreateArchiveActionError {
+    #[error("Exactly one output file must be specified for a create_archive action, got {0}")]
+    WrongNumberOfOutputs(usize),
+    #[error("Only artifact inputs are supported in create_archive actions, got {0}")]
+    UnsupportedInput(ArtifactGroup),
+    #[error("Path `{0}` is added to the archive more than once")]
+    DuplicatePath(ForwardRelativePathBuf),
+    #[error("Zip archives cannot contain symlinks, but `{0}` is a symlink; use a tar format")]
+    ZipSymlink(ForwardRelativePathBuf),
+    #[error("`{0}` is not a directory, so it must be added to the archive at a non-empty path")]
+    EmptyPath(ProjectRelativePathBuf),
+}
+
+/// An entry of the archive we are about to write.
+#[derive(Debug, PartialEq, Eq)]
+enum ArchiveEntry {
+    Dir,
+    File {
+        src: ProjectRelativePathBuf,
+        executable: bool,
+    },
+    Symlink(String),
+}
+
+impl ArchiveEntry {
+    fn mode(&self) -> u32 {
+        match self {
+            Self::Dir => 0o755,
+            Self::File {
+                executable: true, ..
+            } => 0o755,
+            Self::File { .. } => 0o644,
+            Self::Symlink(..) => 0o777,
+        }
+    }
+}
+
+/// All the entries of the archive, sorted by path, so that parent directories come before their
+/// contents.
+#[derive(Debug, Default)]
+struct ArchiveEntries(BTreeMap<ForwardRelativePathBuf, ArchiveEntry>);
+
+impl ArchiveEntries {
+    fn insert(&mut self, path: &ForwardRelativePath, entry: ArchiveEntry) -> anyhow::Result<()> {
+        let mut parent = path.parent();
+        while let Some(dir) = parent {
+            if dir.is_empty() {
+                break;
+            }
+            self.insert_one(dir, ArchiveEntry::Dir)?;
+            parent = dir.parent();
+        }
+        self.insert_one(path, entry)
+    }
+
+    fn insert_one(
+        &mut self,
+        path: &ForwardRelativePath,
+        entry: ArchiveEntry,
+    ) -> anyhow::Result<()> {
+        match self.0.get(path) {
+            None => {
+                self.0.insert(path.to_buf(), entry);
+                Ok(())
+            }
+            // Directories may be implied by several entries.
+            Some(ArchiveEntry::Dir) if entry == ArchiveEntry::Dir => Ok(()),
+            Some(_) => Err(CreateArchiveActionError::DuplicatePath(path.to_buf()).into()),
+        }
+    }
+
+    /// Add the artifact at `src` (with value `value`) at `dest`.
+    fn insert_artifact(
+        &mut self,
+        dest: &ForwardRelativePath,
+        src: &ProjectRelativePathBuf,
+        value: &ArtifactValue,
+    ) -> anyhow::Result<()> {
+        match value.entry() {
+            DirectoryEntry::Dir(dir) => {
+                if !dest.is_empty() {
+                    self.insert(dest, ArchiveEntry::Dir)?;
+                }
+                for (path, entry) in dir.unordered_walk().with_paths() {
+                    let entry = match entry {
+                        DirectoryEntry::Dir(..) => ArchiveEntry::Dir,
+                        DirectoryEntry::Leaf(leaf) => Self::leaf_entry(src.join(&path), leaf),
+                    };
+                    self.insert(&dest.join(&path), entry)?;
+                }
+                Ok(())
+            }
+            DirectoryEntry::Leaf(..) if dest.is_empty() => {
+                Err(CreateArchiveActionError::EmptyPath(src.clone()).into())
+            }
+            DirectoryEntry::Leaf(leaf) => self.insert(dest, Self::leaf_entry(src.clone(), leaf)),
+        }
+    }
+
+    fn leaf_entry(src: ProjectRelativePathBuf, leaf: &ActionDirectoryMember) -> ArchiveEntry {
+        match leaf {
+            ActionDirectoryMember::File(meta) => ArchiveEntry::File {
+                src,
+                executable: meta.is_executable,
+            },
+            ActionDirectoryMember::Symlink(s) => ArchiveEntry::Symlink(s.target().to_string()),
+            ActionDirectoryMember::ExternalSymlink(s) => {
+                ArchiveEntry::Symlink(s.to_path_buf().to_string_lossy().into_owned())
+            }
+        }
+    }
+
+    /// Write the archive to `out`.
+    fn write(
+        &self,
+        fs: &ProjectRoot,
+        format: ArchiveFormat,
+        out: &AbsNormPath,
+    ) -> anyhow::Result<()> {
+        if let Some(dir) = out.parent() {
+            fs_util::create_dir_all(dir)?;
+        }
+        // Zip needs to seek back to write local headers, so we can't use `fs_util::create_file`.
+        let file = File::create(out).with_context(|| format!("create({})", out))?;
+        let file = match format {
+            ArchiveFormat::Tar => self.write_tar(fs, file)?,
+            ArchiveFormat::TarGz => {
+                let encoder = flate2::write::GzEncoder::new(file, flate2::Compression::default());
+                self.write_tar(fs, encoder)?.finish()?
+            }
+            ArchiveFormat::TarZst => {
+                let encoder = zstd::stream::write::Encoder::new(file, 0)?;
+                self.write_tar(fs, encoder)?.finish()?
+            }
+            ArchiveFormat::TarXz => {
+                let encoder = xz2::write::XzEncoder::new(file, 6);
+                self.write_tar(fs, encoder)?.finish()?
+            }
+            ArchiveFormat::Zip => self.write_zip(fs, file)?,
+        };
+        drop(file);
+        Ok(())
+    }
+
+    fn write_tar<W: Write>(&self, fs: &ProjectRoot, out: W) -> anyhow::Result<W> {
+        let mut builder = tar::Builder::new(out);
+        for (path, entry) in &self.0 {
+            let mut header = tar::Header::new_gnu();
+            header.set_mtime(TAR_MTIME);
+            header.set_uid(0);
+            header.set_gid(0);
+            header.set_mode(entry.mode());
+            match entry {
+                ArchiveEntry::Dir => {
+                    header.set_entry_type(tar::EntryType::Directory);
+                    header.set_size(0);
+                    builder.append_data(&mut header, path.as_str(), std::io::empty())?;
+                }
+                ArchiveEntry::File { src, .. } => {
+                    let abs_src = fs.resolve(src);
+                    let file = fs_util::open_file(&abs_src)?;
+                    header.set_entry_type(tar::EntryType::Regular);
+                    header.set_size(
+                        file.metadata()
+                            .with_context(|| format!("stat({})", abs_src))?
+                            .len(),
+                    );
+                    builder.append_data(&mut header, path.as_str(), file)?;
+                }
+                ArchiveEntry::Symlink(target) => {
+                    header.set_entry_type(tar::EntryType::Symlink);
+                    header.set_size(0);
+                    builder.append_link(&mut header, path.as_str(), target)?;
+                }
+            }
+        }
+        Ok(builder.into_inner()?)
+    }
+
+    fn write_zip<W: Write + Seek>(&self, fs: &ProjectRoot, out: W) -> anyhow::Result<W> {
+        let mut zip = zip::ZipWriter::new(out);
+        for (path, entry) in &self.0 {
+            let options = zip::write::FileOptions::default()
+                .compression_method(zip::CompressionMethod::Deflated)
+                .last_modified_time(zip::DateTime::default())
+                .unix_permissions(entry.mode());
+            match entry {
+                ArchiveEntry::Dir => zip.add_directory(path.as_str(), options)?,
+                ArchiveEntry::File { src, .. } => {
+                    zip.start_file(path.as_str(), options)?;
+                    let mut file = fs_util::open_file(fs.resolve(src))?;
+                    std::io::copy(&mut file, &mut zip)?;
+                }
+                ArchiveEntry::Symlink(..) => {
+                    return Err(CreateArchiveActionError::ZipSymlink(path.clone()).into());
+                }
+            }
+        }
+        Ok(zip.finish()?)
+    }
+}
+
+#[derive(Debug, Allocative)]
+pub(crate) struct UnregisteredCreateArchiveAction {
+    format: Option<ArchiveFormat>,
+    /// Where each input goes in the archive.
+    srcs: Vec<(ForwardRelativePathBuf, ArtifactGroup)>,
+}
+
+impl UnregisteredCreateArchiveAction {
+    pub(crate) fn new(
+        format: Option<ArchiveFormat>,
+        srcs: Vec<(ForwardRelativePathBuf, ArtifactGroup)>,
+    ) -> Self {
+        Self { format, srcs }
+    }
+
+    pub(crate) fn inputs(&self) -> IndexSet<ArtifactGroup> {
+        self.srcs.iter().map(|(_, input)| input.dupe()).collect()
+    }
+}
+
+impl UnregisteredAction for UnregisteredCreateArchiveAction {
+    fn register(
+        self: Box<Self>,
+        inputs: IndexSet<ArtifactGroup>,
+        outputs: IndexSet<BuildArtifact>,
+        _starlark_data: Option<OwnedFrozenValue>,
+    ) -> anyhow::Result<Box<dyn Action>> {
+        Ok(Box::new(CreateArchiveAction::new(inputs, outputs, *self)?))
+    }
+}
+
+#[derive(Debug, Allocative)]
+struct CreateArchiveAction {
+    inputs: BoxSliceSet<ArtifactGroup>,
+    outputs: BoxSliceSet<BuildArtifact>,
+    format: Option<ArchiveFormat>,
+    srcs: Box<[(ForwardRelativePathBuf, ArtifactGroup)]>,
+}
+
+impl CreateArchiveAction {
+    fn new(
+        inputs: IndexSet<ArtifactGroup>,
+        outputs: IndexSet<BuildArtifact>,
+        inner: UnregisteredCreateArchiveAction,
+    ) -> anyhow::Result<Self> {
+        for input in &inputs {
+            match input {
+                ArtifactGroup::Artifact(..) => {}
+                other => {
+                    return Err(CreateArchiveActionError::UnsupportedInput(other.dupe()).into());
+                }
+            }
+        }
+
+        if outputs.len() != 1 {
+            return Err(CreateArchiveActionError::WrongNumberOfOutputs(outputs.len()).into());
+        }
+
+        Ok(Self {
+            inputs: BoxSliceSet::from(inputs),
+            outputs: BoxSliceSet::from(outputs),
+            format: inner.format,
+            srcs: inner.srcs.into_boxed_slice(),
+        })
+    }
+
+    fn output(&self) -> &BuildArtifact {
+        self.outputs
+            .iter()
+            .next()
+            .expect("a single artifact by construction")
+    }
+}
+
+#[async_trait]
+impl Action for CreateArchiveAction {
+    fn kind(&self) -> buck2_data::ActionKind {
+        buck2_data::ActionKind::CreateArchive
+    }
+
+    fn inputs(&self) -> anyhow::Result<Cow<'_, [ArtifactGroup]>> {
+        Ok(Cow::Borrowed(self.inputs.as_slice()))
+    }
+
+    fn outputs(&self) -> anyhow::Result<Cow<'_, [BuildArtifact]>> {
+        Ok(Cow::Borrowed(self.outputs.as_slice()))
+    }
+
+    fn as_executable(&self) -> ActionExecutable<'_> {
+        ActionExecutable::Incremental(self)
+    }
+
+    fn category(&self) -> &Category {
+        static CREATE_ARCHIVE_CATEGORY: Lazy<Category> =
+            Lazy::new(|| Category::try_from("create_archive").unwrap());
+
+        &CREATE_ARCHIVE_CATEGORY
+    }
+
+    fn identifier(&self) -> Option<&str> {
+        Some(self.output().get_path().path().as_str())
+    }
+
+    fn aquery_attributes(
+        &self,
+        _fs: &buck2_execute::execute::request::ExecutorFs,
+    ) -> IndexMap<String, String> {
+        indexmap::indexmap! {
+            "format".to_owned() => match self.format {
+                Some(format) => format.to_string(),
+                None => "None".to_owned(),
+            },
+            "srcs".to_owned() => format!(
+                "{:?}",
+                self.srcs
+                    .iter()
+                    .map(|(path, input)| format!("{}: {}", path, input))
+                    .collect::<Vec<_>>(),
+            ),
+        }
+    }
+}
+
+#[async_trait]
+impl IncrementalActionExecutable for CreateArchiveAction {
+    async fn execute(
+        &self,
+        ctx: &mut dyn ActionExecutionCtx,
+    ) -> anyhow::Result<(ActionOutputs, ActionExecutionMetadata)> {
+        let artifact_fs = ctx.fs();
+        let fs = artifact_fs.fs();
+        let dest = artifact_fs.resolve_build(self.output().get_path());
+        let format = match self.format {
+            Some(format) => format,
+            None => ArchiveFormat::from_file_name(dest.as_str())?,
+        };
+
+        let mut entries = ArchiveEntries::default();
+        let mut to_materialize = Vec::new();
+        for (path, input) in self.srcs.iter() {
+            let (artifact, value) = ctx
+                .artifact_values(input)
+                .iter()
+                .into_singleton()
+                .context("Input did not dereference to exactly one artifact")?;
+            let src = artifact.resolve_path(artifact_fs)?;
+            entries.insert_artifact(path, &src, value)?;
+            to_materialize.push(src);
+        }
+
+        ctx.materializer()
+            .ensure_materialized(to_materialize)
+            .await?;
+        ctx.cleanup_outputs().await?;
+
+        let cas_digest_config = ctx.digest_config().cas_digest_config();
+        let value = ctx
+            .blocking_executor()
+            .execute_io_inline(|| {
+                let abs_dest = fs.resolve(&dest);
+                entries
+                    .write(fs, format, &abs_dest)
+                    .with_context(|| format!("Error writing archive `{}`", dest))?;
+                let digest = FileDigest::from_file(&abs_dest, cas_digest_config)?;
+                Ok(ArtifactValue::file(FileMetadata {
+                    digest: TrackedFileDigest::new(digest, cas_digest_config),
+                    is_executable: false,
+                }))
+            })
+            .await?;
+
+        ctx.materializer()
+            .declare_existing(vec![(dest, value.dupe())])
+            .await?;
+
+        Ok((
+            ActionOutputs::from_single(self.output().get_path().dupe(), value),
+            ActionExecutionMetadata {
+                execution_kind: ActionExecutionKind::Simple,
+                timing: ActionExecutionTimingData::default(),
+            },
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use buck2_common::cas_digest::testing;
+    use buck2_core::fs::project::ProjectRootTemp;
+    use buck2_core::fs::project_rel_path::ProjectRelativePath;
+
+    use super::*;
+
+    fn path(p: &str) -> &ForwardRelativePath {
+        ForwardRelativePath::unchecked_new(p)
+    }
+
+    fn file(src: &str, executable: bool) -> ArchiveEntry {
+        ArchiveEntry::File {
+            src: ProjectRelativePathBuf::unchecked_new(src.to_owned()),
+            executable,
+        }
+    }
+
+    #[test]
+    fn test_insert_adds_parents() -> anyhow::Result<()> {
+        let mut entries = ArchiveEntries::default();
+        entries.insert(path("b/c/d.txt"), file("d.txt", false))?;
+        entries.insert(path("a.txt"), file("a.txt", false))?;
+        entries.insert(path("b/e.txt"), file("e.txt", false))?;
+
+        assert_eq!(
+            vec!["a.txt", "b", "b/c", "b/c/d.txt", "b/e.txt"],
+            entries.0.keys().map(|p| p.as_str()).collect::<Vec<_>>()
+        );
+
+        assert!(entries.insert(path("a.txt"), file("x", false)).is_err());
+        assert!(entries.insert(path("a.txt/x"), file("x", false)).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_insert_file_at_empty_path() -> anyhow::Result<()> {
+        let mut entries = ArchiveEntries::default();
+        let src = ProjectRelativePathBuf::unchecked_new("a.txt".to_owned());
+        let value = ArtifactValue::file(FileMetadata::empty(testing::sha1()));
+        assert!(entries.insert_artifact(path(""), &src, &value).is_err());
+        assert!(entries.0.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_creates_parent_dirs() -> anyhow::Result<()> {
+        let temp = ProjectRootTemp::new()?;
+        let fs = temp.path();
+        temp.write_file("src/data.txt", "data");
+
+        let mut entries = ArchiveEntries::default();
+        entries.insert(path("data.txt"), file("src/data.txt", false))?;
+
+        let out = fs.resolve(ProjectRelativePath::unchecked_new(
+            "buck-out/v2/gen/pkg/archive.zip",
+        ));
+        entries.write(fs, ArchiveFormat::Zip, &out)?;
+        let mut archive = zip::ZipArchive::new(File::open(&out)?)?;
+        assert_eq!(1, archive.len());
+        assert_eq!("data.txt", archive.by_index(0)?.name());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_tar_is_deterministic() -> anyhow::Result<()> {
+        let temp = ProjectRootTemp::new()?;
+        let fs = temp.path();
+        temp.write_file("src/tool", "#!/bin/sh\n");
+        temp.write_file("src/data.txt", "data");
+
+        let mut entries = ArchiveEntries::default();
+        entries.insert(path("bin/tool"), file("src/tool", true))?;
+        entries.insert(path("share/data.txt"), file("src/data.txt", false))?;
+        entries.insert(
+            path("share/link"),
+            ArchiveEntry::Symlink("data.txt".to_owned()),
+        )?;
+
+        let first = fs.resolve(ProjectRelativePath::unchecked_new("first.tar.gz"));
+        let second = fs.resolve(ProjectRelativePath::unchecked_new("second.tar.gz"));
+        entries.write(fs, ArchiveFormat::TarGz, &first)?;
+        entries.write(fs, ArchiveFormat::TarGz, &second)?;
+        let bytes = std::fs::read(&first)?;
+        assert_eq!(bytes, std::fs::read(&second)?);
+
+        let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(&bytes[..]));
+        let mut listed = Vec::new();
+        for entry in archive.entries()? {
+            let entry = entry?;
+            assert_eq!(TAR_MTIME, entry.header().mtime()?);
+            listed.push((
+                entry
+                    .path()?
+                    .to_string_lossy()
+                    .trim_end_matches('/')
+                    .to_owned(),
+                entry.header().mode()?,
+            ));
+        }
+        assert_eq!(
+            vec![
+                ("bin".to_owned(), 0o755),
+                ("bin/tool".to_owned(), 0o755),
+                ("share".to_owned(), 0o755),
+                ("share/data.txt".to_owned(), 0o644),
+                ("share/link".to_owned(), 0o777),
+            ],
+            listed
+        );
+
+        Ok(())
+    }
+}
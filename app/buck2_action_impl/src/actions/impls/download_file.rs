@@ -35,6 +35,7 @@ use buck2_execute::materialize::http::http_client;
 use buck2_execute::materialize::http::http_download;
 use buck2_execute::materialize::http::http_head;
 use buck2_execute::materialize::http::Checksum;
+use buck2_execute::materialize::http::HttpClient;
 use buck2_execute::materialize::http::HttpError;
 use buck2_execute::materialize::materializer::HttpDownloadInfo;
 use dupe::Dupe;
@@ -56,7 +57,8 @@ enum DownloadFileActionError {
 #[derive(Debug, Allocative)]
 pub(crate) struct UnregisteredDownloadFileAction {
     checksum: Checksum,
-    url: Arc<str>,
+    /// The URL and its fallbacks, in order. Never empty.
+    urls: Arc<[Arc<str>]>,
     is_executable: bool,
     is_deferrable: bool,
 }
@@ -64,13 +66,13 @@ pub(crate) struct UnregisteredDownloadFileAction {
 impl UnregisteredDownloadFileAction {
     pub(crate) fn new(
         checksum: Checksum,
-        url: Arc<str>,
+        urls: Arc<[Arc<str>]>,
         is_executable: bool,
         is_deferrable: bool,
     ) -> Self {
         Self {
             checksum,
-            url,
+            urls,
             is_executable,
             is_deferrable,
         }
@@ -128,13 +130,18 @@ impl DownloadFileAction {
     /// Try to produce a FileMetadata without downloading the file.
     async fn declared_metadata(
         &self,
-        client: &reqwest::Client,
+        client: &HttpClient,
         digest_config: DigestConfig,
     ) -> anyhow::Result<Option<FileMetadata>> {
         if !self.inner.is_deferrable {
             return Ok(None);
         }
 
+        // Downloading from the cache is cheap, and doesn't depend on the network.
+        if client.is_cached(&self.inner.checksum) {
+            return Ok(None);
+        }
+
         if !digest_config.cas_digest_config().allows_sha1() {
             return Ok(None);
         }
@@ -149,7 +156,7 @@ impl DownloadFileAction {
             Err(_) => return Ok(None),
         };
 
-        let head = http_head(client, &self.inner.url).await?;
+        let head = http_head(client, &self.inner.urls).await?;
 
         // NOTE: Don't use reqwest's content_length() method here, that always returns zero!
         // https://github.com/seanmonstar/reqwest/issues/843
@@ -169,7 +176,7 @@ impl DownloadFileAction {
             .with_context(|| {
                 format!(
                     "Request to `{}` returned an invalid `{}` header",
-                    self.inner.url,
+                    self.inner.urls[0],
                     http::header::CONTENT_LENGTH
                 )
             })?;
@@ -229,7 +236,7 @@ impl IncrementalActionExecutable for DownloadFileAction {
         &self,
         ctx: &mut dyn ActionExecutionCtx,
     ) -> anyhow::Result<(ActionOutputs, ActionExecutionMetadata)> {
-        let client = http_client()?;
+        let client = http_client(ctx.download_config())?;
 
         let (metadata, execution_kind) =
             match self.declared_metadata(&client, ctx.digest_config()).await? {
@@ -242,10 +249,11 @@ impl IncrementalActionExecutable for DownloadFileAction {
                         .declare_http(
                             rel_path,
                             HttpDownloadInfo {
-                                url: self.inner.url.dupe(),
+                                urls: self.inner.urls.dupe(),
                                 checksum: self.inner.checksum.dupe(),
                                 metadata: metadata.dupe(),
                                 owner: ctx.target().owner().dupe().into_dyn(),
+                                config: ctx.download_config(),
                             },
                         )
                         .await?;
@@ -262,10 +270,11 @@ impl IncrementalActionExecutable for DownloadFileAction {
                     // Slow path: download now.
                     let digest = http_download(
                         &client,
+                        ctx.blocking_executor(),
                         project_fs,
                         ctx.digest_config(),
                         &rel_path,
-                        &self.inner.url,
+                        &self.inner.urls,
                         &self.inner.checksum,
                         self.inner.is_executable,
                     )
@@ -0,0 +1,1052 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+//! Extract a tar or zip archive into a directory, without running a command. The output's
+//! directory value is computed as we write the files, so nothing has to be hashed after the fact.
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io::Read;
+use std::io::Write;
+use std::str::FromStr;
+
+use allocative::Allocative;
+use anyhow::Context as _;
+use async_trait::async_trait;
+use buck2_build_api::actions::artifact::build_artifact::BuildArtifact;
+use buck2_build_api::actions::box_slice_set::BoxSliceSet;
+use buck2_build_api::actions::execute::action_executor::ActionExecutionKind;
+use buck2_build_api::actions::execute::action_executor::ActionExecutionMetadata;
+use buck2_build_api::actions::execute::action_executor::ActionOutputs;
+use buck2_build_api::actions::Action;
+use buck2_build_api::actions::ActionExecutable;
+use buck2_build_api::actions::ActionExecutionCtx;
+use buck2_build_api::actions::IncrementalActionExecutable;
+use buck2_build_api::actions::UnregisteredAction;
+use buck2_build_api::artifact_groups::ArtifactGroup;
+use buck2_common::cas_digest::CasDigestConfig;
+use buck2_common::file_ops::FileDigest;
+use buck2_common::file_ops::FileMetadata;
+use buck2_common::file_ops::TrackedFileDigest;
+use buck2_core::category::Category;
+use buck2_core::directory::find;
+use buck2_core::directory::DirectoryEntry;
+use buck2_core::directory::DirectoryFindError;
+use buck2_core::directory::DirectoryIterator;
+use buck2_core::fs::fs_util;
+use buck2_core::fs::paths::abs_norm_path::AbsNormPath;
+use buck2_core::fs::paths::forward_rel_path::ForwardRelativePath;
+use buck2_core::fs::paths::forward_rel_path::ForwardRelativePathBuf;
+use buck2_execute::artifact::artifact_dyn::ArtifactDyn;
+use buck2_execute::artifact_value::ArtifactValue;
+use buck2_execute::directory::extract_artifact_value;
+use buck2_execute::directory::new_symlink;
+use buck2_execute::directory::ActionDirectoryBuilder;
+use buck2_execute::directory::ActionDirectoryMember;
+use buck2_execute::execute::command_executor::ActionExecutionTimingData;
+use dupe::Dupe;
+use gazebo::prelude::*;
+use globset::Glob;
+use globset::GlobSet;
+use globset::GlobSetBuilder;
+use indexmap::IndexMap;
+use indexmap::IndexSet;
+use once_cell::sync::Lazy;
+use starlark::values::OwnedFrozenValue;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+enum ExtractArchiveActionError {
+    #[error("Exactly one input archive must be specified for an extract_archive action, got {0}")]
+    WrongNumberOfInputs(usize),
+    #[error(
+        "Exactly one output directory must be specified for an extract_archive action, got {0}"
+    )]
+    WrongNumberOfOutputs(usize),
+    #[error("Only artifact inputs are supported in extract_archive actions, got {0}")]
+    UnsupportedInput(ArtifactGroup),
+    #[error(
+        "Unknown archive format `{0}`, expected one of `tar`, `tar.gz`, `tar.zst`, `tar.xz`, `zip`"
+    )]
+    UnknownFormat(String),
+    #[error("Cannot infer the format of archive `{0}` from its extension, pass `format`")]
+    CannotInferFormat(String),
+    #[error("Invalid glob `{0}`")]
+    InvalidGlob(String),
+    #[error("Archive entry `{0}` is not a normalized relative path")]
+    InvalidPath(String),
+    #[error("Symlink `{0}` in the archive points outside of the extracted directory: `{1}`")]
+    SymlinkEscapes(String, String),
+    #[error("Archive entry `{0}` would be extracted through a symlink or file in the archive")]
+    PathThroughLeaf(String),
+    #[error("Hard link `{0}` in the archive points to `{1}`, which was not extracted before it")]
+    HardLinkTargetMissing(String, String),
+    #[error("Archive entry `{0}` has unsupported type `{1}`")]
+    UnsupportedEntryType(String, String),
+}
+
+#[derive(Debug, Clone, Copy, Dupe, PartialEq, Eq, Allocative)]
+pub(crate) enum ArchiveFormat {
+    Tar,
+    TarGz,
+    TarZst,
+    TarXz,
+    Zip,
+}
+
+impl ArchiveFormat {
+    pub(crate) fn from_file_name(name: &str) -> anyhow::Result<Self> {
+        const EXTENSIONS: &[(&str, ArchiveFormat)] = &[
+            (".tar", ArchiveFormat::Tar),
+            (".tar.gz", ArchiveFormat::TarGz),
+            (".tgz", ArchiveFormat::TarGz),
+            (".tar.zst", ArchiveFormat::TarZst),
+            (".tzst", ArchiveFormat::TarZst),
+            (".tar.xz", ArchiveFormat::TarXz),
+            (".txz", ArchiveFormat::TarXz),
+            (".zip", ArchiveFormat::Zip),
+        ];
+        EXTENSIONS
+            .iter()
+            .find(|(ext, _)| name.ends_with(ext))
+            .map(|(_, format)| *format)
+            .ok_or_else(|| ExtractArchiveActionError::CannotInferFormat(name.to_owned()).into())
+    }
+}
+
+impl FromStr for ArchiveFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "tar" => Ok(Self::Tar),
+            "tar.gz" => Ok(Self::TarGz),
+            "tar.zst" => Ok(Self::TarZst),
+            "tar.xz" => Ok(Self::TarXz),
+            "zip" => Ok(Self::Zip),
+            _ => Err(ExtractArchiveActionError::UnknownFormat(s.to_owned()).into()),
+        }
+    }
+}
+
+impl fmt::Display for ArchiveFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Self::Tar => "tar",
+            Self::TarGz => "tar.gz",
+            Self::TarZst => "tar.zst",
+            Self::TarXz => "tar.xz",
+            Self::Zip => "zip",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// Which entries of the archive to extract, and where.
+#[derive(Debug, Allocative)]
+pub(crate) struct ArchiveFilter {
+    strip_prefix: Option<ForwardRelativePathBuf>,
+    includes: Box<[String]>,
+    excludes: Box<[String]>,
+    #[allocative(skip)]
+    include_set: Option<GlobSet>,
+    #[allocative(skip)]
+    exclude_set: Option<GlobSet>,
+}
+
+impl ArchiveFilter {
+    pub(crate) fn new(
+        strip_prefix: Option<&str>,
+        includes: Vec<String>,
+        excludes: Vec<String>,
+    ) -> anyhow::Result<Self> {
+        let strip_prefix = strip_prefix
+            .map(|p| {
+                ForwardRelativePath::new_trim_trailing_slashes(p)
+                    .map(|p| p.to_buf())
+                    .with_context(|| ExtractArchiveActionError::InvalidPath(p.to_owned()))
+            })
+            .transpose()?;
+
+        fn glob_set(globs: &[String]) -> anyhow::Result<Option<GlobSet>> {
+            if globs.is_empty() {
+                return Ok(None);
+            }
+            let mut builder = GlobSetBuilder::new();
+            for glob in globs {
+                builder.add(
+                    Glob::new(glob)
+                        .with_context(|| ExtractArchiveActionError::InvalidGlob(glob.clone()))?,
+                );
+            }
+            Ok(Some(builder.build()?))
+        }
+
+        Ok(Self {
+            strip_prefix,
+            include_set: glob_set(&includes)?,
+            exclude_set: glob_set(&excludes)?,
+            includes: includes.into_boxed_slice(),
+            excludes: excludes.into_boxed_slice(),
+        })
+    }
+
+    /// Where to extract the archive entry at `path`, relative to the output directory, or `None`
+    /// if it should be skipped.
+    fn output_path(&self, path: &str) -> anyhow::Result<Option<ForwardRelativePathBuf>> {
+        let invalid = || ExtractArchiveActionError::InvalidPath(path.to_owned());
+
+        let mut normalized = path;
+        while let Some(rest) = normalized.strip_prefix("./") {
+            normalized = rest;
+        }
+        if normalized.is_empty() || normalized == "." {
+            return Ok(None);
+        }
+        let normalized =
+            ForwardRelativePath::new_trim_trailing_slashes(normalized).with_context(invalid)?;
+
+        let stripped = match &self.strip_prefix {
+            Some(prefix) => match normalized.strip_prefix(prefix).ok() {
+                Some(stripped) if !stripped.is_empty() => stripped,
+                _ => return Ok(None),
+            },
+            None => normalized,
+        };
+
+        if let Some(includes) = &self.include_set {
+            if !includes.is_match(stripped.as_str()) {
+                return Ok(None);
+            }
+        }
+        if let Some(excludes) = &self.exclude_set {
+            if excludes.is_match(stripped.as_str()) {
+                return Ok(None);
+            }
+        }
+
+        Ok(Some(stripped.to_buf()))
+    }
+}
+
+#[derive(Debug, Allocative)]
+pub(crate) struct UnregisteredExtractArchiveAction {
+    format: Option<ArchiveFormat>,
+    filter: ArchiveFilter,
+}
+
+impl UnregisteredExtractArchiveAction {
+    pub(crate) fn new(format: Option<ArchiveFormat>, filter: ArchiveFilter) -> Self {
+        Self { format, filter }
+    }
+}
+
+impl UnregisteredAction for UnregisteredExtractArchiveAction {
+    fn register(
+        self: Box<Self>,
+        inputs: IndexSet<ArtifactGroup>,
+        outputs: IndexSet<BuildArtifact>,
+        _starlark_data: Option<OwnedFrozenValue>,
+    ) -> anyhow::Result<Box<dyn Action>> {
+        Ok(Box::new(ExtractArchiveAction::new(inputs, outputs, *self)?))
+    }
+}
+
+#[derive(Debug, Allocative)]
+struct ExtractArchiveAction {
+    inputs: BoxSliceSet<ArtifactGroup>,
+    outputs: BoxSliceSet<BuildArtifact>,
+    inner: UnregisteredExtractArchiveAction,
+}
+
+impl ExtractArchiveAction {
+    fn new(
+        inputs: IndexSet<ArtifactGroup>,
+        outputs: IndexSet<BuildArtifact>,
+        inner: UnregisteredExtractArchiveAction,
+    ) -> anyhow::Result<Self> {
+        match inputs.iter().into_singleton() {
+            Some(ArtifactGroup::Artifact(..)) => {}
+            Some(other) => {
+                return Err(ExtractArchiveActionError::UnsupportedInput(other.dupe()).into());
+            }
+            None => {
+                return Err(ExtractArchiveActionError::WrongNumberOfInputs(inputs.len()).into());
+            }
+        };
+
+        if outputs.len() != 1 {
+            return Err(ExtractArchiveActionError::WrongNumberOfOutputs(outputs.len()).into());
+        }
+
+        Ok(Self {
+            inputs: BoxSliceSet::from(inputs),
+            outputs: BoxSliceSet::from(outputs),
+            inner,
+        })
+    }
+
+    fn input(&self) -> &ArtifactGroup {
+        self.inputs
+            .iter()
+            .next()
+            .expect("a single input by construction")
+    }
+
+    fn output(&self) -> &BuildArtifact {
+        self.outputs
+            .iter()
+            .next()
+            .expect("a single artifact by construction")
+    }
+}
+
+#[async_trait]
+impl Action for ExtractArchiveAction {
+    fn kind(&self) -> buck2_data::ActionKind {
+        buck2_data::ActionKind::ExtractArchive
+    }
+
+    fn inputs(&self) -> anyhow::Result<Cow<'_, [ArtifactGroup]>> {
+        Ok(Cow::Borrowed(self.inputs.as_slice()))
+    }
+
+    fn outputs(&self) -> anyhow::Result<Cow<'_, [BuildArtifact]>> {
+        Ok(Cow::Borrowed(self.outputs.as_slice()))
+    }
+
+    fn as_executable(&self) -> ActionExecutable<'_> {
+        ActionExecutable::Incremental(self)
+    }
+
+    fn category(&self) -> &Category {
+        static EXTRACT_ARCHIVE_CATEGORY: Lazy<Category> =
+            Lazy::new(|| Category::try_from("extract_archive").unwrap());
+
+        &EXTRACT_ARCHIVE_CATEGORY
+    }
+
+    fn identifier(&self) -> Option<&str> {
+        Some(self.output().get_path().path().as_str())
+    }
+
+    fn aquery_attributes(
+        &self,
+        _fs: &buck2_execute::execute::request::ExecutorFs,
+    ) -> IndexMap<String, String> {
+        let filter = &self.inner.filter;
+        indexmap::indexmap! {
+            "format".to_owned() => match self.inner.format {
+                Some(format) => format.to_string(),
+                None => "None".to_owned(),
+            },
+            "strip_prefix".to_owned() => match &filter.strip_prefix {
+                Some(prefix) => prefix.to_string(),
+                None => "None".to_owned(),
+            },
+            "includes".to_owned() => format!("{:?}", filter.includes),
+            "excludes".to_owned() => format!("{:?}", filter.excludes),
+        }
+    }
+}
+
+#[async_trait]
+impl IncrementalActionExecutable for ExtractArchiveAction {
+    async fn execute(
+        &self,
+        ctx: &mut dyn ActionExecutionCtx,
+    ) -> anyhow::Result<(ActionOutputs, ActionExecutionMetadata)> {
+        let (input, _) = ctx
+            .artifact_values(self.input())
+            .iter()
+            .into_singleton()
+            .context("Input did not dereference to exactly one artifact")?;
+        let src = input.resolve_path(ctx.fs())?;
+        let format = match self.inner.format {
+            Some(format) => format,
+            None => ArchiveFormat::from_file_name(src.as_str())?,
+        };
+
+        ctx.materializer()
+            .ensure_materialized(vec![src.clone()])
+            .await?;
+        ctx.cleanup_outputs().await?;
+
+        let artifact_fs = ctx.fs();
+        let fs = artifact_fs.fs();
+        let dest = artifact_fs.resolve_build(self.output().get_path());
+        let digest_config = ctx.digest_config();
+
+        let value = ctx
+            .blocking_executor()
+            .execute_io_inline(|| {
+                let extracted = extract(
+                    &fs.resolve(&src),
+                    format,
+                    &self.inner.filter,
+                    &fs.resolve(&dest),
+                    digest_config.cas_digest_config(),
+                )
+                .with_context(|| format!("Error extracting `{}`", src))?;
+
+                let mut builder = ActionDirectoryBuilder::empty();
+                builder.insert(&dest, DirectoryEntry::Dir(extracted))?;
+                Ok(extract_artifact_value(&builder, &dest, digest_config)?
+                    .unwrap_or_else(|| ArtifactValue::dir(digest_config.empty_directory())))
+            })
+            .await?;
+
+        ctx.materializer()
+            .declare_existing(vec![(dest, value.dupe())])
+            .await?;
+
+        Ok((
+            ActionOutputs::from_single(self.output().get_path().dupe(), value),
+            ActionExecutionMetadata {
+                execution_kind: ActionExecutionKind::Simple,
+                timing: ActionExecutionTimingData::default(),
+            },
+        ))
+    }
+}
+
+/// Extract `archive` into `dest`, which must not exist, and return what we extracted, relative
+/// to `dest`.
+fn extract(
+    archive: &AbsNormPath,
+    format: ArchiveFormat,
+    filter: &ArchiveFilter,
+    dest: &AbsNormPath,
+    digest_config: CasDigestConfig,
+) -> anyhow::Result<ActionDirectoryBuilder> {
+    fs_util::create_dir_all(dest)?;
+    let mut extractor = Extractor {
+        dest,
+        digest_config,
+        builder: ActionDirectoryBuilder::empty(),
+        files: HashMap::new(),
+    };
+
+    let file = File::open(archive).with_context(|| format!("open({})", archive))?;
+    match format {
+        ArchiveFormat::Tar => extractor.extract_tar(file, filter)?,
+        ArchiveFormat::TarGz => {
+            extractor.extract_tar(flate2::read::GzDecoder::new(file), filter)?
+        }
+        ArchiveFormat::TarZst => {
+            extractor.extract_tar(zstd::stream::read::Decoder::new(file)?, filter)?
+        }
+        ArchiveFormat::TarXz => extractor.extract_tar(xz2::read::XzDecoder::new(file), filter)?,
+        ArchiveFormat::Zip => extractor.extract_zip(file, filter)?,
+    }
+
+    // Symlinks are checked as they are extracted, but a symlink can also be made to escape by
+    // symlinks that come after it in the archive, so check them all again.
+    for (path, entry) in extractor.builder.unordered_walk().with_paths() {
+        if let DirectoryEntry::Leaf(ActionDirectoryMember::Symlink(symlink)) = entry {
+            let target = symlink.target().as_str();
+            if symlink_escapes(&extractor.builder, &path, target)? {
+                return Err(ExtractArchiveActionError::SymlinkEscapes(
+                    path.to_string(),
+                    target.to_owned(),
+                )
+                .into());
+            }
+        }
+    }
+
+    Ok(extractor.builder)
+}
+
+struct Extractor<'a> {
+    dest: &'a AbsNormPath,
+    digest_config: CasDigestConfig,
+    builder: ActionDirectoryBuilder,
+    /// The files we extracted, which hard links may point to.
+    files: HashMap<ForwardRelativePathBuf, FileMetadata>,
+}
+
+impl Extractor<'_> {
+    fn extract_tar(&mut self, reader: impl Read, filter: &ArchiveFilter) -> anyhow::Result<()> {
+        let mut archive = tar::Archive::new(reader);
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let entry_type = entry.header().entry_type();
+            // Metadata headers (pax extensions and GNU long names) describe the entries that
+            // follow them rather than files to extract. `tar` applies most of them itself, but
+            // global pax headers and stray extension headers are still yielded.
+            if matches!(
+                entry_type,
+                tar::EntryType::XGlobalHeader
+                    | tar::EntryType::XHeader
+                    | tar::EntryType::GNULongName
+                    | tar::EntryType::GNULongLink
+            ) {
+                continue;
+            }
+
+            let entry_path = entry.path()?.to_string_lossy().into_owned();
+            let path = match filter.output_path(&entry_path)? {
+                Some(path) => path,
+                None => continue,
+            };
+
+            match entry_type {
+                tar::EntryType::Regular | tar::EntryType::Continuous => {
+                    let executable = entry.header().mode()? & 0o111 != 0;
+                    self.add_file(&path, &mut entry, executable)?;
+                }
+                tar::EntryType::Directory => self.add_dir(&path)?,
+                tar::EntryType::Symlink => {
+                    let target = entry
+                        .link_name()?
+                        .with_context(|| format!("Symlink `{}` has no target", entry_path))?
+                        .to_string_lossy()
+                        .into_owned();
+                    self.add_symlink(&path, &target)?;
+                }
+                tar::EntryType::Link => {
+                    let target = entry
+                        .link_name()?
+                        .with_context(|| format!("Hard link `{}` has no target", entry_path))?
+                        .to_string_lossy()
+                        .into_owned();
+                    let meta = filter
+                        .output_path(&target)?
+                        .and_then(|p| Some((self.files.get(&p)?.dupe(), p)));
+                    let (meta, target_path) = match meta {
+                        Some(found) => found,
+                        None => {
+                            return Err(ExtractArchiveActionError::HardLinkTargetMissing(
+                                entry_path, target,
+                            )
+                            .into());
+                        }
+                    };
+                    let mut file = self.create_file(&path)?;
+                    let mut source = fs_util::open_file(self.dest.join(&target_path))?;
+                    std::io::copy(&mut source, &mut file)
+                        .with_context(|| format!("copy({} -> {})", target_path, path))?;
+                    if meta.is_executable {
+                        fs_util::set_executable(self.dest.join(&path))?;
+                    }
+                    self.insert_file(&path, meta)?;
+                }
+                other => {
+                    return Err(ExtractArchiveActionError::UnsupportedEntryType(
+                        entry_path,
+                        format!("{:?}", other),
+                    )
+                    .into());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn extract_zip(&mut self, file: File, filter: &ArchiveFilter) -> anyhow::Result<()> {
+        const S_IFMT: u32 = 0o170000;
+        const S_IFLNK: u32 = 0o120000;
+
+        let mut archive = zip::ZipArchive::new(file)?;
+        for i in 0..archive.len() {
+            let mut entry = archive.by_index(i)?;
+            let path = match filter.output_path(entry.name())? {
+                Some(path) => path,
+                None => continue,
+            };
+
+            let mode = entry.unix_mode();
+            if entry.is_dir() {
+                self.add_dir(&path)?;
+            } else if mode.map_or(false, |m| m & S_IFMT == S_IFLNK) {
+                let mut target = String::new();
+                entry.read_to_string(&mut target)?;
+                self.add_symlink(&path, &target)?;
+            } else {
+                let executable = mode.map_or(false, |m| m & 0o111 != 0);
+                self.add_file(&path, &mut entry, executable)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn add_file(
+        &mut self,
+        path: &ForwardRelativePath,
+        reader: &mut impl Read,
+        executable: bool,
+    ) -> anyhow::Result<()> {
+        let abs_path = self.dest.join(path);
+        let mut file = self.create_file(path)?;
+        let mut digester = FileDigest::digester(self.digest_config);
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            let n = reader.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            digester.update(&buf[..n]);
+            file.write_all(&buf[..n])
+                .with_context(|| format!("write({})", abs_path))?;
+        }
+        file.flush()
+            .with_context(|| format!("flush({})", abs_path))?;
+        if executable {
+            fs_util::set_executable(&abs_path)?;
+        }
+
+        let meta = FileMetadata {
+            digest: TrackedFileDigest::new(digester.finalize(), self.digest_config),
+            is_executable: executable,
+        };
+        self.insert_file(path, meta)
+    }
+
+    /// Create a new file at `path` to write to. Whatever we extracted there before is replaced
+    /// rather than written through, since it may be a symlink pointing anywhere.
+    fn create_file(&self, path: &ForwardRelativePath) -> anyhow::Result<File> {
+        self.check_not_through_leaf(path)?;
+        let abs_path = self.dest.join(path);
+        if let Some(dir) = abs_path.parent() {
+            fs_util::create_dir_all(dir)?;
+        }
+
+        match fs_util::symlink_metadata_if_exists(&abs_path)? {
+            Some(meta) if !meta.is_dir() => fs_util::remove_file(&abs_path)?,
+            _ => {}
+        }
+        // `create_new` also refuses to follow a symlink at `abs_path`, in case one was created
+        // since we checked.
+        OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&abs_path)
+            .with_context(|| format!("create_file({})", abs_path))
+    }
+
+    fn insert_file(
+        &mut self,
+        path: &ForwardRelativePath,
+        meta: FileMetadata,
+    ) -> anyhow::Result<()> {
+        self.files.insert(path.to_buf(), meta.dupe());
+        self.builder.insert(
+            path,
+            DirectoryEntry::Leaf(ActionDirectoryMember::File(meta)),
+        )?;
+        Ok(())
+    }
+
+    fn add_dir(&mut self, path: &ForwardRelativePath) -> anyhow::Result<()> {
+        self.check_not_through_leaf(path)?;
+        fs_util::create_dir_all(self.dest.join(path))?;
+        // Don't replace what we already extracted into the directory.
+        if find(&self.builder, path)?.is_none() {
+            self.builder.mkdir(path)?;
+        }
+        Ok(())
+    }
+
+    /// Writing to `path` would follow a symlink we extracted if any of its parents is one, and
+    /// could then write outside of the output directory.
+    fn check_not_through_leaf(&self, path: &ForwardRelativePath) -> anyhow::Result<()> {
+        match find(&self.builder, path) {
+            Ok(_) => Ok(()),
+            Err(DirectoryFindError::CannotTraverseLeaf { .. }) => {
+                Err(ExtractArchiveActionError::PathThroughLeaf(path.to_string()).into())
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn add_symlink(&mut self, path: &ForwardRelativePath, target: &str) -> anyhow::Result<()> {
+        self.check_not_through_leaf(path)?;
+        if symlink_escapes(&self.builder, path, target)? {
+            return Err(ExtractArchiveActionError::SymlinkEscapes(
+                path.to_string(),
+                target.to_owned(),
+            )
+            .into());
+        }
+
+        let abs_path = self.dest.join(path);
+        if let Some(dir) = abs_path.parent() {
+            fs_util::create_dir_all(dir)?;
+        }
+        fs_util::symlink(target, &abs_path)?;
+        self.builder
+            .insert(path, DirectoryEntry::Leaf(new_symlink(target)?))?;
+        Ok(())
+    }
+}
+
+/// Whether the symlink at `path` (relative to the output directory) pointing to `target` points
+/// outside of the output directory. Symlinks in `builder` that the target goes through are
+/// followed, since e.g. `b -> a/..` escapes if `a -> .`.
+fn symlink_escapes(
+    builder: &ActionDirectoryBuilder,
+    path: &ForwardRelativePath,
+    target: &str,
+) -> anyhow::Result<bool> {
+    // Like the kernel, give up on symlink loops.
+    const MAX_SYMLINK_HOPS: usize = 40;
+
+    fn is_absolute(target: &str) -> bool {
+        target.starts_with('/') || target.starts_with('\\') || target.contains(':')
+    }
+
+    fn components(target: &str) -> impl Iterator<Item = String> + '_ {
+        target.split(['/', '\\']).map(str::to_owned)
+    }
+
+    if is_absolute(target) {
+        return Ok(true);
+    }
+
+    // The directory we're in, starting with the one the symlink is in.
+    let mut dir: Vec<String> = path.iter().map(|c| c.as_str().to_owned()).collect();
+    dir.pop();
+    let mut pending: VecDeque<String> = components(target).collect();
+    let mut hops = 0;
+    while let Some(component) = pending.pop_front() {
+        match component.as_str() {
+            "" | "." => {}
+            ".." => {
+                if dir.pop().is_none() {
+                    return Ok(true);
+                }
+            }
+            name => {
+                dir.push(name.to_owned());
+                // We only go through this component if there's more to resolve. If it's the last
+                // one and a symlink, that symlink is checked separately.
+                if pending.iter().all(|c| c.is_empty() || c == ".") {
+                    continue;
+                }
+                let dir_path = ForwardRelativePathBuf::new(dir.join("/"))?;
+                let symlink = match find(builder, &*dir_path) {
+                    Ok(Some(DirectoryEntry::Leaf(ActionDirectoryMember::Symlink(s)))) => s.dupe(),
+                    Ok(_) => continue,
+                    // We went through a file, which won't resolve anyway.
+                    Err(DirectoryFindError::CannotTraverseLeaf { .. }) => continue,
+                    Err(e) => return Err(e.into()),
+                };
+                hops += 1;
+                let link_target = symlink.target().as_str();
+                if hops > MAX_SYMLINK_HOPS || is_absolute(link_target) {
+                    return Ok(true);
+                }
+                dir.pop();
+                for c in components(link_target)
+                    .collect::<Vec<_>>()
+                    .into_iter()
+                    .rev()
+                {
+                    pending.push_front(c);
+                }
+            }
+        }
+    }
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use buck2_common::cas_digest::testing;
+    use buck2_core::fs::paths::abs_norm_path::AbsNormPathBuf;
+    use buck2_core::fs::project::ProjectRoot;
+    use buck2_core::fs::project::ProjectRootTemp;
+    use buck2_core::fs::project_rel_path::ProjectRelativePath;
+
+    use super::*;
+
+    fn path(p: &str) -> ForwardRelativePathBuf {
+        ForwardRelativePathBuf::unchecked_new(p.to_owned())
+    }
+
+    #[test]
+    fn test_output_path() -> anyhow::Result<()> {
+        let filter = ArchiveFilter::new(
+            Some("pkg-1.0/"),
+            vec!["src/**".to_owned(), "LICENSE".to_owned()],
+            vec!["**/*_test.c".to_owned()],
+        )?;
+
+        assert_eq!(
+            Some(path("src/a.c")),
+            filter.output_path("./pkg-1.0/src/a.c")?
+        );
+        assert_eq!(
+            Some(path("LICENSE")),
+            filter.output_path("pkg-1.0/LICENSE")?
+        );
+        assert_eq!(None, filter.output_path("pkg-1.0/src/a_test.c")?);
+        assert_eq!(None, filter.output_path("pkg-1.0/README")?);
+        assert_eq!(None, filter.output_path("pkg-1.0/")?);
+        assert_eq!(None, filter.output_path("other/src/a.c")?);
+        assert!(filter.output_path("pkg-1.0/../../etc/passwd").is_err());
+        assert!(filter.output_path("/etc/passwd").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_symlink_escapes() -> anyhow::Result<()> {
+        let mut builder = ActionDirectoryBuilder::empty();
+        assert!(!symlink_escapes(&builder, &path("a/b"), "c")?);
+        assert!(!symlink_escapes(&builder, &path("a/b"), "../c")?);
+        assert!(symlink_escapes(&builder, &path("a/b"), "../../c")?);
+        assert!(symlink_escapes(&builder, &path("a"), "/etc/passwd")?);
+
+        builder.insert(&path("a"), DirectoryEntry::Leaf(new_symlink(".")?))?;
+        assert!(!symlink_escapes(&builder, &path("b"), "a")?);
+        assert!(symlink_escapes(&builder, &path("b"), "a/..")?);
+        assert!(symlink_escapes(&builder, &path("b"), "a/a/a/..")?);
+
+        builder.insert(&path("loop"), DirectoryEntry::Leaf(new_symlink("loop/x")?))?;
+        assert!(symlink_escapes(&builder, &path("b"), "loop/x")?);
+
+        Ok(())
+    }
+
+    fn write_tar(
+        fs: &ProjectRoot,
+        entries: &[(&str, tar::EntryType, &str)],
+    ) -> anyhow::Result<AbsNormPathBuf> {
+        let mut tar = tar::Builder::new(Vec::new());
+        for (name, entry_type, contents) in entries {
+            let mut header = tar::Header::new_gnu();
+            header.set_entry_type(*entry_type);
+            header.set_mode(0o644);
+            if matches!(entry_type, tar::EntryType::Symlink | tar::EntryType::Link) {
+                header.set_link_name(contents)?;
+                header.set_size(0);
+                header.set_cksum();
+                tar.append_data(&mut header, name, std::io::empty())?;
+            } else {
+                header.set_size(contents.len() as u64);
+                header.set_cksum();
+                tar.append_data(&mut header, name, contents.as_bytes())?;
+            }
+        }
+        let archive = fs.resolve(ProjectRelativePath::unchecked_new("archive.tar"));
+        fs_util::write(&archive, tar.into_inner()?)?;
+        Ok(archive)
+    }
+
+    #[test]
+    fn test_extract_tar_rejects_symlink_chains() -> anyhow::Result<()> {
+        let temp = ProjectRootTemp::new()?;
+        let fs = temp.path();
+        let filter = ArchiveFilter::new(None, Vec::new(), Vec::new())?;
+
+        for (i, entries) in [
+            vec![
+                ("a", tar::EntryType::Symlink, "."),
+                ("b", tar::EntryType::Symlink, "a/.."),
+                ("b/x", tar::EntryType::Regular, "x"),
+            ],
+            // Writing through a symlink that doesn't escape is rejected too.
+            vec![
+                ("a", tar::EntryType::Symlink, "."),
+                ("a/x", tar::EntryType::Regular, "x"),
+            ],
+            // The escaping symlink comes before the one that makes it escape.
+            vec![
+                ("b", tar::EntryType::Symlink, "a/.."),
+                ("a", tar::EntryType::Symlink, "."),
+            ],
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            let archive = write_tar(fs, &entries)?;
+            let dest = fs.resolve(ProjectRelativePath::unchecked_new(&format!("out{}", i)));
+            assert!(
+                extract(
+                    &archive,
+                    ArchiveFormat::Tar,
+                    &filter,
+                    &dest,
+                    testing::sha1()
+                )
+                .is_err(),
+                "{:?}",
+                entries
+            );
+            assert!(!fs_util::try_exists(
+                fs.resolve(ProjectRelativePath::unchecked_new("x"))
+            )?);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_extract_tar_does_not_write_through_symlinks() -> anyhow::Result<()> {
+        let temp = ProjectRootTemp::new()?;
+        let fs = temp.path();
+        let filter = ArchiveFilter::new(None, Vec::new(), Vec::new())?;
+
+        // Neither symlink escapes when it's extracted, but once `c` exists, `a` points to
+        // `out/../victim`. The file `a` must replace the symlink rather than write through it.
+        for (i, last) in [
+            ("a", tar::EntryType::Regular, "x"),
+            ("a", tar::EntryType::Link, "f"),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            let archive = write_tar(
+                fs,
+                &[
+                    ("f", tar::EntryType::Regular, "x"),
+                    ("a", tar::EntryType::Symlink, "c/../victim"),
+                    ("d/", tar::EntryType::Directory, ""),
+                    ("c", tar::EntryType::Symlink, "d/.."),
+                    last,
+                ],
+            )?;
+            let dest = fs.resolve(ProjectRelativePath::unchecked_new(&format!("out{}", i)));
+            extract(
+                &archive,
+                ArchiveFormat::Tar,
+                &filter,
+                &dest,
+                testing::sha1(),
+            )?;
+            assert!(!fs_util::try_exists(
+                fs.resolve(ProjectRelativePath::unchecked_new("victim"))
+            )?);
+            assert!(!fs_util::symlink_metadata(dest.join(&path("a")))?.is_symlink());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_extract_tar_hard_link_creates_parents() -> anyhow::Result<()> {
+        let temp = ProjectRootTemp::new()?;
+        let fs = temp.path();
+        let filter = ArchiveFilter::new(None, Vec::new(), Vec::new())?;
+
+        let archive = write_tar(
+            fs,
+            &[
+                ("f", tar::EntryType::Regular, "x"),
+                ("sub/dir/link", tar::EntryType::Link, "f"),
+            ],
+        )?;
+        let dest = fs.resolve(ProjectRelativePath::unchecked_new("out"));
+        extract(
+            &archive,
+            ArchiveFormat::Tar,
+            &filter,
+            &dest,
+            testing::sha1(),
+        )?;
+        assert_eq!(
+            "x",
+            fs_util::read_to_string(dest.join(&path("sub/dir/link")))?
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_extract_tar_skips_metadata_headers() -> anyhow::Result<()> {
+        let temp = ProjectRootTemp::new()?;
+        let fs = temp.path();
+        let filter = ArchiveFilter::new(None, Vec::new(), Vec::new())?;
+
+        // `git archive` starts its tarballs with a global pax header.
+        let archive = write_tar(
+            fs,
+            &[
+                (
+                    "pax_global_header",
+                    tar::EntryType::XGlobalHeader,
+                    "52 comment=0123456789abcdef0123456789abcdef01234567\n",
+                ),
+                ("a.txt", tar::EntryType::Regular, "a"),
+            ],
+        )?;
+        let dest = fs.resolve(ProjectRelativePath::unchecked_new("out"));
+        let extracted = extract(
+            &archive,
+            ArchiveFormat::Tar,
+            &filter,
+            &dest,
+            testing::sha1(),
+        )?;
+
+        let paths: Vec<_> = extracted
+            .unordered_walk()
+            .with_paths()
+            .map(|(p, _)| p.to_string())
+            .collect();
+        assert_eq!(vec!["a.txt".to_owned()], paths);
+        assert!(!fs_util::try_exists(dest.join(&path("pax_global_header")))?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_extract_tar() -> anyhow::Result<()> {
+        let temp = ProjectRootTemp::new()?;
+        let fs = temp.path();
+
+        let mut tar = tar::Builder::new(Vec::new());
+        for (name, contents, mode) in [
+            ("pkg/bin/tool", "#!/bin/sh\n", 0o755),
+            ("pkg/lib/a.txt", "a", 0o644),
+        ] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_mode(mode);
+            header.set_cksum();
+            tar.append_data(&mut header, name, contents.as_bytes())?;
+        }
+        let archive = fs.resolve(ProjectRelativePath::unchecked_new("archive.tar"));
+        fs_util::write(&archive, tar.into_inner()?)?;
+
+        let dest = fs.resolve(ProjectRelativePath::unchecked_new("out"));
+        let filter = ArchiveFilter::new(Some("pkg"), Vec::new(), Vec::new())?;
+        let extracted = extract(
+            &archive,
+            ArchiveFormat::Tar,
+            &filter,
+            &dest,
+            testing::sha1(),
+        )?;
+
+        let mut files = Vec::new();
+        for (p, entry) in extracted.unordered_walk().with_paths() {
+            if let DirectoryEntry::Leaf(ActionDirectoryMember::File(meta)) = entry {
+                files.push((p.to_string(), meta.is_executable));
+            }
+        }
+        files.sort();
+        assert_eq!(
+            vec![
+                ("bin/tool".to_owned(), true),
+                ("lib/a.txt".to_owned(), false)
+            ],
+            files
+        );
+        assert_eq!("a", fs_util::read_to_string(dest.join(&path("lib/a.txt")))?);
+
+        Ok(())
+    }
+}
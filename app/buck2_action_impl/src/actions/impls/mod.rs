@@ -9,7 +9,9 @@
 
 pub(crate) mod cas_artifact;
 pub(crate) mod copy;
+pub(crate) mod create_archive;
 pub(crate) mod download_file;
+pub(crate) mod extract_archive;
 pub mod run;
 pub(crate) mod symlinked_dir;
 pub(crate) mod write;
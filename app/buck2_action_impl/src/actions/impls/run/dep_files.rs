@@ -8,6 +8,7 @@
  */
 
 use std::borrow::Cow;
+use std::collections::BTreeMap;
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::fmt::Display;
@@ -21,6 +22,7 @@ use buck2_build_api::actions::artifact::build_artifact::BuildArtifact;
 use buck2_build_api::actions::execute::action_execution_target::ActionExecutionTarget;
 use buck2_build_api::actions::execute::action_executor::ActionOutputs;
 use buck2_build_api::actions::impls::dep_files::FLUSH_DEP_FILES;
+use buck2_build_api::actions::impls::dep_files::INITIALIZE_DEP_FILES_STATE;
 use buck2_build_api::actions::impls::expanded_command_line::ExpandedCommandLineDigest;
 use buck2_build_api::actions::ActionExecutionCtx;
 use buck2_build_api::artifact_groups::ArtifactGroup;
@@ -28,19 +30,24 @@ use buck2_build_api::deferred::base_deferred_key::BaseDeferredKey;
 use buck2_build_api::interpreter::rule_defs::artifact_tagging::ArtifactTag;
 use buck2_build_api::interpreter::rule_defs::cmd_args::CommandLineArtifactVisitor;
 use buck2_common::file_ops::FileDigest;
+use buck2_common::file_ops::FileMetadata;
 use buck2_common::file_ops::TrackedFileDigest;
 use buck2_core::category::Category;
+use buck2_core::directory::DirectoryEntry;
 use buck2_core::directory::DirectorySelector;
 use buck2_core::directory::FingerprintedDirectory;
 use buck2_core::env_helper::EnvHelper;
 use buck2_core::fs::artifact_path_resolver::ArtifactFs;
 use buck2_core::fs::fs_util;
+use buck2_core::fs::paths::abs_norm_path::AbsNormPathBuf;
 use buck2_core::fs::project_rel_path::ProjectRelativePath;
 use buck2_core::soft_error;
 use buck2_execute::artifact::artifact_dyn::ArtifactDyn;
+use buck2_execute::artifact_value::ArtifactValue;
 use buck2_execute::digest_config::DigestConfig;
 use buck2_execute::directory::expand_selector_for_dependencies;
 use buck2_execute::directory::ActionDirectoryBuilder;
+use buck2_execute::directory::ActionDirectoryMember;
 use buck2_execute::directory::ActionImmutableDirectory;
 use buck2_execute::directory::ActionSharedDirectory;
 use buck2_execute::directory::INTERNER;
@@ -51,16 +58,28 @@ use dashmap::DashMap;
 use derive_more::Display;
 use dupe::Dupe;
 use futures::StreamExt;
+use indexmap::IndexMap;
 use once_cell::sync::Lazy;
+use once_cell::sync::OnceCell;
 use parking_lot::MappedMutexGuard;
 use parking_lot::Mutex;
 use parking_lot::MutexGuard;
 use thiserror::Error;
 use tracing::instrument;
 
+use crate::actions::impls::run::dep_files_sqlite::DepFilesSqliteDb;
+use crate::actions::impls::run::dep_files_sqlite::PersistedDepFileState;
+use crate::actions::impls::run::dep_files_sqlite::PersistedDigest;
+use crate::actions::impls::run::dep_files_sqlite::PersistedFile;
+use crate::actions::impls::run::dep_files_sqlite::PersistedFingerprints;
+
 #[allocative::root]
 static DEP_FILES: Lazy<DashMap<DepFilesKey, Arc<DepFileState>>> = Lazy::new(DashMap::new);
 
+/// Where dep file states are persisted, so that they survive daemon restarts. States found there
+/// are restored when an action doesn't have one in `DEP_FILES`.
+static DEP_FILES_DB: OnceCell<DepFilesSqliteDb> = OnceCell::new();
+
 /// When this is set, we retain directories after fingerprintig, so that we can output them later
 /// for debugging via `buck2 audit dep-files`.
 static KEEP_DIRECTORIES: EnvHelper<bool> = EnvHelper::new("BUCK2_KEEP_DEP_FILE_DIRECTORIES");
@@ -69,11 +88,28 @@ static KEEP_DIRECTORIES: EnvHelper<bool> = EnvHelper::new("BUCK2_KEEP_DEP_FILE_D
 /// file was produced and the user wants unblocking, this will provide it.
 fn flush_dep_files() {
     DEP_FILES.clear();
+    if let Some(db) = DEP_FILES_DB.get() {
+        if let Err(e) = db.clear() {
+            tracing::warn!("Error flushing persisted dep files: {:#}", e);
+        }
+    }
+}
+
+fn initialize_dep_files_state(
+    dep_files_state_dir: AbsNormPathBuf,
+    versions: HashMap<String, String>,
+) -> anyhow::Result<()> {
+    let db = DepFilesSqliteDb::initialize(dep_files_state_dir, versions)?;
+    if DEP_FILES_DB.set(db).is_err() {
+        return Err(DepFilesStateError::AlreadyInitialized.into());
+    }
+    Ok(())
 }
 
 #[ctor]
 fn set_flush_dep_files() {
     FLUSH_DEP_FILES.init(flush_dep_files);
+    INITIALIZE_DEP_FILES_STATE.init(initialize_dep_files_state);
 }
 
 pub fn get_dep_files(key: &DepFilesKey) -> Option<Arc<DepFileState>> {
@@ -81,7 +117,7 @@ pub fn get_dep_files(key: &DepFilesKey) -> Option<Arc<DepFileState>> {
 }
 
 /// A key used to associate a RunAction with a possible previous dep file.
-#[derive(Eq, PartialEq, Hash, Display, Allocative)]
+#[derive(Clone, Eq, PartialEq, Hash, Display, Allocative)]
 #[display(
     fmt = "{} {} {}",
     owner,
@@ -125,6 +161,11 @@ enum DepFileStateInputSignatures {
     /// Computed represents the case where we have produced the input signatures. We only do this
     /// once at most.
     Computed(StoredFingerprints),
+
+    /// Unavailable means this state was restored from disk, where it was persisted before its
+    /// signatures were computed. We no longer have the input directories to compute them, so only
+    /// an unchanged input directory can match this state.
+    Unavailable,
 }
 
 #[derive(Allocative)]
@@ -197,22 +238,28 @@ impl DepFileState {
         match *self.input_signatures.lock() {
             DepFileStateInputSignatures::Computed(..) => true,
             DepFileStateInputSignatures::Deferred(..) => false,
+            DepFileStateInputSignatures::Unavailable => false,
         }
     }
 
     /// Compute the signature for this DepFileState, having provided the dep files from
-    /// read_dep_files.
+    /// read_dep_files. Returns None if the signatures are unavailable because this state was
+    /// restored from disk before they were computed.
     pub fn locked_compute_fingerprints<'a>(
         &'a self,
         dep_files: Cow<'_, ConcreteDepFiles>,
         keep_directories: bool,
         digest_config: DigestConfig,
-    ) -> MappedMutexGuard<'a, StoredFingerprints> {
+    ) -> Option<MappedMutexGuard<'a, StoredFingerprints>> {
         // Now we need to know the signatures on the original action. Produce them if they're
         // missing. We're either storing input directories or outputs here.
 
         let mut guard = self.input_signatures.lock();
 
+        if let DepFileStateInputSignatures::Unavailable = *guard {
+            return None;
+        }
+
         if let DepFileStateInputSignatures::Deferred(ref mut directories) = *guard {
             let directories = directories
                 .take()
@@ -230,10 +277,200 @@ impl DepFileState {
             *guard = DepFileStateInputSignatures::Computed(fingerprints);
         }
 
-        MutexGuard::map(guard, |v| match v {
+        Some(MutexGuard::map(guard, |v| match v {
             DepFileStateInputSignatures::Computed(signatures) => signatures,
             DepFileStateInputSignatures::Deferred(..) => unreachable!(),
-        })
+            DepFileStateInputSignatures::Unavailable => unreachable!(),
+        }))
+    }
+
+    /// The representation of this state on disk. Returns None if this state can't be persisted.
+    fn to_persisted(&self, fs: &ArtifactFs) -> anyhow::Result<Option<PersistedDepFileState>> {
+        let mut outputs = BTreeMap::new();
+        for (path, value) in self.result.iter() {
+            // Restoring directories or symlinks to other artifacts would require persisting their
+            // whole tree, so we only persist the state of actions that only output files.
+            let file = match (value.entry(), value.deps()) {
+                (DirectoryEntry::Leaf(ActionDirectoryMember::File(file)), None) => file,
+                _ => return Ok(None),
+            };
+            outputs.insert(
+                fs.buck_out_path_resolver().resolve_gen(path).to_string(),
+                PersistedFile {
+                    digest: PersistedDigest::new(file.digest.data()),
+                    is_executable: file.is_executable,
+                },
+            );
+        }
+
+        let fingerprints = match &*self.input_signatures.lock() {
+            DepFileStateInputSignatures::Computed(fingerprints) => {
+                let fingerprints = match fingerprints {
+                    StoredFingerprints::Digests(digests) => Cow::Borrowed(digests),
+                    StoredFingerprints::Dirs(dirs) => Cow::Owned(dirs.as_fingerprints()),
+                };
+                Some(PersistedFingerprints {
+                    untagged: PersistedDigest::new(fingerprints.untagged.data()),
+                    tagged: fingerprints
+                        .tagged
+                        .iter()
+                        .map(|(label, digest)| {
+                            ((**label).to_owned(), PersistedDigest::new(digest.data()))
+                        })
+                        .collect(),
+                })
+            }
+            DepFileStateInputSignatures::Deferred(..) => None,
+            DepFileStateInputSignatures::Unavailable => None,
+        };
+
+        Ok(Some(PersistedDepFileState {
+            cli: PersistedDepFileState::encode_cli(self.digests.cli.as_bytes()),
+            directory: PersistedDigest::new(&self.digests.directory),
+            fingerprints,
+            dep_files: self.declared_dep_files.resolve(fs)?,
+            outputs,
+        }))
+    }
+
+    /// Restore a state persisted by `to_persisted` for an action declaring these outputs and dep
+    /// files. Returns None if the action's declarations don't match those of the persisted state.
+    fn from_persisted(
+        persisted: PersistedDepFileState,
+        declared_outputs: &[BuildArtifact],
+        declared_dep_files: &DeclaredDepFiles,
+        fs: &ArtifactFs,
+        digest_config: DigestConfig,
+    ) -> anyhow::Result<Option<Self>> {
+        if declared_dep_files.resolve(fs)? != persisted.dep_files {
+            return Ok(None);
+        }
+
+        let mut result = IndexMap::with_capacity(declared_outputs.len());
+        for output in declared_outputs {
+            let path = fs.buck_out_path_resolver().resolve_gen(output.get_path());
+            let file = match persisted.outputs.get(path.as_str()) {
+                Some(file) => file,
+                None => return Ok(None),
+            };
+            result.insert(
+                output.get_path().dupe(),
+                ArtifactValue::file(FileMetadata {
+                    digest: file.digest.to_tracked(digest_config)?,
+                    is_executable: file.is_executable,
+                }),
+            );
+        }
+
+        let input_signatures = match &persisted.fingerprints {
+            Some(fingerprints) => DepFileStateInputSignatures::Computed(
+                StoredFingerprints::Digests(PartitionedInputs {
+                    untagged: fingerprints.untagged.to_tracked(digest_config)?,
+                    tagged: fingerprints
+                        .tagged
+                        .iter()
+                        .map(|(label, digest)| {
+                            Ok((Arc::from(label.as_str()), digest.to_tracked(digest_config)?))
+                        })
+                        .collect::<anyhow::Result<_>>()?,
+                }),
+            ),
+            None => DepFileStateInputSignatures::Unavailable,
+        };
+
+        Ok(Some(Self {
+            digests: CommandDigests {
+                cli: ExpandedCommandLineDigest::from_bytes(persisted.decode_cli()?),
+                directory: persisted.directory.to_file_digest()?,
+            },
+            input_signatures: Mutex::new(input_signatures),
+            declared_dep_files: declared_dep_files.clone(),
+            result: ActionOutputs::new(result),
+        }))
+    }
+}
+
+/// Write this dep file state to disk, or delete the persisted state if it can't be persisted.
+/// Failing to persist the state is not an error, since it only makes builds after a restart slower.
+async fn persist_dep_file_state(
+    key: &DepFilesKey,
+    state: &DepFileState,
+    ctx: &dyn ActionExecutionCtx,
+) {
+    let db = match DEP_FILES_DB.get() {
+        Some(db) => db,
+        None => return,
+    };
+
+    let res: anyhow::Result<()> = try {
+        let key = key.to_string();
+        let persisted = state.to_persisted(ctx.fs())?;
+        ctx.blocking_executor()
+            .execute_io_inline(|| match persisted {
+                Some(persisted) => db.insert(&key, &persisted),
+                None => db.delete(&key),
+            })
+            .await?;
+    };
+
+    if let Err(e) = res {
+        tracing::warn!("Error persisting dep file state for `{}`: {:#}", key, e);
+    }
+}
+
+/// Restore the dep file state persisted for this key, if any, and make it the current state.
+async fn restore_dep_file_state(
+    key: &DepFilesKey,
+    declared_outputs: &[BuildArtifact],
+    declared_dep_files: &DeclaredDepFiles,
+    ctx: &dyn ActionExecutionCtx,
+) -> Option<Arc<DepFileState>> {
+    let db = DEP_FILES_DB.get()?;
+
+    let res: anyhow::Result<Option<DepFileState>> = try {
+        let key = key.to_string();
+        let persisted = ctx
+            .blocking_executor()
+            .execute_io_inline(|| db.get(&key))
+            .await?;
+        match persisted {
+            Some(persisted) => DepFileState::from_persisted(
+                persisted,
+                declared_outputs,
+                declared_dep_files,
+                ctx.fs(),
+                ctx.digest_config(),
+            )?,
+            None => None,
+        }
+    };
+
+    match res {
+        Ok(state) => {
+            let state = Arc::new(state?);
+            DEP_FILES.insert(key.clone(), state.dupe());
+            Some(state)
+        }
+        Err(e) => {
+            tracing::warn!("Error restoring dep file state for `{}`: {:#}", key, e);
+            None
+        }
+    }
+}
+
+/// Forget the dep file state for this key, both in memory and on disk.
+async fn remove_dep_file_state(key: &DepFilesKey, ctx: &dyn ActionExecutionCtx) {
+    DEP_FILES.remove(key);
+
+    if let Some(db) = DEP_FILES_DB.get() {
+        let key = key.to_string();
+        let res = ctx
+            .blocking_executor()
+            .execute_io_inline(|| db.delete(&key))
+            .await;
+        if let Err(e) = res {
+            tracing::warn!("Error deleting dep file state for `{}`: {:#}", key, e);
+        }
     }
 }
 
@@ -275,9 +512,16 @@ pub(crate) async fn match_or_clear_dep_file(
 ) -> anyhow::Result<Option<ActionOutputs>> {
     let previous_state = match get_dep_files(key) {
         Some(d) => d.dupe(),
-        None => return Ok(None),
+        None => {
+            match restore_dep_file_state(key, declared_outputs, declared_dep_files, ctx).await {
+                Some(d) => d,
+                None => return Ok(None),
+            }
+        }
     };
 
+    let had_signatures = previous_state.has_signatures();
+
     if dep_files_match(
         &previous_state,
         digests,
@@ -307,12 +551,16 @@ pub(crate) async fn match_or_clear_dep_file(
 
         if materializer_accepts {
             tracing::trace!("Dep files are a hit");
+            if !had_signatures && previous_state.has_signatures() {
+                // Persist the signatures we just computed, so they are available after a restart.
+                persist_dep_file_state(key, &previous_state, ctx).await;
+            }
             return Ok(Some(previous_state.result.dupe()));
         }
     }
 
     tracing::trace!("Dep files are a miss");
-    DEP_FILES.remove(key);
+    remove_dep_file_state(key, ctx).await;
     Ok(None)
 }
 
@@ -346,6 +594,11 @@ async fn dep_files_match(
         return Ok(true);
     }
 
+    if let DepFileStateInputSignatures::Unavailable = *previous_state.input_signatures.lock() {
+        tracing::trace!("Dep files miss: Directory has changed and signatures are unavailable");
+        return Ok(false);
+    }
+
     let dep_files = previous_state
         .read_dep_files(ctx.fs(), ctx.materializer())
         .await
@@ -371,11 +624,14 @@ async fn dep_files_match(
     let fingerprints_match = {
         // NOTE: We don't bother releasing the guard here (we'd have to clone the fingerprints to do
         // so), because this Mutex won't be contended: only one action will look at its value.
-        let previous_fingerprints = previous_state.locked_compute_fingerprints(
+        let previous_fingerprints = match previous_state.locked_compute_fingerprints(
             Cow::Borrowed(&dep_files),
             KEEP_DIRECTORIES.get_copied()?.unwrap_or_default(),
             digest_config,
-        );
+        ) {
+            Some(previous_fingerprints) => previous_fingerprints,
+            None => return Ok(false),
+        };
 
         // NOTE: We use the new directory to e.g. resolve symlinks referenced in the dep file. This
         // makes sense: if a path in the depfile is still a symlink, then we'll compare the new
@@ -442,6 +698,7 @@ pub(crate) async fn populate_dep_files(
         ));
     }
 
+    persist_dep_file_state(&key, &state, ctx).await;
     DEP_FILES.insert(key, Arc::new(state));
 
     Ok(())
@@ -627,7 +884,7 @@ struct DeclaredDepFile {
 }
 
 /// All the dep files declared by a command;
-#[derive(Default, Debug, Allocative)]
+#[derive(Default, Debug, Clone, Allocative)]
 pub(crate) struct DeclaredDepFiles {
     tagged: HashMap<ArtifactTag, DeclaredDepFile>,
 }
@@ -763,6 +1020,19 @@ impl DeclaredDepFiles {
         Ok(Some(ConcreteDepFiles { contents }))
     }
 
+    /// The paths of these dep files, by label.
+    fn resolve(&self, fs: &ArtifactFs) -> anyhow::Result<BTreeMap<String, String>> {
+        self.tagged
+            .values()
+            .map(|d| {
+                Ok((
+                    (*d.label).to_owned(),
+                    d.output.resolve_path(fs)?.to_string(),
+                ))
+            })
+            .collect()
+    }
+
     /// Returns whether two DeclaredDepFile instances have the same dep files. This ignores the tag
     /// identity, but it requires the same paths declared using the same name. This is a
     /// pre-requisite for being able to reuse dep files from a previous invocation.
@@ -773,6 +1043,12 @@ impl DeclaredDepFiles {
     }
 }
 
+#[derive(Error, Debug)]
+enum DepFilesStateError {
+    #[error("Dep files state was already initialized")]
+    AlreadyInitialized,
+}
+
 #[derive(Error, Debug)]
 enum MaterializeDepFilesError {
     #[error("Error materializing dep file")]
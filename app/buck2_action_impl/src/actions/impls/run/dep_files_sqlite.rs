@@ -0,0 +1,238 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+//! On-disk storage for dep file state, so that it survives daemon restarts.
+
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+
+use anyhow::Context;
+use buck2_common::cas_digest::DigestAlgorithmKind;
+use buck2_common::file_ops::FileDigest;
+use buck2_common::file_ops::TrackedFileDigest;
+use buck2_common::sqlite::open_versioned_sqlite_db;
+use buck2_common::sqlite::KeyValueSqliteTable;
+use buck2_core::fs::paths::abs_norm_path::AbsNormPathBuf;
+use buck2_execute::digest_config::DigestConfig;
+use serde::Deserialize;
+use serde::Serialize;
+use thiserror::Error;
+
+/// Hand-maintained schema version for the dep files state sqlite db. PLEASE bump this version if
+/// you are making a breaking change to `PersistedDepFileState`.
+const DB_SCHEMA_VERSION: u64 = 1;
+
+const STATE_TABLE: &str = "dep_files_state";
+
+#[derive(Error, Debug)]
+enum DepFilesSqliteDbError {
+    #[error("Invalid command line digest: `{0}`")]
+    InvalidCommandLineDigest(String),
+}
+
+/// A digest, in a form that can be serialized.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub(crate) struct PersistedDigest {
+    algorithm: u8,
+    hash: String,
+    size: u64,
+}
+
+impl PersistedDigest {
+    pub(crate) fn new(digest: &FileDigest) -> Self {
+        Self {
+            algorithm: digest.raw_digest().algorithm() as _,
+            hash: hex::encode(digest.raw_digest().as_bytes()),
+            size: digest.size(),
+        }
+    }
+
+    pub(crate) fn to_file_digest(&self) -> anyhow::Result<FileDigest> {
+        let algorithm: DigestAlgorithmKind = self
+            .algorithm
+            .try_into()
+            .with_context(|| format!("Invalid digest algorithm: `{}`", self.algorithm))?;
+        let hash = hex::decode(&self.hash)
+            .with_context(|| format!("Invalid digest hash: `{}`", self.hash))?;
+        FileDigest::from_digest_bytes(algorithm, &hash, self.size)
+    }
+
+    pub(crate) fn to_tracked(
+        &self,
+        digest_config: DigestConfig,
+    ) -> anyhow::Result<TrackedFileDigest> {
+        Ok(TrackedFileDigest::new(
+            self.to_file_digest()?,
+            digest_config.cas_digest_config(),
+        ))
+    }
+}
+
+/// An output file of an action.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub(crate) struct PersistedFile {
+    pub(crate) digest: PersistedDigest,
+    pub(crate) is_executable: bool,
+}
+
+/// The fingerprints of the inputs that were selected by the dep files, by tag label.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub(crate) struct PersistedFingerprints {
+    pub(crate) untagged: PersistedDigest,
+    pub(crate) tagged: BTreeMap<String, PersistedDigest>,
+}
+
+/// The on-disk representation of a `DepFileState`. Paths are project relative, since artifacts
+/// can't be restored from disk: they are matched against those of the action being run instead.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub(crate) struct PersistedDepFileState {
+    /// The hex-encoded digest of the command line.
+    pub(crate) cli: String,
+    pub(crate) directory: PersistedDigest,
+    /// Missing if the fingerprints had not been computed yet when the state was persisted.
+    pub(crate) fingerprints: Option<PersistedFingerprints>,
+    /// The path of each dep file, by label.
+    pub(crate) dep_files: BTreeMap<String, String>,
+    /// The outputs of the action, by path. We only persist state for actions that output files.
+    pub(crate) outputs: BTreeMap<String, PersistedFile>,
+}
+
+impl PersistedDepFileState {
+    pub(crate) fn encode_cli(cli: &[u8; 32]) -> String {
+        hex::encode(cli)
+    }
+
+    pub(crate) fn decode_cli(&self) -> anyhow::Result<[u8; 32]> {
+        hex::decode(&self.cli)
+            .ok()
+            .and_then(|cli| cli.try_into().ok())
+            .ok_or_else(|| DepFilesSqliteDbError::InvalidCommandLineDigest(self.cli.clone()).into())
+    }
+}
+
+/// DB that holds the dep file state of actions, keyed by their `DepFilesKey`.
+pub(crate) struct DepFilesSqliteDb {
+    state_table: KeyValueSqliteTable,
+}
+
+impl DepFilesSqliteDb {
+    /// Open the db in `dep_files_state_dir`. If it doesn't exist, can't be read or has a different
+    /// set of versions, it is deleted and an empty one is created in its place.
+    pub(crate) fn initialize(
+        dep_files_state_dir: AbsNormPathBuf,
+        mut versions: HashMap<String, String>,
+    ) -> anyhow::Result<Self> {
+        versions.insert("schema_version".to_owned(), DB_SCHEMA_VERSION.to_string());
+        // Like the materializer state, this is a cache: if it gets corrupted, we lose it and
+        // rebuild, which is better than syncing to disk after every action.
+        let connection = open_versioned_sqlite_db(&dep_files_state_dir, versions, &[STATE_TABLE])?;
+        Ok(Self {
+            state_table: KeyValueSqliteTable::new(STATE_TABLE.to_owned(), connection),
+        })
+    }
+
+    pub(crate) fn get(&self, key: &str) -> anyhow::Result<Option<PersistedDepFileState>> {
+        match self.state_table.get(key)? {
+            Some(state) => {
+                Ok(Some(serde_json::from_str(&state).with_context(|| {
+                    format!("Invalid dep files state for `{}`", key)
+                })?))
+            }
+            None => Ok(None),
+        }
+    }
+
+    pub(crate) fn insert(&self, key: &str, state: &PersistedDepFileState) -> anyhow::Result<()> {
+        self.state_table.insert_all(HashMap::from([(
+            key.to_owned(),
+            serde_json::to_string(state)?,
+        )]))
+    }
+
+    pub(crate) fn delete(&self, key: &str) -> anyhow::Result<()> {
+        self.state_table.delete(key)
+    }
+
+    pub(crate) fn clear(&self) -> anyhow::Result<()> {
+        self.state_table.delete_all()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use buck2_core::fs::project::ProjectRootTemp;
+    use buck2_core::fs::project_rel_path::ProjectRelativePath;
+
+    use super::*;
+
+    fn testing_state() -> PersistedDepFileState {
+        let digest =
+            PersistedDigest::new(DigestConfig::testing_default().empty_file().digest.data());
+
+        PersistedDepFileState {
+            cli: PersistedDepFileState::encode_cli(&[7; 32]),
+            directory: digest.clone(),
+            fingerprints: Some(PersistedFingerprints {
+                untagged: digest.clone(),
+                tagged: BTreeMap::from([("headers".to_owned(), digest.clone())]),
+            }),
+            dep_files: BTreeMap::from([("headers".to_owned(), "buck-out/v2/gen/foo.d".to_owned())]),
+            outputs: BTreeMap::from([(
+                "buck-out/v2/gen/foo.o".to_owned(),
+                PersistedFile {
+                    digest,
+                    is_executable: false,
+                },
+            )]),
+        }
+    }
+
+    #[test]
+    fn test_persisted_digest_roundtrip() -> anyhow::Result<()> {
+        let digest = DigestConfig::testing_default().empty_file().digest;
+        assert_eq!(
+            digest,
+            PersistedDigest::new(digest.data()).to_tracked(DigestConfig::testing_default())?
+        );
+        assert_eq!([7; 32], testing_state().decode_cli()?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_dep_files_sqlite_db() -> anyhow::Result<()> {
+        let fs = ProjectRootTemp::new()?;
+        let dir = fs
+            .path()
+            .resolve(ProjectRelativePath::unchecked_new("dep_files_state"));
+        let versions = HashMap::from([("digest_config".to_owned(), "sha1".to_owned())]);
+
+        let db = DepFilesSqliteDb::initialize(dir.clone(), versions.clone())?;
+        assert_eq!(None, db.get("key")?);
+        db.insert("key", &testing_state())?;
+        assert_eq!(Some(testing_state()), db.get("key")?);
+        drop(db);
+
+        // Reopening with the same versions keeps the state.
+        let db = DepFilesSqliteDb::initialize(dir.clone(), versions)?;
+        assert_eq!(Some(testing_state()), db.get("key")?);
+        db.delete("key")?;
+        assert_eq!(None, db.get("key")?);
+        db.insert("key", &testing_state())?;
+        drop(db);
+
+        // Reopening with different versions discards it.
+        let db = DepFilesSqliteDb::initialize(
+            dir,
+            HashMap::from([("digest_config".to_owned(), "blake3".to_owned())]),
+        )?;
+        assert_eq!(None, db.get("key")?);
+
+        Ok(())
+    }
+}
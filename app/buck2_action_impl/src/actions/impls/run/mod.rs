@@ -9,6 +9,7 @@
 
 use std::borrow::Cow;
 use std::fmt::Display;
+use std::time::Duration;
 
 use allocative::Allocative;
 use async_trait::async_trait;
@@ -30,6 +31,7 @@ use buck2_build_api::interpreter::rule_defs::cmd_args::CommandLineArtifactVisito
 use buck2_build_api::interpreter::rule_defs::cmd_args::DefaultCommandLineContext;
 use buck2_build_api::interpreter::rule_defs::cmd_args::SimpleCommandLineArtifactVisitor;
 use buck2_build_api::interpreter::rule_defs::cmd_args::ValueAsCommandLineLike;
+use buck2_build_api::interpreter::rule_defs::provider::builtin::worker_info::WorkerInfo;
 use buck2_core::category::Category;
 use buck2_core::directory::FingerprintedDirectory;
 use buck2_core::fs::buck_out_path::BuckOutPath;
@@ -43,6 +45,8 @@ use buck2_execute::execute::request::CommandExecutionOutput;
 use buck2_execute::execute::request::CommandExecutionPaths;
 use buck2_execute::execute::request::CommandExecutionRequest;
 use buck2_execute::execute::request::ExecutorPreference;
+use buck2_execute::execute::request::ResourceLimits;
+use buck2_execute::execute::request::WorkerSpec;
 use dupe::Dupe;
 use gazebo::prelude::*;
 use host_sharing::HostSharingRequirements;
@@ -66,6 +70,7 @@ use crate::actions::impls::run::dep_files::RunActionDepFiles;
 use crate::actions::impls::run::metadata::metadata_content;
 
 pub mod dep_files;
+mod dep_files_sqlite;
 mod metadata;
 
 #[derive(Debug, Error)]
@@ -144,6 +149,11 @@ pub(crate) struct UnregisteredRunAction {
     pub(crate) no_outputs_cleanup: bool,
     pub(crate) allow_cache_upload: bool,
     pub(crate) force_full_hybrid_if_capable: bool,
+    pub(crate) resource_limits: ResourceLimits,
+    /// In bytes.
+    pub(crate) memory_estimate: Option<u64>,
+    pub(crate) timeout: Option<Duration>,
+    pub(crate) allow_network: Option<bool>,
 }
 
 impl UnregisteredAction for UnregisteredRunAction {
@@ -172,10 +182,11 @@ impl RunAction {
     ) -> Option<(
         &dyn CommandLineArgLike,
         Vec<(&str, &dyn CommandLineArgLike)>,
+        Option<&WorkerInfo>,
     )> {
-        // We expect (CmdArgs, Option<Dict<String, CmdArgs>>) in the Starlark value
-        let (cli, env) = match TupleRef::from_value(args.value())?.content() {
-            [cli, env] => (*cli, *env),
+        // We expect (CmdArgs, Option<Dict<String, CmdArgs>>, Option<WorkerInfo>) in the Starlark value
+        let (cli, env, worker) = match TupleRef::from_value(args.value())?.content() {
+            [cli, env, worker] => (*cli, *env, *worker),
             _ => return None,
         };
         let cli = cli.as_command_line()?;
@@ -189,7 +200,12 @@ impl RunAction {
             }
             res
         };
-        Some((cli, env))
+        let worker = if worker.is_none() {
+            None
+        } else {
+            Some(WorkerInfo::from_value(worker)?)
+        };
+        Some((cli, env, worker))
     }
 
     /// Get the command line expansion for this RunAction.
@@ -201,7 +217,13 @@ impl RunAction {
         let mut cli_rendered = Vec::<String>::new();
         let mut ctx = DefaultCommandLineContext::new(fs);
 
-        let (cli, env) = Self::unpack(&self.starlark_cli).unwrap();
+        let (cli, env, worker) = Self::unpack(&self.starlark_cli).unwrap();
+        if let Some(worker) = worker {
+            worker
+                .exe()
+                .add_to_command_line(&mut cli_rendered, &mut ctx)?;
+            worker.exe().visit_artifacts(artifact_visitor)?;
+        }
         cli.add_to_command_line(&mut cli_rendered, &mut ctx)?;
         cli.visit_artifacts(artifact_visitor)?;
 
@@ -251,6 +273,7 @@ impl RunAction {
         let fs = ctx.fs();
 
         let expanded = self.expand_command_line(&ctx.executor_fs(), visitor)?;
+        let worker = self.worker_spec(&ctx.executor_fs())?;
 
         // TODO (@torozco): At this point, might as well just receive the list already. Finding
         // those things in a HashMap is just not very useful.
@@ -301,14 +324,35 @@ impl RunAction {
             expanded,
             extra_env,
             paths,
+            worker,
         })
     }
+
+    /// The worker that can run this action, if any. Its `exe` is the beginning of the command
+    /// line of the action.
+    fn worker_spec(&self, fs: &ExecutorFs) -> anyhow::Result<Option<WorkerSpec>> {
+        let (_, _, worker) = Self::unpack(&self.starlark_cli).unwrap();
+        let worker = match worker {
+            Some(worker) => worker,
+            None => return Ok(None),
+        };
+
+        let mut exe = Vec::<String>::new();
+        worker
+            .exe()
+            .add_to_command_line(&mut exe, &mut DefaultCommandLineContext::new(fs))?;
+        Ok(Some(WorkerSpec {
+            exe,
+            concurrency: worker.concurrency(),
+        }))
+    }
 }
 
 struct PreparedRunAction {
     expanded: ExpandedCommandLine,
     extra_env: Option<(String, String)>,
     paths: CommandExecutionPaths,
+    worker: Option<WorkerSpec>,
 }
 
 impl PreparedRunAction {
@@ -317,13 +361,14 @@ impl PreparedRunAction {
             expanded: ExpandedCommandLine { cli, mut env },
             extra_env,
             paths,
+            worker,
         } = self;
 
         for (k, v) in extra_env.into_iter() {
             env.insert(k, v);
         }
 
-        CommandExecutionRequest::new(cli, paths, env)
+        CommandExecutionRequest::new(cli, paths, env).with_worker(worker)
     }
 }
 
@@ -358,8 +403,11 @@ impl Action for RunAction {
     }
 
     fn inputs(&self) -> anyhow::Result<Cow<'_, [ArtifactGroup]>> {
-        let (cli, env) = Self::unpack(&self.starlark_cli).unwrap();
+        let (cli, env, worker) = Self::unpack(&self.starlark_cli).unwrap();
         let mut artifact_visitor = SimpleCommandLineArtifactVisitor::new();
+        if let Some(worker) = worker {
+            worker.exe().visit_artifacts(&mut artifact_visitor)?;
+        }
         cli.visit_artifacts(&mut artifact_visitor)?;
         for (_, v) in env.iter() {
             v.visit_artifacts(&mut artifact_visitor)?;
@@ -388,14 +436,28 @@ impl Action for RunAction {
     }
 
     fn aquery_attributes(&self, fs: &ExecutorFs) -> indexmap::IndexMap<String, String> {
-        let mut cli_rendered = Vec::<String>::new();
-        let mut ctx = DefaultCommandLineContext::new(fs);
-        let (cli, _env) = Self::unpack(&self.starlark_cli).unwrap();
-        cli.add_to_command_line(&mut cli_rendered, &mut ctx)
-            .unwrap();
-        let cmd = format!("[{}]", cli_rendered.iter().join(", "));
+        // Attributes are only displayed, so render errors instead of failing the query.
+        let (cmd, env) =
+            match self.expand_command_line(fs, &mut SimpleCommandLineArtifactVisitor::new()) {
+                Ok(expanded) => (
+                    format!("[{}]", expanded.cli.iter().join(", ")),
+                    format!(
+                        "{{{}}}",
+                        expanded
+                            .env
+                            .iter()
+                            .map(|(k, v)| format!("{}={}", k, v))
+                            .join(", ")
+                    ),
+                ),
+                Err(e) => {
+                    let error = format!("<error: {:#}>", e);
+                    (error.clone(), error)
+                }
+            };
         indexmap! {
             "cmd".to_owned() => cmd,
+            "env".to_owned() => env,
             "executor_preference".to_owned() => self.inner.executor_preference.to_string(),
             "always_print_stderr".to_owned() => self.inner.always_print_stderr.to_string(),
             "weight".to_owned() => self.inner.weight.to_string(),
@@ -405,8 +467,32 @@ impl Action for RunAction {
                 Some(x) => x.to_string(),
             },
             "no_outputs_cleanup".to_owned() => self.inner.no_outputs_cleanup.to_string(),
+            "worker".to_owned() => match self.worker_spec(fs) {
+                Ok(Some(worker)) => format!("[{}]", worker.exe.iter().join(", ")),
+                _ => "None".to_owned(),
+            },
+            "resource_limits".to_owned() => format!("{:?}", self.inner.resource_limits),
+            "memory_estimate".to_owned() => match self.inner.memory_estimate {
+                None => "None".to_owned(),
+                Some(x) => x.to_string(),
+            },
+            "timeout".to_owned() => match self.inner.timeout {
+                None => "None".to_owned(),
+                Some(x) => format!("{}s", x.as_secs()),
+            },
+            "allow_network".to_owned() => match self.inner.allow_network {
+                None => "None".to_owned(),
+                Some(x) => x.to_string(),
+            },
         }
     }
+
+    fn aquery_command_line(&self, fs: &ExecutorFs) -> anyhow::Result<Option<ExpandedCommandLine>> {
+        Ok(Some(self.expand_command_line(
+            fs,
+            &mut SimpleCommandLineArtifactVisitor::new(),
+        )?))
+    }
 }
 
 #[async_trait]
@@ -480,7 +566,7 @@ impl IncrementalActionExecutable for RunAction {
         // Run actions are assumed to be shared
         let host_sharing_requirements = HostSharingRequirements::Shared(self.inner.weight);
 
-        let req = prepared
+        let mut req = prepared
             .into_command_execution_request()
             .with_prefetch_lossy_stderr(true)
             .with_executor_preference(self.inner.executor_preference)
@@ -489,7 +575,13 @@ impl IncrementalActionExecutable for RunAction {
             .with_allow_cache_upload(self.inner.allow_cache_upload)
             .with_local_environment_inheritance(EnvironmentInheritance::local_command_exclusions())
             .with_force_full_hybrid_if_capable(self.inner.force_full_hybrid_if_capable)
+            .with_resource_limits(self.inner.resource_limits)
+            .with_memory_estimate(self.inner.memory_estimate)
+            .with_allow_network(self.inner.allow_network)
             .with_custom_tmpdir(ctx.target().custom_tmpdir());
+        if let Some(timeout) = self.inner.timeout {
+            req = req.with_timeout(timeout);
+        }
 
         let (outputs, meta) = ctx.exec_cmd(&req).await?;
 
@@ -9,7 +9,9 @@
 
 use std::collections::hash_map::Entry;
 use std::collections::HashMap;
+use std::str::FromStr;
 use std::sync::Arc;
+use std::time::Duration;
 
 use anyhow::Context;
 use buck2_build_api::actions::artifact::artifact_type::OutputArtifact;
@@ -30,12 +32,16 @@ use buck2_build_api::interpreter::rule_defs::cmd_args::ValueAsCommandLineLike;
 use buck2_build_api::interpreter::rule_defs::cmd_args::WriteToFileMacroVisitor;
 use buck2_build_api::interpreter::rule_defs::context::AnalysisActions;
 use buck2_build_api::interpreter::rule_defs::context::REGISTER_CONTEXT_ACTIONS;
+use buck2_build_api::interpreter::rule_defs::provider::builtin::worker_info::WorkerInfo;
 use buck2_common::cas_digest::CasDigest;
 use buck2_common::executor_config::RemoteExecutorUseCase;
 use buck2_core::category::Category;
 use buck2_core::collections::ordered_set::OrderedSet;
+use buck2_core::fs::buck_out_path::BuckOutPathKind;
+use buck2_core::fs::paths::forward_rel_path::ForwardRelativePath;
 use buck2_core::fs::paths::forward_rel_path::ForwardRelativePathBuf;
 use buck2_execute::execute::request::OutputType;
+use buck2_execute::execute::request::ResourceLimits;
 use buck2_execute::materialize::http::Checksum;
 use buck2_interpreter::starlark_promise::StarlarkPromise;
 use buck2_interpreter_for_build::rule::FrozenRuleCallable;
@@ -71,7 +77,11 @@ use crate::actions::impls::cas_artifact::DirectoryKind;
 use crate::actions::impls::cas_artifact::UnregisteredCasArtifactAction;
 use crate::actions::impls::copy::CopyMode;
 use crate::actions::impls::copy::UnregisteredCopyAction;
+use crate::actions::impls::create_archive::UnregisteredCreateArchiveAction;
 use crate::actions::impls::download_file::UnregisteredDownloadFileAction;
+use crate::actions::impls::extract_archive::ArchiveFilter;
+use crate::actions::impls::extract_archive::ArchiveFormat;
+use crate::actions::impls::extract_archive::UnregisteredExtractArchiveAction;
 use crate::actions::impls::run::dep_files::RunActionDepFiles;
 use crate::actions::impls::run::new_executor_preference;
 use crate::actions::impls::run::MetadataParameter;
@@ -131,6 +141,12 @@ enum RunActionError {
         "Recursion limit exceeded when visiting artifacts: do you have a cycle in your inputs or outputs?"
     )]
     ArtifactVisitRecursionLimitExceeded,
+    #[error("`worker` must be a `WorkerInfo`, got `{0}`")]
+    InvalidWorker(String),
+    #[error("`{0}` must be a positive integer, got `{1}`")]
+    InvalidResourceLimit(&'static str, i32),
+    #[error("`timeout_seconds` must be a positive integer, got `{0}`")]
+    InvalidTimeout(i32),
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -197,11 +213,13 @@ fn register_context_actions(builder: &mut MethodsBuilder) {
     /// Returns an `artifact` with the name filename, which when asked for its name, will return filename (which may include a directory portion)
     ///
     /// * `prefix` (optional): provides a silent part of the filename, which can be used to disambiguate but whose presence will not be visible to anyone using the `artifact`. By default, outputs are considered files; pass `dir = True` to indicate it is a directory
+    /// * `configuration_independent` (optional): places the output at a path that doesn't include the target's configuration, so that the target builds it at the same path in every configuration (including the execution configuration), which avoids duplicate outputs for things like shared codegen. Within a build, the action producing it only runs in the first configuration that needs it, and the other configurations reuse its outputs, so only use this for outputs whose contents don't depend on the configuration. All the outputs of that action must be configuration-independent: the build fails if two configurations declare the same configuration-independent output for actions that also have per-configuration outputs
     fn declare_output<'v>(
         this: &AnalysisActions<'v>,
         #[starlark(require = pos)] prefix: &str,
         #[starlark(require = pos)] filename: Option<&str>,
         #[starlark(require = named, default = false)] dir: bool,
+        #[starlark(require = named, default = false)] configuration_independent: bool,
         eval: &mut Evaluator<'v, '_>,
     ) -> anyhow::Result<StarlarkDeclaredArtifact> {
         // We take either one or two positional arguments, namely (filename) or (prefix, filename).
@@ -217,11 +235,17 @@ fn register_context_actions(builder: &mut MethodsBuilder) {
         } else {
             OutputType::FileOrDirectory
         };
+        let path_kind = if configuration_independent {
+            BuckOutPathKind::ConfigurationIndependent
+        } else {
+            BuckOutPathKind::Configuration
+        };
         let artifact = this.state().declare_output(
             prefix,
             filename,
             output_type,
             eval.call_stack_top_location(),
+            path_kind,
         )?;
 
         Ok(StarlarkDeclaredArtifact::new(
@@ -376,6 +400,7 @@ fn register_context_actions(builder: &mut MethodsBuilder) {
                     &format!("{}/{}.macro", &macro_directory_path, i),
                     OutputType::File,
                     eval.call_stack_top_location(),
+                    BuckOutPathKind::default(),
                 )?;
                 written_macro_files.insert(macro_file);
             }
@@ -524,6 +549,86 @@ fn register_context_actions(builder: &mut MethodsBuilder) {
         create_dir_tree(eval, this, output, srcs, true)
     }
 
+    /// Extracts the `archive` artifact into a directory at `output` (which can be a string representing a filename or an output `artifact`) and returns the output `artifact`.
+    ///
+    /// * `format`: one of `tar`, `tar.gz`, `tar.zst`, `tar.xz` or `zip`; if unset, it is inferred from the extension of `archive`
+    /// * `strip_prefix`: a directory within the archive to extract instead of the whole archive; entries outside of it are skipped
+    /// * `includes` and `excludes`: globs matched against paths relative to the output (after applying `strip_prefix`); only entries matching one of `includes` (if any are given) and none of `excludes` are extracted
+    fn extract_archive<'v>(
+        this: &AnalysisActions<'v>,
+        #[starlark(require = pos)] output: Value<'v>,
+        #[starlark(require = pos)] archive: Value<'v>,
+        #[starlark(require = named, default = NoneOr::None)] format: NoneOr<&str>,
+        #[starlark(require = named, default = NoneOr::None)] strip_prefix: NoneOr<&str>,
+        #[starlark(require = named, default = Vec::new())] includes: Vec<String>,
+        #[starlark(require = named, default = Vec::new())] excludes: Vec<String>,
+        eval: &mut Evaluator<'v, '_>,
+    ) -> anyhow::Result<Value<'v>> {
+        let archive = archive
+            .as_artifact()
+            .ok_or_else(|| ValueError::IncorrectParameterTypeNamed("archive".to_owned()))?;
+        let format = format
+            .into_option()
+            .map(ArchiveFormat::from_str)
+            .transpose()?;
+        let filter = ArchiveFilter::new(strip_prefix.into_option(), includes, excludes)?;
+
+        let (artifact, associated_artifacts) =
+            archive.get_bound_artifact_and_associated_artifacts()?;
+        let mut this = this.state();
+        let (declaration, output_artifact) =
+            this.get_or_declare_output(eval, output, "output", OutputType::Directory)?;
+
+        this.register_action(
+            indexset![ArtifactGroup::Artifact(artifact)],
+            indexset![output_artifact],
+            UnregisteredExtractArchiveAction::new(format, filter),
+            None,
+        )?;
+
+        Ok(declaration.into_declared_artifact(associated_artifacts.dupe()))
+    }
+
+    /// Creates an archive at `output` (which can be a string representing a filename or an output `artifact`) and returns the output `artifact`.
+    /// The srcs must be a dictionary of path (as string, relative to the root of the archive) to the bound `artifact`; directories are added recursively.
+    /// The archive is reproducible: entries are sorted by path, owned by root, with fixed timestamps, and with permissions `0755` for directories and executables and `0644` for other files.
+    ///
+    /// * `format`: one of `tar`, `tar.gz`, `tar.zst`, `tar.xz` or `zip`; if unset, it is inferred from the extension of `output`. Zip archives cannot contain symlinks
+    fn create_archive<'v>(
+        this: &AnalysisActions<'v>,
+        #[starlark(require = pos)] output: Value<'v>,
+        #[starlark(require = pos)] srcs: DictOf<'v, &'v str, Value<'v>>,
+        #[starlark(require = named, default = NoneOr::None)] format: NoneOr<&str>,
+        eval: &mut Evaluator<'v, '_>,
+    ) -> anyhow::Result<Value<'v>> {
+        let format = format
+            .into_option()
+            .map(ArchiveFormat::from_str)
+            .transpose()?;
+
+        let mut archive_srcs = Vec::new();
+        let mut associated_artifacts = SmallSet::new();
+        for (path, src) in srcs.collect_entries() {
+            let (artifact, associates) = src
+                .as_artifact()
+                .ok_or_else(|| ValueError::IncorrectParameterTypeNamed("srcs".to_owned()))?
+                .get_bound_artifact_and_associated_artifacts()?;
+            for a in associates.iter() {
+                associated_artifacts.insert(a.dupe());
+            }
+            let path = ForwardRelativePath::new_trim_trailing_slashes(path)?.to_buf();
+            archive_srcs.push((path, ArtifactGroup::Artifact(artifact)));
+        }
+
+        let action = UnregisteredCreateArchiveAction::new(format, archive_srcs);
+        let mut this = this.state();
+        let (declaration, output_artifact) =
+            this.get_or_declare_output(eval, output, "output", OutputType::File)?;
+        this.register_action(action.inputs(), indexset![output_artifact], action, None)?;
+
+        Ok(declaration.into_declared_artifact(Arc::new(OrderedSet::from(associated_artifacts))))
+    }
+
     /// Runs a command
     ///
     /// * `arguments`: must be of type `cmd_args`, or a type convertible to such (such as a list of strings and artifacts) and must contain at least one `.as_output()` artifact
@@ -534,6 +639,11 @@ fn register_context_actions(builder: &mut MethodsBuilder) {
     ///     * `metadata_path`: defines a path relative to the result directory for a file with action metadata, which will be created right before the command will be run.
     ///     * Metadata contains the path relative to the Buck2 project root and hash digest for every action input (this excludes symlinks as they could be resolved by a user script if needed). The resolved path relative to the Buck2 project for the metadata file will be passed to command from arguments, via the environment variable, with its name set by `metadata_env_var`
     ///     * Both `metadata_env_var` and `metadata_path` are useful when making actions behave in an incremental manner (for details, see [Incremental Actions](https://buck2.build/docs/rule_authors/incremental_actions/))
+    /// * `worker`: a `WorkerInfo` whose `exe` is prepended to the arguments; when running locally, the arguments are sent to a persistent instance of the worker instead of starting a new process
+    /// * `memory_limit_mb` and `cpu_limit`: the max memory (in MiB) and number of CPUs the command may use when running locally; only enforced when `buck2.resource_control` is enabled and cgroup v2 is available
+    /// * `memory_estimate_mb`: how much memory (in MiB) the command is expected to use when running locally; with `build.memory_aware_scheduling`, Buck2 holds off running it until that much memory is available (if unset, Buck2 uses what the command used last time it ran, when known)
+    /// * `timeout_seconds`: fail the command if it runs for longer than this, whether it runs locally or remotely
+    /// * `allow_network`: whether the command may access the network when it runs locally; if unset, the execution platform decides (see `block_local_network`). Use `download_file` to fetch things instead where possible
     fn run<'v>(
         this: &AnalysisActions<'v>,
         #[starlark(require = pos)] arguments: Value<'v>,
@@ -553,6 +663,12 @@ fn register_context_actions(builder: &mut MethodsBuilder) {
         #[starlark(require = named, default = false)] no_outputs_cleanup: bool,
         #[starlark(require = named, default = false)] allow_cache_upload: bool,
         #[starlark(require = named, default = false)] force_full_hybrid_if_capable: bool,
+        #[starlark(require = named)] worker: Option<Value<'v>>,
+        #[starlark(require = named)] memory_limit_mb: Option<i32>,
+        #[starlark(require = named)] cpu_limit: Option<i32>,
+        #[starlark(require = named)] memory_estimate_mb: Option<i32>,
+        #[starlark(require = named)] timeout_seconds: Option<i32>,
+        #[starlark(require = named, default = NoneOr::None)] allow_network: NoneOr<bool>,
         eval: &mut Evaluator<'v, '_>,
     ) -> anyhow::Result<NoneType> {
         struct RunCommandArtifactVisitor {
@@ -627,6 +743,22 @@ fn register_context_actions(builder: &mut MethodsBuilder) {
             }
         };
 
+        let positive = |name, value: Option<i32>| match value {
+            Some(v) if v < 1 => Err(RunActionError::InvalidResourceLimit(name, v)),
+            v => Ok(v.map(|v| v as u32)),
+        };
+        let resource_limits = ResourceLimits {
+            memory_bytes: positive("memory_limit_mb", memory_limit_mb)?
+                .map(|mb| u64::from(mb) * 1024 * 1024),
+            cpus: positive("cpu_limit", cpu_limit)?,
+        };
+        let memory_estimate = positive("memory_estimate_mb", memory_estimate_mb)?
+            .map(|mb| u64::from(mb) * 1024 * 1024);
+        let timeout = match timeout_seconds {
+            Some(s) if s < 1 => return Err(RunActionError::InvalidTimeout(s).into()),
+            s => s.map(|s| Duration::from_secs(s as u64)),
+        };
+
         let starlark_env = match env {
             None => Value::new_none(),
             Some(env) => {
@@ -638,6 +770,17 @@ fn register_context_actions(builder: &mut MethodsBuilder) {
             }
         };
 
+        let starlark_worker = match worker {
+            None => Value::new_none(),
+            Some(worker) => {
+                WorkerInfo::from_value(worker)
+                    .ok_or_else(|| RunActionError::InvalidWorker(worker.to_repr()))?
+                    .exe()
+                    .visit_artifacts(&mut artifact_visitor)?;
+                worker
+            }
+        };
+
         let RunCommandArtifactVisitor {
             inner: artifacts,
             tagged_outputs,
@@ -698,7 +841,9 @@ fn register_context_actions(builder: &mut MethodsBuilder) {
         if artifacts.outputs.is_empty() {
             return Err(RunActionError::NoOutputsSpecified.into());
         }
-        let starlark = eval.heap().alloc((starlark_cli, starlark_env));
+        let starlark = eval
+            .heap()
+            .alloc((starlark_cli, starlark_env, starlark_worker));
 
         let action = UnregisteredRunAction {
             category,
@@ -711,6 +856,10 @@ fn register_context_actions(builder: &mut MethodsBuilder) {
             no_outputs_cleanup,
             allow_cache_upload,
             force_full_hybrid_if_capable,
+            resource_limits,
+            memory_estimate,
+            timeout,
+            allow_network: allow_network.into_option(),
         };
         this.state().register_action(
             artifacts.inputs,
@@ -724,10 +873,13 @@ fn register_context_actions(builder: &mut MethodsBuilder) {
     /// Downloads a URL to an output (filename as string or output artifact).
     /// The file at the URL must have the given sha1 or the command will fail.
     /// The optional parameter is_executable indicates whether the resulting file should be marked with executable permissions.
+    /// The optional parameter fallback_urls lists other URLs to download the file from, in order, if downloading from `url` fails.
+    /// Mirrors, credentials and a local download cache can be configured in the `[download]` section of the root buckconfig.
     fn download_file<'v>(
         this: &AnalysisActions<'v>,
         #[starlark(require = pos)] output: Value<'v>,
         #[starlark(require = pos)] url: &str,
+        #[starlark(require = named, default = Vec::new())] fallback_urls: Vec<String>,
         #[starlark(require = named, default = NoneOr::None)] sha1: NoneOr<&str>,
         #[starlark(require = named, default = NoneOr::None)] sha256: NoneOr<&str>,
         #[starlark(require = named, default = false)] is_executable: bool,
@@ -753,7 +905,9 @@ fn register_context_actions(builder: &mut MethodsBuilder) {
             indexset![output_artifact],
             UnregisteredDownloadFileAction::new(
                 checksum,
-                Arc::from(url),
+                std::iter::once(Arc::from(url))
+                    .chain(fallback_urls.into_iter().map(Arc::from))
+                    .collect(),
                 is_executable,
                 is_deferrable,
             ),
@@ -1119,4 +1273,24 @@ mod tests {
             ),
         })
     }
+
+    #[test]
+    fn run_validates_timeout() -> anyhow::Result<()> {
+        let content = indoc!(
+            r#"
+             def test(c):
+                 out = c.actions.declare_output("out")
+                 c.actions.run(["foo", out.as_output()], category = "test_category", timeout_seconds = 0)
+             "#
+        );
+
+        let expect = "`timeout_seconds` must be a positive integer";
+        run_ctx_test(content, |ret| match ret {
+            Err(e) if e.to_string().contains(expect) => Ok(()),
+            _ => panic!(
+                "Expected a specific failure containing `{}`, got {:?}",
+                expect, ret
+            ),
+        })
+    }
 }
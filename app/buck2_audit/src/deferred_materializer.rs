@@ -35,7 +35,18 @@ pub struct DeferredMaterializerCommand {
 #[derive(Debug, clap::Subcommand, serde::Serialize, serde::Deserialize)]
 pub enum DeferredMaterializerSubcommand {
     List,
-    Fsck,
+    Fsck {
+        /// Delete the artifacts that don't match, so that they get rebuilt or re-downloaded by
+        /// the next build that needs them. Artifacts in use by the running daemon are
+        /// re-materialized if they were downloaded or written; others can't be repaired until
+        /// it is restarted with `buck2 kill`.
+        #[clap(long)]
+        repair: bool,
+        /// Also hash file contents. This catches edits that preserve file sizes, but reads every
+        /// materialized file.
+        #[clap(long)]
+        check_digests: bool,
+    },
     Refresh {
         /// Minimum TTL to require for actions.
         #[clap()]
@@ -47,6 +58,8 @@ pub enum DeferredMaterializerSubcommand {
         #[clap(long, default_value = "1")]
         count: usize,
     },
+    /// List the artifacts that the configured GC policy would evict if it ran now.
+    GcPreview,
 }
 
 #[async_trait]
@@ -74,9 +87,12 @@ impl AuditSubcommand for DeferredMaterializerCommand {
                     writeln!(stdout, "{}\t{}", path, entry)?;
                 }
             }
-            DeferredMaterializerSubcommand::Fsck => {
+            DeferredMaterializerSubcommand::Fsck {
+                repair,
+                check_digests,
+            } => {
                 let mut stream = deferred_materializer
-                    .fsck()
+                    .fsck(repair, check_digests)
                     .context("Failed to start iterating")?;
 
                 let mut n = 0;
@@ -111,6 +127,23 @@ impl AuditSubcommand for DeferredMaterializerCommand {
 
                 write!(stdout, "{}", text)?;
             }
+            DeferredMaterializerSubcommand::GcPreview => {
+                let candidates = deferred_materializer
+                    .gc_preview()
+                    .await
+                    .context("Failed to gc_preview")?;
+
+                for (path, entry) in &candidates {
+                    writeln!(stdout, "{}\t{}", path, entry)?;
+                }
+
+                let mut stderr = server_ctx.stderr()?;
+                writeln!(
+                    &mut stderr,
+                    "total artifacts to evict: {}",
+                    candidates.len()
+                )?;
+            }
         }
 
         anyhow::Ok(())
@@ -101,11 +101,13 @@ impl AuditSubcommand for AuditDepFilesCommand {
                     .context("Failed to read dep files")?
                     .context("Dep fils have expired")?;
 
-                let fingerprints = state.locked_compute_fingerprints(
-                    Cow::Owned(dep_files),
-                    true,
-                    ctx.global_data().get_digest_config(),
-                );
+                let fingerprints = state
+                    .locked_compute_fingerprints(
+                        Cow::Owned(dep_files),
+                        true,
+                        ctx.global_data().get_digest_config(),
+                    )
+                    .context("Dep files were restored from disk without their fingerprints")?;
 
                 let dirs = match &*fingerprints {
                     StoredFingerprints::Digests(..) => {
@@ -29,6 +29,7 @@ use buck2_client_ctx::common::CommonCommandOptions;
 use buck2_common::dice::cells::HasCellResolver;
 use buck2_core::cells::CellResolver;
 use buck2_core::fs::paths::forward_rel_path::ForwardRelativePathBuf;
+use buck2_core::fs::project::ProjectRoot;
 use buck2_core::fs::project_rel_path::ProjectRelativePath;
 use buck2_core::target::label::TargetLabel;
 use buck2_query::query::syntax::simple::eval::set::TargetSet;
@@ -103,6 +104,7 @@ async fn write_output(
     json: bool,
     output_attributes: &[String],
     cell_resolver: &CellResolver,
+    project_root: &ProjectRoot,
 ) -> anyhow::Result<()> {
     // Dot/DotCompact output format don't make sense here.
     let unstable_output_format = if json {
@@ -113,6 +115,7 @@ async fn write_output(
 
     let query_result_printer = QueryResultPrinter::from_request_options(
         cell_resolver,
+        project_root,
         output_attributes,
         unstable_output_format,
     )?;
@@ -255,7 +258,7 @@ impl AuditSubcommand for AuditOutputCommand {
                     Some(result) => {
                         match result {
                             AuditOutputResult::Match(action) => {
-                                write_output(&mut stdout, action, self.json, &self.query_attributes.get()?, &cell_resolver).await?
+                                write_output(&mut stdout, action, self.json, &self.query_attributes.get()?, &cell_resolver, server_ctx.project_root()).await?
                             },
                             AuditOutputResult::MaybeRelevant(label) => {
                                 writeln!(
@@ -364,6 +364,7 @@ async fn command_details(
             command,
             env,
             digest,
+            ..
         } => {
             if omit_details {
                 buck2_data::OmittedLocalCommand {
@@ -483,6 +484,7 @@ mod tests {
     use crate::actions::artifact::source_artifact::SourceArtifact;
     use crate::actions::calculation::command_details;
     use crate::actions::calculation::ActionCalculation;
+    use crate::actions::execute::shared_outputs::HasSharedOutputs;
     use crate::actions::impls::run_action_knobs::RunActionKnobs;
     use crate::actions::testings::SimpleAction;
     use crate::actions::Action;
@@ -603,6 +605,7 @@ mod tests {
         extra.set_re_client(ManagedRemoteExecutionClient::testing_new_dummy());
         extra.data.set(EventDispatcher::null());
         extra.data.set(RunActionKnobs::default());
+        extra.set_shared_outputs(Default::default());
         extra.spawner = Arc::new(BuckSpawner::default());
 
         let mut computations = dice_builder.build(extra)?;
@@ -910,6 +913,8 @@ mod tests {
                     digest: ActionDigest::empty(digest_config.cas_digest_config()),
                     command: vec![],
                     env: sorted_vector_map![],
+                    network_blocked: false,
+                    undeclared_inputs: Vec::new(),
                 },
             },
             timing: Default::default(),
@@ -935,6 +940,8 @@ mod tests {
                 digest: ActionDigest::empty(digest_config.cas_digest_config()),
                 command: vec![],
                 env: sorted_vector_map![],
+                network_blocked: false,
+                undeclared_inputs: Vec::new(),
             },
         };
         let proto = command_details(&report, true).await;
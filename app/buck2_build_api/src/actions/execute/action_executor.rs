@@ -15,14 +15,27 @@ use anyhow::Context;
 use async_trait::async_trait;
 use buck2_common::events::HasEvents;
 use buck2_common::executor_config::CommandExecutorConfig;
+use buck2_common::executor_config::Executor;
+use buck2_common::executor_config::LocalExecutorOptions;
+use buck2_common::executor_config::RemoteEnabledExecutor;
 use buck2_common::liveliness_observer::NoopLivelinessObserver;
+use buck2_core::directory::DirectoryEntry;
 use buck2_core::fs::artifact_path_resolver::ArtifactFs;
 use buck2_core::fs::buck_out_path::BuckOutPath;
+use buck2_core::fs::buck_out_path::BuckOutPathKind;
+use buck2_core::fs::fs_util;
+use buck2_core::fs::paths::forward_rel_path::ForwardRelativePath;
+use buck2_core::fs::paths::forward_rel_path::ForwardRelativePathBuf;
+use buck2_core::fs::project::ProjectRoot;
+use buck2_core::fs::project_rel_path::ProjectRelativePath;
+use buck2_core::fs::project_rel_path::ProjectRelativePathBuf;
 use buck2_events::dispatch::EventDispatcher;
 use buck2_execute::artifact::fs::ExecutorFs;
 use buck2_execute::artifact_value::ArtifactValue;
 use buck2_execute::digest_config::DigestConfig;
 use buck2_execute::digest_config::HasDigestConfig;
+use buck2_execute::directory::diff_entries;
+use buck2_execute::directory::ActionDirectoryMember;
 use buck2_execute::execute::blocking::BlockingExecutor;
 use buck2_execute::execute::blocking::HasBlockingExecutor;
 use buck2_execute::execute::claim::MutexClaimManager;
@@ -34,11 +47,14 @@ use buck2_execute::execute::dice_data::GetReClient;
 use buck2_execute::execute::dice_data::HasCommandExecutor;
 use buck2_execute::execute::kind::CommandExecutionKind;
 use buck2_execute::execute::manager::CommandExecutionManager;
+use buck2_execute::execute::request::CommandExecutionOutput;
 use buck2_execute::execute::request::CommandExecutionRequest;
 use buck2_execute::execute::request::OutputType;
 use buck2_execute::execute::result::CommandExecutionReport;
 use buck2_execute::execute::result::CommandExecutionResult;
 use buck2_execute::execute::result::CommandExecutionStatus;
+use buck2_execute::materialize::http::DownloadConfig;
+use buck2_execute::materialize::http::HasDownloadConfig;
 use buck2_execute::materialize::materializer::HasMaterializer;
 use buck2_execute::materialize::materializer::Materializer;
 use buck2_execute::output_size::OutputCountAndBytes;
@@ -55,7 +71,15 @@ use itertools::Itertools;
 use crate::actions::artifact::build_artifact::BuildArtifact;
 use crate::actions::execute::action_execution_target::ActionExecutionTarget;
 use crate::actions::execute::error::CommandExecutionErrorMarker;
+use crate::actions::execute::error::CommandTimedOutMarker;
 use crate::actions::execute::error::ExecuteError;
+use crate::actions::execute::error::NetworkAccessBlockedMarker;
+use crate::actions::execute::error::UndeclaredInputsMarker;
+use crate::actions::execute::shared_outputs::HasSharedOutputs;
+use crate::actions::execute::shared_outputs::SharedAction;
+use crate::actions::execute::shared_outputs::SharedActionKey;
+use crate::actions::execute::shared_outputs::SharedOutputs;
+use crate::actions::impls::run_action_knobs::DeterminismCheck;
 use crate::actions::impls::run_action_knobs::HasRunActionKnobs;
 use crate::actions::impls::run_action_knobs::RunActionKnobs;
 use crate::actions::ActionExecutable;
@@ -221,16 +245,62 @@ impl HasActionExecutor for DiceComputations {
         let events = self.per_transaction_data().get_dispatcher().dupe();
         let re_client = self.per_transaction_data().get_re_client();
         let run_action_knobs = self.per_transaction_data().get_run_action_knobs();
+        let download_config = self.per_transaction_data().get_download_config();
+        let shared_outputs = self.per_transaction_data().get_shared_outputs();
+
+        // Determinism checks run commands a second time with a local-only executor, which never
+        // uses the action cache.
+        let determinism_check_executor = match (
+            &run_action_knobs.check_determinism,
+            local_executor_options(&executor_config.executor),
+        ) {
+            (DeterminismCheck::Disabled, _) | (_, None) => None,
+            (_, Some(local)) => {
+                let local_config = CommandExecutorConfig {
+                    executor: Executor::Local(local.clone()),
+                    options: executor_config.options,
+                };
+                // This fails if the execution strategy bans local execution, in which case
+                // there's nothing to check.
+                self.get_command_executor(&artifact_fs, &local_config)
+                    .ok()
+                    .map(|CommandExecutorResponse { executor, platform }| {
+                        CommandExecutor::new(
+                            executor,
+                            artifact_fs.clone(),
+                            local_config.options,
+                            platform,
+                        )
+                    })
+            }
+        };
 
-        Ok(Arc::new(BuckActionExecutor::new(
-            CommandExecutor::new(executor, artifact_fs, executor_config.options, platform),
-            blocking_executor,
-            materializer,
-            events,
-            re_client,
-            digest_config,
-            run_action_knobs,
-        )))
+        Ok(Arc::new(
+            BuckActionExecutor::new(
+                CommandExecutor::new(executor, artifact_fs, executor_config.options, platform),
+                blocking_executor,
+                materializer,
+                events,
+                re_client,
+                digest_config,
+                run_action_knobs,
+                shared_outputs,
+            )
+            .with_determinism_check_executor(determinism_check_executor)
+            .with_download_config(download_config),
+        ))
+    }
+}
+
+fn local_executor_options(executor: &Executor) -> Option<&LocalExecutorOptions> {
+    match executor {
+        Executor::Local(local) => Some(local),
+        Executor::RemoteEnabled { executor, .. } => match executor {
+            RemoteEnabledExecutor::Local(local) | RemoteEnabledExecutor::Hybrid { local, .. } => {
+                Some(local)
+            }
+            RemoteEnabledExecutor::Remote(..) => None,
+        },
     }
 }
 
@@ -242,6 +312,10 @@ pub struct BuckActionExecutor {
     re_client: ManagedRemoteExecutionClient,
     digest_config: DigestConfig,
     run_action_knobs: RunActionKnobs,
+    shared_outputs: Arc<SharedOutputs>,
+    /// Runs commands locally without the action cache, to check whether they're deterministic.
+    determinism_check_executor: Option<CommandExecutor>,
+    download_config: Arc<DownloadConfig>,
 }
 
 impl BuckActionExecutor {
@@ -253,6 +327,7 @@ impl BuckActionExecutor {
         re_client: ManagedRemoteExecutionClient,
         digest_config: DigestConfig,
         run_action_knobs: RunActionKnobs,
+        shared_outputs: Arc<SharedOutputs>,
     ) -> Self {
         Self {
             command_executor,
@@ -262,10 +337,202 @@ impl BuckActionExecutor {
             re_client,
             digest_config,
             run_action_knobs,
+            shared_outputs,
+            determinism_check_executor: None,
+            download_config: Arc::default(),
+        }
+    }
+
+    pub fn with_download_config(mut self, download_config: Arc<DownloadConfig>) -> Self {
+        self.download_config = download_config;
+        self
+    }
+
+    pub fn with_determinism_check_executor(
+        mut self,
+        determinism_check_executor: Option<CommandExecutor>,
+    ) -> Self {
+        self.determinism_check_executor = determinism_check_executor;
+        self
+    }
+
+    /// If `action` has configuration-independent outputs, claim them, and return the action that
+    /// should produce them, which is this one unless another configuration got there first.
+    fn claim_shared_outputs(
+        &self,
+        action: &RegisteredAction,
+        inputs: &IndexMap<ArtifactGroup, ArtifactGroupValues>,
+    ) -> anyhow::Result<Option<Arc<SharedAction>>> {
+        let outputs = action.outputs()?;
+        let fs = self.command_executor.fs();
+
+        let mut paths = Vec::with_capacity(outputs.len());
+        let mut shared = Vec::new();
+        for output in outputs.iter() {
+            let path = fs.resolve_build(output.get_path());
+            if output.get_path().kind() == BuckOutPathKind::ConfigurationIndependent {
+                shared.push(path.clone());
+            }
+            paths.push(path);
+        }
+
+        if shared.is_empty() {
+            return Ok(None);
+        }
+        let key = SharedActionKey::new(action, inputs, &self.command_executor.executor_fs());
+        self.shared_outputs
+            .claim(action.owner().to_string(), paths, &shared, key)
+            .map(Some)
+    }
+
+    /// The outputs that another configuration produced for `shared` in a previous command, if
+    /// they are still what the materializer has at their paths and so may still be used by that
+    /// configuration. Fails if they were produced by a different action, since replacing them
+    /// would change the outputs of that configuration.
+    async fn previous_shared_outputs(
+        &self,
+        shared: &SharedAction,
+    ) -> anyhow::Result<Option<ActionOutputs>> {
+        let previous = match self.shared_outputs.previous(shared) {
+            Some(previous) => previous,
+            None => return Ok(None),
+        };
+        if !self
+            .materializer
+            .declare_match(previous.declared())
+            .await?
+            .is_match()
+        {
+            return Ok(None);
+        }
+        if previous.key() != shared.key() {
+            return Err(SharedOutputs::overwrite_error(shared, &previous));
+        }
+        Ok(Some(previous.outputs().dupe()))
+    }
+
+    async fn execute_unshared(
+        &self,
+        inputs: IndexMap<ArtifactGroup, ArtifactGroupValues>,
+        action: &RegisteredAction,
+        command_reports: &mut Vec<CommandExecutionReport>,
+    ) -> Result<(ActionOutputs, ActionExecutionMetadata), ExecuteError> {
+        let outputs = action.outputs()?;
+
+        let mut ctx = BuckActionExecutionContext {
+            executor: self,
+            action,
+            inputs,
+            outputs: outputs.as_ref(),
+            command_reports,
+        };
+
+        let (result, metadata) = match action.as_executable() {
+            ActionExecutable::Pristine(exe) => {
+                ctx.cleanup_outputs().await?;
+                exe.execute(&mut ctx).await?
+            }
+            ActionExecutable::Incremental(exe) => {
+                // Let the action perform clean up in this case.
+                exe.execute(&mut ctx).await?
+            }
+        };
+
+        // Check that all the outputs are the right output_type
+        for x in outputs.iter() {
+            let declared = x.output_type();
+            // FIXME: One day we should treat FileOrDirectory as a File, and soft_error if it is a directory
+            if declared != OutputType::FileOrDirectory {
+                if let Some(t) = result.0.outputs.get(x.get_path()) {
+                    let real = if t.is_dir() {
+                        OutputType::Directory
+                    } else {
+                        OutputType::File
+                    };
+                    if real != declared {
+                        return Err(ExecuteError::WrongOutputType {
+                            path: self.command_executor.fs().resolve_build(x.get_path()),
+                            declared,
+                            real,
+                        });
+                    }
+                }
+            }
+        }
+
+        // Check all the outputs were returned, and no additional outputs
+        // TODO (T122966509): Check projections here as well
+        if !outputs
+            .iter()
+            .map(|b| b.get_path())
+            .eq(result.0.outputs.keys())
+        {
+            let declared = outputs
+                .iter()
+                .filter(|x| !result.0.outputs.contains_key(x.get_path()))
+                .map(|x| self.command_executor.fs().resolve_build(x.get_path()))
+                .collect();
+            let real = result
+                .0
+                .outputs
+                .keys()
+                .filter(|x| {
+                    // This is error message, linear search is fine.
+                    !outputs.iter().map(|b| b.get_path()).contains(x)
+                })
+                .map(|x| self.command_executor.fs().resolve_build(x))
+                .collect::<Vec<_>>();
+            if real.is_empty() {
+                Err(ExecuteError::MissingOutputs { declared })
+            } else {
+                Err(ExecuteError::MismatchedOutputs { declared, real })
+            }
+        } else {
+            Ok((result, metadata))
         }
     }
 }
 
+/// The files and symlinks that differ between the outputs of two runs of a command.
+fn nondeterministic_outputs(
+    fs: &ArtifactFs,
+    first: &CommandExecutionResult,
+    second: &CommandExecutionResult,
+) -> Vec<buck2_data::NondeterministicOutput> {
+    let describe = |member: Option<ActionDirectoryMember>| {
+        member.map_or_else(String::new, |member| member.to_string())
+    };
+
+    let mut res = Vec::new();
+    for (output, first_value) in &first.outputs {
+        let path = output.as_ref().resolve(fs).into_path();
+
+        let second_value = match second.outputs.get(output) {
+            Some(second_value) => second_value,
+            None => {
+                res.push(buck2_data::NondeterministicOutput {
+                    path: path.to_string(),
+                    first: match first_value.entry() {
+                        DirectoryEntry::Dir(d) => format!("dir({})", d.fingerprint()),
+                        DirectoryEntry::Leaf(member) => member.to_string(),
+                    },
+                    second: String::new(),
+                });
+                continue;
+            }
+        };
+
+        for diff in diff_entries(first_value.entry(), second_value.entry()) {
+            res.push(buck2_data::NondeterministicOutput {
+                path: path.join(&diff.path).to_string(),
+                first: describe(diff.before),
+                second: describe(diff.after),
+            });
+        }
+    }
+    res
+}
+
 struct BuckActionExecutionContext<'a> {
     executor: &'a BuckActionExecutor,
     action: &'a RegisteredAction,
@@ -274,6 +541,217 @@ struct BuckActionExecutionContext<'a> {
     command_reports: &'a mut Vec<CommandExecutionReport>,
 }
 
+impl BuckActionExecutionContext<'_> {
+    /// Run a command that succeeded locally a second time, and report the outputs that differ
+    /// between the two runs. The second run always runs locally and skips the action cache, and
+    /// the outputs of the first run are moved aside while it runs and put back afterwards, so the
+    /// action always keeps the outputs of its first run.
+    async fn check_determinism(
+        &mut self,
+        request: &CommandExecutionRequest,
+        mut first: CommandExecutionResult,
+    ) -> CommandExecutionResult {
+        match &first.report.status {
+            CommandExecutionStatus::Success {
+                execution_kind: CommandExecutionKind::Local { .. },
+            } => {}
+            _ => return first,
+        }
+
+        let command_executor = match &self.executor.determinism_check_executor {
+            Some(command_executor) => command_executor,
+            None => {
+                self.report_unchecked_determinism("no local executor is available");
+                return first;
+            }
+        };
+
+        let fs = self.fs().fs().dupe();
+        let output_paths: Vec<_> = first
+            .outputs
+            .keys()
+            .map(|output| output.as_ref().resolve(self.fs()).into_path())
+            .collect();
+        let stash = match self.determinism_check_stash() {
+            Ok(stash) => stash,
+            Err(e) => {
+                self.report_unchecked_determinism(&format!("{:#}", e));
+                return first;
+            }
+        };
+
+        if let Err(e) = self
+            .blocking_executor()
+            .execute_io_inline(|| stash_outputs(&fs, &output_paths, &stash))
+            .await
+        {
+            // Put back whatever was moved already.
+            let restored = self
+                .blocking_executor()
+                .execute_io_inline(|| restore_outputs(&fs, &output_paths, &stash))
+                .await;
+            if let Err(e) = restored {
+                first.report.status = CommandExecutionStatus::Error {
+                    stage: "determinism_check",
+                    error: e,
+                };
+                return first;
+            }
+            self.report_unchecked_determinism(&format!("{:#}", e));
+            return first;
+        }
+
+        let action = self.target();
+        let manager = CommandExecutionManager::new(
+            Box::new(MutexClaimManager::new()),
+            self.executor.events.dupe(),
+            NoopLivelinessObserver::create(),
+        );
+        let second = command_executor
+            .exec_cmd(&action as _, request, manager, self.digest_config())
+            .await;
+
+        match &second.report.status {
+            CommandExecutionStatus::Success {
+                execution_kind: CommandExecutionKind::Local { .. },
+            } => {
+                let outputs = nondeterministic_outputs(self.fs(), &first, &second);
+                if !outputs.is_empty() {
+                    self.report_nondeterminism(outputs);
+                }
+            }
+            CommandExecutionStatus::Success { execution_kind } => self
+                .report_unchecked_determinism(&format!(
+                    "the second run did not run locally ({})",
+                    execution_kind
+                )),
+            _ => self.report_unchecked_determinism("the second run failed"),
+        }
+
+        // Keep the second run in the reports, so that both runs show up in the logs.
+        self.command_reports.extend(second.rejected_execution);
+        self.command_reports.push(second.report);
+
+        // The second run replaced the outputs in the materializer, so declare the first ones again.
+        let restored = async {
+            self.blocking_executor()
+                .execute_io_inline(|| restore_outputs(&fs, &output_paths, &stash))
+                .await?;
+            let to_declare = first
+                .outputs
+                .iter()
+                .filter(|(output, _)| {
+                    matches!(output, CommandExecutionOutput::BuildArtifact { .. })
+                })
+                .map(|(output, value)| {
+                    (output.as_ref().resolve(self.fs()).into_path(), value.dupe())
+                })
+                .collect();
+            self.materializer().declare_existing(to_declare).await
+        }
+        .await;
+        if let Err(e) = restored {
+            first.report.status = CommandExecutionStatus::Error {
+                stage: "determinism_check",
+                error: e,
+            };
+        }
+
+        first
+    }
+
+    /// Where the outputs of the first run are kept during the determinism check.
+    fn determinism_check_stash(&self) -> anyhow::Result<ProjectRelativePathBuf> {
+        let resolver = self.fs().buck_out_path_resolver();
+        let scratch = resolver.resolve_scratch(&self.target().custom_tmpdir());
+        Ok(resolver
+            .root()
+            .join(ForwardRelativePath::unchecked_new("determinism_check"))
+            .join(scratch.strip_prefix(resolver.root())?))
+    }
+
+    fn report_nondeterminism(&self, outputs: Vec<buck2_data::NondeterministicOutput>) {
+        let missing = |s: &str| {
+            if s.is_empty() {
+                "<missing>".to_owned()
+            } else {
+                s.to_owned()
+            }
+        };
+        self.executor.events.console_message(format!(
+            "Action `{}` ({}) produced different outputs when run twice:\n{}",
+            self.action.owner(),
+            self.action.category(),
+            outputs
+                .iter()
+                .map(|o| format!(
+                    "  {}: {} -> {}",
+                    o.path,
+                    missing(&o.first),
+                    missing(&o.second)
+                ))
+                .join("\n"),
+        ));
+        self.executor
+            .events
+            .instant_event(buck2_data::NondeterministicAction {
+                key: Some(self.action.key().as_proto()),
+                name: Some(buck2_data::ActionName {
+                    category: self.action.category().as_str().to_owned(),
+                    identifier: self.action.identifier().unwrap_or("").to_owned(),
+                }),
+                outputs,
+            });
+    }
+
+    fn report_unchecked_determinism(&self, reason: &str) {
+        self.executor.events.console_message(format!(
+            "Could not check whether action `{}` ({}) is deterministic: {}",
+            self.action.owner(),
+            self.action.category(),
+            reason
+        ));
+    }
+}
+
+/// Move the outputs of a command to `stash`, so that it can run again without overwriting them.
+fn stash_outputs(
+    fs: &ProjectRoot,
+    outputs: &[ProjectRelativePathBuf],
+    stash: &ProjectRelativePath,
+) -> anyhow::Result<()> {
+    fs_util::remove_all(fs.resolve(stash))?;
+    fs_util::create_dir_all(fs.resolve(stash))?;
+    for (i, output) in outputs.iter().enumerate() {
+        let path = fs.resolve(output);
+        if fs_util::symlink_metadata_if_exists(&path)?.is_some() {
+            fs_util::rename(&path, fs.resolve(&stash_path(stash, i)))?;
+        }
+    }
+    Ok(())
+}
+
+/// Undo `stash_outputs`, replacing whatever is at the output paths now.
+fn restore_outputs(
+    fs: &ProjectRoot,
+    outputs: &[ProjectRelativePathBuf],
+    stash: &ProjectRelativePath,
+) -> anyhow::Result<()> {
+    for (i, output) in outputs.iter().enumerate() {
+        let stashed = fs.resolve(&stash_path(stash, i));
+        if fs_util::symlink_metadata_if_exists(&stashed)?.is_some() {
+            let path = fs.resolve(output);
+            fs_util::remove_all(&path)?;
+            fs_util::rename(&stashed, &path)?;
+        }
+    }
+    fs_util::remove_all(fs.resolve(stash))
+}
+
+fn stash_path(stash: &ProjectRelativePath, i: usize) -> ProjectRelativePathBuf {
+    stash.join(ForwardRelativePathBuf::unchecked_new(i.to_string()))
+}
+
 #[async_trait]
 impl ActionExecutionCtx for BuckActionExecutionContext<'_> {
     fn target(&self) -> ActionExecutionTarget<'_> {
@@ -313,7 +791,11 @@ impl ActionExecutionCtx for BuckActionExecutionContext<'_> {
     }
 
     fn run_action_knobs(&self) -> RunActionKnobs {
-        self.executor.run_action_knobs
+        self.executor.run_action_knobs.dupe()
+    }
+
+    fn download_config(&self) -> Arc<DownloadConfig> {
+        self.executor.download_config.dupe()
     }
 
     async fn exec_cmd(
@@ -329,17 +811,28 @@ impl ActionExecutionCtx for BuckActionExecutionContext<'_> {
             self.executor.events.dupe(),
             NoopLivelinessObserver::create(),
         );
+        let mut result = self
+            .executor
+            .command_executor
+            .exec_cmd(&action as _, request, manager, self.digest_config())
+            .await;
+
+        if self
+            .executor
+            .run_action_knobs
+            .check_determinism
+            .includes(self.action.category())
+        {
+            result = self.check_determinism(request, result).await;
+        }
+
         let CommandExecutionResult {
             outputs,
             report,
             rejected_execution,
             did_cache_upload,
             eligible_for_full_hybrid,
-        } = self
-            .executor
-            .command_executor
-            .exec_cmd(&action as _, request, manager, self.digest_config())
-            .await;
+        } = result;
 
         // TODO (@torozco): The execution kind should be made to come via the command reports too.
         let res = match &report.status {
@@ -361,6 +854,31 @@ impl ActionExecutionCtx for BuckActionExecutionContext<'_> {
                 },
             )),
 
+            CommandExecutionStatus::TimedOut { duration, .. } => Err(CommandTimedOutMarker {
+                duration: *duration,
+            }
+            .into()),
+
+            // The command ran in the local sandbox, and tried to read files it didn't declare.
+            CommandExecutionStatus::Failure {
+                execution_kind:
+                    CommandExecutionKind::Local {
+                        undeclared_inputs, ..
+                    },
+            } if !undeclared_inputs.is_empty() => Err(UndeclaredInputsMarker {
+                paths: undeclared_inputs.clone(),
+            }
+            .into()),
+
+            // The command ran in a network namespace without network access.
+            CommandExecutionStatus::Failure {
+                execution_kind:
+                    CommandExecutionKind::Local {
+                        network_blocked: true,
+                        ..
+                    },
+            } => Err(NetworkAccessBlockedMarker.into()),
+
             _ => Err(CommandExecutionErrorMarker.into()),
         };
 
@@ -421,82 +939,58 @@ impl ActionExecutor for BuckActionExecutor {
     ) {
         let mut command_reports = Vec::new();
 
-        let res = async {
-            let outputs = action.outputs()?;
-
-            let mut ctx = BuckActionExecutionContext {
-                executor: self,
-                action,
-                inputs,
-                outputs: outputs.as_ref(),
-                command_reports: &mut command_reports,
-            };
-
-            let (result, metadata) = match action.as_executable() {
-                ActionExecutable::Pristine(exe) => {
-                    ctx.cleanup_outputs().await?;
-                    exe.execute(&mut ctx).await?
-                }
-                ActionExecutable::Incremental(exe) => {
-                    // Let the action perform clean up in this case.
-                    exe.execute(&mut ctx).await?
-                }
-            };
-
-            // Check that all the outputs are the right output_type
-            for x in outputs.iter() {
-                let declared = x.output_type();
-                // FIXME: One day we should treat FileOrDirectory as a File, and soft_error if it is a directory
-                if declared != OutputType::FileOrDirectory {
-                    if let Some(t) = result.0.outputs.get(x.get_path()) {
-                        let real = if t.is_dir() {
-                            OutputType::Directory
-                        } else {
-                            OutputType::File
-                        };
-                        if real != declared {
-                            return Err(ExecuteError::WrongOutputType {
-                                path: self.command_executor.fs().resolve_build(x.get_path()),
-                                declared,
-                                real,
-                            });
-                        }
-                    }
-                }
+        let shared = match self.claim_shared_outputs(action, &inputs) {
+            Ok(shared) => shared,
+            Err(e) => return (Err(e.into()), command_reports),
+        };
+        let res = match shared {
+            None => {
+                self.execute_unshared(inputs, action, &mut command_reports)
+                    .await
             }
-
-            // Check all the outputs were returned, and no additional outputs
-            // TODO (T122966509): Check projections here as well
-            if !outputs
-                .iter()
-                .map(|b| b.get_path())
-                .eq(result.0.outputs.keys())
-            {
-                let declared = outputs
-                    .iter()
-                    .filter(|x| !result.0.outputs.contains_key(x.get_path()))
-                    .map(|x| self.command_executor.fs().resolve_build(x.get_path()))
-                    .collect();
-                let real = result
-                    .0
-                    .outputs
-                    .keys()
-                    .filter(|x| {
-                        // This is error message, linear search is fine.
-                        !outputs.iter().map(|b| b.get_path()).contains(x)
+            Some(shared) => {
+                let mut metadata = None;
+                let reports = &mut command_reports;
+                let metadata_slot = &mut metadata;
+                let shared = &*shared;
+                let outputs = shared
+                    .outputs()
+                    .get_or_try_init(move || async move {
+                        if let Some(outputs) = self.previous_shared_outputs(shared).await? {
+                            return Ok(outputs);
+                        }
+                        let (outputs, metadata) =
+                            self.execute_unshared(inputs, action, reports).await?;
+                        self.shared_outputs.record(shared, &outputs);
+                        *metadata_slot = Some(metadata);
+                        Ok(outputs)
                     })
-                    .map(|x| self.command_executor.fs().resolve_build(x))
-                    .collect::<Vec<_>>();
-                if real.is_empty() {
-                    Err(ExecuteError::MissingOutputs { declared })
-                } else {
-                    Err(ExecuteError::MismatchedOutputs { declared, real })
+                    .await;
+                match (outputs, metadata) {
+                    (Err(e), _) => Err(e),
+                    (Ok(outputs), Some(metadata)) => Ok((outputs.dupe(), metadata)),
+                    // Another configuration ran the action, in this command or a previous one:
+                    // its outputs are at the same paths.
+                    (Ok(outputs), None) => action
+                        .outputs()
+                        .map(|own| {
+                            let outputs = own
+                                .iter()
+                                .zip(outputs.values())
+                                .map(|(o, v)| (o.get_path().dupe(), v.dupe()))
+                                .collect();
+                            (
+                                ActionOutputs::new(outputs),
+                                ActionExecutionMetadata {
+                                    execution_kind: ActionExecutionKind::Skipped,
+                                    timing: ActionExecutionTimingData::default(),
+                                },
+                            )
+                        })
+                        .map_err(ExecuteError::from),
                 }
-            } else {
-                Ok((result, metadata))
             }
-        }
-        .await;
+        };
 
         (res, command_reports)
     }
@@ -515,6 +1009,9 @@ mod tests {
     use buck2_common::executor_config::CommandExecutorConfig;
     use buck2_common::executor_config::CommandGenerationOptions;
     use buck2_common::executor_config::PathSeparatorKind;
+    use buck2_common::file_ops::FileMetadata;
+    use buck2_common::file_ops::TrackedFileDigest;
+    use buck2_core::base_deferred_key_dyn::BaseDeferredKeyDyn;
     use buck2_core::buck_path::path::BuckPath;
     use buck2_core::buck_path::resolver::BuckPathResolver;
     use buck2_core::category::Category;
@@ -525,6 +1022,8 @@ mod tests {
     use buck2_core::cells::CellResolver;
     use buck2_core::configuration::data::ConfigurationData;
     use buck2_core::fs::artifact_path_resolver::ArtifactFs;
+    use buck2_core::fs::buck_out_path::BuckOutPath;
+    use buck2_core::fs::buck_out_path::BuckOutPathKind;
     use buck2_core::fs::buck_out_path::BuckOutPathResolver;
     use buck2_core::fs::fs_util;
     use buck2_core::fs::paths::forward_rel_path::ForwardRelativePathBuf;
@@ -537,6 +1036,7 @@ mod tests {
     use buck2_core::target::name::TargetNameRef;
     use buck2_events::dispatch::with_dispatcher_async;
     use buck2_events::dispatch::EventDispatcher;
+    use buck2_execute::artifact::fs::ExecutorFs;
     use buck2_execute::artifact_value::ArtifactValue;
     use buck2_execute::digest_config::DigestConfig;
     use buck2_execute::execute::blocking::testing::DummyBlockingExecutor;
@@ -552,7 +1052,9 @@ mod tests {
     use buck2_execute::materialize::nodisk::NoDiskMaterializer;
     use buck2_execute::re::manager::ManagedRemoteExecutionClient;
     use dupe::Dupe;
+    use indexmap::indexmap;
     use indexmap::indexset;
+    use indexmap::IndexMap;
     use once_cell::sync::Lazy;
     use sorted_vector_map::SortedVectorMap;
 
@@ -620,6 +1122,7 @@ mod tests {
             ManagedRemoteExecutionClient::testing_new_dummy(),
             DigestConfig::testing_default(),
             Default::default(),
+            Default::default(),
         );
 
         #[derive(Debug, Allocative)]
@@ -775,6 +1278,200 @@ mod tests {
         assert_eq!(res.0, ActionOutputs::new(outputs));
     }
 
+    #[tokio::test]
+    async fn configuration_independent_outputs_are_built_once() -> anyhow::Result<()> {
+        let cells = CellResolver::of_names_and_paths(
+            CellName::testing_new("root"),
+            &[(
+                CellName::testing_new("cell"),
+                CellRootPathBuf::new(ProjectRelativePathBuf::unchecked_new("cell_path".into())),
+            )],
+        );
+
+        let temp_fs = ProjectRootTemp::new()?;
+
+        let project_fs = temp_fs.path().dupe();
+        let artifact_fs = ArtifactFs::new(
+            BuckPathResolver::new(cells),
+            BuckOutPathResolver::new(ProjectRelativePathBuf::unchecked_new(
+                "cell/buck-out/v2".into(),
+            )),
+            project_fs.dupe(),
+        );
+
+        let executor = BuckActionExecutor::new(
+            CommandExecutor::new(
+                Arc::new(DryRunExecutor::new(
+                    Arc::new(Mutex::new(Vec::new())),
+                    artifact_fs.clone(),
+                )),
+                artifact_fs,
+                CommandGenerationOptions {
+                    path_separator: PathSeparatorKind::Unix,
+                    output_paths_behavior: Default::default(),
+                },
+                Default::default(),
+            ),
+            Arc::new(DummyBlockingExecutor { fs: project_fs }),
+            Arc::new(NoDiskMaterializer),
+            EventDispatcher::null(),
+            ManagedRemoteExecutionClient::testing_new_dummy(),
+            DigestConfig::testing_default(),
+            Default::default(),
+            Default::default(),
+        );
+
+        /// Writes `contents` to its outputs.
+        #[derive(Debug, Allocative)]
+        struct GenAction {
+            outputs: BoxSliceSet<BuildArtifact>,
+            contents: String,
+            ran: Arc<AtomicBool>,
+        }
+
+        #[async_trait]
+        impl Action for GenAction {
+            fn kind(&self) -> buck2_data::ActionKind {
+                buck2_data::ActionKind::NotSet
+            }
+
+            fn inputs(&self) -> anyhow::Result<Cow<'_, [ArtifactGroup]>> {
+                Ok(Cow::Borrowed(&[]))
+            }
+
+            fn outputs(&self) -> anyhow::Result<Cow<'_, [BuildArtifact]>> {
+                Ok(Cow::Borrowed(self.outputs.as_slice()))
+            }
+
+            fn as_executable(&self) -> ActionExecutable<'_> {
+                ActionExecutable::Pristine(self)
+            }
+
+            fn category(&self) -> &Category {
+                static TEST_CATEGORY: Lazy<Category> =
+                    Lazy::new(|| Category::try_from("testing").unwrap());
+
+                &TEST_CATEGORY
+            }
+
+            fn identifier(&self) -> Option<&str> {
+                None
+            }
+
+            fn aquery_attributes(&self, _fs: &ExecutorFs) -> IndexMap<String, String> {
+                indexmap! { "contents".to_owned() => self.contents.clone() }
+            }
+        }
+
+        #[async_trait]
+        impl PristineActionExecutable for GenAction {
+            async fn execute(
+                &self,
+                ctx: &mut dyn ActionExecutionCtx,
+            ) -> anyhow::Result<(ActionOutputs, ActionExecutionMetadata)> {
+                self.ran.store(true, Ordering::SeqCst);
+                let mut outputs = IndexMap::new();
+                for x in &self.outputs {
+                    let dest_path = ctx.fs().resolve_build(x.get_path());
+                    ctx.fs()
+                        .fs()
+                        .write_file(&dest_path, &self.contents, false)?;
+                    let digest = ctx.digest_config().cas_digest_config();
+                    outputs.insert(
+                        x.get_path().dupe(),
+                        ArtifactValue::file(FileMetadata {
+                            digest: TrackedFileDigest::from_content(
+                                self.contents.as_bytes(),
+                                digest,
+                            ),
+                            is_executable: false,
+                        }),
+                    );
+                }
+                Ok((
+                    ActionOutputs::new(outputs),
+                    ActionExecutionMetadata {
+                        execution_kind: ActionExecutionKind::Simple,
+                        timing: ActionExecutionTimingData::default(),
+                    },
+                ))
+            }
+        }
+
+        let target = TargetLabel::new(
+            PackageLabel::new(
+                CellName::testing_new("cell"),
+                CellRelativePath::unchecked_new("pkg"),
+            ),
+            TargetNameRef::unchecked_new("foo"),
+        );
+
+        // Build the target in three configurations, the last of which generates something else.
+        let mut results = Vec::new();
+        for (cfg, contents) in [
+            (ConfigurationData::testing_new(), "gen"),
+            (ConfigurationData::unspecified(), "gen"),
+            (ConfigurationData::unbound(), "other"),
+        ] {
+            let label = target.configure(cfg);
+            let key = ActionKey::new(DeferredData::testing_new(DeferredKey::Base(
+                BaseDeferredKey::TargetLabel(label.dupe()),
+                DeferredId::testing_new(0),
+            )));
+            let output = BuildArtifact::new(
+                BuckOutPath::with_action_key_and_kind(
+                    BaseDeferredKeyDyn::TargetLabel(label),
+                    ForwardRelativePathBuf::unchecked_new("gen.rs".into()),
+                    None,
+                    BuckOutPathKind::ConfigurationIndependent,
+                ),
+                key.dupe(),
+                OutputType::File,
+            );
+            let ran = Arc::new(AtomicBool::new(false));
+            let action = RegisteredAction::new(
+                key,
+                Box::new(GenAction {
+                    outputs: BoxSliceSet::from(indexset![output.dupe()]),
+                    contents: contents.to_owned(),
+                    ran: ran.dupe(),
+                }),
+                CommandExecutorConfig::testing_local(),
+            );
+            let res = with_dispatcher_async(
+                EventDispatcher::null(),
+                executor.execute(Default::default(), &action),
+            )
+            .await
+            .0;
+            results.push((ran.load(Ordering::SeqCst), output, res));
+        }
+
+        // Only the first configuration ran the action, and the second reuses its outputs under its
+        // own artifact.
+        let (first_ran, first_output, first) = &results[0];
+        let (second_ran, second_output, second) = &results[1];
+        let (first, second) = (first.as_ref().unwrap(), second.as_ref().unwrap());
+        assert!(*first_ran);
+        assert!(!*second_ran);
+        assert!(matches!(
+            second.1.execution_kind,
+            ActionExecutionKind::Skipped
+        ));
+        assert!(second.0.get(second_output.get_path()).is_some());
+        assert_eq!(
+            first.0.get(first_output.get_path()),
+            second.0.get(second_output.get_path())
+        );
+
+        // A different action can't produce the same outputs.
+        let (third_ran, _, third) = &results[2];
+        assert!(!*third_ran);
+        assert!(third.is_err());
+
+        Ok(())
+    }
+
     #[test]
     fn test_cleanup_path_missing() -> anyhow::Result<()> {
         let fs = ProjectRootTemp::new()?;
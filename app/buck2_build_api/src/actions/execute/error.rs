@@ -9,6 +9,7 @@
 
 use std::fmt::Display;
 use std::fmt::Write;
+use std::time::Duration;
 
 use buck2_core::fs::project_rel_path::ProjectRelativePathBuf;
 use buck2_execute::execute::request::OutputType;
@@ -32,6 +33,13 @@ pub enum ExecuteError {
         error: anyhow::Error,
     },
     CommandExecutionError,
+    CommandTimedOut {
+        duration: Duration,
+    },
+    NetworkAccessBlocked,
+    UndeclaredInputs {
+        paths: Vec<ProjectRelativePathBuf>,
+    },
 }
 
 impl ExecuteError {
@@ -57,6 +65,26 @@ impl ExecuteError {
             .into(),
             ExecuteError::Error { error } => format!("{:#}", error).into(),
             ExecuteError::CommandExecutionError => buck2_data::CommandExecutionError {}.into(),
+            ExecuteError::CommandTimedOut { duration } => buck2_data::CommandTimedOut {
+                message: format!("Command timed out after {:.3}s", duration.as_secs_f64()),
+            }
+            .into(),
+            ExecuteError::NetworkAccessBlocked => buck2_data::NetworkAccessBlocked {
+                message: "The command ran without network access, since network access is \
+                    blocked for local actions. Set `allow_network = True` on the action if it \
+                    needs network access"
+                    .to_owned(),
+            }
+            .into(),
+            ExecuteError::UndeclaredInputs { paths } => buck2_data::UndeclaredInputs {
+                message: format!(
+                    "The command tried to read files that are not declared inputs of the action \
+                    (it ran in the local sandbox): {}",
+                    error_items(paths)
+                ),
+                paths: paths.iter().map(|p| p.to_string()).collect(),
+            }
+            .into(),
         }
     }
 }
@@ -80,6 +108,19 @@ impl From<anyhow::Error> for ExecuteError {
         if error.is::<CommandExecutionErrorMarker>() {
             return Self::CommandExecutionError;
         }
+        if let Some(CommandTimedOutMarker { duration }) = error.downcast_ref() {
+            return Self::CommandTimedOut {
+                duration: *duration,
+            };
+        }
+        if error.is::<NetworkAccessBlockedMarker>() {
+            return Self::NetworkAccessBlocked;
+        }
+        if let Some(UndeclaredInputsMarker { paths }) = error.downcast_ref() {
+            return Self::UndeclaredInputs {
+                paths: paths.clone(),
+            };
+        }
         Self::Error { error }
     }
 }
@@ -87,3 +128,19 @@ impl From<anyhow::Error> for ExecuteError {
 #[derive(Error, Debug)]
 #[error("Command execution failed. Details are in the command report.")]
 pub struct CommandExecutionErrorMarker;
+
+#[derive(Error, Debug)]
+#[error("Command timed out. Details are in the command report.")]
+pub struct CommandTimedOutMarker {
+    pub duration: Duration,
+}
+
+#[derive(Error, Debug)]
+#[error("Command failed without network access. Details are in the command report.")]
+pub struct NetworkAccessBlockedMarker;
+
+#[derive(Error, Debug)]
+#[error("Command failed reading undeclared inputs. Details are in the command report.")]
+pub struct UndeclaredInputsMarker {
+    pub paths: Vec<ProjectRelativePathBuf>,
+}
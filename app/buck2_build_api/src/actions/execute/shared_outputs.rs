@@ -0,0 +1,374 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+//! Outputs declared with `configuration_independent = True` resolve to the same path in every
+//! configuration of their target, so the actions producing them in different configurations
+//! would race to write that path. Within a command, only the first configuration to get there
+//! runs such an action, and the others reuse its outputs, provided they are the same action.
+//!
+//! Across commands, the outputs stay where the last configuration to produce them left them,
+//! and configurations built by earlier commands still refer to them. Another configuration only
+//! reuses them if it is the same action, and may not replace them with those of a different one
+//! while they are still what the materializer has at these paths.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use allocative::Allocative;
+use buck2_core::fs::project_rel_path::ProjectRelativePathBuf;
+use buck2_execute::artifact::fs::ExecutorFs;
+use buck2_execute::artifact_value::ArtifactValue;
+use dice::UserComputationData;
+use dupe::Dupe;
+use indexmap::IndexMap;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use regex::Regex;
+use thiserror::Error;
+use tokio::sync::OnceCell;
+
+use crate::actions::execute::action_executor::ActionOutputs;
+use crate::actions::RegisteredAction;
+use crate::artifact_groups::ArtifactGroup;
+use crate::artifact_groups::ArtifactGroupValues;
+
+#[derive(Debug, Error)]
+enum SharedOutputsError {
+    #[error(
+        "Configuration-independent output `{path}` is declared by `{first}` and `{second}`, which produce it with different actions. Only actions whose outputs are all configuration-independent can be shared between configurations"
+    )]
+    Conflict {
+        path: ProjectRelativePathBuf,
+        first: String,
+        second: String,
+    },
+    #[error(
+        "Configuration-independent output `{path}` was produced by `{previous}` with a different action than `{owner}` would use, and building `{owner}` would replace it. The contents of configuration-independent outputs must not depend on the configuration. If the inputs of `{previous}` changed since, build it again first"
+    )]
+    Overwrite {
+        path: ProjectRelativePathBuf,
+        previous: String,
+        owner: String,
+    },
+}
+
+/// What identifies an action producing configuration-independent outputs, regardless of its
+/// configuration. Two configurations can only share outputs if their actions have the same key.
+#[derive(Debug, Clone, PartialEq, Eq, Allocative)]
+pub(crate) struct SharedActionKey {
+    /// The kind, category and identifier of the action.
+    pub(crate) action: String,
+    /// The contents of the inputs of the action, in order.
+    pub(crate) inputs: Vec<ArtifactValue>,
+    /// The attributes of the action as shown by aquery (e.g. its command line), with the
+    /// configuration hashes of the owner replaced, since they appear in the paths of its inputs.
+    pub(crate) attributes: Vec<(String, String)>,
+}
+
+impl SharedActionKey {
+    pub(crate) fn new(
+        action: &RegisteredAction,
+        inputs: &IndexMap<ArtifactGroup, ArtifactGroupValues>,
+        fs: &ExecutorFs,
+    ) -> Self {
+        // Configuration hashes appear in output paths as `<hash>` or `<hash>-<exec hash>`.
+        static CONFIGURATION_HASH: Lazy<Regex> =
+            Lazy::new(|| Regex::new("[0-9a-f]{16}(-[0-9a-f]{16})?").unwrap());
+
+        Self {
+            action: format!(
+                "{:?} {} {}",
+                action.kind(),
+                action.category(),
+                action.identifier().unwrap_or_default()
+            ),
+            inputs: inputs
+                .values()
+                .flat_map(|values| values.iter().map(|(_, value)| value.dupe()))
+                .collect(),
+            attributes: action
+                .aquery_attributes(fs)
+                .into_iter()
+                .map(|(k, v)| (k, CONFIGURATION_HASH.replace_all(&v, "<cfg>").into_owned()))
+                .collect(),
+        }
+    }
+}
+
+/// An action producing configuration-independent outputs, as run by the first configuration
+/// that claimed them.
+pub(crate) struct SharedAction {
+    owner: String,
+    /// The resolved paths of all the outputs of the action.
+    paths: Vec<ProjectRelativePathBuf>,
+    /// Whether the action can be shared, i.e. whether all its outputs are
+    /// configuration-independent.
+    shareable: bool,
+    key: SharedActionKey,
+    outputs: OnceCell<ActionOutputs>,
+}
+
+impl SharedAction {
+    pub(crate) fn outputs(&self) -> &OnceCell<ActionOutputs> {
+        &self.outputs
+    }
+
+    pub(crate) fn key(&self) -> &SharedActionKey {
+        &self.key
+    }
+}
+
+/// The configuration-independent outputs produced by a previous command.
+#[derive(Allocative)]
+pub(crate) struct ProducedOutputs {
+    owner: String,
+    key: SharedActionKey,
+    /// The resolved paths of the outputs, in the same order as `outputs`.
+    paths: Vec<ProjectRelativePathBuf>,
+    outputs: ActionOutputs,
+}
+
+impl ProducedOutputs {
+    pub(crate) fn key(&self) -> &SharedActionKey {
+        &self.key
+    }
+
+    pub(crate) fn outputs(&self) -> &ActionOutputs {
+        &self.outputs
+    }
+
+    /// The outputs at their resolved paths, as declared to the materializer.
+    pub(crate) fn declared(&self) -> Vec<(ProjectRelativePathBuf, ArtifactValue)> {
+        self.paths
+            .iter()
+            .cloned()
+            .zip(self.outputs.values().map(|v| v.dupe()))
+            .collect()
+    }
+}
+
+/// The configuration-independent outputs produced since the daemon started, by path. This lives
+/// as long as the materializer state it describes.
+#[derive(Default, Allocative)]
+pub struct SharedOutputsHistory {
+    produced: Mutex<HashMap<ProjectRelativePathBuf, Arc<ProducedOutputs>>>,
+}
+
+/// The configuration-independent outputs claimed by actions in the current command. This is
+/// per-command so that an action whose inputs changed runs again in the next command.
+#[derive(Default)]
+pub struct SharedOutputs {
+    claims: Mutex<HashMap<ProjectRelativePathBuf, Arc<SharedAction>>>,
+    history: Arc<SharedOutputsHistory>,
+}
+
+impl SharedOutputs {
+    pub fn new(history: Arc<SharedOutputsHistory>) -> Self {
+        Self {
+            claims: Mutex::default(),
+            history,
+        }
+    }
+
+    /// Claim the configuration-independent `shared` outputs of an action of `owner` whose outputs
+    /// resolve to `paths`. Returns the action that should produce them: either this one, or an
+    /// identical action of another configuration to wait for. Fails if another configuration
+    /// claimed one of these paths for an action that can't be shared with this one.
+    pub(crate) fn claim(
+        &self,
+        owner: String,
+        paths: Vec<ProjectRelativePathBuf>,
+        shared: &[ProjectRelativePathBuf],
+        key: SharedActionKey,
+    ) -> anyhow::Result<Arc<SharedAction>> {
+        let mut claims = self.claims.lock();
+
+        let shareable = shared.len() == paths.len();
+        for path in shared {
+            if let Some(existing) = claims.get(path) {
+                if existing.owner == owner
+                    || (shareable
+                        && existing.shareable
+                        && existing.paths == paths
+                        && existing.key == key)
+                {
+                    return Ok(existing.dupe());
+                }
+                return Err(SharedOutputsError::Conflict {
+                    path: path.clone(),
+                    first: existing.owner.clone(),
+                    second: owner,
+                }
+                .into());
+            }
+        }
+
+        let action = Arc::new(SharedAction {
+            owner,
+            paths,
+            shareable,
+            key,
+            outputs: OnceCell::new(),
+        });
+        for path in shared {
+            claims.insert(path.clone(), action.dupe());
+        }
+        Ok(action)
+    }
+
+    /// The outputs that another configuration than the one of `action` produced at one of the
+    /// configuration-independent paths of `action` in a previous command, if any.
+    pub(crate) fn previous(&self, action: &SharedAction) -> Option<Arc<ProducedOutputs>> {
+        let produced = self.history.produced.lock();
+        action
+            .paths
+            .iter()
+            .filter_map(|path| produced.get(path))
+            .find(|previous| previous.owner != action.owner)
+            .map(|previous| previous.dupe())
+    }
+
+    /// The error for replacing the outputs `previous` with those of `action`, a different action.
+    pub(crate) fn overwrite_error(
+        action: &SharedAction,
+        previous: &ProducedOutputs,
+    ) -> anyhow::Error {
+        let path = action
+            .paths
+            .iter()
+            .find(|path| previous.paths.contains(path))
+            .unwrap_or(&action.paths[0]);
+        SharedOutputsError::Overwrite {
+            path: path.clone(),
+            previous: previous.owner.clone(),
+            owner: action.owner.clone(),
+        }
+        .into()
+    }
+
+    /// Record that `action` produced `outputs`, at its resolved paths.
+    pub(crate) fn record(&self, action: &SharedAction, outputs: &ActionOutputs) {
+        let produced = Arc::new(ProducedOutputs {
+            owner: action.owner.clone(),
+            key: action.key.clone(),
+            paths: action.paths.clone(),
+            outputs: outputs.dupe(),
+        });
+        let mut history = self.history.produced.lock();
+        for path in &action.paths {
+            history.insert(path.clone(), produced.dupe());
+        }
+    }
+}
+
+pub trait HasSharedOutputs {
+    fn set_shared_outputs(&mut self, shared_outputs: Arc<SharedOutputs>);
+
+    fn get_shared_outputs(&self) -> Arc<SharedOutputs>;
+}
+
+impl HasSharedOutputs for UserComputationData {
+    fn set_shared_outputs(&mut self, shared_outputs: Arc<SharedOutputs>) {
+        self.data.set(shared_outputs);
+    }
+
+    fn get_shared_outputs(&self) -> Arc<SharedOutputs> {
+        self.data
+            .get::<Arc<SharedOutputs>>()
+            .expect("SharedOutputs should be set")
+            .dupe()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn paths(paths: &[&str]) -> Vec<ProjectRelativePathBuf> {
+        paths
+            .iter()
+            .map(|p| ProjectRelativePathBuf::unchecked_new((*p).to_owned()))
+            .collect()
+    }
+
+    fn key(cmd: &str) -> SharedActionKey {
+        SharedActionKey {
+            action: "run".to_owned(),
+            inputs: Vec::new(),
+            attributes: vec![("cmd".to_owned(), cmd.to_owned())],
+        }
+    }
+
+    #[test]
+    fn test_claim() -> anyhow::Result<()> {
+        let shared_outputs = SharedOutputs::default();
+
+        let all = paths(&["shared/a", "shared/b"]);
+        let first =
+            shared_outputs.claim("//:t (cfg1)".to_owned(), all.clone(), &all, key("gen"))?;
+        let second =
+            shared_outputs.claim("//:t (cfg2)".to_owned(), all.clone(), &all, key("gen"))?;
+        assert!(Arc::ptr_eq(&first, &second));
+
+        // An action that also has per-configuration outputs can't be shared.
+        let mixed = paths(&["shared/a", "cfg3/c"]);
+        assert!(
+            shared_outputs
+                .claim(
+                    "//:t (cfg3)".to_owned(),
+                    mixed,
+                    &paths(&["shared/a"]),
+                    key("gen")
+                )
+                .is_err()
+        );
+
+        // Nor can one that produces a different set of outputs.
+        let other = paths(&["shared/b"]);
+        assert!(
+            shared_outputs
+                .claim("//:t (cfg4)".to_owned(), other.clone(), &other, key("gen"))
+                .is_err()
+        );
+
+        // Nor can a different action producing the same outputs.
+        assert!(
+            shared_outputs
+                .claim("//:t (cfg5)".to_owned(), all.clone(), &all, key("other"))
+                .is_err()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_previous() -> anyhow::Result<()> {
+        let history = Arc::new(SharedOutputsHistory::default());
+        let all = paths(&["shared/a"]);
+
+        let first_command = SharedOutputs::new(history.dupe());
+        let first = first_command.claim("//:t (cfg1)".to_owned(), all.clone(), &all, key("gen"))?;
+        assert!(first_command.previous(&first).is_none());
+        first_command.record(&first, &ActionOutputs::new(Default::default()));
+
+        // The next command sees what the other configuration produced, but not what its own did.
+        let second_command = SharedOutputs::new(history);
+        let own = second_command.claim("//:t (cfg1)".to_owned(), all.clone(), &all, key("gen"))?;
+        assert!(second_command.previous(&own).is_none());
+
+        let second_command = SharedOutputs::new(second_command.history.dupe());
+        let other =
+            second_command.claim("//:t (cfg2)".to_owned(), all.clone(), &all, key("other"))?;
+        let previous = second_command.previous(&other).unwrap();
+        assert_eq!("//:t (cfg1)", previous.owner);
+        assert_ne!(previous.key(), other.key());
+
+        Ok(())
+    }
+}
@@ -7,12 +7,28 @@
  * of this source tree.
  */
 
+use std::collections::HashMap;
+
+use buck2_core::fs::paths::abs_norm_path::AbsNormPathBuf;
 use buck2_util::late_binding::LateBinding;
 
 pub static FLUSH_DEP_FILES: LateBinding<fn()> = LateBinding::new("FLUSH_DEP_FILES");
 
+pub static INITIALIZE_DEP_FILES_STATE: LateBinding<
+    fn(AbsNormPathBuf, HashMap<String, String>) -> anyhow::Result<()>,
+> = LateBinding::new("INITIALIZE_DEP_FILES_STATE");
+
 /// Forget about all dep files. This isn't really meant to be commonly used, but if an invalid dep
 /// file was produced and the user wants unblocking, this will provide it.
 pub fn flush_dep_files() {
     (FLUSH_DEP_FILES.get().unwrap())();
 }
+
+/// Persist dep file state in a sqlite db in `dep_files_state_dir`, and reuse the state found there
+/// if it was written with the same `versions`. Otherwise, the existing state is discarded.
+pub fn initialize_dep_files_state(
+    dep_files_state_dir: AbsNormPathBuf,
+    versions: HashMap<String, String>,
+) -> anyhow::Result<()> {
+    (INITIALIZE_DEP_FILES_STATE.get()?)(dep_files_state_dir, versions)
+}
@@ -7,11 +7,34 @@
  * of this source tree.
  */
 
+use std::sync::Arc;
+
+use buck2_core::category::Category;
 use dice::UserComputationData;
 use dupe::Dupe;
 
+/// Which actions to run a second time to check that their outputs are reproducible.
+#[derive(Clone, Dupe, Default)]
+pub enum DeterminismCheck {
+    #[default]
+    Disabled,
+    All,
+    /// Only actions in these categories.
+    Categories(Arc<Vec<String>>),
+}
+
+impl DeterminismCheck {
+    pub fn includes(&self, category: &Category) -> bool {
+        match self {
+            Self::Disabled => false,
+            Self::All => true,
+            Self::Categories(categories) => categories.iter().any(|c| c == category.as_str()),
+        }
+    }
+}
+
 /// Knobs controlling how RunAction works.
-#[derive(Copy, Clone, Dupe, Default)]
+#[derive(Clone, Dupe, Default)]
 pub struct RunActionKnobs {
     /// Process dep files as they are generated.
     pub eager_dep_files: bool,
@@ -19,6 +42,10 @@ pub struct RunActionKnobs {
     /// Hash all commands using the same mechanism as dep files. This allows us to skip
     /// re-executing commands if their inputs and outputs haven't changed.
     pub hash_all_commands: bool,
+
+    /// Run commands that executed locally a second time, locally and without the action cache,
+    /// and report the outputs that differ.
+    pub check_determinism: DeterminismCheck,
 }
 
 pub trait HasRunActionKnobs {
@@ -33,9 +60,9 @@ impl HasRunActionKnobs for UserComputationData {
     }
 
     fn get_run_action_knobs(&self) -> RunActionKnobs {
-        *self
-            .data
+        self.data
             .get::<RunActionKnobs>()
             .expect("RunActionKnobs should be set")
+            .dupe()
     }
 }
@@ -50,6 +50,7 @@ use buck2_execute::artifact_value::ArtifactValue;
 use buck2_execute::digest_config::DigestConfig;
 use buck2_execute::execute::blocking::BlockingExecutor;
 use buck2_execute::execute::request::CommandExecutionRequest;
+use buck2_execute::materialize::http::DownloadConfig;
 use buck2_execute::materialize::materializer::Materializer;
 use buck2_execute::re::manager::ManagedRemoteExecutionClient;
 use derivative::Derivative;
@@ -67,6 +68,7 @@ use crate::actions::artifact::provide_outputs::ProvideOutputs;
 use crate::actions::execute::action_execution_target::ActionExecutionTarget;
 use crate::actions::execute::action_executor::ActionExecutionMetadata;
 use crate::actions::execute::action_executor::ActionOutputs;
+use crate::actions::impls::expanded_command_line::ExpandedCommandLine;
 use crate::actions::impls::run_action_knobs::RunActionKnobs;
 use crate::artifact_groups::ArtifactGroup;
 use crate::artifact_groups::ArtifactGroupValues;
@@ -143,6 +145,12 @@ pub trait Action: Allocative + Debug + Send + Sync + 'static {
         indexmap! {}
     }
 
+    /// The command line and environment this action would run with, if it runs a command. This
+    /// is used by aquery to inspect actions without executing them.
+    fn aquery_command_line(&self, _fs: &ExecutorFs) -> anyhow::Result<Option<ExpandedCommandLine>> {
+        Ok(None)
+    }
+
     // TODO this probably wants more data for execution, like printing a short_name and the target
 }
 
@@ -212,6 +220,9 @@ pub trait ActionExecutionCtx: Send + Sync {
 
     /// Obtian per-command knobs for RunAction.
     fn run_action_knobs(&self) -> RunActionKnobs;
+
+    /// Obtain the per-command config for downloads.
+    fn download_config(&self) -> Arc<DownloadConfig>;
 }
 
 #[derive(Error, Debug)]
@@ -347,6 +358,7 @@ pub(crate) mod testings {
     use allocative::Allocative;
     use async_trait::async_trait;
     use buck2_core::category::Category;
+    use buck2_execute::artifact::fs::ExecutorFs;
     use buck2_execute::execute::request::CommandExecutionOutput;
     use buck2_execute::execute::request::CommandExecutionPaths;
     use buck2_execute::execute::request::CommandExecutionRequest;
@@ -361,6 +373,7 @@ pub(crate) mod testings {
     use crate::actions::box_slice_set::BoxSliceSet;
     use crate::actions::execute::action_executor::ActionExecutionMetadata;
     use crate::actions::execute::action_executor::ActionOutputs;
+    use crate::actions::impls::expanded_command_line::ExpandedCommandLine;
     use crate::actions::Action;
     use crate::actions::ActionExecutable;
     use crate::actions::ActionExecutionCtx;
@@ -461,6 +474,16 @@ pub(crate) mod testings {
         fn identifier(&self) -> Option<&str> {
             self.identifier.as_deref()
         }
+
+        fn aquery_command_line(
+            &self,
+            _fs: &ExecutorFs,
+        ) -> anyhow::Result<Option<ExpandedCommandLine>> {
+            Ok(Some(ExpandedCommandLine {
+                cli: self.cmd.clone(),
+                env: sorted_vector_map![],
+            }))
+        }
     }
 
     #[async_trait]
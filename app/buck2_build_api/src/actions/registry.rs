@@ -21,6 +21,7 @@ use buck2_core::directory::DirectoryInsertError;
 use buck2_core::directory::DirectoryIterator;
 use buck2_core::directory::NoDigest;
 use buck2_core::fs::buck_out_path::BuckOutPath;
+use buck2_core::fs::buck_out_path::BuckOutPathKind;
 use buck2_core::fs::paths::forward_rel_path::ForwardRelativePath;
 use buck2_core::fs::paths::forward_rel_path::ForwardRelativePathBuf;
 use buck2_execute::execute::request::OutputType;
@@ -151,16 +152,18 @@ impl ActionsRegistry {
         path: ForwardRelativePathBuf,
         output_type: OutputType,
         declaration_location: Option<FileSpan>,
+        path_kind: BuckOutPathKind,
     ) -> anyhow::Result<DeclaredArtifact> {
         let (path, hidden) = match prefix {
             None => (path, 0),
             Some(prefix) => (prefix.join(path), prefix.iter().count()),
         };
         self.claim_output_path(&path, declaration_location)?;
-        let out_path = BuckOutPath::with_action_key(
+        let out_path = BuckOutPath::with_action_key_and_kind(
             self.owner.dupe().into_dyn(),
             path,
             self.action_key.dupe(),
+            path_kind,
         );
         let declared = DeclaredArtifact::new(out_path, output_type, hidden);
         if !self.artifacts.insert(declared.dupe()) {
@@ -263,6 +266,7 @@ mod tests {
     use buck2_core::configuration::data::ConfigurationData;
     use buck2_core::configuration::pair::ConfigurationNoExec;
     use buck2_core::fs::buck_out_path::BuckOutPath;
+    use buck2_core::fs::buck_out_path::BuckOutPathKind;
     use buck2_core::fs::paths::forward_rel_path::ForwardRelativePathBuf;
     use buck2_core::target::label::ConfiguredTargetLabel;
     use buck2_execute::execute::request::OutputType;
@@ -295,20 +299,38 @@ mod tests {
             ActionsRegistry::new(base.dupe(), ExecutionPlatformResolution::unspecified());
         let out1 = ForwardRelativePathBuf::unchecked_new("bar.out".into());
         let buckout1 = BuckOutPath::new(base.dupe().into_dyn(), out1.clone());
-        let declared1 = actions.declare_artifact(None, out1.clone(), OutputType::File, None)?;
+        let declared1 = actions.declare_artifact(
+            None,
+            out1.clone(),
+            OutputType::File,
+            None,
+            BuckOutPathKind::default(),
+        )?;
         declared1
             .get_path()
             .with_full_path(|p| assert_eq!(p, buckout1.path()));
 
         let out2 = ForwardRelativePathBuf::unchecked_new("bar2.out".into());
         let buckout2 = BuckOutPath::new(base.into_dyn(), out2.clone());
-        let declared2 = actions.declare_artifact(None, out2, OutputType::File, None)?;
+        let declared2 = actions.declare_artifact(
+            None,
+            out2,
+            OutputType::File,
+            None,
+            BuckOutPathKind::default(),
+        )?;
         declared2
             .get_path()
             .with_full_path(|p| assert_eq!(p, buckout2.path()));
 
         if actions
-            .declare_artifact(None, out1, OutputType::File, None)
+            .declare_artifact(
+                None,
+                out1,
+                OutputType::File,
+                None,
+                BuckOutPathKind::default(),
+            )
             .is_ok()
         {
             panic!("should error due to duplicate artifact")
@@ -395,7 +417,13 @@ mod tests {
         let mut actions =
             ActionsRegistry::new(base.dupe(), ExecutionPlatformResolution::unspecified());
         let out = ForwardRelativePathBuf::unchecked_new("bar.out".into());
-        let declared = actions.declare_artifact(None, out, OutputType::File, None)?;
+        let declared = actions.declare_artifact(
+            None,
+            out,
+            OutputType::File,
+            None,
+            BuckOutPathKind::default(),
+        )?;
 
         let inputs = indexset![ArtifactGroup::Artifact(
             BuildArtifact::testing_new(
@@ -445,7 +473,13 @@ mod tests {
             ),
         );
         let out = ForwardRelativePathBuf::unchecked_new("bar.out".into());
-        let declared = actions.declare_artifact(None, out, OutputType::File, None)?;
+        let declared = actions.declare_artifact(
+            None,
+            out,
+            OutputType::File,
+            None,
+            BuckOutPathKind::default(),
+        )?;
 
         let inputs = indexset![ArtifactGroup::Artifact(
             BuildArtifact::testing_new(
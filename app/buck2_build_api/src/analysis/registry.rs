@@ -14,6 +14,7 @@ use std::sync::Arc;
 use allocative::Allocative;
 use buck2_core::collections::ordered_set::OrderedSet;
 use buck2_core::fs::buck_out_path::BuckOutPath;
+use buck2_core::fs::buck_out_path::BuckOutPathKind;
 use buck2_core::fs::paths::forward_rel_path::ForwardRelativePath;
 use buck2_execute::execute::request::OutputType;
 use buck2_interpreter::starlark_promise::StarlarkPromise;
@@ -133,6 +134,7 @@ impl<'v> AnalysisRegistry<'v> {
         filename: &str,
         output_type: OutputType,
         declaration_location: Option<FileSpan>,
+        path_kind: BuckOutPathKind,
     ) -> anyhow::Result<DeclaredArtifact> {
         // We want this artifact to be a file/directory inside the current context, which means
         // things like `..` and the empty path `.` can be bad ideas. The `::new` method checks for those
@@ -148,7 +150,7 @@ impl<'v> AnalysisRegistry<'v> {
             Some(x) => Some(ForwardRelativePath::new(x)?.to_owned()),
         };
         self.actions
-            .declare_artifact(prefix, path, output_type, declaration_location)
+            .declare_artifact(prefix, path, output_type, declaration_location, path_kind)
     }
 
     /// Takes a string or artifact/output artifact and converts it into an output artifact
@@ -173,8 +175,13 @@ impl<'v> AnalysisRegistry<'v> {
         let declaration_location = eval.call_stack_top_location();
         let heap = eval.heap();
         if let Some(path) = value.unpack_str() {
-            let artifact =
-                self.declare_output(None, path, output_type, declaration_location.dupe())?;
+            let artifact = self.declare_output(
+                None,
+                path,
+                output_type,
+                declaration_location.dupe(),
+                BuckOutPathKind::default(),
+            )?;
             Ok((
                 ArtifactDeclaration {
                     artifact: ArtifactDeclarationKind::DeclaredArtifact(artifact.dupe()),
@@ -56,6 +56,7 @@ pub mod testing {
     use buck2_core::collections::ordered_set::OrderedSet;
     use buck2_core::configuration::data::ConfigurationData;
     use buck2_core::fs::artifact_path_resolver::ArtifactFs;
+    use buck2_core::fs::buck_out_path::BuckOutPathKind;
     use buck2_core::fs::buck_out_path::BuckOutPathResolver;
     use buck2_core::fs::paths::abs_norm_path::AbsNormPathBuf;
     use buck2_core::fs::paths::forward_rel_path::ForwardRelativePathBuf;
@@ -159,6 +160,7 @@ pub mod testing {
                 ForwardRelativePathBuf::try_from(path.to_owned()).unwrap(),
                 OutputType::File,
                 None,
+                BuckOutPathKind::default(),
             )?;
             Ok(StarlarkDeclaredArtifact::new(
                 None,
@@ -185,6 +187,7 @@ pub mod testing {
                 ForwardRelativePathBuf::try_from(path.to_owned()).unwrap(),
                 OutputType::File,
                 None,
+                BuckOutPathKind::default(),
             )?;
             let outputs = indexset![artifact.as_output()];
             registry.register(
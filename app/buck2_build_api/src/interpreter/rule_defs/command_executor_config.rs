@@ -17,6 +17,7 @@ use buck2_common::executor_config::CommandGenerationOptions;
 use buck2_common::executor_config::Executor;
 use buck2_common::executor_config::HybridExecutionLevel;
 use buck2_common::executor_config::LocalExecutorOptions;
+use buck2_common::executor_config::LocalSandboxOptions;
 use buck2_common::executor_config::PathSeparatorKind;
 use buck2_common::executor_config::RemoteEnabledExecutor;
 use buck2_common::executor_config::RemoteExecutorOptions;
@@ -46,6 +47,12 @@ enum CommandExecutorConfigErrors {
         "executor config must specify at least `local_enabled = True` or `remote_enabled = True`"
     )]
     NoExecutor,
+    #[error("`use_local_sandbox` requires `local_enabled = True`")]
+    SandboxWithoutLocal,
+    #[error("`local_sandbox_isolate_network` requires `use_local_sandbox = True`")]
+    IsolateNetworkWithoutSandbox,
+    #[error("`use_adaptive_hybrid` can't be used with `use_limited_hybrid = True`")]
+    AdaptiveWithLimitedHybrid,
 }
 
 #[derive(Debug, Display, NoSerialize, ProvidesStaticType, Allocative)]
@@ -81,7 +88,15 @@ pub fn register_command_executor_config(builder: &mut GlobalsBuilder) {
     /// * `allow_cache_uploads`: Whether to upload local actions to the RE cache
     /// * `max_cache_upload_mebibytes`: Maximum size to upload in cache uploads
     /// * `experimental_low_pass_filter`: Whether to use the experimental low pass filter
+    /// * `use_adaptive_hybrid`: Whether to only race actions until we know which executor is faster
+    /// for them, based on how long they took in previous builds
     /// * `remote_output_paths`: How to express output paths to RE
+    /// * `use_local_sandbox`: Whether to run local actions in a Linux namespace sandbox where only
+    /// their declared inputs are visible (Linux only)
+    /// * `local_sandbox_isolate_network`: Whether sandboxed local actions should also lose network access
+    /// * `block_local_network`: Whether to run local actions without network access, unless they
+    /// set `allow_network = True`. This requires Linux and the forkserver: elsewhere, those actions
+    /// fail, unless `buck2.allow_unenforced_network_block` is set
     #[starlark(type = "command_executor_config")]
     fn CommandExecutorConfig<'v>(
         local_enabled: bool,
@@ -103,7 +118,11 @@ pub fn register_command_executor_config(builder: &mut GlobalsBuilder) {
             i32,
         >,
         #[starlark(default = false, require = named)] experimental_low_pass_filter: bool,
+        #[starlark(default = false, require = named)] use_adaptive_hybrid: bool,
         #[starlark(default = NoneOr::None, require = named)] remote_output_paths: NoneOr<&str>,
+        #[starlark(default = false, require = named)] use_local_sandbox: bool,
+        #[starlark(default = false, require = named)] local_sandbox_isolate_network: bool,
+        #[starlark(default = false, require = named)] block_local_network: bool,
         heap: &'v Heap,
     ) -> anyhow::Result<Value<'v>> {
         let command_executor_config = {
@@ -143,8 +162,27 @@ pub fn register_command_executor_config(builder: &mut GlobalsBuilder) {
                 Some(RemoteExecutorUseCase::new(re_use_case.to_owned()))
             };
 
+            if use_adaptive_hybrid && use_limited_hybrid {
+                return Err(CommandExecutorConfigErrors::AdaptiveWithLimitedHybrid.into());
+            }
+            if use_local_sandbox && !local_enabled {
+                return Err(CommandExecutorConfigErrors::SandboxWithoutLocal.into());
+            }
+            if local_sandbox_isolate_network && !use_local_sandbox {
+                return Err(CommandExecutorConfigErrors::IsolateNetworkWithoutSandbox.into());
+            }
+
             let local_options = if local_enabled {
-                Some(LocalExecutorOptions {})
+                Some(LocalExecutorOptions {
+                    sandbox: if use_local_sandbox {
+                        Some(LocalSandboxOptions {
+                            isolate_network: local_sandbox_isolate_network,
+                        })
+                    } else {
+                        None
+                    },
+                    block_network: block_local_network,
+                })
             } else {
                 None
             };
@@ -184,6 +222,9 @@ pub fn register_command_executor_config(builder: &mut GlobalsBuilder) {
                     fallback_on_failure,
                 },
                 (true, false) => HybridExecutionLevel::Limited,
+                (false, _) if use_adaptive_hybrid => HybridExecutionLevel::Adaptive {
+                    fallback_on_failure,
+                },
                 (false, _) => HybridExecutionLevel::Full {
                     fallback_on_failure,
                     low_pass_filter: experimental_low_pass_filter,
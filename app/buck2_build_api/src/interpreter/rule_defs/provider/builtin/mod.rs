@@ -21,3 +21,4 @@ pub mod platform_info;
 pub mod run_info;
 pub mod template_placeholder_info;
 mod tests;
+pub mod worker_info;
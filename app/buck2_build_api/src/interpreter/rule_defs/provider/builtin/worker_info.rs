@@ -0,0 +1,135 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+use std::fmt::Debug;
+
+use allocative::Allocative;
+use buck2_build_api_derive::internal_provider;
+use starlark::any::ProvidesStaticType;
+use starlark::coerce::Coerce;
+use starlark::environment::GlobalsBuilder;
+use starlark::eval::Evaluator;
+use starlark::values::list::AllocList;
+use starlark::values::Freeze;
+use starlark::values::Trace;
+use starlark::values::Value;
+use starlark::values::ValueLike;
+use thiserror::Error;
+
+use crate::interpreter::rule_defs::cmd_args::CommandLineArgLike;
+use crate::interpreter::rule_defs::cmd_args::StarlarkCommandLine;
+use crate::interpreter::rule_defs::cmd_args::ValueAsCommandLineLike;
+
+#[derive(Debug, Error)]
+enum WorkerInfoError {
+    #[error("`concurrency` must be a positive integer, got `{0}`")]
+    InvalidConcurrency(i32),
+}
+
+/// Provider that signals that a rule can be run as a persistent worker. Passing it as the
+/// `worker` of a `run` action runs the action in a long-lived instance of `exe` when possible.
+#[internal_provider(worker_info_creator)]
+#[derive(Clone, Debug, Trace, Coerce, Freeze, ProvidesStaticType, Allocative)]
+#[repr(C)]
+pub struct WorkerInfoGen<V> {
+    /// The command that starts the worker, stored as CommandLine. It is prepended to the
+    /// arguments of run actions using the worker.
+    #[provider(field_type = "StarlarkCommandLine")]
+    exe: V,
+    /// How many requests a single instance of the worker can handle concurrently
+    #[provider(field_type = "i32")]
+    concurrency: V,
+}
+
+impl<'v, V: ValueLike<'v>> WorkerInfoGen<V> {
+    pub fn exe(&self) -> &'v dyn CommandLineArgLike {
+        self.exe
+            .to_value()
+            .as_command_line()
+            .expect("a command line from construction")
+    }
+
+    pub fn concurrency(&self) -> usize {
+        self.concurrency
+            .to_value()
+            .unpack_i32()
+            .expect("an integer from construction") as usize
+    }
+}
+
+#[starlark_module]
+fn worker_info_creator(globals: &mut GlobalsBuilder) {
+    #[starlark(type = "WorkerInfo")]
+    fn WorkerInfo<'v>(
+        #[starlark(require = named, default = AllocList::EMPTY)] exe: Value<'v>,
+        #[starlark(require = named, default = 1)] concurrency: i32,
+        eval: &mut Evaluator<'v, '_>,
+    ) -> anyhow::Result<WorkerInfo<'v>> {
+        let heap = eval.heap();
+        let valid_exe = StarlarkCommandLine::try_from_value(exe)?;
+        if concurrency < 1 {
+            return Err(WorkerInfoError::InvalidConcurrency(concurrency).into());
+        }
+        Ok(WorkerInfo {
+            exe: heap.alloc(valid_exe),
+            concurrency: heap.alloc(concurrency),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use buck2_common::result::SharedResult;
+    use buck2_interpreter_for_build::interpreter::testing::Tester;
+    use indoc::indoc;
+
+    use crate::interpreter::rule_defs::artifact::testing::artifactory;
+    use crate::interpreter::rule_defs::provider::collection::tester::collection_creator;
+    use crate::interpreter::rule_defs::register_rule_defs;
+
+    fn worker_info_tester() -> Tester {
+        let mut tester = Tester::new().unwrap();
+        tester.additional_globals(artifactory);
+        tester.additional_globals(register_rule_defs);
+        tester.additional_globals(collection_creator);
+        tester
+    }
+
+    #[test]
+    fn worker_info_works_as_provider_key() -> SharedResult<()> {
+        let mut tester = worker_info_tester();
+
+        let content = indoc!(
+            r#"
+            a = source_artifact("foo/bar", "worker.sh")
+            c = create_collection([WorkerInfo(exe=[a, "--flag"], concurrency=4), DefaultInfo()])
+            def test():
+                assert_eq(True, contains_provider(c, WorkerInfo))
+                assert_eq(4, c[WorkerInfo].concurrency)
+            "#
+        );
+
+        tester.run_starlark_bzl_test(content)
+    }
+
+    #[test]
+    fn worker_info_validates_concurrency() {
+        let content = indoc!(
+            r#"
+            def test():
+                WorkerInfo(exe=["worker"], concurrency=0)
+            "#
+        );
+        let mut tester = worker_info_tester();
+        tester.run_starlark_bzl_test_expecting_error(
+            content,
+            "`concurrency` must be a positive integer",
+        );
+    }
+}
@@ -9,6 +9,7 @@
 
 pub mod calculation;
 pub mod lookup;
+pub mod rdeps_index;
 
 use buck2_node::attrs::coerced_attr::CoercedAttr;
 
@@ -0,0 +1,282 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+//! A reverse dependency index over all the unconfigured target nodes in the repository.
+//!
+//! The index is assembled on DICE from per-package edge lists. When a build file changes, only
+//! the affected package is re-evaluated, and the index is only rebuilt if that package's edges
+//! actually changed. Packages that fail to evaluate are left out of the index rather than
+//! failing it, so that a single broken build file doesn't make `allrdeps` unusable.
+
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+use allocative::Allocative;
+use async_trait::async_trait;
+use buck2_common::dice::cells::HasCellResolver;
+use buck2_common::pattern::package_roots::find_package_roots_stream;
+use buck2_common::result::SharedResult;
+use buck2_common::result::ToSharedResultExt;
+use buck2_common::result::ToUnsharedResultExt;
+use buck2_core::cells::cell_path::CellPath;
+use buck2_core::cells::paths::CellRelativePath;
+use buck2_core::package::PackageLabel;
+use buck2_core::target::label::TargetLabel;
+use buck2_interpreter_for_build::interpreter::calculation::InterpreterCalculation;
+use derive_more::Display;
+use dice::DiceComputations;
+use dice::Key;
+use dupe::Dupe;
+use futures::TryStreamExt;
+use indexmap::IndexSet;
+use tracing::warn;
+
+/// Reverse dependencies of all the unconfigured target nodes in the repository.
+#[derive(Debug, Default, PartialEq, Eq, Allocative)]
+pub struct ReverseDepsIndex {
+    rdeps: HashMap<TargetLabel, Vec<TargetLabel>>,
+    /// The packages that failed to evaluate, and whose targets are therefore missing.
+    broken_packages: Vec<PackageLabel>,
+}
+
+impl ReverseDepsIndex {
+    fn from_edges<'a>(
+        edges: impl IntoIterator<Item = &'a (TargetLabel, TargetLabel)>,
+        mut broken_packages: Vec<PackageLabel>,
+    ) -> Self {
+        let mut rdeps: HashMap<TargetLabel, Vec<TargetLabel>> = HashMap::new();
+        for (dep, dependent) in edges {
+            rdeps.entry(dep.dupe()).or_default().push(dependent.dupe());
+        }
+        for dependents in rdeps.values_mut() {
+            dependents.sort();
+            dependents.dedup();
+        }
+        broken_packages.sort();
+        Self {
+            rdeps,
+            broken_packages,
+        }
+    }
+
+    /// The packages that failed to evaluate. Their targets and deps are not in the index.
+    pub fn broken_packages(&self) -> &[PackageLabel] {
+        &self.broken_packages
+    }
+
+    /// The targets that directly depend on `target`.
+    pub fn rdeps(&self, target: &TargetLabel) -> &[TargetLabel] {
+        self.rdeps.get(target).map_or(&[], |v| v.as_slice())
+    }
+
+    /// All the targets that depend on any of `targets` within `depth` steps, including `targets`
+    /// themselves. Targets are returned in breadth-first order. A `depth` of `None` is unbounded.
+    pub fn transitive_rdeps(
+        &self,
+        targets: impl IntoIterator<Item = TargetLabel>,
+        depth: Option<u32>,
+    ) -> IndexSet<TargetLabel> {
+        let mut seen = IndexSet::new();
+        let mut queue = VecDeque::new();
+        for target in targets {
+            if seen.insert(target.dupe()) {
+                queue.push_back((target, 0));
+            }
+        }
+        while let Some((target, distance)) = queue.pop_front() {
+            if depth.map_or(false, |depth| distance >= depth) {
+                continue;
+            }
+            for rdep in self.rdeps(&target) {
+                if seen.insert(rdep.dupe()) {
+                    queue.push_back((rdep.dupe(), distance + 1));
+                }
+            }
+        }
+        seen
+    }
+}
+
+/// The `(dependency, dependent)` edges contributed by the targets of a single package.
+#[derive(Debug, PartialEq, Eq, Allocative)]
+struct PackageReverseDeps(Vec<(TargetLabel, TargetLabel)>);
+
+#[async_trait]
+pub trait ReverseDepsIndexCalculation {
+    /// Returns the reverse dependency index over every unconfigured target node in the
+    /// repository. This evaluates all the build files the first time it is requested.
+    async fn get_reverse_deps_index(&self) -> anyhow::Result<Arc<ReverseDepsIndex>>;
+}
+
+#[async_trait]
+impl ReverseDepsIndexCalculation for DiceComputations {
+    async fn get_reverse_deps_index(&self) -> anyhow::Result<Arc<ReverseDepsIndex>> {
+        #[async_trait]
+        impl Key for ReverseDepsIndexKey {
+            type Value = SharedResult<Arc<ReverseDepsIndex>>;
+
+            async fn compute(&self, ctx: &DiceComputations) -> Self::Value {
+                compute_reverse_deps_index(ctx).await.shared_error()
+            }
+
+            fn equality(x: &Self::Value, y: &Self::Value) -> bool {
+                match (x, y) {
+                    (Ok(x), Ok(y)) => x == y,
+                    _ => false,
+                }
+            }
+
+            fn validity(x: &Self::Value) -> bool {
+                x.is_ok()
+            }
+        }
+
+        self.compute(&ReverseDepsIndexKey).await?.unshared_error()
+    }
+}
+
+async fn compute_reverse_deps_index(
+    ctx: &DiceComputations,
+) -> anyhow::Result<Arc<ReverseDepsIndex>> {
+    #[async_trait]
+    impl Key for PackageReverseDepsKey {
+        type Value = SharedResult<Arc<PackageReverseDeps>>;
+
+        async fn compute(&self, ctx: &DiceComputations) -> Self::Value {
+            compute_package_reverse_deps(ctx, self.0.dupe())
+                .await
+                .shared_error()
+        }
+
+        fn equality(x: &Self::Value, y: &Self::Value) -> bool {
+            // Comparing here is what keeps the index from being rebuilt when a build file
+            // changes without changing any deps.
+            match (x, y) {
+                (Ok(x), Ok(y)) => x == y,
+                _ => false,
+            }
+        }
+
+        fn validity(x: &Self::Value) -> bool {
+            x.is_ok()
+        }
+    }
+
+    let cell_resolver = ctx.get_cell_resolver().await?;
+    let roots = cell_resolver
+        .cells()
+        .map(|(cell, _)| CellPath::new(cell, CellRelativePath::empty().to_buf()))
+        .collect();
+    let mut packages: Vec<PackageLabel> =
+        find_package_roots_stream(ctx, roots).try_collect().await?;
+    packages.sort();
+    packages.dedup();
+
+    let per_package = futures::future::join_all(packages.into_iter().map(|package| async move {
+        let edges: anyhow::Result<Arc<PackageReverseDeps>> = try {
+            ctx.compute(&PackageReverseDepsKey(package.dupe()))
+                .await?
+                .unshared_error()?
+        };
+        (package, edges)
+    }))
+    .await;
+
+    let mut edges = Vec::new();
+    let mut broken_packages = Vec::new();
+    for (package, package_edges) in per_package {
+        match package_edges {
+            Ok(package_edges) => edges.push(package_edges),
+            Err(e) => {
+                warn!(
+                    "Error evaluating `{}` for the reverse dependency index: {:#}",
+                    package, e
+                );
+                broken_packages.push(package);
+            }
+        }
+    }
+
+    Ok(Arc::new(ReverseDepsIndex::from_edges(
+        edges.iter().flat_map(|edges| edges.0.iter()),
+        broken_packages,
+    )))
+}
+
+async fn compute_package_reverse_deps(
+    ctx: &DiceComputations,
+    package: PackageLabel,
+) -> anyhow::Result<Arc<PackageReverseDeps>> {
+    let eval_result = ctx.get_interpreter_results(package).await?;
+    let mut edges = Vec::new();
+    for node in eval_result.targets().values() {
+        for dep in node.deps() {
+            edges.push((dep.dupe(), node.label().dupe()));
+        }
+    }
+    edges.sort();
+    edges.dedup();
+    Ok(Arc::new(PackageReverseDeps(edges)))
+}
+
+#[derive(Clone, Dupe, Display, Debug, Eq, Hash, PartialEq, Allocative)]
+#[display(fmt = "ReverseDepsIndex")]
+struct ReverseDepsIndexKey;
+
+#[derive(Clone, Dupe, Display, Debug, Eq, Hash, PartialEq, Allocative)]
+#[display(fmt = "PackageReverseDeps({})", _0)]
+struct PackageReverseDepsKey(PackageLabel);
+
+#[cfg(test)]
+mod tests {
+    use buck2_core::target::label::TargetLabel;
+
+    use super::ReverseDepsIndex;
+
+    fn label(s: &str) -> TargetLabel {
+        TargetLabel::testing_parse(s)
+    }
+
+    fn index() -> ReverseDepsIndex {
+        // c -> b -> a, d -> a
+        let edges = vec![
+            (label("root//:a"), label("root//:b")),
+            (label("root//:b"), label("root//:c")),
+            (label("root//:a"), label("root//:d")),
+        ];
+        ReverseDepsIndex::from_edges(&edges, Vec::new())
+    }
+
+    #[test]
+    fn test_transitive_rdeps() {
+        let index = index();
+        let rdeps = index.transitive_rdeps([label("root//:a")], None);
+        assert_eq!(
+            vec![
+                label("root//:a"),
+                label("root//:b"),
+                label("root//:d"),
+                label("root//:c"),
+            ],
+            rdeps.into_iter().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_transitive_rdeps_depth() {
+        let index = index();
+        let rdeps = index.transitive_rdeps([label("root//:a")], Some(1));
+        assert_eq!(
+            vec![label("root//:a"), label("root//:b"), label("root//:d")],
+            rdeps.into_iter().collect::<Vec<_>>()
+        );
+        assert!(index.rdeps(&label("root//:c")).is_empty());
+    }
+}
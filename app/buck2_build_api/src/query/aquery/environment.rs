@@ -17,6 +17,7 @@ use async_trait::async_trait;
 use buck2_core::build_file_path::BuildFilePath;
 use buck2_core::cells::cell_path::CellPath;
 use buck2_core::fs::artifact_path_resolver::ArtifactFs;
+use buck2_core::fs::project_rel_path::ProjectRelativePathBuf;
 use buck2_execute::artifact::fs::ExecutorFs;
 use buck2_query::query::compatibility::MaybeCompatible;
 use buck2_query::query::environment::LabeledNode;
@@ -40,8 +41,10 @@ use ref_cast::RefCast;
 use serde::Serialize;
 use serde::Serializer;
 
+use crate::actions::impls::expanded_command_line::ExpandedCommandLine;
 use crate::actions::key::ActionKey;
 use crate::actions::RegisteredAction;
+use crate::artifact_groups::ArtifactGroup;
 use crate::artifact_groups::TransitiveSetProjectionKey;
 use crate::query::cquery::environment::CqueryDelegate;
 use crate::query::uquery::environment::QueryLiterals;
@@ -114,10 +117,58 @@ impl ActionQueryNode {
         }
     }
 
-    pub fn attrs(&self) -> IndexMap<String, String> {
-        self.action.action().aquery_attributes(&ExecutorFs::new(
+    fn executor_fs(&self) -> ExecutorFs {
+        ExecutorFs::new(
             &self.fs,
             self.action.execution_config().options.path_separator,
+        )
+    }
+
+    pub fn attrs(&self) -> IndexMap<String, String> {
+        self.action.action().aquery_attributes(&self.executor_fs())
+    }
+
+    /// The expanded command line and environment of this action, if it runs a command.
+    pub fn command_line(&self) -> anyhow::Result<Option<ExpandedCommandLine>> {
+        self.action
+            .action()
+            .aquery_command_line(&self.executor_fs())
+    }
+
+    /// The resolved paths of all the outputs of this action.
+    pub fn output_paths(&self) -> anyhow::Result<Vec<ProjectRelativePathBuf>> {
+        Ok(self
+            .action
+            .action()
+            .outputs()?
+            .iter()
+            .map(|output| self.fs.resolve_build(output.get_path()))
+            .collect())
+    }
+
+    /// Renders the direct inputs of this action. Transitive set inputs are not flattened and are
+    /// shown as their projection key.
+    fn inputs_attr(&self) -> anyhow::Result<String> {
+        let mut inputs = Vec::new();
+        for input in &*self.action.action().inputs()? {
+            match input {
+                ArtifactGroup::Artifact(artifact) => {
+                    inputs.push(artifact.get_path().resolve(&self.fs)?.to_string())
+                }
+                ArtifactGroup::TransitiveSetProjection(key) => inputs.push(key.to_string()),
+            }
+        }
+        Ok(format!("[{}]", inputs.join(", ")))
+    }
+
+    fn outputs_attr(&self) -> anyhow::Result<String> {
+        Ok(format!(
+            "[{}]",
+            self.output_paths()?
+                .iter()
+                .map(|p| p.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
         ))
     }
 
@@ -230,9 +281,14 @@ impl QueryTarget for ActionQueryNode {
             "identifier",
             ActionAttr::new(self.action.identifier().unwrap_or("")),
         )?;
-        // TODO(cjhopman): impl inputs/outputs for actions in aquery
-        func("inputs", ActionAttr::new(""))?;
-        func("outputs", ActionAttr::new(""))?;
+        let inputs = self
+            .inputs_attr()
+            .unwrap_or_else(|e| format!("<error: {:#}>", e));
+        func("inputs", ActionAttr::new(&inputs))?;
+        let outputs = self
+            .outputs_attr()
+            .unwrap_or_else(|e| format!("<error: {:#}>", e));
+        func("outputs", ActionAttr::new(&outputs))?;
 
         for (k, v) in self.attrs() {
             func(&k, ActionAttr::new(&v))?;
@@ -256,12 +312,20 @@ impl QueryTarget for ActionQueryNode {
         }
     }
 
-    fn inputs_for_each<E, F: FnMut(CellPath) -> Result<(), E>>(
+    /// The source files this action reads directly. Build artifacts and transitive set inputs
+    /// aren't files in the repository, so they are not included.
+    fn inputs_for_each<E: From<anyhow::Error>, F: FnMut(CellPath) -> Result<(), E>>(
         &self,
-        mut _func: F,
+        mut func: F,
     ) -> Result<(), E> {
-        // TODO(cjhopman): In addition to implementing this, we should be able to return an anyhow::Error here rather than panicking.
-        unimplemented!("inputs not yet implemented in aquery")
+        for input in &*self.action.action().inputs()? {
+            if let ArtifactGroup::Artifact(artifact) = input {
+                if let Some(source) = artifact.get_source() {
+                    func(source.get_path().to_cell_path())?;
+                }
+            }
+        }
+        Ok(())
     }
 
     fn call_stack(&self) -> Option<String> {
@@ -305,6 +369,23 @@ impl<'c> AqueryEnvironment<'c> {
     async fn get_node(&self, label: &ActionKey) -> anyhow::Result<ActionQueryNode> {
         self.delegate.get_node(label).await
     }
+
+    /// Returns the actions that produce the inputs of the given actions, including those reached
+    /// through transitive sets.
+    pub(crate) async fn inputs_of(
+        &self,
+        targets: &TargetSet<ActionQueryNode>,
+    ) -> anyhow::Result<TargetSet<ActionQueryNode>> {
+        let mut result = TargetSet::new();
+        for target in targets.iter() {
+            for dep in target.deps() {
+                if !result.contains(dep) {
+                    result.insert(self.get_node(dep).await?);
+                }
+            }
+        }
+        Ok(result)
+    }
 }
 
 #[async_trait]
@@ -372,3 +453,34 @@ impl<'c> QueryEnvironment for AqueryEnvironment<'c> {
         Err(QueryError::NotAvailableInContext("owner").into())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use buck2_core::fs::project::ProjectRootTemp;
+
+    use super::*;
+    use crate::query::aquery::testing::testing_actions;
+
+    #[test]
+    fn test_inputs_for_each() -> anyhow::Result<()> {
+        let temp = ProjectRootTemp::new()?;
+        let actions = testing_actions(&temp)?;
+
+        let inputs = |node: &ActionQueryNode| -> anyhow::Result<Vec<String>> {
+            let mut inputs = Vec::new();
+            node.inputs_for_each(|cell_path| {
+                inputs.push(cell_path.to_string());
+                anyhow::Ok(())
+            })?;
+            Ok(inputs)
+        };
+        assert_eq!(
+            vec!["cell//pkg/foo.c".to_owned()],
+            inputs(&actions.compile)?
+        );
+        // The only input of the link action is built by another action, not an input file.
+        assert!(inputs(&actions.link)?.is_empty());
+
+        Ok(())
+    }
+}
@@ -13,20 +13,20 @@ use std::sync::Arc;
 use buck2_core::fs::project_rel_path::ProjectRelativePath;
 use buck2_core::target::label::TargetLabel;
 use buck2_query::query::syntax::simple::eval::values::QueryEvaluationResult;
-use buck2_query::query::syntax::simple::functions::DefaultQueryFunctionsModule;
 use dice::DiceComputations;
 use dupe::Dupe;
 
 use crate::query::analysis::evaluator::eval_query;
 use crate::query::aquery::environment::ActionQueryNode;
 use crate::query::aquery::environment::AqueryEnvironment;
+use crate::query::aquery::functions::AqueryFunctions;
 use crate::query::dice::aquery::DiceAqueryDelegate;
 use crate::query::dice::get_dice_query_delegate;
 use crate::query::uquery::environment::PreresolvedQueryLiterals;
 
 pub struct AqueryEvaluator<'c> {
     dice_query_delegate: Arc<DiceAqueryDelegate<'c>>,
-    functions: DefaultQueryFunctionsModule<AqueryEnvironment<'c>>,
+    functions: AqueryFunctions<'c>,
 }
 
 impl AqueryEvaluator<'_> {
@@ -56,7 +56,7 @@ pub async fn get_aquery_evaluator<'a, 'c: 'a>(
 ) -> anyhow::Result<AqueryEvaluator<'c>> {
     let dice_query_delegate =
         get_dice_aquery_delegate(ctx, working_dir, global_target_platform).await?;
-    let functions = DefaultQueryFunctionsModule::new();
+    let functions = AqueryFunctions::new();
     Ok(AqueryEvaluator {
         dice_query_delegate,
         functions,
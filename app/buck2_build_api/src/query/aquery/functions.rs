@@ -0,0 +1,292 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+use std::fmt;
+use std::fmt::Debug;
+use std::marker::PhantomData;
+
+use buck2_query::query::syntax::simple::eval::error::QueryError;
+use buck2_query::query::syntax::simple::eval::set::TargetSet;
+use buck2_query::query::syntax::simple::eval::set::TargetSetExt;
+use buck2_query::query::syntax::simple::eval::values::QueryValue;
+use buck2_query::query::syntax::simple::functions::helpers::QueryBinaryOp;
+use buck2_query::query::syntax::simple::functions::helpers::QueryFunction;
+use buck2_query::query::syntax::simple::functions::DefaultQueryFunctionsModule;
+use buck2_query::query::syntax::simple::functions::QueryFunctions;
+use buck2_query::query_module;
+use buck2_query_parser::BinaryOp;
+use fancy_regex::Regex;
+
+use crate::query::aquery::environment::ActionQueryNode;
+use crate::query::aquery::environment::AqueryEnvironment;
+
+/// Functions that are only available in aquery, for filtering actions by their content.
+#[derive(Debug)]
+struct AqueryExtraFunctions<'c>(PhantomData<&'c ()>);
+
+#[query_module(AqueryEnvironment<'c>)]
+impl<'c> AqueryExtraFunctions<'c> {
+    /// Filters the actions to those whose category matches the given regex.
+    ///
+    /// This is the equivalent of filtering on the mnemonic in Bazel's aquery, for example
+    /// `mnemonic('cxx_compile', deps('//foo:bar'))`.
+    async fn mnemonic(
+        &self,
+        regex: String,
+        targets: TargetSet<ActionQueryNode>,
+    ) -> Result<QueryValue<ActionQueryNode>, QueryError> {
+        Ok(targets.attrregexfilter("category", &regex)?.into())
+    }
+
+    /// Computes the set of actions that produce the inputs of the given actions.
+    ///
+    /// Inputs that are reached through transitive sets are included. Source files are not
+    /// produced by actions and are therefore not part of the result.
+    async fn inputs_of(
+        &self,
+        env: &AqueryEnvironment<'c>,
+        targets: TargetSet<ActionQueryNode>,
+    ) -> Result<QueryValue<ActionQueryNode>, QueryError> {
+        Ok(env.inputs_of(&targets).await?.into())
+    }
+
+    /// Filters the actions to those that have at least one output whose path matches the given
+    /// regex.
+    async fn outputs_matching(
+        &self,
+        regex: String,
+        targets: TargetSet<ActionQueryNode>,
+    ) -> Result<QueryValue<ActionQueryNode>, QueryError> {
+        let re = Regex::new(&regex).map_err(anyhow::Error::from)?;
+        // `TargetSet` has a private inherent `filter`, so name the trait explicitly.
+        Ok(TargetSetExt::filter(&targets, |node| {
+            for path in node.output_paths()? {
+                if re.is_match(path.as_str())? {
+                    return Ok(true);
+                }
+            }
+            Ok(false)
+        })?
+        .into())
+    }
+
+    /// Filters the actions to those whose expanded command line has at least one argument
+    /// matching the given regex.
+    ///
+    /// Actions that do not run a command (for example `write` or `copy`) never match.
+    async fn argv_filter(
+        &self,
+        regex: String,
+        targets: TargetSet<ActionQueryNode>,
+    ) -> Result<QueryValue<ActionQueryNode>, QueryError> {
+        let re = Regex::new(&regex).map_err(anyhow::Error::from)?;
+        Ok(TargetSetExt::filter(&targets, |node| {
+            if let Some(command_line) = node.command_line()? {
+                for arg in &command_line.cli {
+                    if re.is_match(arg)? {
+                        return Ok(true);
+                    }
+                }
+            }
+            Ok(false)
+        })?
+        .into())
+    }
+}
+
+/// The query functions available in aquery: the common query functions plus the
+/// aquery-specific ones.
+pub(crate) struct AqueryFunctions<'c> {
+    defaults: DefaultQueryFunctionsModule<AqueryEnvironment<'c>>,
+    extra_functions: AqueryExtraFunctions<'c>,
+}
+
+impl<'c> AqueryFunctions<'c> {
+    pub(crate) fn new() -> Self {
+        Self {
+            defaults: DefaultQueryFunctionsModule::new(),
+            extra_functions: AqueryExtraFunctions(PhantomData),
+        }
+    }
+}
+
+impl Debug for AqueryFunctions<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AqueryFunctions").finish_non_exhaustive()
+    }
+}
+
+impl<'c> QueryFunctions for AqueryFunctions<'c> {
+    type Env = AqueryEnvironment<'c>;
+
+    fn get(&self, name: &str) -> Option<&dyn QueryFunction<AqueryEnvironment<'c>>> {
+        if let Some(v) = self.extra_functions.get(name) {
+            Some(v)
+        } else {
+            self.defaults.get(name)
+        }
+    }
+
+    fn get_op(&self, op: BinaryOp) -> Option<&dyn QueryBinaryOp<AqueryEnvironment<'c>>> {
+        if let Some(v) = self.extra_functions.get_op(op) {
+            Some(v)
+        } else {
+            self.defaults.get_op(op)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    use async_trait::async_trait;
+    use buck2_common::result::ToSharedResultExt;
+    use buck2_core::fs::project::ProjectRootTemp;
+    use buck2_query::query::environment::LabeledNode;
+    use buck2_query::query::syntax::simple::eval::evaluator::QueryEvaluator;
+    use dupe::Dupe;
+
+    use super::*;
+    use crate::actions::key::ActionKey;
+    use crate::query::aquery::environment::AqueryDelegate;
+    use crate::query::aquery::testing::testing_actions;
+    use crate::query::aquery::testing::TestingActions;
+    use crate::query::cquery::environment::CqueryDelegate;
+    use crate::query::uquery::environment::PreresolvedQueryLiterals;
+
+    struct TestAqueryDelegate {
+        nodes: HashMap<ActionKey, ActionQueryNode>,
+    }
+
+    #[async_trait]
+    impl AqueryDelegate for TestAqueryDelegate {
+        fn cquery_delegate(&self) -> &dyn CqueryDelegate {
+            panic!(
+                "TestAqueryDelegate has no cquery delegate: only aquery functions that work on \
+                 action nodes alone can be tested with it"
+            )
+        }
+
+        async fn get_node(&self, key: &ActionKey) -> anyhow::Result<ActionQueryNode> {
+            Ok(self.nodes.get(key).unwrap().dupe())
+        }
+    }
+
+    /// The actions of `testing_actions`, with `all` resolving to both of them and `link` to the
+    /// link action.
+    struct Actions {
+        compile: ActionKey,
+        link: ActionKey,
+        env: AqueryEnvironment<'static>,
+    }
+
+    fn testing_env(temp: &ProjectRootTemp) -> anyhow::Result<Actions> {
+        let TestingActions { compile, link } = testing_actions(temp)?;
+
+        let mut all = TargetSet::new();
+        all.insert(compile.dupe());
+        all.insert(link.dupe());
+        let mut link_only = TargetSet::new();
+        link_only.insert(link.dupe());
+        let literals = PreresolvedQueryLiterals::new(HashMap::from([
+            ("all".to_owned(), anyhow::Ok(all).shared_error()),
+            ("link".to_owned(), anyhow::Ok(link_only).shared_error()),
+        ]));
+
+        let compile_key = compile.node_ref().dupe();
+        let link_key = link.node_ref().dupe();
+        let delegate = TestAqueryDelegate {
+            nodes: HashMap::from([(compile_key.dupe(), compile), (link_key.dupe(), link)]),
+        };
+
+        Ok(Actions {
+            compile: compile_key,
+            link: link_key,
+            env: AqueryEnvironment::new(Arc::new(delegate), Arc::new(literals)),
+        })
+    }
+
+    async fn eval(actions: &Actions, query: &str) -> anyhow::Result<Vec<ActionKey>> {
+        let functions = AqueryFunctions::new();
+        let result = QueryEvaluator::new(&actions.env, &functions)
+            .eval_query(query)
+            .await?
+            .try_into_targets()?;
+        Ok(result.iter().map(|n| n.node_ref().dupe()).collect())
+    }
+
+    #[tokio::test]
+    async fn test_mnemonic() -> anyhow::Result<()> {
+        let temp = ProjectRootTemp::new()?;
+        let actions = testing_env(&temp)?;
+
+        assert_eq!(
+            vec![actions.link.dupe()],
+            eval(&actions, "mnemonic(cxx_link, all)").await?
+        );
+        assert_eq!(
+            vec![actions.compile.dupe(), actions.link.dupe()],
+            eval(&actions, "mnemonic('cxx_.*', all)").await?
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_inputs_of() -> anyhow::Result<()> {
+        let temp = ProjectRootTemp::new()?;
+        let actions = testing_env(&temp)?;
+
+        assert_eq!(
+            vec![actions.compile.dupe()],
+            eval(&actions, "inputs_of(link)").await?
+        );
+        assert!(
+            eval(&actions, "inputs_of(inputs_of(link))")
+                .await?
+                .is_empty()
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_outputs_matching() -> anyhow::Result<()> {
+        let temp = ProjectRootTemp::new()?;
+        let actions = testing_env(&temp)?;
+
+        assert_eq!(
+            vec![actions.compile.dupe()],
+            eval(&actions, "outputs_matching('\\.o$', all)").await?
+        );
+        assert!(eval(&actions, "outputs_matching('(', all)").await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_argv_filter() -> anyhow::Result<()> {
+        let temp = ProjectRootTemp::new()?;
+        let actions = testing_env(&temp)?;
+
+        assert_eq!(
+            vec![actions.compile.dupe()],
+            eval(&actions, "argv_filter('^-c$', all)").await?
+        );
+        assert_eq!(
+            vec![actions.link.dupe()],
+            eval(&actions, "argv_filter(ld, all)").await?
+        );
+        assert!(eval(&actions, "argv_filter('(', all)").await.is_err());
+
+        Ok(())
+    }
+}
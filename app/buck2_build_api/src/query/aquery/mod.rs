@@ -9,3 +9,6 @@
 
 pub mod environment;
 pub mod evaluator;
+pub mod functions;
+#[cfg(test)]
+pub(crate) mod testing;
@@ -0,0 +1,134 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+use std::sync::Arc;
+
+use buck2_common::executor_config::CommandExecutorConfig;
+use buck2_core::buck_path::path::BuckPath;
+use buck2_core::buck_path::resolver::BuckPathResolver;
+use buck2_core::category::Category;
+use buck2_core::cells::cell_root_path::CellRootPathBuf;
+use buck2_core::cells::name::CellName;
+use buck2_core::cells::paths::CellRelativePath;
+use buck2_core::cells::testing::CellResolverExt;
+use buck2_core::cells::CellResolver;
+use buck2_core::configuration::data::ConfigurationData;
+use buck2_core::fs::artifact_path_resolver::ArtifactFs;
+use buck2_core::fs::buck_out_path::BuckOutPathResolver;
+use buck2_core::fs::paths::forward_rel_path::ForwardRelativePathBuf;
+use buck2_core::fs::project::ProjectRootTemp;
+use buck2_core::fs::project_rel_path::ProjectRelativePathBuf;
+use buck2_core::package::package_relative_path::PackageRelativePathBuf;
+use buck2_core::package::PackageLabel;
+use buck2_core::target::label::TargetLabel;
+use buck2_core::target::name::TargetNameRef;
+use dupe::Dupe;
+use indexmap::indexset;
+use indexmap::IndexSet;
+
+use crate::actions::artifact::artifact_type::testing::BuildArtifactTestingExt;
+use crate::actions::artifact::artifact_type::Artifact;
+use crate::actions::artifact::build_artifact::BuildArtifact;
+use crate::actions::artifact::source_artifact::SourceArtifact;
+use crate::actions::key::ActionKey;
+use crate::actions::testings::SimpleAction;
+use crate::actions::RegisteredAction;
+use crate::artifact_groups::ArtifactGroup;
+use crate::deferred::base_deferred_key::BaseDeferredKey;
+use crate::deferred::types::testing::DeferredDataExt;
+use crate::deferred::types::testing::DeferredIdExt;
+use crate::deferred::types::DeferredData;
+use crate::deferred::types::DeferredId;
+use crate::deferred::types::DeferredKey;
+use crate::query::aquery::environment::ActionInput;
+use crate::query::aquery::environment::ActionQueryNode;
+
+/// A compile action of the source file `cell//pkg/foo.c`, whose output `foo.o` is the input of a
+/// link action. Both belong to `cell//pkg:foo`.
+pub(crate) struct TestingActions {
+    pub(crate) compile: ActionQueryNode,
+    pub(crate) link: ActionQueryNode,
+}
+
+pub(crate) fn testing_actions(temp: &ProjectRootTemp) -> anyhow::Result<TestingActions> {
+    let cells = CellResolver::of_names_and_paths(
+        CellName::testing_new("root"),
+        &[(
+            CellName::testing_new("cell"),
+            CellRootPathBuf::new(ProjectRelativePathBuf::unchecked_new("cell_path".into())),
+        )],
+    );
+    let fs = Arc::new(ArtifactFs::new(
+        BuckPathResolver::new(cells),
+        BuckOutPathResolver::new(ProjectRelativePathBuf::unchecked_new("buck-out/v2".into())),
+        temp.path().dupe(),
+    ));
+
+    let pkg = PackageLabel::new(
+        CellName::testing_new("cell"),
+        CellRelativePath::unchecked_new("pkg"),
+    );
+    let label = TargetLabel::new(pkg.dupe(), TargetNameRef::unchecked_new("foo"))
+        .configure(ConfigurationData::testing_new());
+
+    let action = |id: u32,
+                  inputs: IndexSet<ArtifactGroup>,
+                  output: &str,
+                  cmd: &[&str],
+                  category: &str|
+     -> anyhow::Result<RegisteredAction> {
+        Ok(RegisteredAction::new(
+            ActionKey::new(DeferredData::testing_new(DeferredKey::Base(
+                BaseDeferredKey::TargetLabel(label.dupe()),
+                DeferredId::testing_new(id),
+            ))),
+            Box::new(SimpleAction::new(
+                inputs,
+                indexset![BuildArtifact::testing_new(
+                    label.dupe(),
+                    ForwardRelativePathBuf::unchecked_new(output.to_owned()),
+                    DeferredId::testing_new(id),
+                )],
+                cmd.iter().map(|s| (*s).to_owned()).collect(),
+                Category::try_from(category)?,
+                None,
+            )),
+            CommandExecutorConfig::testing_local(),
+        ))
+    };
+
+    let source = ArtifactGroup::Artifact(Artifact::from(SourceArtifact::new(
+        BuckPath::testing_new(pkg, PackageRelativePathBuf::unchecked_new("foo.c".into())),
+    )));
+    let compile = action(
+        0,
+        indexset![source],
+        "foo.o",
+        &["cc", "-c", "foo.c"],
+        "cxx_compile",
+    )?;
+    let compile_output = compile.outputs()?[0].dupe();
+    let link = action(
+        1,
+        indexset![ArtifactGroup::Artifact(Artifact::from(compile_output))],
+        "foo",
+        &["ld", "foo.o"],
+        "cxx_link",
+    )?;
+
+    let compile_key = compile.key().dupe();
+    Ok(TestingActions {
+        compile: ActionQueryNode::new(Arc::new(compile), Vec::new(), fs.dupe()),
+        link: ActionQueryNode::new(
+            Arc::new(link),
+            vec![ActionInput::ActionKey(compile_key)],
+            fs,
+        ),
+    })
+}
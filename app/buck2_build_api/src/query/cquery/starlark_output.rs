@@ -0,0 +1,49 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+//! Formatting of cquery results with a user-provided Starlark function
+//! (`buck2 cquery --output-starlark-fn`).
+
+use async_trait::async_trait;
+use buck2_core::bzl::ImportPath;
+use buck2_node::nodes::configured::ConfiguredTargetNode;
+use buck2_util::late_binding::LateBinding;
+use dice::DiceComputations;
+
+#[async_trait]
+pub trait CqueryStarlarkOutputDyn: Send + Sync + 'static {
+    /// Loads the function `name` from the `.bzl` file at `path`, calls it on each of the nodes
+    /// and returns the strings it produced, in the same order as the nodes.
+    async fn format_nodes(
+        &self,
+        ctx: &DiceComputations,
+        path: &ImportPath,
+        name: &str,
+        nodes: Vec<ConfiguredTargetNode>,
+    ) -> anyhow::Result<Vec<String>>;
+}
+
+/// Dependency injection for the Starlark formatter.
+///
+/// Nodes are passed to the function as BXL `target_node` values, and BXL lives in a downstream
+/// crate. This field is initialized at program start.
+pub static CQUERY_STARLARK_OUTPUT_IMPL: LateBinding<&'static dyn CqueryStarlarkOutputDyn> =
+    LateBinding::new("CQUERY_STARLARK_OUTPUT_IMPL");
+
+pub async fn format_nodes_with_starlark_fn(
+    ctx: &DiceComputations,
+    path: &ImportPath,
+    name: &str,
+    nodes: Vec<ConfiguredTargetNode>,
+) -> anyhow::Result<Vec<String>> {
+    CQUERY_STARLARK_OUTPUT_IMPL
+        .get()?
+        .format_nodes(ctx, path, name, nodes)
+        .await
+}
@@ -60,6 +60,8 @@ use crate::calculation::Calculation;
 use crate::calculation::MissingTargetBehavior;
 use crate::configure_targets::load_compatible_patterns;
 use crate::nodes::calculation::NodeCalculation;
+use crate::nodes::rdeps_index::ReverseDepsIndex;
+use crate::nodes::rdeps_index::ReverseDepsIndexCalculation;
 use crate::query::cquery::environment::CqueryDelegate;
 use crate::query::uquery::environment::QueryLiterals;
 use crate::query::uquery::environment::UqueryDelegate;
@@ -84,7 +86,7 @@ pub(crate) struct LiteralParser {
 
 impl LiteralParser {
     // We allow provider names and flavors in the value and it gets stripped out for the result as queries operate on the target graphs.
-    fn parse_target_pattern(
+    pub(crate) fn parse_target_pattern(
         &self,
         value: &str,
     ) -> anyhow::Result<ParsedPattern<TargetPatternExtra>> {
@@ -261,6 +263,10 @@ impl<'c> UqueryDelegate for DiceQueryDelegate<'c> {
         let cell_path = self.literal_parser.parse_file_literal(literal)?;
         Ok(FileSet::new(indexset![FileNode(cell_path)]))
     }
+
+    async fn get_reverse_deps_index(&self) -> anyhow::Result<Arc<ReverseDepsIndex>> {
+        self.ctx.get_reverse_deps_index().await
+    }
 }
 
 #[async_trait]
@@ -24,6 +24,7 @@ use buck2_core::fs::paths::file_name::FileNameBuf;
 use buck2_core::package::PackageLabel;
 use buck2_core::pattern::pattern_type::TargetPatternExtra;
 use buck2_core::target::label::TargetLabel;
+use buck2_events::dispatch::console_message;
 use buck2_node::nodes::eval_result::EvaluationResult;
 use buck2_node::nodes::unconfigured::TargetNode;
 use buck2_query::query::compatibility::MaybeCompatible;
@@ -54,6 +55,9 @@ use ref_cast::RefCast;
 use thiserror::Error;
 use tracing::warn;
 
+use crate::nodes::rdeps_index::ReverseDepsIndex;
+use crate::query::uquery::functions::UqueryExtraFunctions;
+
 type ArcCellPath = Arc<CellPath>;
 
 #[derive(Debug, Error)]
@@ -100,6 +104,9 @@ pub trait UqueryDelegate: Send + Sync {
     // This always includes the immediate enclosing package of the path but can also include
     // all parent packages if the package matches `project.package_boundary_exceptions` buckconfig.
     async fn get_enclosing_packages(&self, path: &CellPath) -> anyhow::Result<Vec<PackageLabel>>;
+
+    /// Returns the reverse dependency index over all the targets in the repository.
+    async fn get_reverse_deps_index(&self) -> anyhow::Result<Arc<ReverseDepsIndex>>;
 }
 
 #[async_trait]
@@ -172,7 +179,10 @@ impl<'c> UqueryEnvironment<'c> {
     pub fn describe() -> QueryEnvironmentDescription {
         QueryEnvironmentDescription {
             name: "Uquery Environment".to_owned(),
-            mods: vec![DefaultQueryFunctionsModule::<Self>::describe()],
+            mods: vec![
+                DefaultQueryFunctionsModule::<Self>::describe(),
+                UqueryExtraFunctions::describe(),
+            ],
         }
     }
 
@@ -185,6 +195,27 @@ impl<'c> UqueryEnvironment<'c> {
         let node = package.resolve_target(target.name())?;
         Ok(node.dupe())
     }
+
+    /// Returns the targets in the whole repository that depend on `targets` within `depth`
+    /// steps, using the reverse dependency index rather than a universe.
+    pub(crate) async fn allrdeps(
+        &self,
+        targets: &TargetSet<TargetNode>,
+        depth: Option<u32>,
+    ) -> anyhow::Result<TargetSet<TargetNode>> {
+        let index = self.delegate.get_reverse_deps_index().await?;
+        if let Some(package) = index.broken_packages().first() {
+            console_message(format!(
+                "allrdeps: skipped {} package(s) that failed to evaluate, including `{}`",
+                index.broken_packages().len(),
+                package
+            ));
+        }
+        let labels = index.transitive_rdeps(targets.iter().map(|t| t.label().dupe()), depth);
+        let nodes =
+            futures::future::try_join_all(labels.iter().map(|label| self.get_node(label))).await?;
+        Ok(nodes.into_iter().collect())
+    }
 }
 
 #[async_trait]
@@ -11,10 +11,15 @@
 use std::sync::Arc;
 
 use buck2_core::fs::project_rel_path::ProjectRelativePath;
+use buck2_core::pattern::pattern_type::TargetPatternExtra;
+use buck2_core::pattern::ParsedPattern;
 use buck2_core::target::label::TargetLabel;
 use buck2_node::nodes::unconfigured::TargetNode;
 use buck2_query::query::syntax::simple::eval::values::QueryEvaluationResult;
-use buck2_query::query::syntax::simple::functions::DefaultQueryFunctionsModule;
+use buck2_query_parser::parse_expr;
+use buck2_query_parser::BinaryOp;
+use buck2_query_parser::Expr;
+use buck2_query_parser::SpannedExpr;
 use dice::DiceComputations;
 use dupe::Dupe;
 
@@ -23,10 +28,11 @@ use crate::query::dice::get_dice_query_delegate;
 use crate::query::dice::DiceQueryDelegate;
 use crate::query::uquery::environment::PreresolvedQueryLiterals;
 use crate::query::uquery::environment::UqueryEnvironment;
+use crate::query::uquery::functions::UqueryFunctions;
 
 pub struct UqueryEvaluator<'c> {
     dice_query_delegate: Arc<DiceQueryDelegate<'c>>,
-    functions: DefaultQueryFunctionsModule<UqueryEnvironment<'c>>,
+    functions: UqueryFunctions<'c>,
 }
 
 impl UqueryEvaluator<'_> {
@@ -45,6 +51,47 @@ impl UqueryEvaluator<'_> {
         })
         .await
     }
+
+    /// If the query is only target patterns combined with `+`/`union`, returns the parsed
+    /// patterns. The result of such a query can be produced package by package without
+    /// evaluating the whole target set first.
+    pub fn streamable_patterns(
+        &self,
+        query: &str,
+    ) -> anyhow::Result<Option<Vec<ParsedPattern<TargetPatternExtra>>>> {
+        let expr = parse_expr(query)?;
+        let mut literals = Vec::new();
+        if !collect_union_literals(&expr, &mut literals) {
+            return Ok(None);
+        }
+        let literal_parser = self.dice_query_delegate.literal_parser();
+        Ok(Some(
+            literals
+                .into_iter()
+                .map(|literal| literal_parser.parse_target_pattern(literal))
+                .collect::<anyhow::Result<_>>()?,
+        ))
+    }
+}
+
+fn collect_union_literals<'a>(expr: &SpannedExpr<'a>, literals: &mut Vec<&'a str>) -> bool {
+    match &expr.value {
+        Expr::String(literal) => {
+            literals.push(literal);
+            true
+        }
+        Expr::Set(items) => {
+            literals.extend(items.iter().map(|item| *item.fragment()));
+            true
+        }
+        Expr::BinaryOpSequence(left, rest) => {
+            collect_union_literals(left, literals)
+                && rest.iter().all(|(op, right)| {
+                    matches!(op, BinaryOp::Union) && collect_union_literals(right, literals)
+                })
+        }
+        Expr::Integer(_) | Expr::Function { .. } | Expr::FileSet(_) => false,
+    }
 }
 
 /// Evaluates some query expression. TargetNodes are resolved via the interpreter from
@@ -56,10 +103,39 @@ pub async fn get_uquery_evaluator<'a, 'c: 'a>(
 ) -> anyhow::Result<UqueryEvaluator<'c>> {
     let dice_query_delegate =
         Arc::new(get_dice_query_delegate(ctx, working_dir, global_target_platform).await?);
-    let functions = DefaultQueryFunctionsModule::new();
+    let functions = UqueryFunctions::new();
 
     Ok(UqueryEvaluator {
         dice_query_delegate,
         functions,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use buck2_query_parser::parse_expr;
+
+    use super::collect_union_literals;
+
+    fn union_literals(query: &str) -> Option<Vec<String>> {
+        let expr = parse_expr(query).unwrap();
+        let mut literals = Vec::new();
+        collect_union_literals(&expr, &mut literals)
+            .then(|| literals.into_iter().map(str::to_owned).collect())
+    }
+
+    #[test]
+    fn test_collect_union_literals() {
+        assert_eq!(Some(vec!["//...".to_owned()]), union_literals("//..."));
+        assert_eq!(
+            Some(vec![
+                "//a/...".to_owned(),
+                "//b:c".to_owned(),
+                "//d:".to_owned()
+            ]),
+            union_literals("//a/... + set(//b:c //d:)")
+        );
+        assert_eq!(None, union_literals("//a/... - //a/b/..."));
+        assert_eq!(None, union_literals("deps(//a:b)"));
+    }
+}
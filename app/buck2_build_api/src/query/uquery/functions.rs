@@ -0,0 +1,95 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+use std::fmt;
+use std::fmt::Debug;
+use std::marker::PhantomData;
+
+use buck2_node::nodes::unconfigured::TargetNode;
+use buck2_query::query::syntax::simple::eval::error::QueryError;
+use buck2_query::query::syntax::simple::eval::set::TargetSet;
+use buck2_query::query::syntax::simple::eval::values::QueryValue;
+use buck2_query::query::syntax::simple::functions::helpers::QueryBinaryOp;
+use buck2_query::query::syntax::simple::functions::helpers::QueryFunction;
+use buck2_query::query::syntax::simple::functions::DefaultQueryFunctionsModule;
+use buck2_query::query::syntax::simple::functions::QueryFunctions;
+use buck2_query::query_module;
+use buck2_query_parser::BinaryOp;
+
+use crate::query::uquery::environment::UqueryEnvironment;
+
+/// Functions that are only available in uquery.
+#[derive(Debug)]
+pub(crate) struct UqueryExtraFunctions<'c>(PhantomData<&'c ()>);
+
+#[query_module(UqueryEnvironment<'c>)]
+impl<'c> UqueryExtraFunctions<'c> {
+    /// Computes the reverse dependencies of the given targets across the whole repository.
+    ///
+    /// `allrdeps(x, depth)` is like `rdeps(//..., x, depth)`, but it does not need to evaluate the
+    /// universe on every query. Instead, the daemon maintains a reverse dependency index over all
+    /// the targets in the repository which is updated incrementally as build files change. The
+    /// first use after the daemon starts evaluates every build file in the repository.
+    ///
+    /// The result includes the given targets themselves. If `depth` is omitted, the search is
+    /// unbounded.
+    async fn allrdeps(
+        &self,
+        env: &UqueryEnvironment<'c>,
+        targets: TargetSet<TargetNode>,
+        depth: Option<u64>,
+    ) -> Result<QueryValue<TargetNode>, QueryError> {
+        let depth = depth
+            .map(|v| u32::try_from(v).map_err(|_| QueryError::DepthTooLarge(v)))
+            .transpose()?;
+        Ok(env.allrdeps(&targets, depth).await?.into())
+    }
+}
+
+/// The query functions available in uquery: the common query functions plus the uquery-specific
+/// ones.
+pub(crate) struct UqueryFunctions<'c> {
+    defaults: DefaultQueryFunctionsModule<UqueryEnvironment<'c>>,
+    extra_functions: UqueryExtraFunctions<'c>,
+}
+
+impl<'c> UqueryFunctions<'c> {
+    pub(crate) fn new() -> Self {
+        Self {
+            defaults: DefaultQueryFunctionsModule::new(),
+            extra_functions: UqueryExtraFunctions(PhantomData),
+        }
+    }
+}
+
+impl Debug for UqueryFunctions<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UqueryFunctions").finish_non_exhaustive()
+    }
+}
+
+impl<'c> QueryFunctions for UqueryFunctions<'c> {
+    type Env = UqueryEnvironment<'c>;
+
+    fn get(&self, name: &str) -> Option<&dyn QueryFunction<UqueryEnvironment<'c>>> {
+        if let Some(v) = self.extra_functions.get(name) {
+            Some(v)
+        } else {
+            self.defaults.get(name)
+        }
+    }
+
+    fn get_op(&self, op: BinaryOp) -> Option<&dyn QueryBinaryOp<UqueryEnvironment<'c>>> {
+        if let Some(v) = self.extra_functions.get_op(op) {
+            Some(v)
+        } else {
+            self.defaults.get_op(op)
+        }
+    }
+}
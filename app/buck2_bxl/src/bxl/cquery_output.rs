@@ -0,0 +1,178 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+//! Implementation of `buck2 cquery --output-starlark-fn`, which calls a user function on the
+//! BXL representation of each configured target node.
+
+use async_trait::async_trait;
+use buck2_build_api::query::cquery::starlark_output::CqueryStarlarkOutputDyn;
+use buck2_build_api::query::cquery::starlark_output::CQUERY_STARLARK_OUTPUT_IMPL;
+use buck2_core::bzl::ImportPath;
+use buck2_core::target::label::ConfiguredTargetLabel;
+use buck2_events::dispatch::get_dispatcher;
+use buck2_interpreter_for_build::interpreter::calculation::InterpreterCalculation;
+use buck2_interpreter_for_build::interpreter::print_handler::EventDispatcherPrintHandler;
+use buck2_node::nodes::configured::ConfiguredTargetNode;
+use ctor::ctor;
+use dice::DiceComputations;
+use dupe::Dupe;
+use starlark::environment::FrozenModule;
+use starlark::environment::Module;
+use starlark::eval::Evaluator;
+use thiserror::Error;
+
+use crate::bxl::starlark_defs::nodes::configured::StarlarkConfiguredTargetNode;
+
+#[derive(Debug, Error)]
+enum CqueryStarlarkOutputError {
+    #[error("Function `{0}` not found in `{1}`")]
+    NotFound(String, ImportPath),
+    #[error("Function `{0}` must return a string, but returned `{1}` for `{2}`")]
+    NotAString(String, String, ConfiguredTargetLabel),
+}
+
+struct CqueryStarlarkOutputImpl;
+
+#[async_trait]
+impl CqueryStarlarkOutputDyn for CqueryStarlarkOutputImpl {
+    async fn format_nodes(
+        &self,
+        ctx: &DiceComputations,
+        path: &ImportPath,
+        name: &str,
+        nodes: Vec<ConfiguredTargetNode>,
+    ) -> anyhow::Result<Vec<String>> {
+        let loaded = ctx.get_loaded_module_from_import_path(path).await?;
+
+        let env = Module::new();
+        let print = EventDispatcherPrintHandler(get_dispatcher());
+        let mut eval = Evaluator::new(&env);
+        eval.set_print_handler(&print);
+        format_nodes_with_module(&mut eval, loaded.env(), path, name, nodes)
+    }
+}
+
+/// Calls the function `name` of `module` on each of the nodes.
+fn format_nodes_with_module<'v>(
+    eval: &mut Evaluator<'v, '_>,
+    module: &FrozenModule,
+    path: &ImportPath,
+    name: &str,
+    nodes: Vec<ConfiguredTargetNode>,
+) -> anyhow::Result<Vec<String>> {
+    let function = module
+        .get(name)
+        .map_err(|_| CqueryStarlarkOutputError::NotFound(name.to_owned(), path.clone()))?;
+    let function = function.owned_value(eval.frozen_heap());
+
+    nodes
+        .into_iter()
+        .map(|node| {
+            let label = node.label().dupe();
+            let node = eval.heap().alloc(StarlarkConfiguredTargetNode(node));
+            let result = eval.eval_function(function.to_value(), &[node], &[])?;
+            match result.unpack_str() {
+                Some(s) => Ok(s.to_owned()),
+                None => Err(CqueryStarlarkOutputError::NotAString(
+                    name.to_owned(),
+                    result.to_repr(),
+                    label,
+                )
+                .into()),
+            }
+        })
+        .collect()
+}
+
+#[ctor]
+fn set_cquery_starlark_output_impl() {
+    CQUERY_STARLARK_OUTPUT_IMPL.init(&CqueryStarlarkOutputImpl);
+}
+
+#[cfg(test)]
+mod tests {
+    use buck2_core::configuration::data::ConfigurationData;
+    use buck2_core::target::label::TargetLabel;
+    use starlark::environment::Globals;
+    use starlark::syntax::AstModule;
+    use starlark::syntax::Dialect;
+
+    use super::*;
+
+    const FORMATTERS: &str = r#"
+def label(node):
+    return str(node.label)
+
+def not_a_string(node):
+    return 1
+
+def fails(node):
+    fail("cannot format")
+"#;
+
+    fn format(name: &str) -> anyhow::Result<Vec<String>> {
+        let path = ImportPath::testing_new("root//:formatters.bzl");
+        let ast = AstModule::parse(&path.to_string(), FORMATTERS.to_owned(), &Dialect::Extended)?;
+        let module = Module::new();
+        Evaluator::new(&module).eval_module(ast, &Globals::standard())?;
+        let module = module.freeze()?;
+
+        let nodes = ["root//pkg:a", "root//pkg:b"]
+            .iter()
+            .map(|label| {
+                ConfiguredTargetNode::testing_new(
+                    TargetLabel::testing_parse(label).configure(ConfigurationData::testing_new()),
+                    "foo_lib",
+                )
+            })
+            .collect();
+        let env = Module::new();
+        format_nodes_with_module(&mut Evaluator::new(&env), &module, &path, name, nodes)
+    }
+
+    #[test]
+    fn test_format_nodes() -> anyhow::Result<()> {
+        let expected: Vec<String> = ["root//pkg:a", "root//pkg:b"]
+            .iter()
+            .map(|label| {
+                TargetLabel::testing_parse(label)
+                    .configure(ConfigurationData::testing_new())
+                    .to_string()
+            })
+            .collect();
+        assert_eq!(expected, format("label")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_format_nodes_not_a_string() {
+        let err = format("not_a_string").unwrap_err();
+        assert!(
+            format!("{:#}", err).contains("must return a string, but returned `1`"),
+            "{:#}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_format_nodes_fails() {
+        let err = format("fails").unwrap_err();
+        assert!(format!("{:#}", err).contains("cannot format"), "{:#}", err);
+    }
+
+    #[test]
+    fn test_format_nodes_not_found() {
+        let err = format("missing").unwrap_err();
+        assert!(
+            format!("{:#}", err).contains("Function `missing` not found"),
+            "{:#}",
+            err
+        );
+    }
+}
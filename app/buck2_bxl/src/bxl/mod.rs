@@ -8,6 +8,7 @@
  */
 
 pub mod calculation;
+mod cquery_output;
 mod deferred;
 pub mod eval;
 pub mod starlark_defs;
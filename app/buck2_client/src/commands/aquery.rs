@@ -35,6 +35,14 @@ use buck2_query_common::query_args::CommonQueryArgs;
 ///
 /// `buck2 aquery 'kind(run, deps("//java/com/example/app:amazing+more"))' --output-attribute=cmd`
 ///
+/// Print the command line and environment of all compile actions passing `-O3`
+///
+/// `buck2 aquery 'argv_filter("^-O3$", mnemonic(cxx_compile, deps("//foo:bar")))' --output-attribute=cmd --output-attribute=env`
+///
+/// Other aquery-only functions are `inputs_of(set)`, which returns the actions producing the
+/// inputs of the given actions, and `outputs_matching(regex, set)`, which keeps the actions that
+/// have an output path matching the regex.
+///
 /// Dynamic outputs (`ctx.actions.dynamic_output`):
 ///
 /// Currently, aquery interacts poorly with dynamic outputs. It may return incorrect results or otherwise
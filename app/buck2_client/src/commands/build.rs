@@ -127,6 +127,16 @@ pub struct BuildCommand {
     )]
     materializations: Option<FinalArtifactMaterializations>,
 
+    #[clap(
+        long = "materialize-only",
+        value_name = "FILTER",
+        conflicts_with = "materializations",
+        help = "Only materialize the final artifacts matching one of these filters (may be \
+                repeated): a sub-target, e.g. `[compile_commands]`, which is built for each \
+                requested target that has it, or a glob on the output path, e.g. `*.so`."
+    )]
+    materialize_only: Vec<String>,
+
     #[allow(unused)]
     #[clap(
         long,
@@ -278,6 +288,7 @@ impl StreamingCommand for BuildCommand {
                     }),
                     build_opts: Some(self.build_opts.to_proto()),
                     final_artifact_materializations: self.materializations.to_proto() as i32,
+                    materialize_only: self.materialize_only,
                     target_universe: self.target_universe,
                 },
                 ctx.stdin()
@@ -84,6 +84,14 @@ pub struct CqueryCommand {
     /// See this post https://fburl.com/1mf2d2xj for details.
     #[clap(long)]
     correct_owner: bool,
+
+    /// Format each target of the result with a Starlark function, given as
+    /// `path/to/file.bzl:function`.
+    ///
+    /// The function is called with the configured target node (the same `target_node` value
+    /// BXL provides) and must return a string, which is printed on its own line.
+    #[clap(long, value_name = "PATH:FUNCTION", conflicts_with = "show-providers")]
+    output_starlark_fn: Option<String>,
 }
 
 #[async_trait]
@@ -129,6 +137,7 @@ impl StreamingCommand for CqueryCommand {
                     unstable_output_format,
                     target_call_stacks: self.query_common.target_call_stacks,
                     correct_owner,
+                    output_starlark_fn: self.output_starlark_fn.unwrap_or_default(),
                 },
                 ctx.stdin()
                     .console_interaction_stream(&self.common_opts.console_opts),
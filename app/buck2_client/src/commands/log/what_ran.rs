@@ -55,6 +55,10 @@ use crate::commands::log::LogCommandOutputFormat;
 /// To reproduce an action that ran locally, make sure your working directory is the project root
 /// (if unsure, use `buck2 root --kind project` to find it), then run the command. The command is
 /// already shell-quoted.
+///
+///
+/// With `--show-resource-usage`, local commands are followed by the resources they used (peak
+/// memory, CPU time and IO), when those were measured (see `buck2.resource_control`).
 #[derive(Debug, clap::Parser)]
 pub struct WhatRanCommand {
     #[clap(flatten)]
@@ -81,6 +85,11 @@ pub struct WhatRanCommandCommon {
 
     #[clap(flatten)]
     pub options: WhatRanOptions,
+
+    /// Show the resources used by local commands. Commands are then shown once their action
+    /// finishes, rather than when they start.
+    #[clap(long)]
+    pub show_resource_usage: bool,
 }
 
 impl WhatRanCommand {
@@ -91,6 +100,7 @@ impl WhatRanCommand {
                     event_log,
                     mut output,
                     options,
+                    show_resource_usage,
                 },
             failed,
         } = self;
@@ -107,10 +117,14 @@ impl WhatRanCommand {
                 invocation.display_command_line()
             )?;
 
-            if failed {
-                WhatFailedImpl::execute(events, &mut output, &options).await?;
+            if failed || show_resource_usage {
+                WhatRanOnActionEndImpl::new(failed, show_resource_usage)
+                    .execute(events, &mut output, &options)
+                    .await?;
             } else {
-                WhatRanImpl::execute(events, &mut output, &options).await?;
+                WhatRanImpl::default()
+                    .execute(events, &mut output, &options)
+                    .await?;
             };
 
             anyhow::Ok(())
@@ -121,7 +135,7 @@ impl WhatRanCommand {
 }
 
 #[async_trait]
-trait WhatRanCommandImplementation: Default {
+trait WhatRanCommandImplementation: Send + Sized {
     fn event(
         &mut self,
         event: Box<buck2_data::BuckEvent>,
@@ -130,15 +144,14 @@ trait WhatRanCommandImplementation: Default {
     ) -> anyhow::Result<()>;
 
     async fn execute(
+        mut self,
         mut events: impl Stream<Item = anyhow::Result<StreamValue>> + Unpin + Send,
         output: &mut (impl WhatRanOutputWriter + Send),
         options: &WhatRanOptions,
     ) -> anyhow::Result<()> {
-        let mut cmd = Self::default();
-
         while let Some(event) = events.try_next().await? {
             match event {
-                StreamValue::Event(event) => cmd.event(event, output, options)?,
+                StreamValue::Event(event) => self.event(event, output, options)?,
                 _ => {}
             }
         }
@@ -187,16 +200,31 @@ impl WhatRanCommandImplementation for WhatRanImpl {
     }
 }
 
-/// The state for a WhatRan command when only showing actions that failed. This stores all the events
+/// The state for a WhatRan command that shows the commands of each action once the action
+/// finishes, when we know whether it failed and what its commands used. This stores all the events
 /// we have seen that are WhatRanRelevantActions, and the CommandReproducer associated with them.
-#[derive(Default)]
-pub struct WhatFailedImpl {
+pub struct WhatRanOnActionEndImpl {
     /// Maps action spans to their details.
-    known_actions: HashMap<u64, WhatFailedEntry>,
+    known_actions: HashMap<u64, WhatRanOnActionEndEntry>,
+
+    /// Only show the commands of actions that failed.
+    failed_only: bool,
+
+    show_resource_usage: bool,
+}
+
+impl WhatRanOnActionEndImpl {
+    pub fn new(failed_only: bool, show_resource_usage: bool) -> Self {
+        Self {
+            known_actions: HashMap::new(),
+            failed_only,
+            show_resource_usage,
+        }
+    }
 }
 
 #[allow(clippy::vec_box)]
-struct WhatFailedEntry {
+struct WhatRanOnActionEndEntry {
     /// Known to be a WhatRanRelevantAction.
     event: Box<buck2_data::BuckEvent>,
 
@@ -204,7 +232,7 @@ struct WhatFailedEntry {
     reproducers: Vec<Box<buck2_data::BuckEvent>>,
 }
 
-impl WhatRanState<u64> for WhatFailedImpl {
+impl WhatRanState<u64> for WhatRanOnActionEndImpl {
     fn get(&self, span_id: u64) -> Option<WhatRanRelevantAction<'_>> {
         self.known_actions
             .get(&span_id)
@@ -213,7 +241,7 @@ impl WhatRanState<u64> for WhatFailedImpl {
     }
 }
 
-impl WhatRanCommandImplementation for WhatFailedImpl {
+impl WhatRanCommandImplementation for WhatRanOnActionEndImpl {
     /// Receive a new event. We start by emitting it if it's relevant (since that only takes a
     /// borrow), and then if it's relevant as a parent, we store it for latter use. Note that in
     /// practice we don't expect the event to be *both* relevant to emit *and* a
@@ -228,7 +256,7 @@ impl WhatRanCommandImplementation for WhatFailedImpl {
             if WhatRanRelevantAction::from_buck_data(data).is_some() {
                 self.known_actions.insert(
                     event.span_id,
-                    WhatFailedEntry {
+                    WhatRanOnActionEndEntry {
                         event,
                         reproducers: Default::default(),
                     },
@@ -245,25 +273,30 @@ impl WhatRanCommandImplementation for WhatFailedImpl {
 
             match data {
                 buck2_data::buck_event::Data::SpanEnd(span) => match &span.data {
-                    Some(buck2_data::span_end_event::Data::ActionExecution(action))
-                        if action.failed =>
-                    {
-                        if let Some(entry) = self.known_actions.remove(&event.span_id) {
-                            let action = WhatRanRelevantAction::from_buck_data(
-                                entry.event.data.as_ref().expect("Checked above"),
-                            );
-
-                            for repro in entry.reproducers.iter() {
-                                what_ran::emit_reproducer(
-                                    action,
-                                    CommandReproducer::from_buck_data(
-                                        repro.data.as_ref().expect("Checked above"),
-                                        options,
-                                    )
-                                    .expect("Checked above"),
-                                    output,
-                                )?;
-                            }
+                    Some(buck2_data::span_end_event::Data::ActionExecution(action_end)) => {
+                        let entry = match self.known_actions.remove(&event.span_id) {
+                            Some(entry) if action_end.failed || !self.failed_only => entry,
+                            _ => return Ok(()),
+                        };
+
+                        let action = WhatRanRelevantAction::from_buck_data(
+                            entry.event.data.as_ref().expect("Checked above"),
+                        );
+
+                        for repro in entry.reproducers.iter() {
+                            let repro = CommandReproducer::from_buck_data(
+                                repro.data.as_ref().expect("Checked above"),
+                                options,
+                            )
+                            .expect("Checked above");
+
+                            let resource_usage = if self.show_resource_usage {
+                                what_ran::local_resource_usage(action_end, repro)
+                            } else {
+                                None
+                            };
+
+                            what_ran::emit_reproducer(action, repro, resource_usage, output)?;
                         }
                     }
                     _ => {}
@@ -280,15 +313,23 @@ impl WhatRanCommandImplementation for WhatFailedImpl {
 impl WhatRanOutputWriter for LogCommandOutputFormat {
     fn emit_command(&mut self, command: WhatRanOutputCommand<'_>) -> anyhow::Result<()> {
         match self {
-            Self::Tabulated => {
-                buck2_client_ctx::println!(
+            Self::Tabulated => match command.resource_usage() {
+                Some(resource_usage) => buck2_client_ctx::println!(
+                    "{}\t{}\t{}\t{}\t{}",
+                    command.reason(),
+                    command.identity(),
+                    command.repro().executor(),
+                    command.repro().as_human_readable(),
+                    what_ran::resource_usage_to_string(resource_usage)
+                ),
+                None => buck2_client_ctx::println!(
                     "{}\t{}\t{}\t{}",
                     command.reason(),
                     command.identity(),
                     command.repro().executor(),
                     command.repro().as_human_readable()
-                )
-            }
+                ),
+            },
             Self::Json => {
                 let reproducer = match command.repro() {
                     CommandReproducer::CacheQuery(cache_hit) => JsonReproducer::CacheQuery {
@@ -322,6 +363,7 @@ impl WhatRanOutputWriter for LogCommandOutputFormat {
                     identity: command.identity(),
                     reproducer,
                     extra: command.extra().map(Into::into),
+                    resource_usage: command.resource_usage().map(Into::into),
                 };
 
                 buck2_client_ctx::stdio::print_with_writer(|mut w| {
@@ -336,6 +378,8 @@ impl WhatRanOutputWriter for LogCommandOutputFormat {
                     identity: &'a str,
                     executor: String,
                     reproducer: String,
+                    #[serde(skip_serializing_if = "Option::is_none")]
+                    resource_usage: Option<String>,
                 }
 
                 buck2_client_ctx::stdio::print_with_writer(|w| {
@@ -345,6 +389,9 @@ impl WhatRanOutputWriter for LogCommandOutputFormat {
                         identity: command.identity(),
                         executor: command.repro().executor(),
                         reproducer: command.repro().as_human_readable().to_string(),
+                        resource_usage: command
+                            .resource_usage()
+                            .map(what_ran::resource_usage_to_string),
                     })
                 })
             }
@@ -368,6 +415,8 @@ struct JsonCommand<'a> {
     reproducer: JsonReproducer<'a>,
     #[serde(skip_serializing_if = "Option::is_none")]
     extra: Option<JsonExtra<'a>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    resource_usage: Option<JsonResourceUsage>,
 }
 
 mod json_reproducer {
@@ -405,6 +454,33 @@ enum JsonExtra<'a> {
     TestCases(&'a [String]),
 }
 
+/// The measured parts of `CommandExecutionStats`, in bytes and microseconds.
+#[derive(serde::Serialize)]
+struct JsonResourceUsage {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    memory_peak_bytes: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cpu_user_us: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cpu_system_us: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    io_read_bytes: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    io_write_bytes: Option<u64>,
+}
+
+impl From<&buck2_data::CommandExecutionStats> for JsonResourceUsage {
+    fn from(stats: &buck2_data::CommandExecutionStats) -> Self {
+        Self {
+            memory_peak_bytes: stats.memory_peak_bytes,
+            cpu_user_us: stats.cpu_user_us,
+            cpu_system_us: stats.cpu_system_us,
+            io_read_bytes: stats.io_read_bytes,
+            io_write_bytes: stats.io_write_bytes,
+        }
+    }
+}
+
 impl<'a> From<WhatRanOutputCommandExtra<'a>> for JsonExtra<'a> {
     fn from(extra: WhatRanOutputCommandExtra<'a>) -> JsonExtra<'a> {
         match extra {
@@ -427,6 +503,7 @@ mod tests {
             identity: "some/target",
             reproducer: JsonReproducer::Local { command, env },
             extra: None,
+            resource_usage: None,
         }
     }
 
@@ -442,6 +519,7 @@ mod tests {
                 action_key: None,
             },
             extra: None,
+            resource_usage: None,
         }
     }
 
@@ -500,6 +578,41 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn serialize_what_ran_command_with_resource_usage() -> anyhow::Result<()> {
+        let mut command = make_base_command();
+        command.resource_usage = Some(JsonResourceUsage::from(
+            &buck2_data::CommandExecutionStats {
+                memory_peak_bytes: Some(1024),
+                cpu_user_us: Some(20),
+                ..Default::default()
+            },
+        ));
+
+        let expected = r#"{
+  "reason": "test.run",
+  "identity": "some/target",
+  "reproducer": {
+    "executor": "Local",
+    "details": {
+      "command": [
+        "some",
+        "command"
+      ],
+      "env": {
+        "KEY": "val"
+      }
+    }
+  },
+  "resource_usage": {
+    "memory_peak_bytes": 1024,
+    "cpu_user_us": 20
+  }
+}"#;
+        assert_eq!(expected, serde_json::to_string_pretty(&command)?);
+        Ok(())
+    }
+
     #[test]
     fn serialize_what_ran_command_in_re() -> anyhow::Result<()> {
         let command = make_base_command_in_re();
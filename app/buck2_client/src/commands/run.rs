@@ -116,6 +116,7 @@ impl StreamingCommand for RunCommand {
                     response_options: None,
                     build_opts: Some(self.build_opts.to_proto()),
                     final_artifact_materializations: Materializations::Materialize as i32,
+                    materialize_only: Vec::new(),
                     target_universe: Vec::new(),
                 },
                 ctx.stdin()
@@ -167,6 +167,12 @@ pub struct TargetsCommand {
     #[clap(long, requires = "streaming")]
     imports: bool,
 
+    /// Also print every target in the repository that transitively depends on the
+    /// specified targets. This uses the reverse dependency index kept by the daemon, so it is
+    /// cheap to repeat after build files change, which is useful for impact analysis.
+    #[clap(long, conflicts_with_all = &["streaming", "resolve-alias"])]
+    rdeps: bool,
+
     /// File to put the output in, rather than sending to stdout.
     ///
     /// File will be created if it does not exist, and overwritten if it does.
@@ -291,6 +297,7 @@ impl StreamingCommand for TargetsCommand {
                     streaming: self.streaming,
                     cached: !self.no_cache,
                     imports: self.imports,
+                    rdeps: self.rdeps,
                 })
             }),
             output: self
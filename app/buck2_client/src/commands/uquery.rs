@@ -57,6 +57,15 @@ pub struct UqueryCommand {
 
     #[clap(flatten)]
     query_common: CommonQueryArgs,
+
+    /// Print targets as soon as their package is loaded, instead of once the whole query has
+    /// been evaluated. This keeps memory usage low for queries such as `//...` on large repos.
+    ///
+    /// Only queries made of target patterns combined with `+` or `union` can be streamed. Other
+    /// queries, including multi-queries, fall back to printing the result once it is fully
+    /// evaluated. Only the default and JSON output formats are supported.
+    #[clap(long)]
+    streaming: bool,
 }
 
 #[async_trait]
@@ -88,6 +97,7 @@ impl StreamingCommand for UqueryCommand {
                     output_attributes,
                     unstable_output_format,
                     target_call_stacks: self.query_common.target_call_stacks,
+                    streaming: self.streaming,
                 },
                 ctx.stdin()
                     .console_interaction_stream(&self.common_opts.console_opts),
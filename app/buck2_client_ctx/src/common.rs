@@ -361,6 +361,24 @@ pub struct CommonBuildOptions {
     /// Exit the current command if there was a state change in the concurrently-running command.
     #[clap(long)]
     exit_when_different_state: bool,
+
+    /// Run actions that execute locally a second time, and report the ones whose outputs differ
+    /// between the two runs. The second run is always local and skips the action cache. The
+    /// outputs of the first run are moved aside while it runs, and they're the ones the action
+    /// keeps.
+    #[clap(long)]
+    check_determinism: bool,
+
+    /// Only check the determinism of actions in this category. Can be passed multiple times.
+    /// Implies `--check-determinism`.
+    #[clap(long, value_name = "CATEGORY")]
+    check_determinism_category: Vec<String>,
+
+    /// Report files that actions running locally leave in their output directories without
+    /// declaring them as outputs, as well as such files left over by previous runs of an action,
+    /// which later builds could accidentally rely on. Requires the deferred materializer.
+    #[clap(long)]
+    check_stale_outputs: bool,
 }
 
 impl CommonBuildOptions {
@@ -401,6 +419,10 @@ impl CommonBuildOptions {
             upload_all_actions: self.upload_all_actions,
             no_remote_cache: self.no_remote_cache,
             exit_when_different_state: self.exit_when_different_state,
+            check_determinism: self.check_determinism
+                || !self.check_determinism_category.is_empty(),
+            check_determinism_categories: self.check_determinism_category.clone(),
+            check_stale_outputs: self.check_stale_outputs,
         }
     }
 }
@@ -20,8 +20,19 @@ use internment_tweaks::Intern;
 use internment_tweaks::StaticInterner;
 use once_cell::sync::Lazy;
 
-#[derive(Debug, Eq, Hash, PartialEq, Clone, Dupe, Allocative)]
-pub struct LocalExecutorOptions {}
+#[derive(Debug, Default, Eq, Hash, PartialEq, Clone, Dupe, Allocative)]
+pub struct LocalExecutorOptions {
+    /// Run local actions in a Linux namespace sandbox that only exposes their declared inputs.
+    pub sandbox: Option<LocalSandboxOptions>,
+    /// Run local actions without network access, unless they explicitly allow it.
+    pub block_network: bool,
+}
+
+#[derive(Debug, Eq, Hash, PartialEq, Copy, Clone, Dupe, Allocative)]
+pub struct LocalSandboxOptions {
+    /// Run the action in its own network namespace, so that it has no network access.
+    pub isolate_network: bool,
+}
 
 #[derive(Debug, Eq, PartialEq, Copy, Clone, Dupe, Display, Allocative)]
 pub struct RemoteExecutorUseCase(Intern<String>);
@@ -179,12 +190,17 @@ pub enum HybridExecutionLevel {
         fallback_on_failure: bool,
         low_pass_filter: bool,
     },
+    /// Race both executors until we know which one is faster for an action, then only run it on
+    /// that one (falling back to the other one like `Fallback` does). Which executor won is
+    /// remembered per action key, so small actions that are slow to upload end up running locally
+    /// and expensive actions end up running remotely. Actions are raced again once in a while.
+    Adaptive { fallback_on_failure: bool },
 }
 
 impl CommandExecutorConfig {
     pub fn testing_local() -> Arc<CommandExecutorConfig> {
         Arc::new(CommandExecutorConfig {
-            executor: Executor::Local(LocalExecutorOptions {}),
+            executor: Executor::Local(LocalExecutorOptions::default()),
             options: CommandGenerationOptions {
                 path_separator: PathSeparatorKind::system_default(),
                 output_paths_behavior: Default::default(),
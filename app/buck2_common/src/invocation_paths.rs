@@ -155,6 +155,12 @@ impl InvocationPaths {
             .join(ForwardRelativePath::unchecked_new("build_count"))
     }
 
+    /// Where persistent workers write their stderr.
+    pub fn worker_logs_dir(&self) -> AbsNormPathBuf {
+        self.buck_out_path()
+            .join(ForwardRelativePath::unchecked_new("workers"))
+    }
+
     pub fn dice_dump_dir(&self) -> AbsNormPathBuf {
         self.buck_out_path()
             .join(ForwardRelativePath::unchecked_new("dice_dump"))
@@ -188,6 +194,29 @@ impl InvocationPaths {
             .join(self.materializer_state_dir_name())
     }
 
+    /// Subdirectory of `cache_dir` responsible for storing dep files state
+    pub fn dep_files_state_path(&self) -> AbsNormPathBuf {
+        self.cache_dir_path().join(self.dep_files_state_dir_name())
+    }
+
+    /// Subdirectory of `cache_dir` responsible for storing the action timings used by the adaptive
+    /// hybrid executor
+    pub fn action_timings_path(&self) -> AbsNormPathBuf {
+        self.cache_dir_path().join(self.action_timings_dir_name())
+    }
+
+    /// Subdirectory of `cache_dir` responsible for storing the undeclared outputs of local actions,
+    /// so that they can be reported as stale across daemon restarts
+    pub fn stale_outputs_path(&self) -> AbsNormPathBuf {
+        self.cache_dir_path().join(self.stale_outputs_dir_name())
+    }
+
+    /// Subdirectory of `cache_dir` where lazy outputs keep the outputs that aren't lazy, and the
+    /// blobs fetched for those that are
+    pub fn lazy_outputs_dir(&self) -> ProjectRelativePathBuf {
+        self.cache_dir().join(self.lazy_outputs_dir_name())
+    }
+
     /// This is used by the forkserver to write the miniperf wrapper binary (if used), as well as
     /// temporary files used by miniperf. We put this in buck-out because that directory gets
     /// allowlisted for execution (because we write lots of tools there).
@@ -200,8 +229,30 @@ impl InvocationPaths {
         FileName::unchecked_new("materializer_state")
     }
 
+    pub fn dep_files_state_dir_name(&self) -> &FileName {
+        FileName::unchecked_new("dep_files_state")
+    }
+
+    pub fn action_timings_dir_name(&self) -> &FileName {
+        FileName::unchecked_new("action_timings")
+    }
+
+    pub fn stale_outputs_dir_name(&self) -> &FileName {
+        FileName::unchecked_new("stale_outputs")
+    }
+
+    pub fn lazy_outputs_dir_name(&self) -> &FileName {
+        FileName::unchecked_new("lazy_outputs")
+    }
+
     pub fn valid_cache_dirs(&self) -> Vec<&FileName> {
-        vec![self.materializer_state_dir_name()]
+        vec![
+            self.materializer_state_dir_name(),
+            self.dep_files_state_dir_name(),
+            self.action_timings_dir_name(),
+            self.stale_outputs_dir_name(),
+            self.lazy_outputs_dir_name(),
+        ]
     }
 }
 
@@ -311,5 +362,35 @@ mod tests {
             paths.materializer_state_path().as_os_str(),
             OsStr::new(expected_path),
         );
+
+        let expected_path = if cfg!(windows) {
+            "C:\\my\\project\\buck-out\\isolation\\cache\\dep_files_state"
+        } else {
+            "/my/project/buck-out/isolation/cache/dep_files_state"
+        };
+        assert_eq!(
+            paths.dep_files_state_path().as_os_str(),
+            OsStr::new(expected_path),
+        );
+
+        let expected_path = if cfg!(windows) {
+            "C:\\my\\project\\buck-out\\isolation\\cache\\action_timings"
+        } else {
+            "/my/project/buck-out/isolation/cache/action_timings"
+        };
+        assert_eq!(
+            paths.action_timings_path().as_os_str(),
+            OsStr::new(expected_path),
+        );
+
+        let expected_path = if cfg!(windows) {
+            "C:\\my\\project\\buck-out\\isolation\\cache\\stale_outputs"
+        } else {
+            "/my/project/buck-out/isolation/cache/stale_outputs"
+        };
+        assert_eq!(
+            paths.stale_outputs_path().as_os_str(),
+            OsStr::new(expected_path),
+        );
     }
 }
@@ -11,9 +11,93 @@ use std::collections::HashMap;
 use std::sync::Arc;
 
 use anyhow::Context;
+use buck2_core::fs::fs_util;
+use buck2_core::fs::paths::abs_norm_path::AbsNormPath;
+use buck2_core::fs::paths::abs_norm_path::AbsNormPathBuf;
+use buck2_core::fs::paths::file_name::FileName;
+use dupe::Dupe;
 use itertools::Itertools;
 use parking_lot::Mutex;
 use rusqlite::Connection;
+use thiserror::Error;
+
+const DB_FILENAME: &str = "db.sqlite";
+
+const VERSIONS_TABLE: &str = "versions";
+
+#[derive(Error, Debug)]
+enum VersionedSqliteDbError {
+    #[error("Expected versions {expected:?}. Found versions {found:?} in sqlite db at {path}")]
+    VersionMismatch {
+        expected: HashMap<String, String>,
+        found: HashMap<String, String>,
+        path: AbsNormPathBuf,
+    },
+}
+
+/// Open the sqlite db in `dir`, with the given key-value `tables`. When opening an existing db, we
+/// check that the versions it was created with match `versions`. If they don't, or if the db can't
+/// be read, we throw away the whole db and create an empty one in its place.
+///
+/// Writes aren't synced to disk: this is meant for state that we can afford to lose, like caches.
+pub fn open_versioned_sqlite_db(
+    dir: &AbsNormPath,
+    versions: HashMap<String, String>,
+    tables: &[&str],
+) -> anyhow::Result<Arc<Mutex<Connection>>> {
+    let db_path = dir.join(FileName::unchecked_new(DB_FILENAME));
+
+    match open_existing(&db_path, &versions) {
+        Ok(connection) => Ok(connection),
+        Err(e) => {
+            tracing::debug!("Discarding sqlite db at `{}`: {:#}", db_path, e);
+
+            // We delete the entire directory and not just the db file because sqlite can leave
+            // behind other files.
+            if dir.exists() {
+                fs_util::remove_dir_all(dir)?;
+            }
+            fs_util::create_dir_all(dir)?;
+
+            let connection = open_connection(&db_path)?;
+            for table in tables {
+                KeyValueSqliteTable::new((*table).to_owned(), connection.dupe()).create_table()?;
+            }
+            let versions_table =
+                KeyValueSqliteTable::new(VERSIONS_TABLE.to_owned(), connection.dupe());
+            versions_table.create_table()?;
+            versions_table.insert_all(versions)?;
+            Ok(connection)
+        }
+    }
+}
+
+fn open_existing(
+    db_path: &AbsNormPathBuf,
+    versions: &HashMap<String, String>,
+) -> anyhow::Result<Arc<Mutex<Connection>>> {
+    let connection = open_connection(db_path)?;
+    let found =
+        KeyValueSqliteTable::new(VERSIONS_TABLE.to_owned(), connection.dupe()).read_all()?;
+    if found != *versions {
+        return Err(VersionedSqliteDbError::VersionMismatch {
+            expected: versions.clone(),
+            found,
+            path: db_path.clone(),
+        }
+        .into());
+    }
+    Ok(connection)
+}
+
+fn open_connection(path: &AbsNormPath) -> anyhow::Result<Arc<Mutex<Connection>>> {
+    let connection = Connection::open(path)?;
+    if cfg!(unix) {
+        connection.pragma_update(None, "journal_mode", "WAL")?;
+    }
+    connection.pragma_update(None, "synchronous", "OFF")?;
+    Ok(Arc::new(Mutex::new(connection)))
+}
 
 /// A generic sqlite table for storing string key-value pairs.
 pub struct KeyValueSqliteTable {
@@ -89,6 +173,43 @@ impl KeyValueSqliteTable {
             .with_context(|| format!("reading `{}` from sqlite table {}", key, self.table_name))?;
         Ok(row)
     }
+
+    pub fn delete(&self, key: &str) -> anyhow::Result<()> {
+        let sql = format!("DELETE FROM {} WHERE key = ?", self.table_name);
+        tracing::trace!(sql = %sql, key = %key, "deleting from table");
+        self.connection
+            .lock()
+            .execute(&sql, [key])
+            .with_context(|| format!("deleting `{}` from sqlite table {}", key, self.table_name))?;
+        Ok(())
+    }
+
+    pub fn delete_many(&self, keys: &[String]) -> anyhow::Result<()> {
+        // Stay well below the default limit on the number of placeholders in a query.
+        for keys in keys.chunks(500) {
+            let sql = format!(
+                "DELETE FROM {} WHERE key IN ({})",
+                self.table_name,
+                keys.iter().map(|_| "?").join(", ")
+            );
+            tracing::trace!(sql = %sql, keys = ?keys, "deleting from table");
+            self.connection
+                .lock()
+                .execute(&sql, rusqlite::params_from_iter(keys))
+                .with_context(|| format!("deleting from sqlite table {}", self.table_name))?;
+        }
+        Ok(())
+    }
+
+    pub fn delete_all(&self) -> anyhow::Result<()> {
+        let sql = format!("DELETE FROM {}", self.table_name);
+        tracing::trace!(sql = %sql, "deleting all from table");
+        self.connection
+            .lock()
+            .execute(&sql, [])
+            .with_context(|| format!("deleting from sqlite table {}", self.table_name))?;
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -124,5 +245,51 @@ mod tests {
 
         assert_eq!(table.get("foo").unwrap().as_deref(), Some("foo"));
         assert_eq!(table.get("baz").unwrap(), None);
+
+        table.delete("foo").unwrap();
+        assert_eq!(table.get("foo").unwrap(), None);
+        assert_eq!(table.get("bar").unwrap().as_deref(), Some("bar"));
+
+        table
+            .insert_all(HashMap::from([("baz".to_owned(), "baz".to_owned())]))
+            .unwrap();
+        table
+            .delete_many(&["bar".to_owned(), "qux".to_owned()])
+            .unwrap();
+        assert_eq!(
+            HashMap::from([("baz".to_owned(), "baz".to_owned())]),
+            table.read_all().unwrap()
+        );
+
+        table.delete_all().unwrap();
+        assert!(table.read_all().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_open_versioned_sqlite_db() -> anyhow::Result<()> {
+        let fs = ProjectRootTemp::new()?;
+        let dir = fs.path().resolve(ProjectRelativePath::unchecked_new("db"));
+        let versions = HashMap::from([("version".to_owned(), "1".to_owned())]);
+
+        let connection = open_versioned_sqlite_db(&dir, versions.clone(), &["data"])?;
+        KeyValueSqliteTable::new("data".to_owned(), connection)
+            .insert_all(HashMap::from([("foo".to_owned(), "foo".to_owned())]))?;
+
+        // Same versions: the data is still there.
+        let connection = open_versioned_sqlite_db(&dir, versions, &["data"])?;
+        let table = KeyValueSqliteTable::new("data".to_owned(), connection);
+        assert_eq!(table.get("foo")?.as_deref(), Some("foo"));
+        drop(table);
+
+        // Different versions: we start over.
+        let connection = open_versioned_sqlite_db(
+            &dir,
+            HashMap::from([("version".to_owned(), "2".to_owned())]),
+            &["data"],
+        )?;
+        let table = KeyValueSqliteTable::new("data".to_owned(), connection);
+        assert_eq!(table.get("foo")?, None);
+
+        Ok(())
     }
 }
@@ -82,46 +82,25 @@ impl BaseDeferredKeyDyn {
     ) -> ProjectRelativePathBuf {
         match self {
             BaseDeferredKeyDyn::TargetLabel(target) => {
-                let cell_relative_path = target.pkg().cell_relative_path().as_str();
-
-                // It is performance critical that we use slices and allocate via `join` instead of
-                // repeated calls to `join` on the path object because `join` allocates on each call,
-                // which has a significant impact.
-                let parts = [
-                    base.as_str(),
-                    "/",
-                    prefix.as_str(),
-                    "/",
-                    target.pkg().cell_name().as_str(),
-                    "/",
-                    target.cfg().output_hash().as_str(),
-                    if target.exec_cfg().is_some() { "-" } else { "" },
-                    target
-                        .exec_cfg()
-                        .as_ref()
-                        .map_or("", |x| x.output_hash().as_str()),
-                    "/",
-                    cell_relative_path,
-                    if cell_relative_path.is_empty() {
-                        ""
-                    } else {
-                        "/"
-                    },
-                    "__",
-                    target.name().as_str(),
-                    "__",
-                    "/",
-                    if action_key.is_none() {
-                        ""
-                    } else {
-                        "__action__"
-                    },
-                    action_key.unwrap_or_default(),
-                    if action_key.is_none() { "" } else { "__/" },
-                    path.as_str(),
-                ];
-
-                ProjectRelativePathBuf::unchecked_new(parts.concat())
+                make_target_path(target, true, base, prefix, action_key, path)
+            }
+            BaseDeferredKeyDyn::Dyn(d) => d.make_hashed_path(base, prefix, action_key, path),
+        }
+    }
+
+    /// Like `make_hashed_path`, but without the configuration hash, so that the outputs of a
+    /// target resolve to the same path in every configuration. This is only supported for
+    /// targets: other owners get the same path as with `make_hashed_path`.
+    pub fn make_configuration_independent_path(
+        &self,
+        base: &ProjectRelativePath,
+        prefix: &ForwardRelativePath,
+        action_key: Option<&str>,
+        path: &ForwardRelativePath,
+    ) -> ProjectRelativePathBuf {
+        match self {
+            BaseDeferredKeyDyn::TargetLabel(target) => {
+                make_target_path(target, false, base, prefix, action_key, path)
             }
             BaseDeferredKeyDyn::Dyn(d) => d.make_hashed_path(base, prefix, action_key, path),
         }
@@ -138,3 +117,62 @@ impl BaseDeferredKeyDyn {
         }
     }
 }
+
+/// The directory used in place of the configuration hash for configuration-independent paths.
+/// It can't be confused with a hash since those are hexadecimal.
+const CONFIGURATION_INDEPENDENT_DIR: &str = "shared";
+
+fn make_target_path(
+    target: &ConfiguredTargetLabel,
+    include_configuration: bool,
+    base: &ProjectRelativePath,
+    prefix: &ForwardRelativePath,
+    action_key: Option<&str>,
+    path: &ForwardRelativePath,
+) -> ProjectRelativePathBuf {
+    let cell_relative_path = target.pkg().cell_relative_path().as_str();
+    let (cfg_hash, exec_cfg_hash) = if include_configuration {
+        (
+            target.cfg().output_hash().as_str(),
+            target.exec_cfg().as_ref().map(|x| x.output_hash().as_str()),
+        )
+    } else {
+        (CONFIGURATION_INDEPENDENT_DIR, None)
+    };
+
+    // It is performance critical that we use slices and allocate via `join` instead of
+    // repeated calls to `join` on the path object because `join` allocates on each call,
+    // which has a significant impact.
+    let parts = [
+        base.as_str(),
+        "/",
+        prefix.as_str(),
+        "/",
+        target.pkg().cell_name().as_str(),
+        "/",
+        cfg_hash,
+        if exec_cfg_hash.is_some() { "-" } else { "" },
+        exec_cfg_hash.unwrap_or_default(),
+        "/",
+        cell_relative_path,
+        if cell_relative_path.is_empty() {
+            ""
+        } else {
+            "/"
+        },
+        "__",
+        target.name().as_str(),
+        "__",
+        "/",
+        if action_key.is_none() {
+            ""
+        } else {
+            "__action__"
+        },
+        action_key.unwrap_or_default(),
+        if action_key.is_none() { "" } else { "__/" },
+        path.as_str(),
+    ];
+
+    ProjectRelativePathBuf::unchecked_new(parts.concat())
+}
@@ -23,6 +23,19 @@ use crate::fs::paths::forward_rel_path::ForwardRelativePathBuf;
 use crate::fs::project_rel_path::ProjectRelativePath;
 use crate::fs::project_rel_path::ProjectRelativePathBuf;
 
+/// How the path of an output is derived from its owner.
+#[derive(Clone, Copy, Dupe, Debug, Default, Allocative, Hash, Eq, PartialEq)]
+pub enum BuckOutPathKind {
+    /// The path includes the owner's configuration hash, so that the outputs of a target in
+    /// different configurations don't collide.
+    #[default]
+    Configuration,
+    /// The path doesn't depend on the owner's configuration, so the outputs of a target in
+    /// different configurations share it. Only suitable for outputs whose contents don't depend
+    /// on the configuration.
+    ConfigurationIndependent,
+}
+
 #[derive(Clone, Debug, Display, Allocative, Hash, Eq, PartialEq)]
 #[display(fmt = "({})/{}", owner, "path.as_str()")]
 struct BuckOutPathData {
@@ -32,6 +45,8 @@ struct BuckOutPathData {
     action_key: Option<Arc<str>>,
     /// The path relative to that target.
     path: ForwardRelativePathBuf,
+    /// How the owner is turned into a path.
+    kind: BuckOutPathKind,
 }
 
 /// Represents a resolvable path corresponding to outputs of rules that are part
@@ -56,11 +71,21 @@ impl BuckOutPath {
         owner: BaseDeferredKeyDyn,
         path: ForwardRelativePathBuf,
         action_key: Option<Arc<str>>,
+    ) -> Self {
+        Self::with_action_key_and_kind(owner, path, action_key, BuckOutPathKind::default())
+    }
+
+    pub fn with_action_key_and_kind(
+        owner: BaseDeferredKeyDyn,
+        path: ForwardRelativePathBuf,
+        action_key: Option<Arc<str>>,
+        kind: BuckOutPathKind,
     ) -> Self {
         BuckOutPath(Arc::new(BuckOutPathData {
             owner,
             action_key,
             path,
+            kind,
         }))
     }
 
@@ -75,6 +100,10 @@ impl BuckOutPath {
     pub fn path(&self) -> &ForwardRelativePath {
         &self.0.path
     }
+
+    pub fn kind(&self) -> BuckOutPathKind {
+        self.0.kind
+    }
 }
 
 #[derive(Clone, Debug, Display, Eq, PartialEq)]
@@ -181,12 +210,20 @@ impl BuckOutPathResolver {
     /// Resolves a 'BuckOutPath' into a 'ProjectRelativePath' based on the base
     /// directory, target and cell.
     pub fn resolve_gen(&self, path: &BuckOutPath) -> ProjectRelativePathBuf {
-        self.prefixed_path_for_owner(
-            ForwardRelativePath::unchecked_new("gen"),
-            path.owner(),
-            path.action_key(),
-            path.path(),
-        )
+        let prefix = ForwardRelativePath::unchecked_new("gen");
+        match path.kind() {
+            BuckOutPathKind::Configuration => {
+                self.prefixed_path_for_owner(prefix, path.owner(), path.action_key(), path.path())
+            }
+            BuckOutPathKind::ConfigurationIndependent => {
+                path.owner().make_configuration_independent_path(
+                    &self.0,
+                    prefix,
+                    path.action_key(),
+                    path.path(),
+                )
+            }
+        }
     }
 
     pub fn resolve_scratch(&self, path: &BuckOutScratchPath) -> ProjectRelativePathBuf {
@@ -252,6 +289,7 @@ mod tests {
     use crate::cells::CellResolver;
     use crate::configuration::data::ConfigurationData;
     use crate::fs::buck_out_path::BuckOutPath;
+    use crate::fs::buck_out_path::BuckOutPathKind;
     use crate::fs::buck_out_path::BuckOutPathResolver;
     use crate::fs::buck_out_path::BuckOutScratchPath;
     use crate::fs::paths::forward_rel_path::ForwardRelativePathBuf;
@@ -381,6 +419,36 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn buck_configuration_independent_output_path_resolves() -> anyhow::Result<()> {
+        let path_resolver =
+            BuckOutPathResolver::new(ProjectRelativePathBuf::unchecked_new("buck-out".into()));
+
+        let pkg = PackageLabel::new(
+            CellName::testing_new("foo"),
+            CellRelativePath::unchecked_new("baz-package"),
+        );
+        let target = TargetLabel::new(pkg, TargetNameRef::unchecked_new("target-name"));
+
+        let resolve = |cfg| {
+            path_resolver.resolve_gen(&BuckOutPath::with_action_key_and_kind(
+                BaseDeferredKeyDyn::TargetLabel(target.configure(cfg)),
+                ForwardRelativePathBuf::unchecked_new("quux".to_owned()),
+                None,
+                BuckOutPathKind::ConfigurationIndependent,
+            ))
+        };
+
+        let resolved = resolve(ConfigurationData::testing_new());
+        assert_eq!(
+            "buck-out/gen/foo/shared/baz-package/__target-name__/quux",
+            resolved.as_str()
+        );
+        assert_eq!(resolved, resolve(ConfigurationData::unspecified()));
+
+        Ok(())
+    }
+
     #[test]
     fn test_scratch_path_is_sensible() {
         let pkg = PackageLabel::new(
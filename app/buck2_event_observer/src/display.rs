@@ -520,6 +520,23 @@ pub fn display_action_error<'a>(
                 None => "Unexpected command status".to_owned(),
             }
         }
+        Error::CommandTimedOut(timed_out) => timed_out.message.clone(),
+        Error::NetworkAccessBlocked(blocked) => match action.commands.last() {
+            Some(c) => format!(
+                "{}\n{}",
+                failure_reason_for_command_execution(c)?,
+                blocked.message
+            ),
+            None => blocked.message.clone(),
+        },
+        Error::UndeclaredInputs(undeclared) => match action.commands.last() {
+            Some(c) => format!(
+                "{}\n{}",
+                failure_reason_for_command_execution(c)?,
+                undeclared.message
+            ),
+            None => undeclared.message.clone(),
+        },
     };
 
     Ok(ActionErrorDisplay {
@@ -18,6 +18,7 @@ use superconsole::SuperConsole;
 
 use crate::display;
 use crate::display::TargetDisplayOptions;
+use crate::humanized_bytes::HumanizedBytes;
 
 /// Options controlling what WhatRan produces.
 #[derive(Debug, Default, clap::Parser)]
@@ -66,6 +67,7 @@ pub struct WhatRanOutputCommand<'a> {
     identity: &'a str,
     repro: CommandReproducer<'a>,
     extra: Option<WhatRanOutputCommandExtra<'a>>,
+    resource_usage: Option<&'a buck2_data::CommandExecutionStats>,
 }
 
 impl WhatRanOutputCommand<'_> {
@@ -81,6 +83,10 @@ impl WhatRanOutputCommand<'_> {
     pub fn extra(&self) -> Option<WhatRanOutputCommandExtra<'_>> {
         self.extra
     }
+    /// What the command used, if it was measured and requested.
+    pub fn resource_usage(&self) -> Option<&buck2_data::CommandExecutionStats> {
+        self.resource_usage
+    }
 }
 
 #[derive(Clone, Copy, Dupe)]
@@ -123,12 +129,13 @@ fn emit<T: fmt::Display + Copy>(
     state: &impl WhatRanState<T>,
     output: &mut impl WhatRanOutputWriter,
 ) -> anyhow::Result<()> {
-    emit_reproducer(state.get(parent_span_id), repro, output)
+    emit_reproducer(state.get(parent_span_id), repro, None, output)
 }
 
 pub fn emit_reproducer(
     action: Option<WhatRanRelevantAction<'_>>,
     repro: CommandReproducer<'_>,
+    resource_usage: Option<&buck2_data::CommandExecutionStats>,
     output: &mut impl WhatRanOutputWriter,
 ) -> anyhow::Result<()> {
     let (reason, identity, extra) = match action {
@@ -162,6 +169,7 @@ pub fn emit_reproducer(
         identity: &identity,
         repro,
         extra,
+        resource_usage,
     })?;
 
     Ok(())
@@ -273,6 +281,65 @@ impl<'a> fmt::Display for HumanReadableCommandReproducer<'a> {
     }
 }
 
+/// Find what a local command used in the end event of its action.
+pub fn local_resource_usage<'a>(
+    action: &'a buck2_data::ActionExecutionEnd,
+    repro: CommandReproducer<'_>,
+) -> Option<&'a buck2_data::CommandExecutionStats> {
+    use buck2_data::command_execution_details::Command;
+
+    let digest = match repro {
+        CommandReproducer::LocalExecute(local_execute) => {
+            &local_execute.command.as_ref()?.action_digest
+        }
+        _ => return None,
+    };
+
+    action
+        .commands
+        .iter()
+        .filter_map(|c| c.details.as_ref())
+        .find(|details| match &details.command {
+            Some(Command::LocalCommand(command)) => command.action_digest == *digest,
+            Some(Command::OmittedLocalCommand(command)) => command.action_digest == *digest,
+            _ => false,
+        })?
+        .execution_stats
+        .as_ref()
+}
+
+/// The parts of the resource usage that were measured, as `key=value` pairs.
+pub fn resource_usage_to_string(stats: &buck2_data::CommandExecutionStats) -> String {
+    let seconds = |us: u64| format!("{:.2}s", us as f64 / 1_000_000.0);
+
+    [
+        (
+            "memory_peak",
+            stats
+                .memory_peak_bytes
+                .map(|b| HumanizedBytes::new(b).to_string()),
+        ),
+        ("cpu_user", stats.cpu_user_us.map(seconds)),
+        ("cpu_system", stats.cpu_system_us.map(seconds)),
+        (
+            "io_read",
+            stats
+                .io_read_bytes
+                .map(|b| HumanizedBytes::new(b).to_string()),
+        ),
+        (
+            "io_write",
+            stats
+                .io_write_bytes
+                .map(|b| HumanizedBytes::new(b).to_string()),
+        ),
+    ]
+    .into_iter()
+    .filter_map(|(key, value)| Some(format!("{}={}", key, value?)))
+    .collect::<Vec<_>>()
+    .join(", ")
+}
+
 pub fn local_command_to_string(command: &buck2_data::LocalCommand) -> String {
     let mut cmd = vec![];
 
@@ -378,4 +445,42 @@ mod tests {
         let result = executor_with_platform(&execute);
         assert_eq!(result, "re".to_owned());
     }
+
+    #[test]
+    fn test_local_resource_usage() {
+        let local_execute = buck2_data::LocalExecute {
+            command: Some(buck2_data::LocalCommand {
+                action_digest: "digest".to_owned(),
+                ..Default::default()
+            }),
+        };
+        let stats = buck2_data::CommandExecutionStats {
+            memory_peak_bytes: Some(3 * 1024 * 1024),
+            cpu_user_us: Some(1_500_000),
+            ..Default::default()
+        };
+        let action = buck2_data::ActionExecutionEnd {
+            commands: vec![buck2_data::CommandExecution {
+                details: Some(buck2_data::CommandExecutionDetails {
+                    command: Some(
+                        buck2_data::OmittedLocalCommand {
+                            action_digest: "digest".to_owned(),
+                        }
+                        .into(),
+                    ),
+                    execution_stats: Some(stats),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+
+        let usage = local_resource_usage(&action, CommandReproducer::LocalExecute(&local_execute));
+        assert_eq!(Some(&stats), usage);
+        assert_eq!(
+            "memory_peak=3.0 MiB, cpu_user=1.50s",
+            resource_usage_to_string(&stats)
+        );
+    }
 }
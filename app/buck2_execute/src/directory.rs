@@ -7,6 +7,7 @@
  * of this source tree.
  */
 
+use std::collections::BTreeMap;
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::fmt;
@@ -39,6 +40,7 @@ use buck2_core::directory::SharedDirectory;
 use buck2_core::fs::paths::file_name::FileName;
 use buck2_core::fs::paths::file_name::FileNameBuf;
 use buck2_core::fs::paths::forward_rel_path::ForwardRelativePath;
+use buck2_core::fs::paths::forward_rel_path::ForwardRelativePathBuf;
 use buck2_core::fs::paths::RelativePath;
 use buck2_core::fs::paths::RelativePathBuf;
 use buck2_core::fs::project_rel_path::ProjectRelativePath;
@@ -690,6 +692,62 @@ pub fn expand_selector_for_dependencies(
     }
 }
 
+/// A file or symlink that differs between two versions of a directory entry. `before` or `after`
+/// is `None` if the path only exists on the other side.
+#[derive(Debug, PartialEq, Eq)]
+pub struct ActionDirectoryMemberDiff {
+    /// Relative to the entry.
+    pub path: ForwardRelativePathBuf,
+    pub before: Option<ActionDirectoryMember>,
+    pub after: Option<ActionDirectoryMember>,
+}
+
+/// Compare the files and symlinks of two entries, returning the ones that differ, sorted by path.
+/// Empty directories are ignored.
+pub fn diff_entries<D: ActionDirectory>(
+    before: &ActionDirectoryEntry<D>,
+    after: &ActionDirectoryEntry<D>,
+) -> Vec<ActionDirectoryMemberDiff> {
+    fn leaves<D: ActionDirectory>(
+        entry: &ActionDirectoryEntry<D>,
+    ) -> BTreeMap<ForwardRelativePathBuf, ActionDirectoryMember> {
+        let mut leaves = BTreeMap::new();
+        let mut walk = unordered_entry_walk(entry.as_ref());
+        while let Some((path, entry)) = walk.next() {
+            if let DirectoryEntry::Leaf(leaf) = entry {
+                leaves.insert(path.get(), leaf.dupe());
+            }
+        }
+        leaves
+    }
+
+    let mut before = leaves(before);
+    let mut diffs = Vec::new();
+
+    for (path, after) in leaves(after) {
+        let before = before.remove(&path);
+        if before.as_ref() != Some(&after) {
+            diffs.push(ActionDirectoryMemberDiff {
+                path,
+                before,
+                after: Some(after),
+            });
+        }
+    }
+
+    diffs.extend(
+        before
+            .into_iter()
+            .map(|(path, before)| ActionDirectoryMemberDiff {
+                path,
+                before: Some(before),
+                after: None,
+            }),
+    );
+    diffs.sort_by(|a, b| a.path.cmp(&b.path));
+    diffs
+}
+
 /// Given a builder and a Path, return it as an ArtifactValue. Dependencies are produced by
 /// extracting any symlinks found in the builder.
 pub fn extract_artifact_value(
@@ -1128,4 +1186,74 @@ mod tests {
         extract_artifact_value(&builder, path("d1/f1"), digest_config)?.context("Not value!")?;
         Ok(())
     }
+
+    #[test]
+    fn test_diff_entries() -> anyhow::Result<()> {
+        let digest_config = DigestConfig::testing_default();
+        let file = |content: &str| {
+            ActionDirectoryMember::File(FileMetadata {
+                digest: TrackedFileDigest::from_content(
+                    content.as_bytes(),
+                    digest_config.cas_digest_config(),
+                ),
+                is_executable: false,
+            })
+        };
+
+        let mut before = ActionDirectoryBuilder::empty();
+        insert_entry(&mut before, path("same"), DirectoryEntry::Leaf(file("a")))?;
+        insert_entry(
+            &mut before,
+            path("d/changed"),
+            DirectoryEntry::Leaf(file("a")),
+        )?;
+        insert_entry(
+            &mut before,
+            path("removed"),
+            DirectoryEntry::Leaf(file("a")),
+        )?;
+
+        let mut after = ActionDirectoryBuilder::empty();
+        insert_entry(&mut after, path("same"), DirectoryEntry::Leaf(file("a")))?;
+        insert_entry(
+            &mut after,
+            path("d/changed"),
+            DirectoryEntry::Leaf(file("b")),
+        )?;
+        insert_entry(&mut after, path("added"), DirectoryEntry::Leaf(file("a")))?;
+
+        let diffs = diff_entries(&DirectoryEntry::Dir(before), &DirectoryEntry::Dir(after));
+        assert_eq!(
+            vec![
+                ActionDirectoryMemberDiff {
+                    path: ForwardRelativePathBuf::unchecked_new("added".to_owned()),
+                    before: None,
+                    after: Some(file("a")),
+                },
+                ActionDirectoryMemberDiff {
+                    path: ForwardRelativePathBuf::unchecked_new("d/changed".to_owned()),
+                    before: Some(file("a")),
+                    after: Some(file("b")),
+                },
+                ActionDirectoryMemberDiff {
+                    path: ForwardRelativePathBuf::unchecked_new("removed".to_owned()),
+                    before: Some(file("a")),
+                    after: None,
+                },
+            ],
+            diffs
+        );
+
+        // A single file is compared as a whole.
+        assert_eq!(
+            1,
+            diff_entries::<ActionDirectoryBuilder>(
+                &DirectoryEntry::Leaf(file("a")),
+                &DirectoryEntry::Leaf(file("b")),
+            )
+            .len()
+        );
+
+        Ok(())
+    }
 }
@@ -7,6 +7,7 @@
  * of this source tree.
  */
 
+use buck2_core::fs::project_rel_path::ProjectRelativePathBuf;
 use derive_more::Display;
 use sorted_vector_map::SortedVectorMap;
 
@@ -22,6 +23,11 @@ pub enum CommandExecutionKind {
         digest: ActionDigest,
         command: Vec<String>,
         env: SortedVectorMap<String, String>,
+        /// Whether the command ran without network access.
+        network_blocked: bool,
+        /// The files the command failed to read because they are not declared inputs, when it
+        /// ran in the local sandbox.
+        undeclared_inputs: Vec<ProjectRelativePathBuf>,
     },
     /// This action was executed via a remote executor.
     #[display(fmt = "remote")]
@@ -226,6 +226,25 @@ impl CommandExecutionPaths {
     }
 }
 
+/// A persistent worker that the local executor can send a command to instead of spawning it.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct WorkerSpec {
+    /// The command line that starts the worker. It is a prefix of the command's args: the rest
+    /// of the args is what gets sent to the worker.
+    pub exe: Vec<String>,
+    /// How many requests a single worker process accepts concurrently. Workers that don't
+    /// support multiplexing have a concurrency of 1, and we start several of them instead.
+    pub concurrency: usize,
+}
+
+/// Limits on the resources a command may use when it runs locally. They are only enforced where
+/// resource control is available: see `buck2.resource_control`.
+#[derive(Debug, Default, Clone, Copy, Dupe, PartialEq, Eq, Hash, Allocative)]
+pub struct ResourceLimits {
+    pub memory_bytes: Option<u64>,
+    pub cpus: Option<u32>,
+}
+
 /// The data contains the information about the command to be executed.
 pub struct CommandExecutionRequest {
     args: Vec<String>,
@@ -253,6 +272,15 @@ pub struct CommandExecutionRequest {
     force_full_hybrid_if_capable: bool,
     /// Whether to disable capturing performance counters for this execution.
     disable_miniperf: bool,
+    /// A persistent worker that can run this command when it runs locally.
+    worker: Option<WorkerSpec>,
+    resource_limits: ResourceLimits,
+    /// How much memory this command is expected to use when it runs locally, in bytes. Used to
+    /// avoid running more commands at once than fit in memory.
+    memory_estimate: Option<u64>,
+    /// Whether this command may access the network when it runs locally. If unset, the executor
+    /// decides.
+    allow_network: Option<bool>,
 }
 
 impl CommandExecutionRequest {
@@ -276,6 +304,10 @@ impl CommandExecutionRequest {
             allow_cache_upload: false,
             force_full_hybrid_if_capable: false,
             disable_miniperf: false,
+            worker: None,
+            resource_limits: ResourceLimits::default(),
+            memory_estimate: None,
+            allow_network: None,
         }
     }
 
@@ -403,6 +435,42 @@ impl CommandExecutionRequest {
     pub fn disable_miniperf(&self) -> bool {
         self.disable_miniperf
     }
+
+    pub fn with_worker(mut self, worker: Option<WorkerSpec>) -> Self {
+        self.worker = worker;
+        self
+    }
+
+    pub fn worker(&self) -> Option<&WorkerSpec> {
+        self.worker.as_ref()
+    }
+
+    pub fn with_resource_limits(mut self, resource_limits: ResourceLimits) -> Self {
+        self.resource_limits = resource_limits;
+        self
+    }
+
+    pub fn resource_limits(&self) -> &ResourceLimits {
+        &self.resource_limits
+    }
+
+    pub fn with_memory_estimate(mut self, memory_estimate: Option<u64>) -> Self {
+        self.memory_estimate = memory_estimate;
+        self
+    }
+
+    pub fn memory_estimate(&self) -> Option<u64> {
+        self.memory_estimate
+    }
+
+    pub fn with_allow_network(mut self, allow_network: Option<bool>) -> Self {
+        self.allow_network = allow_network;
+        self
+    }
+
+    pub fn allow_network(&self) -> Option<bool> {
+        self.allow_network
+    }
 }
 
 /// Is an output a file or a directory
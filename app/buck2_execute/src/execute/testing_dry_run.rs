@@ -76,6 +76,8 @@ impl PreparedCommandExecutor for DryRunExecutor {
             digest: ActionDigest::empty(digest_config.cas_digest_config()),
             command: Default::default(),
             env: Default::default(),
+            network_blocked: false,
+            undeclared_inputs: Vec::new(),
         };
 
         match request
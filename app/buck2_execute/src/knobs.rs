@@ -13,4 +13,7 @@ use dupe::Dupe;
 #[derive(Clone, Dupe, Default)]
 pub struct ExecutorGlobalKnobs {
     pub enable_miniperf: bool,
+    /// Run local actions that should not have network access with network access where it
+    /// can't be blocked, instead of failing them.
+    pub allow_unenforced_network_block: bool,
 }
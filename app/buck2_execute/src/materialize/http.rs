@@ -7,7 +7,31 @@
  * of this source tree.
  */
 
+//! Downloads for `download_file`.
+//!
+//! How files are downloaded is configured by the `[download]` section of the root buckconfig,
+//! which is read for each command:
+//!
+//! * `mirrors`: comma-separated list of `<prefix>=><mirror prefix>`. URLs that start with a prefix
+//!   are first downloaded from their mirrors, in order, and then from the original URL.
+//! * `mirrors_only`: don't fall back to the original URL of downloads that have mirrors, i.e. the
+//!   mirrors are rewrites.
+//! * `headers`: comma-separated list of `<host>=><name>: <value>`, headers to send with requests
+//!   to that host (e.g. for token-based auth).
+//! * `netrc`: absolute path to a netrc file, used for basic auth with hosts that don't have
+//!   headers. If it can't be read, downloads that would use it fail.
+//! * `cache_dir`: absolute path to a local cache of downloads, keyed by sha256. Downloads that
+//!   declare a sha256 are served from there when possible. This can be shared by multiple repos.
+//!
+//! Headers and credentials are only sent to the host of the URL being downloaded: redirects to
+//! other hosts are followed without them.
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::io::Read;
 use std::io::Write;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
 use std::time::Duration;
 
@@ -15,22 +39,35 @@ use allocative::Allocative;
 use anyhow::Context as _;
 use buck2_common::cas_digest::CasDigestConfig;
 use buck2_common::cas_digest::DigestAlgorithmKind;
+use buck2_common::cas_digest::Digester;
 use buck2_common::file_ops::FileDigest;
+use buck2_common::file_ops::FileDigestKind;
 use buck2_common::file_ops::TrackedFileDigest;
+use buck2_common::legacy_configs::LegacyBuckConfig;
 use buck2_core::fs::fs_util;
+use buck2_core::fs::paths::abs_norm_path::AbsNormPath;
+use buck2_core::fs::paths::abs_norm_path::AbsNormPathBuf;
+use buck2_core::fs::paths::file_name::FileName;
 use buck2_core::fs::project::ProjectRoot;
 use buck2_core::fs::project_rel_path::ProjectRelativePath;
 use buck2_core::is_open_source;
 use bytes::Bytes;
+use dice::UserComputationData;
 use digest::DynDigest;
 use dupe::Dupe;
 use futures::future::Future;
 use futures::stream::Stream;
 use futures::StreamExt;
+use reqwest::header::HeaderName;
+use reqwest::header::HeaderValue;
+use reqwest::header::LOCATION;
+use reqwest::redirect;
 use reqwest::Client;
+use reqwest::Method;
 use reqwest::RequestBuilder;
 use reqwest::Response;
 use reqwest::StatusCode;
+use reqwest::Url;
 use sha1::Digest;
 use sha1::Sha1;
 use sha2::Sha256;
@@ -38,6 +75,7 @@ use smallvec::SmallVec;
 use thiserror::Error;
 
 use crate::digest_config::DigestConfig;
+use crate::execute::blocking::BlockingExecutor;
 
 #[derive(Debug, Clone, Dupe, Allocative)]
 pub enum Checksum {
@@ -100,6 +138,12 @@ pub enum HttpError {
         #[source]
         source: reqwest::Error,
     },
+
+    #[error("Too many redirects when querying URL: {}", .url)]
+    TooManyRedirects { url: String },
+
+    #[error("Error reading `download.netrc` for URL: {}: {}", .url, .error)]
+    Netrc { url: String, error: String },
 }
 
 impl HttpError {
@@ -118,6 +162,7 @@ impl HttpError {
             }
             Self::HttpHeadersTransferError { source, .. }
             | Self::HttpTransferError { source, .. } => !source.is_connect(),
+            Self::TooManyRedirects { .. } | Self::Netrc { .. } => false,
         }
     }
 }
@@ -134,6 +179,227 @@ fn http_error_label(status: StatusCode) -> &'static str {
     "Unknown"
 }
 
+#[derive(Debug, Error)]
+enum DownloadConfigError {
+    #[error("Invalid `download.{0}` entry (expected `<from>=><to>`): `{1}`")]
+    InvalidEntry(&'static str, String),
+    #[error("Invalid header for `{0}` in `download.headers` (expected `<name>: <value>`): `{1}`")]
+    InvalidHeader(String, String),
+    #[error("`download.{0}` must be an absolute path, got `{1}`")]
+    RelativePath(&'static str, String),
+}
+
+/// The `[download]` section of the root buckconfig, see the module docs.
+#[derive(Debug, Default)]
+pub struct DownloadConfig {
+    mirrors: Vec<(String, String)>,
+    mirrors_only: bool,
+    headers: HashMap<String, Vec<(HeaderName, HeaderValue)>>,
+    netrc: Netrc,
+    /// The error reading the netrc file, if any, reported by the downloads that need it.
+    netrc_error: Option<String>,
+    cache_dir: Option<AbsNormPathBuf>,
+}
+
+pub trait HasDownloadConfig {
+    fn set_download_config(&mut self, config: Arc<DownloadConfig>);
+
+    /// Downloads use the default config if it wasn't set (e.g. in tests).
+    fn get_download_config(&self) -> Arc<DownloadConfig>;
+}
+
+impl HasDownloadConfig for UserComputationData {
+    fn set_download_config(&mut self, config: Arc<DownloadConfig>) {
+        self.data.set(config);
+    }
+
+    fn get_download_config(&self) -> Arc<DownloadConfig> {
+        self.data
+            .get::<Arc<DownloadConfig>>()
+            .map_or_else(|_| Arc::default(), |config| config.dupe())
+    }
+}
+
+impl DownloadConfig {
+    /// This reads the netrc file, so it should run on the IO executor.
+    pub fn from_config(config: &LegacyBuckConfig) -> anyhow::Result<Self> {
+        let mirrors = config
+            .parse_list::<String>("download", "mirrors")?
+            .unwrap_or_default()
+            .iter()
+            .map(|entry| parse_entry("mirrors", entry))
+            .collect::<anyhow::Result<_>>()?;
+
+        let mut headers: HashMap<_, Vec<_>> = HashMap::new();
+        for entry in config
+            .parse_list::<String>("download", "headers")?
+            .unwrap_or_default()
+        {
+            let (host, header) = parse_entry("headers", &entry)?;
+            let invalid = || DownloadConfigError::InvalidHeader(host.clone(), header.clone());
+            let (name, value) = header.split_once(':').with_context(invalid)?;
+            let name = HeaderName::try_from(name.trim()).with_context(invalid)?;
+            let mut value = HeaderValue::try_from(value.trim()).with_context(invalid)?;
+            value.set_sensitive(true);
+            headers.entry(host).or_default().push((name, value));
+        }
+
+        let (netrc, netrc_error) = match config.get("download", "netrc") {
+            Some(path) => match fs_util::read_to_string(&absolute_path("netrc", path)?) {
+                Ok(contents) => (Netrc::parse(&contents), None),
+                Err(e) => (Netrc::default(), Some(format!("{:#}", e))),
+            },
+            None => (Netrc::default(), None),
+        };
+
+        let cache_dir = config
+            .get("download", "cache_dir")
+            .map(|path| absolute_path("cache_dir", path))
+            .transpose()?;
+
+        Ok(Self {
+            mirrors,
+            mirrors_only: config
+                .parse::<bool>("download", "mirrors_only")?
+                .unwrap_or(false),
+            headers,
+            netrc,
+            netrc_error,
+            cache_dir,
+        })
+    }
+
+    /// The URLs to try, in order, to download a file declared with `urls`.
+    fn candidate_urls(&self, urls: &[Arc<str>]) -> Vec<String> {
+        let mut candidates = Vec::new();
+        for url in urls {
+            let mut mirrored = false;
+            for (prefix, mirror) in &self.mirrors {
+                if let Some(rest) = url.strip_prefix(prefix.as_str()) {
+                    candidates.push(format!("{}{}", mirror, rest));
+                    mirrored = true;
+                }
+            }
+            if !mirrored || !self.mirrors_only {
+                candidates.push(url.to_string());
+            }
+        }
+        let mut seen = HashSet::new();
+        candidates.retain(|url| seen.insert(url.clone()));
+        candidates
+    }
+
+    /// Add the configured credentials for the host of `url` to `req`.
+    fn authenticate(&self, req: RequestBuilder, url: &str) -> Result<RequestBuilder, HttpError> {
+        let host = match host(url) {
+            Some(host) => host,
+            None => return Ok(req),
+        };
+        if let Some(headers) = self.headers.get(&host) {
+            return Ok(headers.iter().fold(req, |req, (name, value)| {
+                req.header(name.clone(), value.clone())
+            }));
+        }
+        if let Some(error) = &self.netrc_error {
+            return Err(HttpError::Netrc {
+                url: url.to_owned(),
+                error: error.clone(),
+            });
+        }
+        Ok(match self.netrc.get(&host) {
+            Some((login, password)) => req.basic_auth(login, Some(password)),
+            None => req,
+        })
+    }
+
+    /// Where a download with this checksum is cached, if we cache it.
+    fn cache_path(&self, checksum: &Checksum) -> Option<AbsNormPathBuf> {
+        let sha256 = checksum.sha256()?;
+        // Don't let a malformed checksum escape the cache.
+        if !sha256.chars().all(|c| c.is_ascii_hexdigit()) {
+            return None;
+        }
+        Some(
+            self.cache_dir
+                .as_ref()?
+                .join(FileName::unchecked_new("sha256"))
+                .join(FileName::new(sha256).ok()?),
+        )
+    }
+}
+
+fn host(url: &str) -> Option<String> {
+    Some(Url::parse(url).ok()?.host_str()?.to_owned())
+}
+
+fn parse_entry(key: &'static str, entry: &str) -> anyhow::Result<(String, String)> {
+    match entry.split_once("=>") {
+        Some((from, to)) if !from.trim().is_empty() && !to.trim().is_empty() => {
+            Ok((from.trim().to_owned(), to.trim().to_owned()))
+        }
+        _ => Err(DownloadConfigError::InvalidEntry(key, entry.to_owned()).into()),
+    }
+}
+
+fn absolute_path(key: &'static str, path: &str) -> anyhow::Result<AbsNormPathBuf> {
+    AbsNormPathBuf::try_from(path.to_owned())
+        .with_context(|| DownloadConfigError::RelativePath(key, path.to_owned()))
+}
+
+/// The credentials in a netrc file, by host. `macdef` and `account` entries are not supported.
+#[derive(Debug, Default)]
+struct Netrc {
+    machines: HashMap<String, (String, String)>,
+    default: Option<(String, String)>,
+}
+
+impl Netrc {
+    fn parse(contents: &str) -> Self {
+        let mut netrc = Netrc::default();
+        // The machine we're reading credentials for, `None` for `default`.
+        let mut machine: Option<Option<String>> = None;
+        let mut login = String::new();
+        let mut password = String::new();
+
+        let mut tokens = contents.split_whitespace();
+        loop {
+            let token = tokens.next();
+            if matches!(token, None | Some("machine") | Some("default")) {
+                match machine.take() {
+                    Some(Some(host)) => {
+                        netrc.machines.insert(
+                            host,
+                            (std::mem::take(&mut login), std::mem::take(&mut password)),
+                        );
+                    }
+                    Some(None) => {
+                        netrc.default =
+                            Some((std::mem::take(&mut login), std::mem::take(&mut password)));
+                    }
+                    None => {}
+                }
+            }
+            match token {
+                None => break,
+                Some("machine") => machine = tokens.next().map(|host| Some(host.to_owned())),
+                Some("default") => machine = Some(None),
+                Some("login") => login = tokens.next().unwrap_or_default().to_owned(),
+                Some("password") => password = tokens.next().unwrap_or_default().to_owned(),
+                Some(_) => {}
+            }
+        }
+
+        netrc
+    }
+
+    fn get(&self, host: &str) -> Option<(&str, &str)> {
+        self.machines
+            .get(host)
+            .or(self.default.as_ref())
+            .map(|(login, password)| (login.as_str(), password.as_str()))
+    }
+}
+
 #[derive(Debug, Error)]
 enum HttpHeadError {
     #[error("Error performing a http_head request")]
@@ -173,8 +439,25 @@ impl AsHttpError for HttpDownloadError {
     }
 }
 
-pub fn http_client() -> anyhow::Result<Client> {
-    let mut builder = Client::builder();
+/// A client for downloads, configured by the `[download]` section of the root buckconfig.
+#[derive(Clone)]
+pub struct HttpClient {
+    client: Client,
+    config: Arc<DownloadConfig>,
+}
+
+impl HttpClient {
+    /// Whether a download with this checksum would be served from the local cache.
+    pub fn is_cached(&self, checksum: &Checksum) -> bool {
+        self.config
+            .cache_path(checksum)
+            .map_or(false, |path| path.exists())
+    }
+}
+
+pub fn http_client(config: Arc<DownloadConfig>) -> anyhow::Result<HttpClient> {
+    // Redirects are followed by `http_send`, which knows which hosts to send credentials to.
+    let mut builder = Client::builder().redirect(redirect::Policy::none());
 
     if !is_open_source() {
         // Buck v1 doesn't honor the `$HTTPS_PROXY` variables. That is useful because
@@ -185,7 +468,44 @@ pub fn http_client() -> anyhow::Result<Client> {
         builder = builder.no_proxy();
     }
 
-    builder.build().context("Error creating http client")
+    Ok(HttpClient {
+        client: builder.build().context("Error creating http client")?,
+        config,
+    })
+}
+
+/// Send a request to `url`, following redirects. The configured credentials for the host of `url`
+/// are not sent to other hosts it redirects to.
+async fn http_send(client: &HttpClient, method: Method, url: &str) -> Result<Response, HttpError> {
+    const MAX_REDIRECTS: usize = 10;
+
+    let original_host = host(url);
+    let mut current = url.to_owned();
+    for _ in 0..=MAX_REDIRECTS {
+        let mut req = client.client.request(method.clone(), &current);
+        if host(&current) == original_host {
+            req = client.config.authenticate(req, &current)?;
+        }
+
+        let response = http_dispatch(req, &current).await?;
+        if !response.status().is_redirection() {
+            return Ok(response);
+        }
+        let next = response
+            .headers()
+            .get(LOCATION)
+            .and_then(|location| location.to_str().ok())
+            .and_then(|location| response.url().join(location).ok());
+        match next {
+            Some(next) => current = next.to_string(),
+            // Not a redirect we can follow.
+            None => return http_error_status(response, &current).await,
+        }
+    }
+
+    Err(HttpError::TooManyRedirects {
+        url: url.to_owned(),
+    })
 }
 
 async fn http_dispatch(req: RequestBuilder, url: &str) -> Result<Response, HttpError> {
@@ -199,36 +519,68 @@ async fn http_dispatch(req: RequestBuilder, url: &str) -> Result<Response, HttpE
 
     let status = response.status();
 
-    if !status.is_success() {
-        let text = match response.text().await {
-            Ok(t) => t,
-            Err(e) => format!("Error decoding response text: {}", e),
-        };
-
-        return Err(HttpError::HttpErrorStatus {
-            status,
-            url: url.to_owned(),
-            text,
-        });
+    if !status.is_success() && !status.is_redirection() {
+        return http_error_status(response, url).await;
     }
 
     Ok(response)
 }
 
-pub async fn http_head(client: &Client, url: &str) -> anyhow::Result<Response> {
-    Ok(http_retry(|| async {
-        let response = http_dispatch(client.head(url), url).await?;
-        Result::<_, HttpHeadError>::Ok(response)
+async fn http_error_status<T>(response: Response, url: &str) -> Result<T, HttpError> {
+    let status = response.status();
+    let text = match response.text().await {
+        Ok(t) => t,
+        Err(e) => format!("Error decoding response text: {}", e),
+    };
+
+    Err(HttpError::HttpErrorStatus {
+        status,
+        url: url.to_owned(),
+        text,
     })
-    .await?)
+}
+
+/// Try each of the candidate URLs for `urls` in turn, and return the first success, or the last
+/// error.
+async fn with_fallback<F, Fut, T>(client: &HttpClient, urls: &[Arc<str>], f: F) -> anyhow::Result<T>
+where
+    F: Fn(String) -> Fut,
+    Fut: Future<Output = anyhow::Result<T>>,
+{
+    let mut candidates = client.config.candidate_urls(urls).into_iter().peekable();
+    while let Some(url) = candidates.next() {
+        match f(url.clone()).await {
+            Ok(res) => return Ok(res),
+            Err(e) => match candidates.peek() {
+                Some(next) => {
+                    tracing::warn!("Error downloading `{}`, trying `{}`: {:#}", url, next, e);
+                }
+                None => return Err(e),
+            },
+        }
+    }
+    // Only happens if `urls` is empty.
+    Err(anyhow::anyhow!("No URL to download from"))
+}
+
+pub async fn http_head(client: &HttpClient, urls: &[Arc<str>]) -> anyhow::Result<Response> {
+    with_fallback(client, urls, |url| async move {
+        Ok(http_retry(|| async {
+            let response = http_send(client, Method::HEAD, &url).await?;
+            Result::<_, HttpHeadError>::Ok(response)
+        })
+        .await?)
+    })
+    .await
 }
 
 pub async fn http_download(
-    client: &Client,
+    client: &HttpClient,
+    io_executor: &dyn BlockingExecutor,
     fs: &ProjectRoot,
     digest_config: DigestConfig,
     path: &ProjectRelativePath,
-    url: &str,
+    urls: &[Arc<str>],
     checksum: &Checksum,
     executable: bool,
 ) -> anyhow::Result<TrackedFileDigest> {
@@ -237,127 +589,271 @@ pub async fn http_download(
         fs_util::create_dir_all(fs.resolve(dir))?;
     }
 
-    Ok(http_retry(|| async {
-        let file = std::fs::OpenOptions::new()
+    let open_output = || {
+        std::fs::OpenOptions::new()
             .create(true)
             .write(true)
             .truncate(true)
-            .open(path.to_string())
+            .open(&abs_path)
             .with_context(|| format!("open({})", abs_path))
-            .map_err(HttpDownloadError::IoError)?;
+            .map_err(HttpDownloadError::IoError)
+    };
+
+    let abs_path = &abs_path;
+    let open_output = &open_output;
+    let cache_path = client.config.cache_path(checksum);
+
+    let cached = match &cache_path {
+        Some(cache_path) => {
+            io_executor
+                .execute_io_inline(|| {
+                    if !cache_path.exists() {
+                        return Ok(None);
+                    }
+                    let res: anyhow::Result<FileDigest> = try {
+                        copy_from_cache(
+                            cache_path,
+                            std::io::BufWriter::new(open_output()?),
+                            abs_path,
+                            digest_config.cas_digest_config(),
+                            checksum,
+                        )?
+                    };
+                    match res {
+                        Ok(digest) => Ok(Some(digest)),
+                        Err(e) => {
+                            tracing::warn!("Discarding cached download `{}`: {:#}", cache_path, e);
+                            if let Err(e) = fs_util::remove_file(cache_path) {
+                                tracing::warn!("Error removing `{}`: {:#}", cache_path, e);
+                            }
+                            Ok(None)
+                        }
+                    }
+                })
+                .await?
+        }
+        None => None,
+    };
+
+    let digest = match cached {
+        Some(digest) => digest,
+        None => {
+            let digest = with_fallback(client, urls, |url| async move {
+                Ok(http_retry(|| async {
+                    let file = open_output()?;
+
+                    let response = http_send(client, Method::GET, &url).await?;
+
+                    let mut received = 0;
+                    let stream = response.bytes_stream().map(|chunk| {
+                        let chunk = chunk.map_err(|source| HttpError::HttpTransferError {
+                            received,
+                            url: url.clone(),
+                            source,
+                        })?;
+                        received += chunk.len() as u64;
+                        Ok::<_, HttpDownloadError>(chunk)
+                    });
+                    let buf_writer = std::io::BufWriter::new(file);
+
+                    copy_and_hash(
+                        &url,
+                        abs_path,
+                        stream,
+                        buf_writer,
+                        digest_config.cas_digest_config(),
+                        checksum,
+                    )
+                    .await
+                })
+                .await?)
+            })
+            .await?;
+
+            if let Some(cache_path) = &cache_path {
+                io_executor
+                    .execute_io_inline(|| {
+                        if let Err(e) = insert_into_cache(abs_path, cache_path) {
+                            tracing::warn!("Error caching download at `{}`: {:#}", cache_path, e);
+                        }
+                        Ok(())
+                    })
+                    .await?;
+            }
 
-        let response = http_dispatch(client.get(url), url).await?;
+            digest
+        }
+    };
 
-        let stream = response.bytes_stream();
-        let buf_writer = std::io::BufWriter::new(file);
+    if executable {
+        fs.set_executable(path)?;
+    }
 
-        let digest = copy_and_hash(
-            url,
-            &abs_path,
-            stream,
-            buf_writer,
-            digest_config.cas_digest_config(),
-            checksum,
-        )
-        .await?;
+    Ok(TrackedFileDigest::new(
+        digest,
+        digest_config.cas_digest_config(),
+    ))
+}
 
-        if executable {
-            fs.set_executable(path)
-                .map_err(HttpDownloadError::IoError)?;
+/// Copy a cached download into `writer` while producing its digest and checksumming it.
+fn copy_from_cache(
+    cache_path: &AbsNormPath,
+    mut writer: impl Write,
+    abs_path: &AbsNormPath,
+    digest_config: CasDigestConfig,
+    checksum: &Checksum,
+) -> Result<FileDigest, HttpDownloadError> {
+    let mut file = fs_util::open_file(cache_path).map_err(HttpDownloadError::IoError)?;
+    let mut hasher = ChecksumHasher::new(digest_config, checksum);
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let n = file
+            .read(&mut buf)
+            .with_context(|| format!("read({})", cache_path))
+            .map_err(HttpDownloadError::IoError)?;
+        if n == 0 {
+            break;
         }
+        writer
+            .write_all(&buf[..n])
+            .with_context(|| format!("write({})", abs_path))
+            .map_err(HttpDownloadError::IoError)?;
+        hasher.update(&buf[..n]);
+    }
+    writer
+        .flush()
+        .with_context(|| format!("flush({})", abs_path))
+        .map_err(HttpDownloadError::IoError)?;
 
-        Result::<_, HttpDownloadError>::Ok(TrackedFileDigest::new(
-            digest,
-            digest_config.cas_digest_config(),
-        ))
-    })
-    .await?)
+    hasher.finish(&cache_path.to_string())
+}
+
+/// Copy a download into the cache. The cache may be shared with other daemons, so entries are
+/// written to a temporary file first and then renamed into place.
+fn insert_into_cache(path: &AbsNormPath, cache_path: &AbsNormPath) -> anyhow::Result<()> {
+    let dir = cache_path.parent().context("Cache path has no parent")?;
+    fs_util::create_dir_all(dir)?;
+    let name = cache_path
+        .as_path()
+        .file_name()
+        .context("Cache path has no file name")?;
+    // The same file can be inserted concurrently by several downloads of this daemon, as well as
+    // by other daemons sharing the cache.
+    static NEXT_TMP: AtomicU64 = AtomicU64::new(0);
+    let tmp = dir.join(FileName::new(&format!(
+        ".{}.{}.{}.tmp",
+        name.to_string_lossy(),
+        std::process::id(),
+        NEXT_TMP.fetch_add(1, Ordering::Relaxed)
+    ))?);
+    fs_util::copy(path, &tmp)?;
+    fs_util::rename(&tmp, cache_path)?;
+    Ok(())
 }
 
 /// Copy a stream into a writer while producing its digest and checksumming it.
 async fn copy_and_hash(
     url: &str,
     abs_path: &(impl std::fmt::Display + ?Sized),
-    mut stream: impl Stream<Item = Result<Bytes, reqwest::Error>> + Unpin,
+    mut stream: impl Stream<Item = Result<Bytes, HttpDownloadError>> + Unpin,
     mut writer: impl Write,
     digest_config: CasDigestConfig,
     checksum: &Checksum,
 ) -> Result<FileDigest, HttpDownloadError> {
-    let mut digester = FileDigest::digester(digest_config);
-
-    // For each checksum entry we have, we're going to add a validator. We might have to create
-    // a new hasher, or reuse the `FileDigest::digester` if it matches.
-
-    enum Validator {
-        PrimaryDigest,
-        ExtraDigest(Box<dyn DynDigest + Send>),
-    }
-
-    let mut validators = SmallVec::<[_; 2]>::new();
-
-    if let Some(sha1) = checksum.sha1() {
-        let validator = if digester.algorithm() == DigestAlgorithmKind::Sha1 {
-            Validator::PrimaryDigest
-        } else {
-            Validator::ExtraDigest(Box::new(Sha1::new()) as _)
-        };
-
-        validators.push((validator, sha1, "sha1"));
-    }
-
-    if let Some(sha256) = checksum.sha256() {
-        let validator = if digester.algorithm() == DigestAlgorithmKind::Sha256 {
-            Validator::PrimaryDigest
-        } else {
-            Validator::ExtraDigest(Box::new(Sha256::new()) as _)
-        };
-
-        validators.push((validator, sha256, "sha256"));
-    }
+    let mut hasher = ChecksumHasher::new(digest_config, checksum);
 
     while let Some(chunk) = stream.next().await {
-        let chunk = chunk.map_err(|source| HttpError::HttpTransferError {
-            received: digester.bytes_read(),
-            url: url.to_owned(),
-            source,
-        })?;
+        let chunk = chunk?;
         writer
             .write(&chunk)
             .with_context(|| format!("write({})", abs_path))
             .map_err(HttpDownloadError::IoError)?;
 
-        digester.update(&chunk);
-        for (validator, _expected, _kind) in validators.iter_mut() {
-            if let Validator::ExtraDigest(hasher) = validator {
-                hasher.update(&chunk);
-            }
-        }
+        hasher.update(&chunk);
     }
     writer
         .flush()
         .with_context(|| format!("flush({})", abs_path))
         .map_err(HttpDownloadError::IoError)?;
 
-    let digest = digester.finalize();
+    hasher.finish(url)
+}
+
+/// Produces the digest of a download, and checks it against its checksum.
+struct ChecksumHasher<'a> {
+    digester: Digester<FileDigestKind>,
+    validators: SmallVec<[(Validator, &'a str, &'static str); 2]>,
+}
 
-    // Validate
-    for (validator, expected, kind) in validators {
-        let obtained = match validator {
-            Validator::PrimaryDigest => digest.raw_digest().to_string(),
-            Validator::ExtraDigest(hasher) => hex::encode(hasher.finalize()),
-        };
+// For each checksum entry we have, we're going to add a validator. We might have to create
+// a new hasher, or reuse the `FileDigest::digester` if it matches.
+enum Validator {
+    PrimaryDigest,
+    ExtraDigest(Box<dyn DynDigest + Send>),
+}
+
+impl<'a> ChecksumHasher<'a> {
+    fn new(digest_config: CasDigestConfig, checksum: &'a Checksum) -> Self {
+        let digester = FileDigest::digester(digest_config);
+        let mut validators = SmallVec::new();
+
+        if let Some(sha1) = checksum.sha1() {
+            let validator = if digester.algorithm() == DigestAlgorithmKind::Sha1 {
+                Validator::PrimaryDigest
+            } else {
+                Validator::ExtraDigest(Box::new(Sha1::new()) as _)
+            };
+
+            validators.push((validator, sha1, "sha1"));
+        }
+
+        if let Some(sha256) = checksum.sha256() {
+            let validator = if digester.algorithm() == DigestAlgorithmKind::Sha256 {
+                Validator::PrimaryDigest
+            } else {
+                Validator::ExtraDigest(Box::new(Sha256::new()) as _)
+            };
 
-        if expected != obtained {
-            return Err(HttpDownloadError::InvalidChecksum(
-                kind,
-                expected.to_owned(),
-                obtained,
-                url.to_owned(),
-            ));
+            validators.push((validator, sha256, "sha256"));
+        }
+
+        Self {
+            digester,
+            validators,
+        }
+    }
+
+    fn update(&mut self, chunk: &[u8]) {
+        self.digester.update(chunk);
+        for (validator, _expected, _kind) in self.validators.iter_mut() {
+            if let Validator::ExtraDigest(hasher) = validator {
+                hasher.update(chunk);
+            }
         }
     }
 
-    Ok(digest)
+    fn finish(self, url: &str) -> Result<FileDigest, HttpDownloadError> {
+        let digest = self.digester.finalize();
+
+        // Validate
+        for (validator, expected, kind) in self.validators {
+            let obtained = match validator {
+                Validator::PrimaryDigest => digest.raw_digest().to_string(),
+                Validator::ExtraDigest(hasher) => hex::encode(hasher.finalize()),
+            };
+
+            if expected != obtained {
+                return Err(HttpDownloadError::InvalidChecksum(
+                    kind,
+                    expected.to_owned(),
+                    obtained,
+                    url.to_owned(),
+                ));
+            }
+        }
+
+        Ok(digest)
+    }
 }
 
 async fn http_retry<Exec, F, T, E>(exec: Exec) -> Result<T, E>
@@ -459,6 +955,114 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn test_candidate_urls() -> anyhow::Result<()> {
+        let urls = |urls: &[&str]| urls.iter().map(|u| Arc::from(*u)).collect::<Vec<_>>();
+
+        let mut config = DownloadConfig {
+            mirrors: vec![
+                parse_entry("mirrors", "https://github.com/ => https://proxy/github/")?,
+                parse_entry("mirrors", "https://github.com/=>https://backup/github/")?,
+            ],
+            ..Default::default()
+        };
+        assert_eq!(
+            vec![
+                "https://proxy/github/a.tgz",
+                "https://backup/github/a.tgz",
+                "https://github.com/a.tgz",
+                "https://example.com/a.tgz",
+            ],
+            config.candidate_urls(&urls(&[
+                "https://github.com/a.tgz",
+                "https://example.com/a.tgz"
+            ]))
+        );
+
+        config.mirrors_only = true;
+        assert_eq!(
+            vec!["https://proxy/github/a.tgz", "https://backup/github/a.tgz"],
+            config.candidate_urls(&urls(&["https://github.com/a.tgz"]))
+        );
+
+        assert!(parse_entry("mirrors", "https://github.com/").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_netrc() {
+        let netrc = Netrc::parse(
+            "machine example.com login alice password secret\n\
+             machine other.com\n  login bob\n  password hunter2\n\
+             default login anonymous password guest\n",
+        );
+        assert_eq!(Some(("alice", "secret")), netrc.get("example.com"));
+        assert_eq!(Some(("bob", "hunter2")), netrc.get("other.com"));
+        assert_eq!(Some(("anonymous", "guest")), netrc.get("unknown.com"));
+
+        assert_eq!(
+            None,
+            Netrc::parse("machine example.com login a password b").get("x.com")
+        );
+    }
+
+    #[test]
+    fn test_authenticate_netrc_error() -> anyhow::Result<()> {
+        let config = DownloadConfig {
+            headers: HashMap::from([(
+                "example.com".to_owned(),
+                vec![(
+                    HeaderName::from_static("x-token"),
+                    HeaderValue::from_static("secret"),
+                )],
+            )]),
+            netrc_error: Some("No such file or directory".to_owned()),
+            ..Default::default()
+        };
+        let client = Client::new();
+
+        // Hosts with headers don't need the netrc file.
+        let url = "https://example.com/a.tgz";
+        let req = config.authenticate(client.get(url), url)?.build()?;
+        assert_eq!("secret", req.headers()["x-token"]);
+
+        let url = "https://other.com/a.tgz";
+        assert_matches!(
+            config.authenticate(client.get(url), url),
+            Err(HttpError::Netrc { .. })
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_cache_path() -> anyhow::Result<()> {
+        let config = DownloadConfig {
+            cache_dir: Some(absolute_path(
+                "cache_dir",
+                if cfg!(windows) { "C:\\cache" } else { "/cache" },
+            )?),
+            ..Default::default()
+        };
+        assert!(
+            config
+                .cache_path(&Checksum::Sha256(Arc::from("abc123")))
+                .unwrap()
+                .as_path()
+                .ends_with("sha256/abc123")
+        );
+        assert_eq!(
+            None,
+            config.cache_path(&Checksum::Sha1(Arc::from("abc123")))
+        );
+        assert_eq!(
+            None,
+            config.cache_path(&Checksum::Sha256(Arc::from("../x")))
+        );
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_copy_and_hash_invalid_secondary_hash() -> anyhow::Result<()> {
         assert_matches!(
@@ -38,6 +38,7 @@ use crate::execute::action_digest::TrackedActionDigest;
 #[cfg(any(fbcode_build, cargo_internal_build))]
 use crate::materialize::eden_api::EdenBuckOut;
 use crate::materialize::http::Checksum;
+use crate::materialize::http::DownloadConfig;
 
 // Add a stub EdenBuckOut for when we don't have Eden output enabled
 #[cfg(not(any(fbcode_build, cargo_internal_build)))]
@@ -246,6 +247,16 @@ pub trait Materializer: Allocative + Send + Sync + 'static {
         file_paths: Vec<ProjectRelativePathBuf>,
     ) -> anyhow::Result<Vec<Result<ProjectRelativePathBuf, ArtifactNotMaterializedReason>>>;
 
+    /// For each of `paths`, whether it is, or is in, an artifact that was declared to the
+    /// materializer. Returns `None` if the materializer doesn't keep track of the artifacts it
+    /// materialized.
+    async fn has_declared_artifacts(
+        &self,
+        _paths: Vec<ProjectRelativePathBuf>,
+    ) -> anyhow::Result<Option<Vec<bool>>> {
+        Ok(None)
+    }
+
     /// Expose Eden based buck-out if the materializer is Eden
     /// Return None if not based on Eden.
     fn eden_buck_out(&self) -> Option<&EdenBuckOut> {
@@ -500,10 +511,10 @@ impl CasDownloadInfo {
 
 /// Information about a CAS download we might require when an artifact is not materialized.
 #[derive(Debug, Display)]
-#[display(fmt = "{} declared by {}", "self.url", "self.owner")]
+#[display(fmt = "{} declared by {}", "self.urls[0]", "self.owner")]
 pub struct HttpDownloadInfo {
-    /// URL to download the file from.
-    pub url: Arc<str>,
+    /// URLs to download the file from, in order of preference. Never empty.
+    pub urls: Arc<[Arc<str>]>,
 
     /// Size, whether the file is executable. Also contains a digest, which is a bit of a shame
     /// since it's duplicative of checksum.
@@ -514,6 +525,9 @@ pub struct HttpDownloadInfo {
 
     /// Target that declared the action.
     pub owner: BaseDeferredKeyDyn,
+
+    /// How to download the file, from the command that declared it.
+    pub config: Arc<DownloadConfig>,
 }
 
 #[derive(Debug, Error)]
@@ -584,12 +598,15 @@ pub enum MaterializationMethod {
     DeferredSkipFinalArtifacts,
     /// Let Eden delegate materialzation
     Eden,
+    /// Like `DeferredSkipFinalArtifacts`, but RE outputs that are not materialized can be read
+    /// anyway, and are fetched when they are, using FUSE (Linux only)
+    Fuse,
 }
 
 #[derive(Debug, Error)]
 pub enum MaterializationMethodError {
     #[error(
-        "Invalid value for buckconfig `[buck2] materializations`. Got `{0}`. Expected one of `all`, `deferred`, `deferred_skip_final_artifacts` or `eden`."
+        "Invalid value for buckconfig `[buck2] materializations`. Got `{0}`. Expected one of `all`, `deferred`, `deferred_skip_final_artifacts`, `eden` or `fuse`."
     )]
     InvalidValueForConfig(String),
 }
@@ -609,6 +626,7 @@ impl MaterializationMethod {
                 Ok(MaterializationMethod::DeferredSkipFinalArtifacts)
             }
             Some("eden") => Ok(MaterializationMethod::Eden),
+            Some("fuse") => Ok(MaterializationMethod::Fuse),
             Some(v) => Err(MaterializationMethodError::InvalidValueForConfig(v.to_owned()).into()),
         }
     }
@@ -644,8 +662,14 @@ pub trait DeferredMaterializerExtensions: Send + Sync {
     >;
 
     /// Obtain a list of files that don't match their in-memory representation. This may not catch
-    /// all discrepancies.
-    fn fsck(&self) -> anyhow::Result<BoxStream<'static, (ProjectRelativePathBuf, anyhow::Error)>>;
+    /// all discrepancies, unless `check_digests` is set. With `repair`, the files that don't match
+    /// are deleted so that they get rebuilt, or re-materialized if the running daemon uses them
+    /// and they were downloaded or written.
+    fn fsck(
+        &self,
+        repair: bool,
+        check_digests: bool,
+    ) -> anyhow::Result<BoxStream<'static, (ProjectRelativePathBuf, anyhow::Error)>>;
 
     async fn refresh_ttls(&self, min_ttl: i64) -> anyhow::Result<()>;
 
@@ -660,6 +684,11 @@ pub trait DeferredMaterializerExtensions: Send + Sync {
 
     async fn test_iter(&self, count: usize) -> anyhow::Result<String>;
 
+    /// List the artifacts that GC would evict if it ran now, least recently accessed first.
+    async fn gc_preview(
+        &self,
+    ) -> anyhow::Result<Vec<(ProjectRelativePathBuf, Box<dyn DeferredMaterializerEntry>)>>;
+
     fn queue_size(&self) -> usize;
 
     /// Create a new DeferredMaterializerSubscription.
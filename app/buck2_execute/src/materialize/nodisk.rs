@@ -111,4 +111,12 @@ impl Materializer for NoDiskMaterializer {
     ) -> anyhow::Result<Vec<Result<ProjectRelativePathBuf, ArtifactNotMaterializedReason>>> {
         Ok(paths.into_map(Ok))
     }
+
+    async fn has_declared_artifacts(
+        &self,
+        paths: Vec<ProjectRelativePathBuf>,
+    ) -> anyhow::Result<Option<Vec<bool>>> {
+        // Nothing is written to disk, so none of what's there comes from this materializer.
+        Ok(Some(vec![false; paths.len()]))
+    }
 }
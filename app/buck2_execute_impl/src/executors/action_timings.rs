@@ -0,0 +1,377 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+//! How long actions took on the executor that won the last time they were raced, which lets the
+//! adaptive hybrid executor run them on that executor alone instead of racing them every time.
+//!
+//! Actions are keyed by their owner, category and identifier (like on RE), since actions that
+//! share a category and identifier across targets can take very different times. Placed actions
+//! are raced again once in a while, so that we notice when the other executor became faster.
+//!
+//! Timings are kept in memory for the lifetime of the daemon, and optionally persisted to a
+//! sqlite db so that they survive daemon restarts. Writes to the db are batched, and timings that
+//! weren't updated for a while are dropped when the db is opened.
+
+use std::collections::HashMap;
+use std::time::Duration;
+use std::time::SystemTime;
+
+use allocative::Allocative;
+use buck2_common::sqlite::open_versioned_sqlite_db;
+use buck2_common::sqlite::KeyValueSqliteTable;
+use buck2_core::fs::paths::abs_norm_path::AbsNormPathBuf;
+use dupe::Dupe;
+use parking_lot::Mutex;
+use serde::Deserialize;
+use serde::Serialize;
+
+/// Hand-maintained schema version for the action timings sqlite db. PLEASE bump this version if
+/// you are making a breaking change to `ActionTiming`.
+const DB_SCHEMA_VERSION: u64 = 3;
+
+const TABLE: &str = "action_timings";
+
+/// How many updates to buffer before writing them to the db.
+const DB_BATCH_SIZE: usize = 100;
+
+/// Timings that weren't updated for this long are dropped when the db is opened.
+const MAX_AGE: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// If running an action on the executor it was placed on takes this many times longer than it
+/// did when that executor won the race, the timing is forgotten so that the action is raced
+/// again.
+const REGRESSION_FACTOR: u32 = 2;
+
+/// Actions that were last raced this long ago are raced again.
+const RACE_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Where the adaptive hybrid executor should run an action.
+#[derive(Debug, Clone, Copy, Dupe, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HybridPlacement {
+    Local,
+    Remote,
+    /// We don't know (or no longer know) which executor is faster, so race them.
+    Race,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+struct ActionTiming {
+    /// Never `Race`.
+    placement: HybridPlacement,
+    duration_ms: u64,
+    /// When this was last updated, in seconds since the epoch.
+    updated_at_secs: u64,
+    /// When the action was last raced, in seconds since the epoch.
+    raced_at_secs: u64,
+}
+
+/// The timings of actions run by the adaptive hybrid executor, by action key (see
+/// `CommandExecutionTarget::re_action_key`).
+#[derive(Default, Allocative)]
+pub struct ActionTimings {
+    #[allocative(skip)]
+    timings: Mutex<HashMap<String, ActionTiming>>,
+    #[allocative(skip)]
+    db: Option<ActionTimingsSqliteDb>,
+    /// Updates that weren't written to the db yet: `None` for timings that were forgotten.
+    #[allocative(skip)]
+    pending: Mutex<HashMap<String, Option<ActionTiming>>>,
+}
+
+impl ActionTimings {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Load the timings persisted in `action_timings_dir`, and persist new ones there. If the db
+    /// can't be read or was created with a different set of versions, it is deleted and an empty
+    /// one is created in its place.
+    pub fn initialize(
+        action_timings_dir: AbsNormPathBuf,
+        versions: HashMap<String, String>,
+    ) -> anyhow::Result<Self> {
+        let db = ActionTimingsSqliteDb::initialize(action_timings_dir, versions)?;
+        let mut timings = db.read_all()?;
+
+        let oldest = now_secs().saturating_sub(MAX_AGE.as_secs());
+        let expired: Vec<_> = timings
+            .iter()
+            .filter(|(_, t)| t.updated_at_secs < oldest)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in &expired {
+            timings.remove(key);
+        }
+        db.write(expired.into_iter().map(|key| (key, None)).collect())?;
+
+        Ok(Self {
+            timings: Mutex::new(timings),
+            db: Some(db),
+            pending: Default::default(),
+        })
+    }
+
+    pub fn placement(&self, action_key: &str) -> HybridPlacement {
+        let oldest = now_secs().saturating_sub(RACE_INTERVAL.as_secs());
+        match self.timings.lock().get(action_key) {
+            Some(t) if t.raced_at_secs >= oldest => t.placement,
+            _ => HybridPlacement::Race,
+        }
+    }
+
+    /// Record how long an action took on the executor it ran on. `raced` indicates whether the
+    /// other executor was competing for it, in which case that executor lost.
+    pub fn record(
+        &self,
+        action_key: &str,
+        placement: HybridPlacement,
+        duration: Duration,
+        raced: bool,
+    ) {
+        let duration_ms = duration.as_millis() as u64;
+        let updated_at_secs = now_secs();
+
+        let update = {
+            let mut timings = self.timings.lock();
+            let previous = timings.get(action_key).copied();
+            if raced {
+                let timing = ActionTiming {
+                    placement,
+                    duration_ms,
+                    updated_at_secs,
+                    raced_at_secs: updated_at_secs,
+                };
+                timings.insert(action_key.to_owned(), timing);
+                Some(timing)
+            } else {
+                match previous {
+                    // We only learn from races, since that's the only time we see both executors
+                    // compete.
+                    None => return,
+                    Some(previous) if previous.placement != placement => return,
+                    Some(previous)
+                        if duration_ms > previous.duration_ms * REGRESSION_FACTOR as u64 =>
+                    {
+                        timings.remove(action_key);
+                        None
+                    }
+                    Some(previous) => {
+                        // Only rewrite unchanged timings once in a while, to keep them from
+                        // expiring.
+                        if duration_ms == previous.duration_ms
+                            && updated_at_secs < previous.updated_at_secs + MAX_AGE.as_secs() / 2
+                        {
+                            return;
+                        }
+                        let timing = ActionTiming {
+                            placement,
+                            duration_ms,
+                            updated_at_secs,
+                            raced_at_secs: previous.raced_at_secs,
+                        };
+                        timings.insert(action_key.to_owned(), timing);
+                        Some(timing)
+                    }
+                }
+            }
+        };
+
+        if self.db.is_some() {
+            let batch = {
+                let mut pending = self.pending.lock();
+                pending.insert(action_key.to_owned(), update);
+                if pending.len() < DB_BATCH_SIZE {
+                    return;
+                }
+                std::mem::take(&mut *pending)
+            };
+            self.write(batch);
+        }
+    }
+
+    fn write(&self, batch: HashMap<String, Option<ActionTiming>>) {
+        if let Some(db) = &self.db {
+            if let Err(e) = db.write(batch) {
+                tracing::warn!("Error persisting action timings: {:#}", e);
+            }
+        }
+    }
+}
+
+impl Drop for ActionTimings {
+    fn drop(&mut self) {
+        let batch = std::mem::take(&mut *self.pending.lock());
+        self.write(batch);
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// DB that holds the timings of actions, keyed by their action key.
+struct ActionTimingsSqliteDb {
+    timings_table: KeyValueSqliteTable,
+}
+
+impl ActionTimingsSqliteDb {
+    fn initialize(
+        action_timings_dir: AbsNormPathBuf,
+        mut versions: HashMap<String, String>,
+    ) -> anyhow::Result<Self> {
+        versions.insert("schema_version".to_owned(), DB_SCHEMA_VERSION.to_string());
+        let connection = open_versioned_sqlite_db(&action_timings_dir, versions, &[TABLE])?;
+        Ok(Self {
+            timings_table: KeyValueSqliteTable::new(TABLE.to_owned(), connection),
+        })
+    }
+
+    /// Timings that can't be parsed are skipped, they'll be learned again.
+    fn read_all(&self) -> anyhow::Result<HashMap<String, ActionTiming>> {
+        Ok(self
+            .timings_table
+            .read_all()?
+            .into_iter()
+            .filter_map(|(key, timing)| Some((key, serde_json::from_str(&timing).ok()?)))
+            .collect())
+    }
+
+    /// Insert the `Some` timings and delete the `None` ones.
+    fn write(&self, batch: HashMap<String, Option<ActionTiming>>) -> anyhow::Result<()> {
+        let mut inserted = HashMap::new();
+        let mut deleted = Vec::new();
+        for (key, timing) in batch {
+            match timing {
+                Some(timing) => {
+                    inserted.insert(key, serde_json::to_string(&timing)?);
+                }
+                None => deleted.push(key),
+            }
+        }
+        if !inserted.is_empty() {
+            self.timings_table.insert_all(inserted)?;
+        }
+        if !deleted.is_empty() {
+            self.timings_table.delete_many(&deleted)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use buck2_core::fs::project::ProjectRootTemp;
+    use buck2_core::fs::project_rel_path::ProjectRelativePath;
+
+    use super::*;
+
+    #[test]
+    fn test_action_timings() {
+        let timings = ActionTimings::new();
+        let secs = Duration::from_secs;
+
+        // Nothing is learned outside of races.
+        timings.record("compile", HybridPlacement::Local, secs(1), false);
+        assert_eq!(HybridPlacement::Race, timings.placement("compile"));
+
+        timings.record("compile", HybridPlacement::Remote, secs(10), true);
+        assert_eq!(HybridPlacement::Remote, timings.placement("compile"));
+
+        // Runs on the other executor (e.g. fallbacks) don't change the placement.
+        timings.record("compile", HybridPlacement::Local, secs(1), false);
+        assert_eq!(HybridPlacement::Remote, timings.placement("compile"));
+
+        timings.record("compile", HybridPlacement::Remote, secs(15), false);
+        assert_eq!(HybridPlacement::Remote, timings.placement("compile"));
+
+        // Regressions make us race again.
+        timings.record("compile", HybridPlacement::Remote, secs(31), false);
+        assert_eq!(HybridPlacement::Race, timings.placement("compile"));
+
+        // So does time.
+        timings.record("link", HybridPlacement::Local, secs(1), true);
+        assert_eq!(HybridPlacement::Local, timings.placement("link"));
+        timings
+            .timings
+            .lock()
+            .get_mut("link")
+            .unwrap()
+            .raced_at_secs -= RACE_INTERVAL.as_secs() + 1;
+        assert_eq!(HybridPlacement::Race, timings.placement("link"));
+    }
+
+    #[test]
+    fn test_action_timings_persisted() -> anyhow::Result<()> {
+        let fs = ProjectRootTemp::new()?;
+        let dir = fs
+            .path()
+            .resolve(ProjectRelativePath::unchecked_new("action_timings"));
+        let versions = HashMap::from([("hostname".to_owned(), "a".to_owned())]);
+
+        let timings = ActionTimings::initialize(dir.clone(), versions.clone())?;
+        timings.record(
+            "write",
+            HybridPlacement::Local,
+            Duration::from_millis(5),
+            true,
+        );
+        drop(timings);
+
+        // Reopening with the same versions keeps the timings.
+        let timings = ActionTimings::initialize(dir.clone(), versions)?;
+        assert_eq!(HybridPlacement::Local, timings.placement("write"));
+
+        // Timings are written in batches.
+        let db = timings.db.as_ref().unwrap();
+        for i in 0..DB_BATCH_SIZE - 1 {
+            timings.record(
+                &format!("compile {}", i),
+                HybridPlacement::Remote,
+                Duration::from_millis(5),
+                true,
+            );
+        }
+        assert_eq!(1, db.read_all()?.len());
+        timings.record(
+            "link",
+            HybridPlacement::Remote,
+            Duration::from_millis(5),
+            true,
+        );
+        assert_eq!(DB_BATCH_SIZE + 1, db.read_all()?.len());
+
+        // Old timings are dropped.
+        db.write(HashMap::from([(
+            "write".to_owned(),
+            Some(ActionTiming {
+                placement: HybridPlacement::Local,
+                duration_ms: 5,
+                updated_at_secs: 0,
+                raced_at_secs: 0,
+            }),
+        )]))?;
+        drop(timings);
+        let timings = ActionTimings::initialize(dir.clone(), versions.clone())?;
+        assert_eq!(HybridPlacement::Race, timings.placement("write"));
+        assert_eq!(HybridPlacement::Remote, timings.placement("link"));
+        drop(timings);
+
+        // Reopening with different versions discards them.
+        let timings = ActionTimings::initialize(
+            dir,
+            HashMap::from([("hostname".to_owned(), "b".to_owned())]),
+        )?;
+        assert_eq!(HybridPlacement::Race, timings.placement("write"));
+
+        Ok(())
+    }
+}
@@ -35,6 +35,8 @@ use futures::future::Future;
 use futures::FutureExt;
 use host_sharing::HostSharingRequirements;
 
+use crate::executors::action_timings::ActionTimings;
+use crate::executors::action_timings::HybridPlacement;
 use crate::executors::local::LocalExecutor;
 use crate::executors::re::ReExecutor;
 use crate::low_pass_filter::LowPassFilter;
@@ -51,6 +53,8 @@ pub struct HybridExecutor {
     pub level: HybridExecutionLevel,
     pub executor_preference: ExecutorPreference,
     pub low_pass_filter: Arc<LowPassFilter>,
+    /// Used to place actions when the level is `Adaptive`.
+    pub action_timings: Option<Arc<ActionTimings>>,
 }
 
 impl HybridExecutor {
@@ -95,6 +99,26 @@ impl PreparedCommandExecutor for HybridExecutor {
             Err(e) => return manager.error("prepare_hybrid", e),
         };
 
+        // When adaptive, actions that have no preference of their own run on whichever executor
+        // won the last time they were raced, and only fall back to the other one.
+        let adaptive_timings = match (self.level, &self.action_timings) {
+            (HybridExecutionLevel::Adaptive { .. }, Some(timings))
+                if matches!(executor_preference, ExecutorPreference::Default) =>
+            {
+                Some((timings, command.target.re_action_key()))
+            }
+            _ => None,
+        };
+
+        let executor_preference = match &adaptive_timings {
+            Some((timings, action_key)) => match timings.placement(action_key) {
+                HybridPlacement::Local => ExecutorPreference::LocalPreferred,
+                HybridPlacement::Remote => ExecutorPreference::RemotePreferred,
+                HybridPlacement::Race => executor_preference,
+            },
+            None => executor_preference,
+        };
+
         // inspect that and construct our own result. Especially in the case of secondary fallback,
         // the current approach effectively loses the data from the primary result (for example, we
         // should have stage events the reflect the full duration not just the fallback part).
@@ -149,8 +173,8 @@ impl PreparedCommandExecutor for HybridExecutor {
         }
 
         let jobs = HybridExecutorJobs {
-            local: local_result.map(|r| (r, JobPriority(1))),
-            remote: remote_result.map(|r| (r, JobPriority(0))),
+            local: local_result.map(|r| (r, LOCAL_PRIORITY)),
+            remote: remote_result.map(|r| (r, REMOTE_PRIORITY)),
             executor_preference,
         };
 
@@ -163,6 +187,9 @@ impl PreparedCommandExecutor for HybridExecutor {
                 fallback_on_failure,
                 low_pass_filter,
             } => (false, false, fallback_on_failure, low_pass_filter),
+            HybridExecutionLevel::Adaptive {
+                fallback_on_failure,
+            } => (false, false, fallback_on_failure, false),
         };
 
         if is_limited {
@@ -201,6 +228,9 @@ impl PreparedCommandExecutor for HybridExecutor {
 
         let fallback_only = fallback_only && !command.request.force_full_hybrid_if_capable();
 
+        // Only races tell us which executor is faster.
+        let raced = !(executor_preference.prefers_local() || executor_preference.prefers_remote());
+
         let ((mut first_res, first_priority), second) =
             if executor_preference.prefers_local() || executor_preference.prefers_remote() {
                 // Don't race in this scenario, since this is typically used for
@@ -241,6 +271,22 @@ impl PreparedCommandExecutor for HybridExecutor {
                 jobs.execute_concurrent().await
             };
 
+        // Learn from whichever executor succeeds, including the second one when the first one
+        // failed.
+        let record_timing = |res: &CommandExecutionResult, priority: JobPriority| {
+            if let Some((timings, action_key)) = &adaptive_timings {
+                if matches!(res.report.status, CommandExecutionStatus::Success { .. }) {
+                    let placement = if priority == LOCAL_PRIORITY {
+                        HybridPlacement::Local
+                    } else {
+                        HybridPlacement::Remote
+                    };
+                    timings.record(action_key, placement, res.report.timing.wall_time, raced);
+                }
+            }
+        };
+        record_timing(&first_res, first_priority);
+
         let mut res = if is_retryable_status(&first_res) {
             // If the first result had made a claim, then cancel it now to let the other result
             // proceed.
@@ -254,6 +300,7 @@ impl PreparedCommandExecutor for HybridExecutor {
             }
 
             let (second_res, second_priority) = second.await;
+            record_timing(&second_res, second_priority);
 
             // For the purposes of giving users a good UX, if both things failed, give them the
             // local executor's error, which is likely to not have failed because of e.g.
@@ -457,3 +504,6 @@ where
 
 #[derive(PartialOrd, Ord, PartialEq, Eq)]
 struct JobPriority(u8);
+
+const LOCAL_PRIORITY: JobPriority = JobPriority(1);
+const REMOTE_PRIORITY: JobPriority = JobPriority(0);
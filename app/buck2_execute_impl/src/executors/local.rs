@@ -8,7 +8,9 @@
  */
 
 use std::borrow::Cow;
+use std::collections::BTreeMap;
 use std::ffi::OsStr;
+use std::ffi::OsString;
 use std::ops::ControlFlow;
 use std::path::Path;
 use std::process::Command;
@@ -19,26 +21,18 @@ use std::time::SystemTime;
 
 use anyhow::Context as _;
 use async_trait::async_trait;
-use buck2_common::file_ops::FileDigest;
-use buck2_common::file_ops::FileMetadata;
-use buck2_common::file_ops::TrackedFileDigest;
+use buck2_common::executor_config::LocalSandboxOptions;
 use buck2_common::liveliness_observer::LivelinessObserver;
 use buck2_common::liveliness_observer::LivelinessObserverExt;
-use buck2_core::directory::DirectoryEntry;
 use buck2_core::fs::artifact_path_resolver::ArtifactFs;
 use buck2_core::fs::fs_util;
 use buck2_core::fs::paths::abs_norm_path::AbsNormPathBuf;
-use buck2_core::fs::paths::file_name::FileNameBuf;
 use buck2_core::fs::project_rel_path::ProjectRelativePath;
 use buck2_core::quiet_soft_error;
 use buck2_execute::artifact_value::ArtifactValue;
 use buck2_execute::digest_config::DigestConfig;
 use buck2_execute::directory::extract_artifact_value;
 use buck2_execute::directory::insert_entry;
-use buck2_execute::directory::new_symlink;
-use buck2_execute::directory::ActionDirectoryBuilder;
-use buck2_execute::directory::ActionDirectoryEntry;
-use buck2_execute::directory::ActionDirectoryMember;
 use buck2_execute::execute::action_digest::ActionDigest;
 use buck2_execute::execute::blocking::BlockingExecutor;
 use buck2_execute::execute::clean_output_paths::CleanOutputPaths;
@@ -56,8 +50,11 @@ use buck2_execute::execute::request::CommandExecutionInput;
 use buck2_execute::execute::request::CommandExecutionOutput;
 use buck2_execute::execute::request::CommandExecutionOutputRef;
 use buck2_execute::execute::request::CommandExecutionRequest;
+use buck2_execute::execute::request::ResourceLimits;
+use buck2_execute::execute::request::WorkerSpec;
 use buck2_execute::execute::result::CommandExecutionMetadata;
 use buck2_execute::execute::result::CommandExecutionResult;
+use buck2_execute::execute::result::CommandExecutionStatus;
 use buck2_execute::knobs::ExecutorGlobalKnobs;
 use buck2_execute::materialize::materializer::MaterializationError;
 use buck2_execute::materialize::materializer::Materializer;
@@ -68,7 +65,6 @@ use buck2_forkserver::run::GatherOutputStatus;
 use buck2_util::process::background_command;
 use derive_more::From;
 use dupe::Dupe;
-use faccess::PathExt;
 use futures::future;
 use futures::future::select;
 use futures::future::FutureExt;
@@ -81,6 +77,23 @@ use more_futures::cancellable_future::CancellationObserver;
 use thiserror::Error;
 use tracing::info;
 
+use crate::executors::memory_estimates::MemoryEstimates;
+use crate::executors::sandbox::LocalSandbox;
+use crate::executors::stale_outputs::StaleOutputs;
+use crate::executors::worker::is_worker_death;
+use crate::executors::worker::WorkerPool;
+use crate::materializers::io::build_entry_from_disk;
+
+/// Log a warning the first time this is reached, and never again.
+macro_rules! warn_once {
+    ($($arg:tt)*) => {{
+        static WARNED: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+        if !WARNED.swap(true, std::sync::atomic::Ordering::Relaxed) {
+            tracing::warn!($($arg)*);
+        }
+    }};
+}
+
 #[derive(Debug, Error)]
 enum LocalExecutionError {
     #[error("Args list was empty")]
@@ -88,6 +101,15 @@ enum LocalExecutionError {
 
     #[error("Trying to execute a remote-only action on a local executor")]
     RemoteOnlyAction,
+
+    #[error("Running local actions in a sandbox requires the forkserver")]
+    SandboxRequiresForkserver,
+
+    #[error(
+        "Blocking network access for local actions requires Linux and the forkserver. Set \
+        `buck2.allow_unenforced_network_block = true` to run them with network access instead"
+    )]
+    NetworkBlockRequiresForkserver,
 }
 
 #[derive(Clone)]
@@ -101,6 +123,11 @@ pub struct LocalExecutor {
     forkserver: Option<ForkserverClient>,
     #[allow(unused)]
     knobs: ExecutorGlobalKnobs,
+    sandbox: Option<LocalSandboxOptions>,
+    worker_pool: Option<Arc<WorkerPool>>,
+    memory_estimates: Option<Arc<MemoryEstimates>>,
+    stale_outputs: Option<Arc<StaleOutputs>>,
+    block_network: bool,
 }
 
 impl LocalExecutor {
@@ -121,6 +148,117 @@ impl LocalExecutor {
             root,
             forkserver,
             knobs,
+            sandbox: None,
+            worker_pool: None,
+            memory_estimates: None,
+            stale_outputs: None,
+            block_network: false,
+        }
+    }
+
+    /// Run actions in a Linux namespace sandbox where only their declared inputs are visible.
+    pub fn with_sandbox(mut self, sandbox: Option<LocalSandboxOptions>) -> Self {
+        self.sandbox = sandbox;
+        self
+    }
+
+    /// Run actions that declare a persistent worker in the workers of this pool.
+    pub fn with_worker_pool(mut self, worker_pool: Option<Arc<WorkerPool>>) -> Self {
+        self.worker_pool = worker_pool;
+        self
+    }
+
+    /// Record the peak memory of the commands we run here, and use it to schedule actions that
+    /// don't declare how much memory they need.
+    pub fn with_memory_estimates(mut self, memory_estimates: Option<Arc<MemoryEstimates>>) -> Self {
+        self.memory_estimates = memory_estimates;
+        self
+    }
+
+    /// Report files that the commands we run leave in their output directories without declaring
+    /// them, and those that are still around from previous runs.
+    pub fn with_stale_outputs(mut self, stale_outputs: Option<Arc<StaleOutputs>>) -> Self {
+        self.stale_outputs = stale_outputs;
+        self
+    }
+
+    /// Run commands without network access, unless their request allows it. Where that is not
+    /// possible (off Linux, or without the forkserver), those commands fail, unless
+    /// `buck2.allow_unenforced_network_block` is set, in which case they keep network access.
+    pub fn with_block_network(mut self, block_network: bool) -> Self {
+        self.block_network = block_network;
+        self
+    }
+
+    /// Whether commands that must run without network access actually will. Errors if they can't
+    /// and running them with network access wasn't opted into.
+    fn enforce_network_block(&self) -> anyhow::Result<bool> {
+        if cfg!(target_os = "linux") && self.forkserver.is_some() {
+            return Ok(true);
+        }
+        if !self.knobs.allow_unenforced_network_block {
+            return Err(LocalExecutionError::NetworkBlockRequiresForkserver.into());
+        }
+        warn_once!(
+            "Local actions were configured to run without network access, but this requires \
+            Linux and the forkserver, so they run with network access \
+            (`buck2.allow_unenforced_network_block` is set)"
+        );
+        Ok(false)
+    }
+
+    /// Run a command in a persistent worker. Returns `None` if the worker could not run it, in
+    /// which case it should run as a one-off command instead. If the worker died running it, the
+    /// command fails.
+    async fn exec_in_worker(
+        &self,
+        worker_pool: &WorkerPool,
+        worker: &WorkerSpec,
+        request: &CommandExecutionRequest,
+        daemon_uuid: &str,
+        liveliness_observer: impl LivelinessObserver,
+    ) -> Option<anyhow::Result<(GatherOutputStatus, Vec<u8>, Vec<u8>)>> {
+        let args = request.args().strip_prefix(worker.exe.as_slice())?;
+
+        let working_directory = match request.working_directory() {
+            Some(d) => Cow::Owned(self.root.join(d)),
+            None => Cow::Borrowed(&self.root),
+        };
+        let working_directory: &Path = working_directory.as_ref();
+
+        // Unlike one-off commands, workers don't get a per-action $TMPDIR since they are shared.
+        let mut env = InheritedEnvironment::default();
+        apply_local_execution_environment(
+            &mut env,
+            working_directory,
+            request
+                .env()
+                .iter()
+                .map(|(k, v)| (k.as_str(), v.as_str()))
+                .chain(std::iter::once(("BUCK2_DAEMON_UUID", daemon_uuid))),
+            request.local_environment_inheritance(),
+        );
+
+        match worker_pool
+            .execute(
+                worker,
+                args,
+                env.0,
+                working_directory,
+                request.timeout(),
+                liveliness_observer,
+            )
+            .await
+        {
+            Ok(r) => Some(Ok(r)),
+            Err(e) if is_worker_death(&e) => Some(Err(e)),
+            Err(e) => {
+                tracing::warn!(
+                    "Error running command in persistent worker, running it as a one-off command instead: {:#}",
+                    e
+                );
+                None
+            }
         }
     }
 
@@ -136,6 +274,9 @@ impl LocalExecutor {
         env_inheritance: Option<&'a EnvironmentInheritance>,
         liveliness_observer: impl LivelinessObserver + 'static,
         disable_miniperf: bool,
+        sandbox: Option<&'a LocalSandbox>,
+        resource_limits: ResourceLimits,
+        block_network: bool,
     ) -> impl futures::future::Future<
         Output = anyhow::Result<(GatherOutputStatus, Vec<u8>, Vec<u8>)>,
     > + Send
@@ -162,18 +303,34 @@ impl LocalExecutor {
                             env_inheritance,
                             liveliness_observer,
                             self.knobs.enable_miniperf && !disable_miniperf,
+                            sandbox.map(|s| s.to_proto(self.artifact_fs.fs())),
+                            resource_limits,
+                            block_network,
                         )
                         .await
                     }
 
                     #[cfg(not(unix))]
                     {
-                        let _unused = (forkserver, disable_miniperf);
+                        let _unused = (
+                            forkserver,
+                            disable_miniperf,
+                            sandbox,
+                            resource_limits,
+                            block_network,
+                        );
                         Err(anyhow::anyhow!("Forkserver is not supported off-UNIX"))
                     }
                 }
 
                 None => {
+                    if sandbox.is_some() {
+                        return Err(LocalExecutionError::SandboxRequiresForkserver.into());
+                    }
+                    if block_network {
+                        return Err(LocalExecutionError::NetworkBlockRequiresForkserver.into());
+                    }
+
                     let mut cmd = background_command(exe);
                     cmd.current_dir(working_directory);
                     cmd.args(args);
@@ -202,6 +359,7 @@ impl LocalExecutor {
     async fn exec_request(
         &self,
         action_digest: &ActionDigest,
+        action_key: Option<&str>,
         request: &CommandExecutionRequest,
         manager: CommandExecutionManager,
         cancellation: CancellationObserver,
@@ -254,6 +412,31 @@ impl LocalExecutor {
                 .resolve_scratch(tmpdir)
         });
 
+        let sandbox = match self
+            .sandbox
+            .as_ref()
+            .map(|options| {
+                LocalSandbox::new(&self.artifact_fs, request, scratch_dir.as_deref(), options)
+            })
+            .transpose()
+        {
+            Ok(sandbox) => sandbox,
+            Err(e) => return manager.error("prepare_sandbox_failed", e),
+        };
+        let sandbox = sandbox.as_ref();
+
+        let network_blocked = if request
+            .allow_network()
+            .map_or(self.block_network, |allow_network| !allow_network)
+        {
+            match self.enforce_network_block() {
+                Ok(network_blocked) => network_blocked,
+                Err(e) => return manager.error("network_block_unsupported", e),
+            }
+        } else {
+            false
+        };
+
         let scratch_dir = &scratch_dir; // So it doesn't move in the block below.
 
         if let Err(e) = executor_stage_async(
@@ -285,6 +468,41 @@ impl LocalExecutor {
             return manager.error("prepare_output_dirs_failed", e);
         };
 
+        let stale_outputs = self.stale_outputs.as_ref().zip(action_key);
+        let output_dirs_snapshot = match stale_outputs {
+            Some((stale_outputs, action_key)) => {
+                let outputs: Vec<_> = request
+                    .outputs()
+                    .map(|output| output.resolve(&self.artifact_fs))
+                    .collect();
+                match stale_outputs
+                    .before_execution(
+                        self.artifact_fs.fs(),
+                        self.blocking_executor.as_ref(),
+                        action_key,
+                        &outputs,
+                    )
+                    .await
+                {
+                    Ok((snapshot, stale)) => {
+                        for path in stale {
+                            manager.events.console_message(format!(
+                                "Stale undeclared output `{}` from a previous run of `{}` exists \
+                                before running it again",
+                                path, action_key
+                            ));
+                        }
+                        Some(snapshot)
+                    }
+                    Err(e) => {
+                        tracing::warn!("Error checking for stale outputs: {:#}", e);
+                        None
+                    }
+                }
+            }
+            None => None,
+        };
+
         info!(
             "Local execution command line:\n```\n$ {}\n```",
             args.join(" "),
@@ -336,6 +554,8 @@ impl LocalExecutor {
                 )))
         };
 
+        let worker_liveliness_observer =
+            manager.liveliness_observer.dupe().and(cancellation.dupe());
         let liveliness_observer = manager.liveliness_observer.dupe().and(cancellation);
 
         let (mut timing, res) = executor_stage_async(
@@ -361,19 +581,42 @@ impl LocalExecutor {
                 let execution_start = Instant::now();
                 let start_time = SystemTime::now();
 
-                let env = iter_env().map(|(k, v)| (k, v.into_os_str()));
-                let r = self
-                    .exec(
-                        &args[0],
-                        &args[1..],
-                        env,
-                        request.working_directory(),
-                        request.timeout(),
-                        request.local_environment_inheritance(),
-                        liveliness_observer,
-                        request.disable_miniperf(),
-                    )
-                    .await;
+                // Workers run outside of the sandbox and have network access, so sandboxed
+                // commands and those without network access never use them.
+                let worker_result = match (request.worker(), &self.worker_pool, sandbox) {
+                    (Some(worker), Some(worker_pool), None) if !network_blocked => {
+                        self.exec_in_worker(
+                            worker_pool,
+                            worker,
+                            request,
+                            daemon_uuid,
+                            worker_liveliness_observer,
+                        )
+                        .await
+                    }
+                    _ => None,
+                };
+
+                let r = match worker_result {
+                    Some(r) => r,
+                    None => {
+                        let env = iter_env().map(|(k, v)| (k, v.into_os_str()));
+                        self.exec(
+                            &args[0],
+                            &args[1..],
+                            env,
+                            request.working_directory(),
+                            request.timeout(),
+                            request.local_environment_inheritance(),
+                            liveliness_observer,
+                            request.disable_miniperf(),
+                            sandbox,
+                            *request.resource_limits(),
+                            network_blocked,
+                        )
+                        .await
+                    }
+                };
 
                 let execution_time = execution_start.elapsed();
 
@@ -390,15 +633,53 @@ impl LocalExecutor {
         )
         .await;
 
+        let (status, stdout, stderr) = match res {
+            Ok(res) => res,
+            Err(e) => return manager.error("exec_failed", e), // TODO (torozco): Can this take CommandExecutionKind? Should this be a failure?
+        };
+
+        if let (Some((stale_outputs, action_key)), Some(snapshot)) =
+            (stale_outputs, output_dirs_snapshot)
+        {
+            match stale_outputs
+                .after_execution(
+                    self.artifact_fs.fs(),
+                    self.blocking_executor.as_ref(),
+                    self.materializer.as_ref(),
+                    action_key,
+                    snapshot,
+                )
+                .await
+            {
+                Ok(undeclared) => {
+                    for path in undeclared {
+                        manager.events.console_message(format!(
+                            "`{}` produced `{}`, which is not one of its declared outputs",
+                            action_key, path
+                        ));
+                    }
+                }
+                Err(e) => tracing::warn!("Error checking for undeclared outputs: {:#}", e),
+            }
+        }
+
+        let undeclared_inputs = match (sandbox, &status) {
+            (Some(sandbox), GatherOutputStatus::Finished { exit_code, .. }) if *exit_code != 0 => {
+                sandbox.find_undeclared_inputs(
+                    &stderr,
+                    self.artifact_fs.fs(),
+                    request.working_directory(),
+                )
+            }
+            _ => Vec::new(),
+        };
+
         let execution_kind = CommandExecutionKind::Local {
             digest: action_digest.dupe(),
             command: args.to_vec(),
             env: request.env().clone(),
-        };
-
-        let (status, stdout, stderr) = match res {
-            Ok(res) => res,
-            Err(e) => return manager.error("exec_failed", e), // TODO (torozco): Can this take CommandExecutionKind? Should this be a failure?
+            network_blocked,
+            undeclared_inputs,
         };
 
         let std_streams = CommandStdStreams::Local { stdout, stderr };
@@ -455,8 +736,15 @@ impl LocalExecutor {
                     Default::default(),
                     CommandStdStreams::Local {
                         stdout: Default::default(),
-                        stderr: format!("Spawning executable `{}` failed: {}", args[0], reason)
-                            .into_bytes(),
+                        stderr: if sandbox.is_some() {
+                            format!(
+                                "Spawning executable `{}` in the local sandbox failed: {}",
+                                args[0], reason
+                            )
+                        } else {
+                            format!("Spawning executable `{}` failed: {}", args[0], reason)
+                        }
+                        .into_bytes(),
                     },
                     None,
                     timing,
@@ -481,8 +769,7 @@ impl LocalExecutor {
         for output in request.outputs() {
             let path = output.resolve(&self.artifact_fs).into_path();
             let abspath = self.root.join(&path);
-            let entry = self
-                .build_entry_from_disk(abspath, digest_config)
+            let entry = build_entry_from_disk(abspath, digest_config)
                 .with_context(|| format!("collecting output {:?}", path))?;
             if let Some(entry) = entry {
                 insert_entry(&mut builder, &path, entry)?;
@@ -516,82 +803,6 @@ impl LocalExecutor {
 
         Ok(mapped_outputs)
     }
-
-    fn build_entry_from_disk(
-        &self,
-        mut path: AbsNormPathBuf,
-        digest_config: DigestConfig,
-    ) -> anyhow::Result<Option<ActionDirectoryEntry<ActionDirectoryBuilder>>> {
-        fn build_dir_from_disk(
-            disk_path: &mut AbsNormPathBuf,
-            digest_config: DigestConfig,
-        ) -> anyhow::Result<ActionDirectoryBuilder> {
-            let mut builder = ActionDirectoryBuilder::empty();
-
-            for file in fs_util::read_dir(&disk_path)? {
-                let file = file?;
-                let filetype = file.file_type()?;
-                let filename = file.file_name();
-
-                let filename = filename
-                    .to_str()
-                    .context("Filename is not UTF-8")
-                    .and_then(|f| FileNameBuf::try_from(f.to_owned()))
-                    .with_context(|| format!("Invalid filename: {}", disk_path.display()))?;
-
-                disk_path.push(&filename);
-
-                if filetype.is_dir() {
-                    let dir = build_dir_from_disk(disk_path, digest_config)?;
-                    builder.insert(filename, DirectoryEntry::Dir(dir))?;
-                } else if filetype.is_symlink() {
-                    builder.insert(
-                        filename,
-                        DirectoryEntry::Leaf(new_symlink(fs_util::read_link(&disk_path)?)?),
-                    )?;
-                } else if filetype.is_file() {
-                    let metadata = FileMetadata {
-                        digest: TrackedFileDigest::new(
-                            FileDigest::from_file(&disk_path, digest_config.cas_digest_config())?,
-                            digest_config.cas_digest_config(),
-                        ),
-                        is_executable: file.path().executable(),
-                    };
-                    builder.insert(
-                        filename,
-                        DirectoryEntry::Leaf(ActionDirectoryMember::File(metadata)),
-                    )?;
-                }
-                disk_path.pop();
-            }
-
-            Ok(builder)
-        }
-
-        // Get file metadata. If the file is missing, ignore it.
-        let m = match std::fs::symlink_metadata(&path) {
-            Ok(m) => m,
-            Err(ref err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
-            Err(err) => return Err(err.into()),
-        };
-
-        let value = if m.file_type().is_symlink() {
-            DirectoryEntry::Leaf(new_symlink(fs_util::read_link(&path)?)?)
-        } else if m.is_file() {
-            DirectoryEntry::Leaf(ActionDirectoryMember::File(FileMetadata {
-                digest: TrackedFileDigest::new(
-                    FileDigest::from_file(&path, digest_config.cas_digest_config())?,
-                    digest_config.cas_digest_config(),
-                ),
-                is_executable: path.executable(),
-            }))
-        } else if m.is_dir() {
-            DirectoryEntry::Dir(build_dir_from_disk(&mut path, digest_config)?)
-        } else {
-            unimplemented!("Path {:?} is of an unknown file type.", path)
-        };
-        Ok(Some(value))
-    }
 }
 
 #[async_trait]
@@ -607,33 +818,68 @@ impl PreparedCommandExecutor for LocalExecutor {
 
         let PreparedCommand {
             request,
-            target: _,
+            target,
             prepared_action,
             digest_config,
         } = command;
 
+        let action_key = (self.memory_estimates.is_some() || self.stale_outputs.is_some())
+            .then(|| target.re_action_key());
+
+        // Actions that declare how much memory they need know better than what they used last
+        // time.
+        let memory_estimate = request.memory_estimate().or_else(|| {
+            self.memory_estimates
+                .as_ref()
+                .zip(action_key.as_ref())
+                .and_then(|(estimates, key)| estimates.get(key))
+        });
+
         let _permit = executor_stage_async(
             buck2_data::LocalStage {
                 stage: Some(buck2_data::LocalQueued {}.into()),
             },
             self.host_sharing_broker
-                .acquire(request.host_sharing_requirements()),
+                .acquire(request.host_sharing_requirements(), memory_estimate),
         )
         .await;
 
         // If we start running something, we don't want this task to get dropped, because if we do
         // we might interfere with e.g. clean up.
-        with_structured_cancellation(|cancellation| {
+        let res = with_structured_cancellation(|cancellation| {
             Self::exec_request(
                 self,
                 &prepared_action.action,
+                action_key.as_deref(),
                 request,
                 manager,
                 cancellation,
                 *digest_config,
             )
         })
-        .await
+        .await;
+
+        if let (Some(estimates), Some(key)) = (&self.memory_estimates, &action_key) {
+            match res
+                .report
+                .timing
+                .execution_stats
+                .as_ref()
+                .and_then(|stats| stats.memory_peak_bytes)
+            {
+                Some(peak) => estimates.record(key, peak),
+                None if matches!(res.report.status, CommandExecutionStatus::Success { .. }) => {
+                    warn_once!(
+                        "The peak memory of local actions is not measured (this requires \
+                        `buck2.resource_control`, cgroup v2 and Linux 5.19 or later), so memory \
+                        aware scheduling only uses the memory estimates that actions declare"
+                    );
+                }
+                None => {}
+            }
+        }
+
+        res
     }
 }
 
@@ -840,6 +1086,37 @@ pub fn apply_local_execution_environment(
     builder.set("PWD", working_directory);
 }
 
+/// The environment of the daemon, to which an `EnvironmentBuilder` applies changes.
+struct InheritedEnvironment(BTreeMap<OsString, OsString>);
+
+impl Default for InheritedEnvironment {
+    fn default() -> Self {
+        Self(std::env::vars_os().collect())
+    }
+}
+
+impl EnvironmentBuilder for InheritedEnvironment {
+    fn clear(&mut self) {
+        self.0.clear();
+    }
+
+    fn set<K, V>(&mut self, key: K, val: V)
+    where
+        K: AsRef<OsStr>,
+        V: AsRef<OsStr>,
+    {
+        self.0
+            .insert(key.as_ref().to_owned(), val.as_ref().to_owned());
+    }
+
+    fn remove<K>(&mut self, key: K)
+    where
+        K: AsRef<OsStr>,
+    {
+        self.0.remove(key.as_ref());
+    }
+}
+
 pub trait EnvironmentBuilder {
     fn clear(&mut self);
 
@@ -892,6 +1169,9 @@ mod unix {
         env_inheritance: Option<&EnvironmentInheritance>,
         liveliness_observer: impl LivelinessObserver + 'static,
         enable_miniperf: bool,
+        sandbox: Option<buck2_forkserver_proto::SandboxConfig>,
+        resource_limits: ResourceLimits,
+        block_network: bool,
     ) -> anyhow::Result<(GatherOutputStatus, Vec<u8>, Vec<u8>)> {
         let exe = exe.as_ref();
 
@@ -907,6 +1187,14 @@ mod unix {
             env: vec![],
             timeout: command_timeout.try_map(|d| d.try_into())?,
             enable_miniperf,
+            sandbox,
+            resource_limits: (resource_limits != ResourceLimits::default()).then_some(
+                buck2_forkserver_proto::ResourceLimits {
+                    memory_max_bytes: resource_limits.memory_bytes,
+                    cpus: resource_limits.cpus,
+                },
+            ),
+            block_network,
         };
         apply_local_execution_environment(&mut req, working_directory, env, env_inheritance);
         forkserver
@@ -1115,6 +1403,9 @@ mod tests {
                 None,
                 NoopLivelinessObserver::create(),
                 false,
+                None,
+                ResourceLimits::default(),
+                false,
             )
             .await?;
         assert!(matches!(status, GatherOutputStatus::Finished { exit_code, .. } if exit_code == 0));
@@ -1151,6 +1442,9 @@ mod tests {
                 Some(&EnvironmentInheritance::empty()),
                 NoopLivelinessObserver::create(),
                 false,
+                None,
+                ResourceLimits::default(),
+                false,
             )
             .await?;
         assert!(matches!(status, GatherOutputStatus::Finished { exit_code, .. } if exit_code == 0));
@@ -0,0 +1,69 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+//! The memory used by the local commands we ran, so that commands that don't declare how much
+//! memory they need can still be scheduled against the memory budget of the host.
+
+use std::collections::HashMap;
+
+use allocative::Allocative;
+use parking_lot::Mutex;
+
+/// Commands whose peak memory is lower than this are not worth scheduling against the memory
+/// budget, so we don't keep track of them. This keeps the number of entries in check.
+const MIN_TRACKED_PEAK_BYTES: u64 = 64 * 1024 * 1024;
+
+/// The peak memory of the last local run of commands, by action key. This is only known when the
+/// forkserver runs actions in cgroups (`buck2.resource_control`).
+#[derive(Default, Allocative)]
+pub struct MemoryEstimates {
+    #[allocative(skip)]
+    peaks: Mutex<HashMap<String, u64>>,
+}
+
+impl MemoryEstimates {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// How much memory the command for this action is expected to use, in bytes, if it is known
+    /// to use a significant amount.
+    pub fn get(&self, action_key: &str) -> Option<u64> {
+        self.peaks.lock().get(action_key).copied()
+    }
+
+    pub fn record(&self, action_key: &str, peak_bytes: u64) {
+        let mut peaks = self.peaks.lock();
+        if peak_bytes >= MIN_TRACKED_PEAK_BYTES {
+            peaks.insert(action_key.to_owned(), peak_bytes);
+        } else {
+            peaks.remove(action_key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_memory_estimates() {
+        let estimates = MemoryEstimates::new();
+        assert_eq!(None, estimates.get("link"));
+
+        estimates.record("link", 2 * MIN_TRACKED_PEAK_BYTES);
+        estimates.record("compile", 1024);
+        assert_eq!(Some(2 * MIN_TRACKED_PEAK_BYTES), estimates.get("link"));
+        assert_eq!(None, estimates.get("compile"));
+
+        // Commands that stop using a lot of memory are forgotten.
+        estimates.record("link", 1024);
+        assert_eq!(None, estimates.get("link"));
+    }
+}
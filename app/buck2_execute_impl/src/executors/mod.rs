@@ -7,7 +7,12 @@
  * of this source tree.
  */
 
+pub mod action_timings;
 pub mod caching;
 pub mod hybrid;
 pub mod local;
+pub mod memory_estimates;
 pub mod re;
+mod sandbox;
+pub mod stale_outputs;
+pub mod worker;
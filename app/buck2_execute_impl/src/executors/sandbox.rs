@@ -0,0 +1,226 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+//! Running local actions in the forkserver's namespace sandbox, where only the declared inputs
+//! of the action are visible, so that actions relying on undeclared inputs fail locally like they
+//! would on RE.
+
+use std::borrow::Cow;
+
+use buck2_common::executor_config::LocalSandboxOptions;
+use buck2_core::fs::artifact_path_resolver::ArtifactFs;
+use buck2_core::fs::paths::abs_norm_path::AbsNormPath;
+use buck2_core::fs::paths::forward_rel_path::ForwardRelativePath;
+use buck2_core::fs::project::ProjectRoot;
+use buck2_core::fs::project_rel_path::ProjectRelativePath;
+use buck2_core::fs::project_rel_path::ProjectRelativePathBuf;
+use buck2_execute::execute::request::CommandExecutionInput;
+use buck2_execute::execute::request::CommandExecutionRequest;
+
+/// Don't stat more than this many missing paths when looking for undeclared inputs.
+const MAX_CANDIDATES: usize = 1000;
+
+pub(crate) struct LocalSandbox {
+    /// The paths the action was declared to read.
+    declared_inputs: Vec<ProjectRelativePathBuf>,
+    /// The paths the action may write to.
+    writable: Vec<ProjectRelativePathBuf>,
+    isolate_network: bool,
+}
+
+impl LocalSandbox {
+    pub(crate) fn new(
+        artifact_fs: &ArtifactFs,
+        request: &CommandExecutionRequest,
+        scratch_dir: Option<&ProjectRelativePath>,
+        options: &LocalSandboxOptions,
+    ) -> anyhow::Result<Self> {
+        let mut declared_inputs = Vec::new();
+        for input in request.inputs() {
+            match input {
+                CommandExecutionInput::Artifact(group) => {
+                    for (artifact, _) in group.iter() {
+                        declared_inputs.push(artifact.resolve_path(artifact_fs)?);
+                    }
+                }
+                CommandExecutionInput::ActionMetadata(metadata) => {
+                    declared_inputs.push(
+                        artifact_fs
+                            .buck_out_path_resolver()
+                            .resolve_gen(&metadata.path),
+                    );
+                }
+            }
+        }
+
+        let mut writable = Vec::new();
+        for output in request.outputs() {
+            if let Some(path) = output.resolve(artifact_fs).path_to_create() {
+                writable.push(path.to_owned());
+            }
+        }
+        writable.extend(scratch_dir.map(|p| p.to_owned()));
+
+        Ok(Self {
+            declared_inputs,
+            writable,
+            isolate_network: options.isolate_network,
+        })
+    }
+
+    #[cfg(unix)]
+    pub(crate) fn to_proto(&self, fs: &ProjectRoot) -> buck2_forkserver_proto::SandboxConfig {
+        use std::os::unix::ffi::OsStrExt;
+
+        let to_bytes = |paths: &[ProjectRelativePathBuf]| {
+            paths
+                .iter()
+                .map(|p| fs.resolve(p).as_path().as_os_str().as_bytes().to_vec())
+                .collect()
+        };
+
+        buck2_forkserver_proto::SandboxConfig {
+            readonly_paths: to_bytes(&self.declared_inputs),
+            writable_paths: to_bytes(&self.writable),
+            isolate_network: self.isolate_network,
+        }
+    }
+
+    fn is_visible(&self, path: &ProjectRelativePath) -> bool {
+        self.declared_inputs
+            .iter()
+            .chain(&self.writable)
+            .any(|p| path.starts_with(p))
+    }
+
+    /// Look for files the action failed to open because they don't exist in the sandbox (as
+    /// reported by ENOENT errors on stderr), but that do exist in the project: those are
+    /// undeclared inputs.
+    pub(crate) fn find_undeclared_inputs(
+        &self,
+        stderr: &[u8],
+        fs: &ProjectRoot,
+        working_directory: Option<&ProjectRelativePath>,
+    ) -> Vec<ProjectRelativePathBuf> {
+        let working_directory = working_directory.unwrap_or_else(ProjectRelativePath::empty);
+        let stderr = String::from_utf8_lossy(stderr);
+
+        let mut found = Vec::new();
+        for missing in stderr.lines().filter_map(missing_path).take(MAX_CANDIDATES) {
+            let path = if missing.starts_with('/') {
+                match AbsNormPath::new(missing).and_then(|p| fs.relativize(p)) {
+                    Ok(p) => Cow::into_owned(p),
+                    Err(_) => continue,
+                }
+            } else {
+                match ForwardRelativePath::new(missing.trim_start_matches("./")) {
+                    Ok(p) => working_directory.join(p),
+                    Err(_) => continue,
+                }
+            };
+
+            if found.contains(&path) || self.is_visible(&path) {
+                continue;
+            }
+
+            if fs.resolve(&path).as_path().is_file() {
+                found.push(path);
+            }
+        }
+        found
+    }
+}
+
+/// The messages tools print when a file does not exist.
+const ENOENT_MESSAGES: &[&str] = &["No such file or directory", "file not found"];
+
+/// Extract the path from a line reporting that a file does not exist, e.g.
+/// `cat: foo.txt: No such file or directory`, `foo.c:1:10: fatal error: 'foo.h' file not found`
+/// or `FileNotFoundError: [Errno 2] No such file or directory: 'foo.txt'`.
+fn missing_path(line: &str) -> Option<&str> {
+    let (before, after) = ENOENT_MESSAGES
+        .iter()
+        .find_map(|message| line.split_once(message))?;
+
+    let path = match after.strip_prefix(": ") {
+        Some(after) if !after.trim().is_empty() => after,
+        _ => {
+            let before = before.trim_end().trim_end_matches(':');
+            before.rsplit_once(": ").map_or(before, |(_, path)| path)
+        }
+    };
+    let path = path.trim().trim_matches(['\'', '"', '`']);
+    if path.is_empty() { None } else { Some(path) }
+}
+
+#[cfg(test)]
+mod tests {
+    use buck2_core::fs::project::ProjectRootTemp;
+
+    use super::*;
+
+    #[test]
+    fn test_missing_path() {
+        assert_eq!(
+            Some("/abs/path.txt"),
+            missing_path("cat: /abs/path.txt: No such file or directory")
+        );
+        assert_eq!(
+            Some("bar/baz.h"),
+            missing_path("foo.c:1:10: fatal error: 'bar/baz.h' file not found")
+        );
+        assert_eq!(
+            Some("with space.txt"),
+            missing_path(
+                "FileNotFoundError: [Errno 2] No such file or directory: 'with space.txt'"
+            )
+        );
+        assert_eq!(None, missing_path("foo.c:1:10: error: expected ';'"));
+    }
+
+    #[test]
+    fn test_find_undeclared_inputs() -> anyhow::Result<()> {
+        let project = ProjectRootTemp::new()?;
+        project.write_file("pkg/declared.h", "");
+        project.write_file("pkg/undeclared.h", "");
+        project.write_file("other/abs.h", "");
+        let fs = project.path();
+
+        let sandbox = LocalSandbox {
+            declared_inputs: vec![ProjectRelativePathBuf::unchecked_new(
+                "pkg/declared.h".to_owned(),
+            )],
+            writable: vec![],
+            isolate_network: false,
+        };
+
+        let stderr = format!(
+            "foo.c:1:10: fatal error: 'declared.h' file not found\n\
+             foo.c:2:10: fatal error: 'undeclared.h' file not found\n\
+             other.h: Permission denied\n\
+             {}: No such file or directory\n\
+             missing.h: No such file or directory\n",
+            fs.resolve(ProjectRelativePath::unchecked_new("other/abs.h"))
+        );
+
+        assert_eq!(
+            vec![
+                ProjectRelativePathBuf::unchecked_new("pkg/undeclared.h".to_owned()),
+                ProjectRelativePathBuf::unchecked_new("other/abs.h".to_owned()),
+            ],
+            sandbox.find_undeclared_inputs(
+                stderr.as_bytes(),
+                fs,
+                Some(ProjectRelativePath::unchecked_new("pkg")),
+            )
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,499 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+//! Detecting local actions that write files they didn't declare into their output directories.
+//! Buck2 doesn't know about those files so it never cleans them up, which lets later builds
+//! accidentally rely on leftovers from previous ones.
+//!
+//! The output directories of an action are the directories created for its outputs before it runs
+//! (which for file outputs is their parent). We list the files in them and in their subdirectories
+//! before and after the action runs: new files that aren't in declared outputs are undeclared
+//! outputs. Output directories are often shared by the actions of a target, so we only do this for
+//! the directories that no other action ran in at the same time, since we can't tell which action
+//! produced what otherwise. Other outputs of the target can also be written there while the action
+//! runs, by the materializer, so we skip the files it knows about, and don't check anything when
+//! the materializer doesn't keep track of the artifacts it materialized.
+//!
+//! We remember undeclared outputs, optionally in a sqlite db so that this survives daemon
+//! restarts, and if they are still around the next time the action runs, we report them as stale.
+
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use allocative::Allocative;
+use buck2_common::sqlite::open_versioned_sqlite_db;
+use buck2_common::sqlite::KeyValueSqliteTable;
+use buck2_core::fs::fs_util;
+use buck2_core::fs::paths::abs_norm_path::AbsNormPathBuf;
+use buck2_core::fs::paths::file_name::FileName;
+use buck2_core::fs::project::ProjectRoot;
+use buck2_core::fs::project_rel_path::ProjectRelativePath;
+use buck2_core::fs::project_rel_path::ProjectRelativePathBuf;
+use buck2_execute::execute::blocking::BlockingExecutor;
+use buck2_execute::execute::request::ResolvedCommandExecutionOutput;
+use buck2_execute::materialize::materializer::Materializer;
+use dupe::Dupe;
+use parking_lot::Mutex;
+
+/// Hand-maintained schema version for the stale outputs sqlite db. PLEASE bump this version if
+/// you are making a breaking change to how undeclared outputs are stored.
+const DB_SCHEMA_VERSION: u64 = 1;
+
+const TABLE: &str = "undeclared_outputs";
+
+/// The actions running in an output directory.
+#[derive(Default)]
+struct DirActivity {
+    running: usize,
+    /// How many actions started running in the directory since it was last idle.
+    started: u64,
+}
+
+/// The output directories local actions are running in, and the undeclared outputs they produced.
+#[derive(Default, Allocative)]
+pub struct StaleOutputs {
+    #[allocative(skip)]
+    dirs: Mutex<HashMap<ProjectRelativePathBuf, DirActivity>>,
+    /// Undeclared outputs, by action key.
+    #[allocative(skip)]
+    undeclared: Mutex<HashMap<String, Vec<ProjectRelativePathBuf>>>,
+    #[allocative(skip)]
+    db: Option<StaleOutputsSqliteDb>,
+}
+
+/// The contents of the output directories of an action before it ran. Dropping it stops tracking
+/// the action in its output directories.
+pub(crate) struct OutputDirsSnapshot {
+    stale_outputs: Arc<StaleOutputs>,
+    /// The output directories, and for those that no other action was running in when this one
+    /// started, how many actions had started in them.
+    dirs: Vec<(ProjectRelativePathBuf, Option<u64>)>,
+    declared: HashSet<ProjectRelativePathBuf>,
+    entries: BTreeSet<ProjectRelativePathBuf>,
+    released: bool,
+}
+
+impl OutputDirsSnapshot {
+    /// Stop tracking the action in its output directories. Returns those that no other action ran
+    /// in while it did.
+    fn release(&mut self) -> Vec<ProjectRelativePathBuf> {
+        if std::mem::replace(&mut self.released, true) {
+            return Vec::new();
+        }
+
+        let mut activity = self.stale_outputs.dirs.lock();
+        let mut exclusive = Vec::new();
+        for (dir, started) in &self.dirs {
+            if let Some(a) = activity.get_mut(dir) {
+                if *started == Some(a.started) {
+                    exclusive.push(dir.clone());
+                }
+                a.running -= 1;
+                if a.running == 0 {
+                    activity.remove(dir);
+                }
+            }
+        }
+        exclusive
+    }
+}
+
+impl Drop for OutputDirsSnapshot {
+    fn drop(&mut self) {
+        self.release();
+    }
+}
+
+impl StaleOutputs {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Load the undeclared outputs persisted in `stale_outputs_dir`, and persist new ones there.
+    /// If the db can't be read or was created with a different set of versions, it is deleted and
+    /// an empty one is created in its place.
+    pub fn initialize(
+        stale_outputs_dir: AbsNormPathBuf,
+        versions: HashMap<String, String>,
+    ) -> anyhow::Result<Self> {
+        let db = StaleOutputsSqliteDb::initialize(stale_outputs_dir, versions)?;
+        let undeclared = db.read_all()?;
+        Ok(Self {
+            dirs: Default::default(),
+            undeclared: Mutex::new(undeclared),
+            db: Some(db),
+        })
+    }
+
+    /// Call this once the output directories of the action are prepared. Returns what's in them,
+    /// and the undeclared outputs of previous runs of the action that are still around.
+    pub(crate) async fn before_execution(
+        self: &Arc<Self>,
+        fs: &ProjectRoot,
+        io_executor: &dyn BlockingExecutor,
+        action_key: &str,
+        outputs: &[ResolvedCommandExecutionOutput],
+    ) -> anyhow::Result<(OutputDirsSnapshot, Vec<ProjectRelativePathBuf>)> {
+        let declared: HashSet<_> = outputs.iter().map(|o| o.path().to_owned()).collect();
+
+        let mut dirs: Vec<_> = outputs
+            .iter()
+            .filter_map(|o| o.path_to_create())
+            .map(|p| p.to_owned())
+            .collect();
+        dirs.sort();
+        dirs.dedup();
+
+        let dirs = {
+            let mut activity = self.dirs.lock();
+            dirs.into_iter()
+                .map(|dir| {
+                    let a = activity.entry(dir.clone()).or_default();
+                    a.running += 1;
+                    a.started += 1;
+                    let started = (a.running == 1).then_some(a.started);
+                    (dir, started)
+                })
+                .collect()
+        };
+        let mut snapshot = OutputDirsSnapshot {
+            stale_outputs: self.dupe(),
+            dirs,
+            declared,
+            entries: BTreeSet::new(),
+            released: false,
+        };
+
+        let previous = self.undeclared.lock().get(action_key).cloned();
+        let (entries, stale) = io_executor
+            .execute_io_inline(|| {
+                let stale = previous
+                    .into_iter()
+                    .flatten()
+                    .filter(|p| fs.resolve(p).symlink_metadata().is_ok())
+                    .collect();
+                let exclusive: Vec<_> = snapshot
+                    .dirs
+                    .iter()
+                    .filter(|(_, started)| started.is_some())
+                    .map(|(dir, _)| dir.clone())
+                    .collect();
+                let entries = list_entries(fs, &exclusive, &snapshot.declared)?;
+                Ok((entries, stale))
+            })
+            .await?;
+        snapshot.entries = entries;
+
+        Ok((snapshot, stale))
+    }
+
+    /// Call this once the action ran. Returns the undeclared outputs it produced.
+    pub(crate) async fn after_execution(
+        &self,
+        fs: &ProjectRoot,
+        io_executor: &dyn BlockingExecutor,
+        materializer: &dyn Materializer,
+        action_key: &str,
+        mut snapshot: OutputDirsSnapshot,
+    ) -> anyhow::Result<Vec<ProjectRelativePathBuf>> {
+        let exclusive = snapshot.release();
+
+        let new_entries: Vec<_> = io_executor
+            .execute_io_inline(|| {
+                Ok(list_entries(fs, &exclusive, &snapshot.declared)?
+                    .into_iter()
+                    .filter(|p| !snapshot.entries.contains(p))
+                    .collect())
+            })
+            .await?;
+
+        // Skip what the materializer wrote there, e.g. other outputs of the target.
+        let undeclared: Vec<_> = match materializer
+            .has_declared_artifacts(new_entries.clone())
+            .await?
+        {
+            Some(known) => new_entries
+                .into_iter()
+                .zip(known)
+                .filter_map(|(p, known)| (!known).then_some(p))
+                .collect(),
+            None => return Ok(Vec::new()),
+        };
+
+        io_executor
+            .execute_io_inline(|| {
+                let changed = {
+                    let mut all_undeclared = self.undeclared.lock();
+                    if undeclared.is_empty() {
+                        all_undeclared.remove(action_key).is_some()
+                    } else {
+                        all_undeclared
+                            .insert(action_key.to_owned(), undeclared.clone())
+                            .as_ref()
+                            != Some(&undeclared)
+                    }
+                };
+
+                if let (true, Some(db)) = (changed, &self.db) {
+                    let res = if undeclared.is_empty() {
+                        db.delete(action_key)
+                    } else {
+                        db.insert(action_key, &undeclared)
+                    };
+                    if let Err(e) = res {
+                        tracing::warn!(
+                            "Error persisting undeclared outputs of `{}`: {:#}",
+                            action_key,
+                            e
+                        );
+                    }
+                }
+
+                Ok(undeclared)
+            })
+            .await
+    }
+}
+
+/// Whether `path` is, or is in, a declared output.
+fn is_declared(declared: &HashSet<ProjectRelativePathBuf>, path: &ProjectRelativePath) -> bool {
+    let mut path = Some(path);
+    while let Some(p) = path {
+        if declared.contains(p) {
+            return true;
+        }
+        path = p.parent();
+    }
+    false
+}
+
+/// The files (and empty directories) in `dirs` and their subdirectories, except for the `declared`
+/// outputs and what's in them.
+fn list_entries(
+    fs: &ProjectRoot,
+    dirs: &[ProjectRelativePathBuf],
+    declared: &HashSet<ProjectRelativePathBuf>,
+) -> anyhow::Result<BTreeSet<ProjectRelativePathBuf>> {
+    let mut entries = BTreeSet::new();
+
+    let mut queue: Vec<_> = dirs
+        .iter()
+        .filter(|dir| fs.resolve(dir).is_dir())
+        .cloned()
+        .collect();
+    while let Some(dir) = queue.pop() {
+        let mut empty = true;
+        for entry in fs_util::read_dir(fs.resolve(&dir))? {
+            let entry = entry?;
+            empty = false;
+            let file_name = entry.file_name();
+            let file_name = match file_name.to_str() {
+                Some(file_name) => FileName::new(file_name)?,
+                None => continue,
+            };
+            let path = dir.join(file_name);
+            if is_declared(declared, &path) {
+                continue;
+            }
+            if entry.file_type()?.is_dir() {
+                queue.push(path);
+            } else {
+                entries.insert(path);
+            }
+        }
+        if empty && !dirs.contains(&dir) {
+            entries.insert(dir);
+        }
+    }
+
+    Ok(entries)
+}
+
+/// DB that holds the undeclared outputs of actions, keyed by their action key.
+struct StaleOutputsSqliteDb {
+    undeclared_table: KeyValueSqliteTable,
+}
+
+impl StaleOutputsSqliteDb {
+    fn initialize(
+        stale_outputs_dir: AbsNormPathBuf,
+        mut versions: HashMap<String, String>,
+    ) -> anyhow::Result<Self> {
+        versions.insert("schema_version".to_owned(), DB_SCHEMA_VERSION.to_string());
+        let connection = open_versioned_sqlite_db(&stale_outputs_dir, versions, &[TABLE])?;
+        Ok(Self {
+            undeclared_table: KeyValueSqliteTable::new(TABLE.to_owned(), connection),
+        })
+    }
+
+    /// Entries that can't be parsed are skipped.
+    fn read_all(&self) -> anyhow::Result<HashMap<String, Vec<ProjectRelativePathBuf>>> {
+        Ok(self
+            .undeclared_table
+            .read_all()?
+            .into_iter()
+            .filter_map(|(key, paths)| {
+                let paths: Vec<String> = serde_json::from_str(&paths).ok()?;
+                let paths = paths
+                    .into_iter()
+                    .map(|p| ProjectRelativePathBuf::try_from(p).ok())
+                    .collect::<Option<_>>()?;
+                Some((key, paths))
+            })
+            .collect())
+    }
+
+    fn insert(&self, key: &str, paths: &[ProjectRelativePathBuf]) -> anyhow::Result<()> {
+        let paths: Vec<&str> = paths.iter().map(|p| p.as_str()).collect();
+        self.undeclared_table.insert_all(HashMap::from([(
+            key.to_owned(),
+            serde_json::to_string(&paths)?,
+        )]))
+    }
+
+    fn delete(&self, key: &str) -> anyhow::Result<()> {
+        self.undeclared_table.delete(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use buck2_core::fs::project::ProjectRootTemp;
+    use buck2_execute::execute::blocking::testing::DummyBlockingExecutor;
+    use buck2_execute::execute::request::OutputCreationBehavior;
+    use buck2_execute::materialize::nodisk::NoDiskMaterializer;
+
+    use super::*;
+
+    fn path(p: &str) -> ProjectRelativePathBuf {
+        ProjectRelativePath::unchecked_new(p).to_owned()
+    }
+
+    #[test]
+    fn test_list_entries() -> anyhow::Result<()> {
+        let temp = ProjectRootTemp::new()?;
+        for file in [
+            "out/a.o",
+            "out/a.d",
+            "out/nested/b.o",
+            "out/tmp/x",
+            "out/other/c.o",
+        ] {
+            temp.write_file(file, "");
+        }
+        let fs = temp.path();
+
+        fs_util::create_dir_all(fs.resolve(ProjectRelativePath::unchecked_new("out/empty")))?;
+        temp.write_file("out/nested/c.o", "");
+
+        let declared = HashSet::from([path("out/a.o"), path("out/nested/b.o")]);
+        assert_eq!(
+            BTreeSet::from([
+                path("out/a.d"),
+                path("out/empty"),
+                path("out/nested/c.o"),
+                path("out/other/c.o"),
+                path("out/tmp/x"),
+            ]),
+            list_entries(fs, &[path("out")], &declared)?
+        );
+
+        assert!(is_declared(&declared, &path("out/nested/b.o")));
+        assert!(!is_declared(&declared, &path("out/nested")));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_actions() -> anyhow::Result<()> {
+        let temp = ProjectRootTemp::new()?;
+        let fs = temp.path();
+        let io = DummyBlockingExecutor { fs: fs.dupe() };
+        let stale_outputs = Arc::new(StaleOutputs::new());
+        let output =
+            |p: &str| ResolvedCommandExecutionOutput::new(path(p), OutputCreationBehavior::Parent);
+
+        // `b` runs while `a` does, so neither gets blamed for the other's files.
+        let (a, _) = stale_outputs
+            .before_execution(fs, &io, "a", &[output("out/a")])
+            .await?;
+        let (b, _) = stale_outputs
+            .before_execution(fs, &io, "b", &[output("out/b")])
+            .await?;
+        temp.write_file("out/a.tmp", "");
+        temp.write_file("out/b.tmp", "");
+        assert!(
+            stale_outputs
+                .after_execution(fs, &io, &NoDiskMaterializer, "b", b)
+                .await?
+                .is_empty()
+        );
+        assert!(
+            stale_outputs
+                .after_execution(fs, &io, &NoDiskMaterializer, "a", a)
+                .await?
+                .is_empty()
+        );
+
+        // On its own, `a` is.
+        let (a, stale) = stale_outputs
+            .before_execution(fs, &io, "a", &[output("out/a")])
+            .await?;
+        assert!(stale.is_empty());
+        temp.write_file("out/a.tmp2", "");
+        assert_eq!(
+            vec![path("out/a.tmp2")],
+            stale_outputs
+                .after_execution(fs, &io, &NoDiskMaterializer, "a", a)
+                .await?
+        );
+
+        let (_a, stale) = stale_outputs
+            .before_execution(fs, &io, "a", &[output("out/a")])
+            .await?;
+        assert_eq!(vec![path("out/a.tmp2")], stale);
+        drop(_a);
+
+        assert!(stale_outputs.dirs.lock().is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_stale_outputs_persisted() -> anyhow::Result<()> {
+        let temp = ProjectRootTemp::new()?;
+        let fs = temp.path();
+        let io = DummyBlockingExecutor { fs: fs.dupe() };
+        let dir = fs.resolve(ProjectRelativePath::unchecked_new("stale_outputs"));
+        let output = [ResolvedCommandExecutionOutput::new(
+            path("out/a"),
+            OutputCreationBehavior::Parent,
+        )];
+
+        let stale_outputs = Arc::new(StaleOutputs::initialize(dir.clone(), HashMap::new())?);
+        let (snapshot, _) = stale_outputs
+            .before_execution(fs, &io, "a", &output)
+            .await?;
+        temp.write_file("out/a.tmp", "");
+        stale_outputs
+            .after_execution(fs, &io, &NoDiskMaterializer, "a", snapshot)
+            .await?;
+        drop(stale_outputs);
+
+        // A new daemon still knows about them.
+        let stale_outputs = Arc::new(StaleOutputs::initialize(dir, HashMap::new())?);
+        let (_snapshot, stale) = stale_outputs
+            .before_execution(fs, &io, "a", &output)
+            .await?;
+        assert_eq!(vec![path("out/a.tmp")], stale);
+
+        Ok(())
+    }
+}
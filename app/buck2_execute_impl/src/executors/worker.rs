@@ -0,0 +1,680 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+//! Persistent workers: long-lived processes that run many commands, so that tools with a high
+//! startup cost (like JVM based compilers) only pay it once.
+//!
+//! Workers speak the JSON flavor of Bazel's worker protocol, so that existing workers can be
+//! used as-is: the worker is started with `--persistent_worker`, then reads one `WorkRequest`
+//! per line on its stdin and writes one `WorkResponse` per line on its stdout. Workers that
+//! support multiplexing receive concurrent requests told apart by their `requestId`.
+//!
+//! The stderr of each worker goes to a log file in `buck-out/v2/workers`. When a worker dies
+//! while running a command, the end of its log is part of the error.
+
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Weak;
+use std::time::Duration;
+use std::time::Instant;
+
+use allocative::Allocative;
+use anyhow::Context as _;
+use buck2_common::liveliness_observer::LivelinessObserver;
+use buck2_core::fs::fs_util;
+use buck2_core::fs::paths::abs_norm_path::AbsNormPathBuf;
+use buck2_core::fs::paths::forward_rel_path::ForwardRelativePath;
+use buck2_execute::execute::request::WorkerSpec;
+use buck2_forkserver::run::GatherOutputStatus;
+use buck2_util::process::background_command;
+use dupe::Dupe;
+use futures::future;
+use futures::future::Either;
+use futures::future::FutureExt;
+use parking_lot::Mutex;
+use serde::Deserialize;
+use serde::Serialize;
+use thiserror::Error;
+use tokio::io::AsyncBufReadExt;
+use tokio::io::AsyncWriteExt;
+use tokio::io::BufReader;
+use tokio::process::Child;
+use tokio::process::ChildStdin;
+use tokio::sync::oneshot;
+use tokio::sync::OwnedSemaphorePermit;
+use tokio::sync::Semaphore;
+
+#[derive(Debug, Error)]
+enum WorkerError {
+    #[error("Worker exited or closed its stdout before responding")]
+    WorkerExited,
+    #[error(
+        "Worker `{exe}` exited or closed its stdout while running the command, the end of its stderr (`{}`) is:\n{stderr}",
+        log.display()
+    )]
+    WorkerDied {
+        exe: String,
+        log: PathBuf,
+        stderr: String,
+    },
+    #[error("Worker sent an invalid response: `{0}`")]
+    InvalidResponse(String),
+}
+
+/// How much of the stderr of a worker that died to include in the error.
+const STDERR_TAIL_BYTES: u64 = 4096;
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct WorkRequest<'a> {
+    arguments: &'a [String],
+    request_id: u64,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct WorkResponse {
+    #[serde(default)]
+    exit_code: i32,
+    #[serde(default)]
+    output: String,
+    #[serde(default)]
+    request_id: u64,
+}
+
+/// Workers are only shared between commands that would start them identically.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct WorkerKey {
+    spec: WorkerSpec,
+    env: Vec<(OsString, OsString)>,
+    working_directory: PathBuf,
+}
+
+/// The workers sharing a key.
+#[derive(Default)]
+struct Workers {
+    workers: Vec<Arc<Worker>>,
+    /// One permit per request that may run concurrently across all the workers of this key, so
+    /// that we never start more than `max_workers` of them.
+    capacity: Option<Arc<Semaphore>>,
+}
+
+/// The workers of this daemon, by key. Workers are started on demand, up to `max_workers` per
+/// key, and kept around for as long as they are healthy and not idle for more than
+/// `idle_timeout`.
+#[derive(Allocative)]
+pub struct WorkerPool {
+    #[allocative(skip)]
+    workers: Arc<Mutex<HashMap<WorkerKey, Workers>>>,
+    max_workers: usize,
+    /// Where the stderr of workers goes.
+    log_dir: AbsNormPathBuf,
+    #[allocative(skip)]
+    next_worker_id: AtomicU64,
+}
+
+impl WorkerPool {
+    /// Create a pool, and a background task that stops idle workers. This must be called from
+    /// within a Tokio runtime. The logs of the workers of previous daemons in `log_dir` are
+    /// deleted.
+    pub fn new(
+        log_dir: AbsNormPathBuf,
+        max_workers: usize,
+        idle_timeout: Duration,
+    ) -> anyhow::Result<Self> {
+        fs_util::remove_all(&log_dir)?;
+        fs_util::create_dir_all(&log_dir)?;
+
+        let workers: Arc<Mutex<HashMap<WorkerKey, Workers>>> = Default::default();
+
+        tokio::spawn({
+            let workers = Arc::downgrade(&workers);
+            async move {
+                let mut interval = tokio::time::interval(idle_timeout / 2);
+                loop {
+                    interval.tick().await;
+                    match Weak::upgrade(&workers) {
+                        Some(workers) => Self::evict_idle(&workers, idle_timeout),
+                        None => break,
+                    }
+                }
+            }
+        });
+
+        Ok(Self {
+            workers,
+            max_workers: max_workers.max(1),
+            log_dir,
+            next_worker_id: AtomicU64::new(0),
+        })
+    }
+
+    /// Stop the workers that have not run anything for `idle_timeout`.
+    fn evict_idle(workers: &Mutex<HashMap<WorkerKey, Workers>>, idle_timeout: Duration) {
+        let mut workers = workers.lock();
+        for key_workers in workers.values_mut() {
+            key_workers
+                .workers
+                .retain(|w| !w.is_dead() && !w.is_idle(idle_timeout));
+        }
+        // Keep the capacity of keys whose requests are still waiting for a worker.
+        workers.retain(|_, w| {
+            !w.workers.is_empty()
+                || w.capacity
+                    .as_ref()
+                    .map_or(false, |c| Arc::strong_count(c) > 1)
+        });
+    }
+
+    /// Run a command in a worker, starting one if none is available. Errors mean that the worker
+    /// could not run the command at all (as opposed to the command failing), in which case the
+    /// caller should run the command as usual, unless [`is_worker_death`] says the worker died
+    /// running it.
+    pub(crate) async fn execute(
+        &self,
+        spec: &WorkerSpec,
+        args: &[String],
+        env: impl IntoIterator<Item = (impl AsRef<OsStr>, impl AsRef<OsStr>)>,
+        working_directory: &Path,
+        timeout: Option<Duration>,
+        liveliness_observer: impl LivelinessObserver,
+    ) -> anyhow::Result<(GatherOutputStatus, Vec<u8>, Vec<u8>)> {
+        let mut env: Vec<_> = env
+            .into_iter()
+            .map(|(k, v)| (k.as_ref().to_owned(), v.as_ref().to_owned()))
+            .collect();
+        env.sort();
+
+        let key = WorkerKey {
+            spec: spec.clone(),
+            env,
+            working_directory: working_directory.to_owned(),
+        };
+
+        let (worker, _permit) = self.acquire(&key).await?;
+
+        let response = worker.send(args);
+        let response = match timeout {
+            Some(timeout) => Either::Left(tokio::time::timeout(timeout, response)),
+            None => Either::Right(response.map(Ok::<_, tokio::time::error::Elapsed>)),
+        };
+
+        futures::pin_mut!(response);
+        let alive = liveliness_observer.while_alive();
+        futures::pin_mut!(alive);
+
+        let status = match future::select(response, alive).await {
+            Either::Left((Ok(response), _)) => {
+                let response = response?;
+                return Ok((
+                    GatherOutputStatus::Finished {
+                        exit_code: response.exit_code,
+                        execution_stats: None,
+                    },
+                    Vec::new(),
+                    response.output.into_bytes(),
+                ));
+            }
+            Either::Left((Err(_elapsed), _)) => {
+                GatherOutputStatus::TimedOut(timeout.unwrap_or_default())
+            }
+            Either::Right(((), _)) => GatherOutputStatus::Cancelled,
+        };
+
+        // We don't know what state the worker is in now, so we get rid of it.
+        self.remove(&key, &worker);
+        Ok((status, Vec::new(), Vec::new()))
+    }
+
+    async fn acquire(&self, key: &WorkerKey) -> anyhow::Result<(Arc<Worker>, WorkerPermit)> {
+        let capacity = self
+            .workers
+            .lock()
+            .entry(key.clone())
+            .or_default()
+            .capacity
+            .get_or_insert_with(|| {
+                Arc::new(Semaphore::new(self.max_workers * key.spec.concurrency))
+            })
+            .dupe();
+        // Holding one of these means that either a worker has a free slot, or fewer than
+        // `max_workers` workers are running.
+        let capacity = capacity
+            .acquire_owned()
+            .await
+            .context("Worker pool is closed")?;
+
+        let mut workers = self.workers.lock();
+        let workers = &mut workers.entry(key.clone()).or_default().workers;
+        workers.retain(|w| !w.is_dead());
+
+        for worker in workers.iter() {
+            if let Ok(slot) = worker.slots.dupe().try_acquire_owned() {
+                return Ok((worker.dupe(), WorkerPermit::new(worker, slot, capacity)));
+            }
+        }
+
+        let log = self
+            .log_dir
+            .join(ForwardRelativePath::unchecked_new(&format!(
+                "{}.log",
+                self.next_worker_id.fetch_add(1, Ordering::Relaxed)
+            )));
+        let worker = Arc::new(Worker::spawn(key, log)?);
+        let slot = worker
+            .slots
+            .dupe()
+            .try_acquire_owned()
+            .expect("a new worker has free slots");
+        workers.push(worker.dupe());
+        let permit = WorkerPermit::new(&worker, slot, capacity);
+        Ok((worker, permit))
+    }
+
+    fn remove(&self, key: &WorkerKey, worker: &Arc<Worker>) {
+        worker.dead.store(true, Ordering::SeqCst);
+        if let Some(workers) = self.workers.lock().get_mut(key) {
+            workers.workers.retain(|w| !Arc::ptr_eq(w, worker));
+        }
+    }
+}
+
+/// A request slot in a worker. The worker is considered in use until this is dropped.
+struct WorkerPermit {
+    last_used: Arc<Mutex<Instant>>,
+    _slot: OwnedSemaphorePermit,
+    _capacity: OwnedSemaphorePermit,
+}
+
+impl WorkerPermit {
+    fn new(worker: &Worker, slot: OwnedSemaphorePermit, capacity: OwnedSemaphorePermit) -> Self {
+        Self {
+            last_used: worker.last_used.dupe(),
+            _slot: slot,
+            _capacity: capacity,
+        }
+    }
+}
+
+impl Drop for WorkerPermit {
+    fn drop(&mut self) {
+        *self.last_used.lock() = Instant::now();
+    }
+}
+
+/// Whether `e`, returned by [`WorkerPool::execute`], means that the worker died while running the
+/// command. The command may well be what killed it, so it shouldn't be run again.
+pub(crate) fn is_worker_death(e: &anyhow::Error) -> bool {
+    matches!(
+        e.downcast_ref::<WorkerError>(),
+        Some(WorkerError::WorkerDied { .. })
+    )
+}
+
+/// The last `STDERR_TAIL_BYTES` of `log`.
+fn read_tail(log: &Path) -> anyhow::Result<String> {
+    let mut file = File::open(log)?;
+    let len = file.metadata()?.len();
+    file.seek(SeekFrom::Start(len.saturating_sub(STDERR_TAIL_BYTES)))?;
+    let mut tail = Vec::new();
+    file.read_to_end(&mut tail)?;
+    Ok(String::from_utf8_lossy(&tail).into_owned())
+}
+
+struct Worker {
+    /// Killed when the worker is dropped.
+    _child: Child,
+    /// For error messages.
+    exe: String,
+    /// Where the stderr of the worker goes. Kept after it dies, for debugging.
+    log: PathBuf,
+    stdin: tokio::sync::Mutex<ChildStdin>,
+    /// Requests sent to the worker, waiting for a response.
+    pending: Arc<Mutex<HashMap<u64, oneshot::Sender<WorkResponse>>>>,
+    multiplex: bool,
+    next_request_id: AtomicU64,
+    /// One permit per request the worker accepts concurrently.
+    slots: Arc<Semaphore>,
+    concurrency: usize,
+    /// When the worker last finished a request.
+    last_used: Arc<Mutex<Instant>>,
+    dead: Arc<AtomicBool>,
+}
+
+impl Worker {
+    fn spawn(key: &WorkerKey, log: AbsNormPathBuf) -> anyhow::Result<Self> {
+        let (exe, args) = key
+            .spec
+            .exe
+            .split_first()
+            .context("Worker command line is empty")?;
+        let stderr =
+            File::create(&log).with_context(|| format!("Error creating `{}`", log.display()))?;
+
+        let mut cmd = background_command(exe);
+        cmd.args(args)
+            .arg("--persistent_worker")
+            .current_dir(&key.working_directory)
+            .env_clear()
+            .envs(key.env.iter().map(|(k, v)| (k, v)))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(stderr);
+
+        let mut cmd = tokio::process::Command::from(cmd);
+        cmd.kill_on_drop(true);
+
+        let mut child = cmd
+            .spawn()
+            .with_context(|| format!("Error starting worker `{}`", key.spec.exe.join(" ")))?;
+
+        let stdin = child.stdin.take().context("Worker stdin is not piped")?;
+        let stdout = child.stdout.take().context("Worker stdout is not piped")?;
+
+        let pending: Arc<Mutex<HashMap<u64, oneshot::Sender<WorkResponse>>>> = Default::default();
+        let dead = Arc::new(AtomicBool::new(false));
+
+        // Dispatch responses to the requests waiting for them. When this exits, the senders of
+        // all pending requests are dropped, which fails them.
+        tokio::spawn({
+            let pending = pending.dupe();
+            let dead = dead.dupe();
+            async move {
+                let mut lines = BufReader::new(stdout).lines();
+                while let Ok(Some(line)) = lines.next_line().await {
+                    let response: WorkResponse = match serde_json::from_str(&line) {
+                        Ok(response) => response,
+                        Err(e) => {
+                            tracing::warn!("{:#}", WorkerError::InvalidResponse(e.to_string()));
+                            break;
+                        }
+                    };
+                    if let Some(sender) = pending.lock().remove(&response.request_id) {
+                        let _ignored = sender.send(response);
+                    }
+                }
+                dead.store(true, Ordering::SeqCst);
+                pending.lock().clear();
+            }
+        });
+
+        Ok(Self {
+            _child: child,
+            exe: key.spec.exe.join(" "),
+            log: log.into_path_buf(),
+            stdin: tokio::sync::Mutex::new(stdin),
+            pending,
+            multiplex: key.spec.concurrency > 1,
+            next_request_id: AtomicU64::new(1),
+            slots: Arc::new(Semaphore::new(key.spec.concurrency)),
+            concurrency: key.spec.concurrency,
+            last_used: Arc::new(Mutex::new(Instant::now())),
+            dead,
+        })
+    }
+
+    fn is_dead(&self) -> bool {
+        self.dead.load(Ordering::SeqCst)
+    }
+
+    fn is_idle(&self, idle_timeout: Duration) -> bool {
+        self.slots.available_permits() == self.concurrency
+            && self.last_used.lock().elapsed() >= idle_timeout
+    }
+
+    async fn send(&self, args: &[String]) -> anyhow::Result<WorkResponse> {
+        // Singleplex workers expect a request id of 0.
+        let request_id = if self.multiplex {
+            self.next_request_id.fetch_add(1, Ordering::SeqCst)
+        } else {
+            0
+        };
+
+        let (sender, receiver) = oneshot::channel();
+        self.pending.lock().insert(request_id, sender);
+
+        // The response dispatcher marks the worker dead before failing the pending requests, so
+        // if it is not dead yet, our request will get either a response or an error.
+        if self.is_dead() {
+            self.pending.lock().remove(&request_id);
+            return Err(WorkerError::WorkerExited.into());
+        }
+
+        let mut request = serde_json::to_vec(&WorkRequest {
+            arguments: args,
+            request_id,
+        })?;
+        request.push(b'\n');
+
+        let written: anyhow::Result<()> = try {
+            let mut stdin = self.stdin.lock().await;
+            stdin.write_all(&request).await?;
+            stdin.flush().await?;
+        };
+        if let Err(e) = written {
+            self.dead.store(true, Ordering::SeqCst);
+            self.pending.lock().remove(&request_id);
+            return Err(e.context("Error sending request to worker"));
+        }
+
+        match receiver.await {
+            Ok(response) => Ok(response),
+            Err(_) => Err(WorkerError::WorkerDied {
+                exe: self.exe.clone(),
+                log: self.log.clone(),
+                stderr: read_tail(&self.log)
+                    .unwrap_or_else(|e| format!("<error reading the log: {:#}>", e)),
+            }
+            .into()),
+        }
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        if !self.is_dead() {
+            let _ignored = std::fs::remove_file(&self.log);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use buck2_common::liveliness_observer::NoopLivelinessObserver;
+    use buck2_core::fs::project::ProjectRootTemp;
+
+    use super::*;
+
+    fn worker_pool(temp: &ProjectRootTemp, max_workers: usize) -> anyhow::Result<WorkerPool> {
+        WorkerPool::new(
+            temp.path()
+                .root()
+                .join(ForwardRelativePath::unchecked_new("workers")),
+            max_workers,
+            Duration::from_secs(600),
+        )
+    }
+
+    fn spec(script: &str, concurrency: usize) -> WorkerSpec {
+        WorkerSpec {
+            exe: vec![
+                "sh".to_owned(),
+                "-c".to_owned(),
+                script.to_owned(),
+                "sh".to_owned(),
+            ],
+            concurrency,
+        }
+    }
+
+    // Answers every request with the exit code 3 and the request line as output.
+    const ECHO_WORKER: &str = r#"
+        while read -r line; do
+            id=$(echo "$line" | sed 's/.*"requestId":\([0-9]*\).*/\1/')
+            printf '{"exitCode":3,"output":"%s","requestId":%s}\n' "$1" "$id"
+        done
+    "#;
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_worker_reused() -> anyhow::Result<()> {
+        let temp = ProjectRootTemp::new()?;
+        let pool = worker_pool(&temp, 4)?;
+        let spec = spec(ECHO_WORKER, 1);
+
+        for _ in 0..2 {
+            let (status, stdout, stderr) = pool
+                .execute(
+                    &spec,
+                    &["a".to_owned()],
+                    Vec::<(String, String)>::new(),
+                    &std::env::temp_dir(),
+                    None,
+                    NoopLivelinessObserver::create(),
+                )
+                .await?;
+            assert!(
+                matches!(status, GatherOutputStatus::Finished { exit_code, .. } if exit_code == 3)
+            );
+            assert!(stdout.is_empty());
+            assert_eq!(b"--persistent_worker", stderr.as_slice());
+        }
+
+        assert_eq!(
+            1,
+            pool.workers
+                .lock()
+                .values()
+                .map(|w| w.workers.len())
+                .sum::<usize>()
+        );
+
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_max_workers() -> anyhow::Result<()> {
+        let temp = ProjectRootTemp::new()?;
+        let pool = worker_pool(&temp, 1)?;
+        let spec = spec(ECHO_WORKER, 1);
+
+        let args = vec!["a".to_owned()];
+        let working_directory = std::env::temp_dir();
+        let run = || {
+            pool.execute(
+                &spec,
+                &args,
+                Vec::<(String, String)>::new(),
+                &working_directory,
+                None,
+                NoopLivelinessObserver::create(),
+            )
+        };
+        let (a, b) = future::join(run(), run()).await;
+        a?;
+        b?;
+
+        assert_eq!(
+            1,
+            pool.workers
+                .lock()
+                .values()
+                .map(|w| w.workers.len())
+                .sum::<usize>()
+        );
+
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_idle_workers_evicted() -> anyhow::Result<()> {
+        let temp = ProjectRootTemp::new()?;
+        let pool = worker_pool(&temp, 1)?;
+
+        pool.execute(
+            &spec(ECHO_WORKER, 1),
+            &["a".to_owned()],
+            Vec::<(String, String)>::new(),
+            &std::env::temp_dir(),
+            None,
+            NoopLivelinessObserver::create(),
+        )
+        .await?;
+        assert_eq!(1, pool.workers.lock().len());
+
+        WorkerPool::evict_idle(&pool.workers, Duration::from_secs(600));
+        assert_eq!(1, pool.workers.lock().len());
+
+        WorkerPool::evict_idle(&pool.workers, Duration::ZERO);
+        assert!(pool.workers.lock().is_empty());
+
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_worker_exits() -> anyhow::Result<()> {
+        let temp = ProjectRootTemp::new()?;
+        let pool = worker_pool(&temp, 4)?;
+
+        let res = pool
+            .execute(
+                &spec("exit 0", 1),
+                &[],
+                Vec::<(String, String)>::new(),
+                &std::env::temp_dir(),
+                None,
+                NoopLivelinessObserver::create(),
+            )
+            .await;
+        assert!(res.is_err());
+
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_worker_dies() -> anyhow::Result<()> {
+        let temp = ProjectRootTemp::new()?;
+        let pool = worker_pool(&temp, 4)?;
+
+        let res = pool
+            .execute(
+                &spec("read -r line; echo oops >&2; exit 1", 1),
+                &[],
+                Vec::<(String, String)>::new(),
+                &std::env::temp_dir(),
+                None,
+                NoopLivelinessObserver::create(),
+            )
+            .await;
+        let e = match res {
+            Ok(..) => panic!("Expected an error"),
+            Err(e) => e,
+        };
+        assert!(is_worker_death(&e));
+        assert!(format!("{:#}", e).ends_with("oops\n"), "{:#}", e);
+
+        Ok(())
+    }
+}
@@ -15,7 +15,7 @@ use buck2_core::directory::DirectoryEntry;
 use buck2_core::fs::fs_util;
 use buck2_core::fs::paths::abs_norm_path::AbsNormPath;
 use buck2_core::fs::paths::file_name::FileName;
-use buck2_core::fs::project::ProjectRoot;
+use buck2_core::fs::project_rel_path::ProjectRelativePath;
 use buck2_core::fs::project_rel_path::ProjectRelativePathBuf;
 use buck2_core::quiet_soft_error;
 use buck2_execute::digest_config::DigestConfig;
@@ -34,6 +34,7 @@ use tracing::error;
 use crate::materializers::deferred::extension::ExtensionCommand;
 use crate::materializers::deferred::file_tree::DataTree;
 use crate::materializers::deferred::join_all_existing_futs;
+use crate::materializers::deferred::lazy::backing_dir;
 use crate::materializers::deferred::ArtifactMaterializationStage;
 use crate::materializers::deferred::ArtifactTree;
 use crate::materializers::deferred::DefaultIoHandler;
@@ -125,10 +126,17 @@ fn gather_clean_futures_for_stale_artifacts(
         .buck_out_path
         .join(ProjectRelativePathBuf::unchecked_new("gen".to_owned()));
     let gen_dir = io.fs.resolve(gen_path);
-    if !fs_util::try_exists(&gen_dir)? {
+    // With lazy outputs, scan what's on disk rather than the filesystem mounted over gen. The
+    // mount also shows the artifacts that were only declared, and looking them up would wait for
+    // this thread.
+    let scan_dir = match &io.lazy_outputs {
+        Some(dir) => io.fs.resolve(&backing_dir(dir)),
+        None => gen_dir.clone(),
+    };
+    if !fs_util::try_exists(&scan_dir)? {
         return skip_clean_response_with_message("Nothing to clean");
     }
-    tracing::trace!(gen_dir = %gen_dir, "Scanning");
+    tracing::trace!(gen_dir = %gen_dir, scan_dir = %scan_dir, "Scanning");
 
     let mut stats = buck2_data::CleanStaleStats {
         stale_artifact_count: 0,
@@ -147,7 +155,11 @@ fn gather_clean_futures_for_stale_artifacts(
         let subtree = tree
             .get_subtree(&mut io.fs.relativize(&gen_dir)?.iter())
             .context("Found a file where gen dir expected")?;
-        find_stale_recursive(&io.fs, subtree, &gen_dir, keep_since_time, &mut stats)?
+        let root = StaleFinderRoot {
+            scan_dir: &scan_dir,
+            gen_path,
+        };
+        find_stale_recursive(&root, subtree, &scan_dir, keep_since_time, &mut stats)?
     };
 
     // If no stale or retained artifact founds, the db should be empty.
@@ -240,8 +252,14 @@ pub fn get_size(path: &AbsNormPath) -> anyhow::Result<u64> {
     Ok(result)
 }
 
+/// The directory `find_stale_recursive` scans, and the path in buck-out it has the contents of.
+struct StaleFinderRoot<'a> {
+    scan_dir: &'a AbsNormPath,
+    gen_path: &'a ProjectRelativePath,
+}
+
 fn find_stale_recursive(
-    fs: &ProjectRoot,
+    root: &StaleFinderRoot,
     subtree: Option<&ArtifactTree>,
     path: &AbsNormPath,
     keep_since_time: DateTime<Utc>,
@@ -249,13 +267,13 @@ fn find_stale_recursive(
 ) -> anyhow::Result<StaleFinderResult> {
     // Use symlink_metadata to not follow symlinks (stale/untracked symlink target may have been cleaned first)
     let path_type = FileType::from(path.symlink_metadata()?.file_type());
-    let rel_path = fs.relativize(&path)?;
+    let rel_path = root.gen_path.join(path.strip_prefix(root.scan_dir)?);
 
     let clean_untracked = |stats: &mut buck2_data::CleanStaleStats| {
         stats.untracked_artifact_count += 1;
         stats.untracked_bytes += get_size(path)?;
         tracing::trace!(path = %path, path_type = ?path_type, "marking as untracked");
-        Ok(StaleFinderResult::CleanPath(rel_path.clone().into_owned()))
+        Ok(StaleFinderResult::CleanPath(rel_path.clone()))
     };
 
     if let Some(DataTree::Data(tree_metadata)) = subtree {
@@ -263,6 +281,7 @@ fn find_stale_recursive(
             last_access_time,
             active,
             metadata,
+            ..
         } = &tree_metadata.stage
         {
             let size = match &metadata.0 {
@@ -276,7 +295,7 @@ fn find_stale_recursive(
                 stats.stale_artifact_count += 1;
                 stats.stale_bytes += size;
                 tracing::trace!(path = %path, path_type = ?path_type, "marking as stale");
-                Ok(StaleFinderResult::CleanPath(rel_path.clone().into_owned()))
+                Ok(StaleFinderResult::CleanPath(rel_path.clone()))
             } else {
                 stats.retained_artifact_count += 1;
                 stats.retained_bytes += size;
@@ -301,7 +320,7 @@ fn find_stale_recursive(
                 .and_then(|f| FileName::new(f).ok());
             let subtree = children.and_then(|c| file_name.and_then(|f| c.get(f)));
             let child_result =
-                find_stale_recursive(fs, subtree, &child_path, keep_since_time, stats)?;
+                find_stale_recursive(root, subtree, &child_path, keep_since_time, stats)?;
 
             if !child_result.clean_all() {
                 clean_dir = false;
@@ -310,7 +329,7 @@ fn find_stale_recursive(
         }
         // If all children should be cleaned, remove this dir instead
         if clean_dir {
-            Ok(StaleFinderResult::CleanPath(rel_path.clone().into_owned()))
+            Ok(StaleFinderResult::CleanPath(rel_path.clone()))
         } else {
             Ok(StaleFinderResult::CleanChildren(children_to_clean))
         }
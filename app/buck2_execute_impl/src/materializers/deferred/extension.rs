@@ -7,6 +7,7 @@
  * of this source tree.
  */
 
+use std::collections::HashMap;
 use std::fmt;
 use std::fmt::Debug;
 use std::fmt::Display;
@@ -16,7 +17,6 @@ use std::sync::Arc;
 use anyhow::Context as _;
 use async_trait::async_trait;
 use buck2_core::directory::DirectoryEntry;
-use buck2_core::fs::fs_util;
 use buck2_core::fs::project_rel_path::ProjectRelativePathBuf;
 use buck2_execute::directory::ActionDirectoryMember;
 use buck2_execute::materialize::materializer::DeferredMaterializerEntry;
@@ -30,6 +30,7 @@ use derivative::Derivative;
 use dupe::Dupe;
 use futures::stream::BoxStream;
 use futures::stream::StreamExt;
+use thiserror::Error;
 use tokio::sync::mpsc;
 use tokio::sync::mpsc::UnboundedSender;
 use tokio::sync::oneshot;
@@ -38,14 +39,17 @@ use tokio::task::JoinHandle;
 use tokio_stream::wrappers::UnboundedReceiverStream;
 
 use crate::materializers::deferred::clean_stale::CleanStaleArtifacts;
+use crate::materializers::deferred::gc::find_gc_candidates;
 use crate::materializers::deferred::io_handler::create_ttl_refresh;
 use crate::materializers::deferred::subscriptions::MaterializerSubscriptionOperation;
+use crate::materializers::deferred::verify::RepairOutcome;
 use crate::materializers::deferred::ArtifactMaterializationMethod;
 use crate::materializers::deferred::ArtifactMaterializationStage;
 use crate::materializers::deferred::DefaultIoHandler;
 use crate::materializers::deferred::DeferredMaterializer;
 use crate::materializers::deferred::DeferredMaterializerCommandProcessor;
 use crate::materializers::deferred::MaterializerCommand;
+use crate::materializers::deferred::Version;
 
 pub(super) trait ExtensionCommand<T>: Debug + Sync + Send + 'static {
     fn execute(self: Box<Self>, processor: &mut DeferredMaterializerCommandProcessor<T>);
@@ -137,6 +141,8 @@ struct Fsck {
     /// materializer command thread.
     #[derivative(Debug = "ignore")]
     sender: UnboundedSender<(ProjectRelativePathBuf, anyhow::Error)>,
+    repair: bool,
+    check_digests: bool,
 }
 
 impl ExtensionCommand<DefaultIoHandler> for Fsck {
@@ -144,27 +150,87 @@ impl ExtensionCommand<DefaultIoHandler> for Fsck {
         self: Box<Self>,
         processor: &mut DeferredMaterializerCommandProcessor<DefaultIoHandler>,
     ) {
-        for (path, data) in processor.tree.iter_with_paths() {
-            match &data.stage {
-                ArtifactMaterializationStage::Declared { .. } => {
-                    continue;
+        let artifacts: Vec<_> = processor
+            .tree
+            .iter_with_paths()
+            .filter_map(|(path, data)| match &data.stage {
+                ArtifactMaterializationStage::Materialized { metadata, .. } => Some((
+                    ProjectRelativePathBuf::from(path),
+                    metadata.dupe(),
+                    data.processing.current_version(),
+                )),
+                ArtifactMaterializationStage::Declared { .. } => None,
+            })
+            .collect();
+
+        // Checking digests reads every file, so like the background verification, this runs on
+        // the IO executor. Repairs then happen back on the command thread.
+        let io = processor.io.dupe();
+        let command_sender = processor.command_sender.dupe();
+        processor.rt.spawn(async move {
+            let failures = io.verify_artifacts(artifacts, self.check_digests).await;
+            if self.repair {
+                let _ignored =
+                    command_sender.send(MaterializerCommand::Extension(Box::new(FsckRepair {
+                        sender: self.sender,
+                        failures,
+                    })));
+            } else {
+                for (path, _version, e) in failures {
+                    let _ignored = self.sender.send((path, e));
                 }
-                ArtifactMaterializationStage::Materialized { .. } => {}
-            };
+            }
+        });
+    }
+}
 
-            // We actually block the thread here. This is to ensure we don't try to delete things
-            // when we check them. This is primarily a debug command. We don't run this while
-            // actual things are in flight.
+#[derive(Derivative)]
+#[derivative(Debug)]
+struct FsckRepair {
+    #[derivative(Debug = "ignore")]
+    sender: UnboundedSender<(ProjectRelativePathBuf, anyhow::Error)>,
+    #[derivative(Debug = "ignore")]
+    failures: Vec<(ProjectRelativePathBuf, Version, anyhow::Error)>,
+}
 
-            let path = ProjectRelativePathBuf::from(path);
-            let res = fs_util::symlink_metadata(processor.io.fs.resolve(&path));
-            match res {
-                Ok(..) => {}
-                Err(e) => {
-                    let _ignored = self.sender.send((path, e));
-                }
+impl ExtensionCommand<DefaultIoHandler> for FsckRepair {
+    fn execute(
+        self: Box<Self>,
+        processor: &mut DeferredMaterializerCommandProcessor<DefaultIoHandler>,
+    ) {
+        // Ignore artifacts that changed since we checked them.
+        let failures: Vec<_> = self
+            .failures
+            .into_iter()
+            .filter(
+                |(path, version, _)| match processor.tree.prefix_get(&mut path.iter()) {
+                    Some(data) => data.processing.current_version() == *version,
+                    None => false,
+                },
+            )
+            .map(|(path, _version, e)| (path, e))
+            .collect();
+
+        let (fut, outcomes) =
+            processor.repair_artifacts(failures.iter().map(|(path, _)| path.clone()).collect());
+        let mut outcomes: HashMap<_, _> = outcomes.into_iter().collect();
+
+        // Only report once the repaired artifacts are deleted, so that the next build rebuilds
+        // them once the command returns.
+        let sender = self.sender;
+        processor.rt.spawn(async move {
+            let deletion_error = fut.await.err().map(|e| format!("{:#}", e));
+            for (path, e) in failures {
+                let e = match (outcomes.remove(&path), &deletion_error) {
+                    (Some(RepairOutcome::Repaired), Some(deletion_error)) => {
+                        e.context(format!("Failed to repair: {}", deletion_error))
+                    }
+                    (Some(outcome), _) => e.context(outcome),
+                    (None, _) => e,
+                };
+                let _ignored = sender.send((path, e));
             }
-        }
+        });
     }
 }
 
@@ -280,6 +346,45 @@ impl ExtensionCommand<DefaultIoHandler> for TestIter {
     }
 }
 
+#[derive(Derivative)]
+#[derivative(Debug)]
+struct GcPreview {
+    #[derivative(Debug = "ignore")]
+    sender:
+        Sender<anyhow::Result<Vec<(ProjectRelativePathBuf, Box<dyn DeferredMaterializerEntry>)>>>,
+}
+
+#[derive(Debug, Error)]
+#[error(
+    "No materializer GC policy is configured, set `buck2.materializer_gc_max_size_mb` or `buck2.materializer_gc_max_age_seconds`"
+)]
+struct GcNotConfigured;
+
+impl ExtensionCommand<DefaultIoHandler> for GcPreview {
+    fn execute(
+        self: Box<Self>,
+        processor: &mut DeferredMaterializerCommandProcessor<DefaultIoHandler>,
+    ) {
+        let res = if processor.gc_policy.is_enabled() {
+            Ok(
+                find_gc_candidates(&processor.tree, processor.gc_policy, Utc::now())
+                    .into_iter()
+                    .map(|candidate| {
+                        let path_data = PathData::Materialized {
+                            ts: candidate.last_access_time,
+                            size: Some(candidate.size),
+                        };
+                        (candidate.path, Box::new(path_data) as _)
+                    })
+                    .collect(),
+            )
+        } else {
+            Err(GcNotConfigured.into())
+        };
+        let _ignored = self.sender.send(res);
+    }
+}
+
 #[async_trait]
 impl DeferredMaterializerExtensions for DeferredMaterializer {
     fn iterate(
@@ -294,11 +399,18 @@ impl DeferredMaterializerExtensions for DeferredMaterializer {
         Ok(UnboundedReceiverStream::new(receiver).boxed())
     }
 
-    fn fsck(&self) -> anyhow::Result<BoxStream<'static, (ProjectRelativePathBuf, anyhow::Error)>> {
+    fn fsck(
+        &self,
+        repair: bool,
+        check_digests: bool,
+    ) -> anyhow::Result<BoxStream<'static, (ProjectRelativePathBuf, anyhow::Error)>> {
         let (sender, receiver) = mpsc::unbounded_channel();
-        self.command_sender.send(MaterializerCommand::Extension(
-            Box::new(Fsck { sender }) as _
-        ))?;
+        self.command_sender
+            .send(MaterializerCommand::Extension(Box::new(Fsck {
+                sender,
+                repair,
+                check_digests,
+            }) as _))?;
         Ok(UnboundedReceiverStream::new(receiver).boxed())
     }
 
@@ -355,6 +467,16 @@ impl DeferredMaterializerExtensions for DeferredMaterializer {
         receiver.await.context("No response from materializer")
     }
 
+    async fn gc_preview(
+        &self,
+    ) -> anyhow::Result<Vec<(ProjectRelativePathBuf, Box<dyn DeferredMaterializerEntry>)>> {
+        let (sender, receiver) = oneshot::channel();
+        self.command_sender.send(MaterializerCommand::Extension(
+            Box::new(GcPreview { sender }) as _,
+        ))?;
+        receiver.await.context("No response from materializer")?
+    }
+
     fn queue_size(&self) -> usize {
         self.command_sender.counters.queue_size()
     }
@@ -0,0 +1,255 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+//! Automatic garbage collection of materialized artifacts.
+//!
+//! `clean --stale` only runs when asked to, so buck-out grows without bound between cleans. When a
+//! [`GcPolicy`] is configured, the deferred materializer periodically evicts artifacts that
+//! haven't been accessed for longer than `max_age`, and then the least recently accessed
+//! artifacts until the artifacts it tracks take no more than `max_size` bytes. It only does so
+//! when it hasn't received a command for a while, so that it doesn't compete with builds.
+//!
+//! Artifacts that were not declared by the running daemon are forgotten and deleted: DICE doesn't
+//! know about them, so that doesn't invalidate anything. Artifacts declared by the running daemon
+//! that were downloaded are deleted and declared again, so that they get downloaded again the
+//! next time they are needed. Other artifacts declared by the running daemon can't be produced
+//! again without invalidating DICE, so they are kept. Access times are persisted in the SQLite
+//! materializer state, so GC requires `buck2.sqlite_materializer_state`.
+//!
+//! The blobs fetched for lazy outputs are trimmed with the same policy, by when they were last
+//! opened. See `LazyBlobCache`.
+
+use buck2_core::directory::DirectoryEntry;
+use buck2_core::fs::project_rel_path::ProjectRelativePathBuf;
+use buck2_execute::artifact_value::ArtifactValue;
+use buck2_execute::directory::ActionDirectoryMember;
+use chrono::DateTime;
+use chrono::Duration;
+use chrono::Utc;
+use dupe::Dupe;
+use tokio::sync::oneshot;
+
+use crate::materializers::deferred::io_handler::IoHandler;
+use crate::materializers::deferred::join_all_existing_futs;
+use crate::materializers::deferred::ArtifactMaterializationMethod;
+use crate::materializers::deferred::ArtifactMaterializationStage;
+use crate::materializers::deferred::ArtifactMetadata;
+use crate::materializers::deferred::ArtifactTree;
+use crate::materializers::deferred::DeferredMaterializerCommandProcessor;
+use crate::materializers::deferred::Processing;
+use crate::materializers::deferred::Rematerialize;
+
+/// Limits on what the deferred materializer keeps in buck-out. Unset limits are not enforced.
+#[derive(Copy, Clone, Dupe, Debug, Default)]
+pub struct GcPolicy {
+    /// The max total size of the artifacts tracked by the materializer, in bytes.
+    pub max_size: Option<u64>,
+    /// The max time since an artifact was last accessed.
+    pub max_age: Option<Duration>,
+}
+
+impl GcPolicy {
+    pub fn is_enabled(&self) -> bool {
+        self.max_size.is_some() || self.max_age.is_some()
+    }
+}
+
+pub struct GcConfiguration {
+    pub policy: GcPolicy,
+    /// How often to check whether GC should run.
+    pub frequency: std::time::Duration,
+    /// How long the materializer must have gone without receiving a command for GC to run.
+    pub idle_time: std::time::Duration,
+}
+
+/// An artifact that GC would evict.
+#[derive(Debug, PartialEq, Eq)]
+pub(super) struct GcCandidate {
+    pub path: ProjectRelativePathBuf,
+    pub size: u64,
+    pub last_access_time: DateTime<Utc>,
+}
+
+pub(super) fn artifact_size(metadata: &ArtifactMetadata) -> u64 {
+    match &metadata.0 {
+        DirectoryEntry::Dir(dir) => dir.total_size,
+        DirectoryEntry::Leaf(ActionDirectoryMember::File(file_metadata)) => {
+            file_metadata.digest.size()
+        }
+        DirectoryEntry::Leaf(_) => 0,
+    }
+}
+
+/// Whether an artifact in use can be evicted by declaring it again. Only downloads are: written
+/// artifacts are written again as soon as they are declared, and local copies may not be
+/// reproducible.
+fn can_redeclare(rematerialize: &Option<Rematerialize>) -> bool {
+    match rematerialize {
+        Some((_, method)) => matches!(
+            &**method,
+            ArtifactMaterializationMethod::CasDownload { .. }
+                | ArtifactMaterializationMethod::HttpDownload { .. }
+        ),
+        None => false,
+    }
+}
+
+/// Find the artifacts to evict from `tree` to satisfy `policy`, least recently accessed first.
+pub(super) fn find_gc_candidates(
+    tree: &ArtifactTree,
+    policy: GcPolicy,
+    now: DateTime<Utc>,
+) -> Vec<GcCandidate> {
+    let mut total_size = 0;
+    let mut evictable = Vec::new();
+
+    for (path, data) in tree.iter_with_paths() {
+        if let ArtifactMaterializationStage::Materialized {
+            metadata,
+            last_access_time,
+            active,
+            rematerialize,
+        } = &data.stage
+        {
+            let size = artifact_size(metadata);
+            total_size += size;
+            // Don't touch artifacts that something is still working on.
+            if matches!(data.processing, Processing::Done(..))
+                && (!active || can_redeclare(rematerialize))
+            {
+                evictable.push(GcCandidate {
+                    path: ProjectRelativePathBuf::from(path),
+                    size,
+                    last_access_time: *last_access_time,
+                });
+            }
+        }
+    }
+
+    evictable.sort_by(|a, b| {
+        a.last_access_time
+            .cmp(&b.last_access_time)
+            .then_with(|| a.path.cmp(&b.path))
+    });
+
+    let mut evict = 0;
+    for candidate in &evictable {
+        let too_old = policy
+            .max_age
+            .map_or(false, |max_age| candidate.last_access_time < now - max_age);
+        let too_big = policy
+            .max_size
+            .map_or(false, |max_size| total_size > max_size);
+        if !too_old && !too_big {
+            break;
+        }
+        total_size -= candidate.size;
+        evict += 1;
+    }
+
+    evictable.truncate(evict);
+    evictable
+}
+
+impl<T: IoHandler> DeferredMaterializerCommandProcessor<T> {
+    /// Declare the evicted artifacts at `paths` that are in use again, which deletes them and
+    /// lets them be materialized again on demand. Returns the other paths, which must be
+    /// forgotten and deleted.
+    pub(super) fn redeclare_evicted(
+        &mut self,
+        paths: Vec<ProjectRelativePathBuf>,
+    ) -> Vec<ProjectRelativePathBuf> {
+        let mut to_delete = Vec::new();
+        let mut to_redeclare = Vec::new();
+        for path in paths {
+            let data = match self.tree.prefix_get(&mut path.iter()) {
+                Some(data) => data,
+                None => continue,
+            };
+            match &data.stage {
+                ArtifactMaterializationStage::Materialized {
+                    active: true,
+                    rematerialize: Some((entry, method)),
+                    ..
+                } => {
+                    let value = ArtifactValue::new(entry.dupe(), data.deps.dupe());
+                    to_redeclare.push((path, value, method.dupe()));
+                }
+                _ => to_delete.push(path),
+            }
+        }
+
+        for (path, value, method) in to_redeclare {
+            self.declare_unchecked(&path, value, method);
+        }
+        to_delete
+    }
+
+    /// Evict the artifacts that don't fit `self.gc_policy`, unless GC is already running. Evicted
+    /// artifacts are removed from the tree right away, and deleted from disk in the background.
+    pub(super) fn run_gc(&mut self) {
+        if let Some(mut instance) = self.gc_instance.take() {
+            if let Err(oneshot::error::TryRecvError::Empty) = instance.try_recv() {
+                self.gc_instance = Some(instance);
+                return;
+            }
+        }
+
+        let paths = match self.sqlite_db.as_mut() {
+            Some(sqlite_db) => {
+                let candidates = find_gc_candidates(&self.tree, self.gc_policy, Utc::now());
+                if !candidates.is_empty() {
+                    let bytes = candidates.iter().map(|c| c.size).sum::<u64>();
+                    tracing::info!(
+                        "Materializer GC: evicting {} artifacts ({} bytes)",
+                        candidates.len(),
+                        bytes
+                    );
+                }
+                candidates.into_iter().map(|c| c.path).collect()
+            }
+            None => {
+                tracing::debug!("Skipping materializer GC: sqlite materializer state is disabled");
+                Vec::new()
+            }
+        };
+        // Lazy output blobs aren't tracked, so they are trimmed with or without sqlite.
+        let lazy_blobs = self.lazy_blobs.dupe();
+        if paths.is_empty() && lazy_blobs.is_none() {
+            return;
+        }
+
+        let paths = self.redeclare_evicted(paths);
+        let existing_futs = self
+            .tree
+            .invalidate_paths_and_collect_futures(paths.clone(), self.sqlite_db.as_mut());
+
+        let io = self.io.dupe();
+        let policy = self.gc_policy;
+        let (tx, rx) = oneshot::channel();
+        self.rt.spawn(async move {
+            let res = async {
+                join_all_existing_futs(existing_futs).await?;
+                if !paths.is_empty() {
+                    io.clean_untracked_paths(paths).await?;
+                }
+                if let Some(lazy_blobs) = lazy_blobs {
+                    lazy_blobs.gc(policy).await?;
+                }
+                anyhow::Ok(())
+            }
+            .await;
+            if let Err(e) = res {
+                tracing::warn!("Materializer GC failed: {:#}", e);
+            }
+            let _ignored = tx.send(());
+        });
+        self.gc_instance = Some(rx);
+    }
+}
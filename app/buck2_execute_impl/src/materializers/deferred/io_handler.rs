@@ -15,6 +15,7 @@ use std::sync::Arc;
 use anyhow::Context;
 use async_trait::async_trait;
 use buck2_common::file_ops::FileDigest;
+use buck2_common::file_ops::FileMetadata;
 use buck2_common::result::SharedError;
 use buck2_common::result::ToSharedResultExt;
 use buck2_core::directory::unordered_entry_walk;
@@ -32,8 +33,10 @@ use buck2_execute::directory::ActionSharedDirectory;
 use buck2_execute::execute::blocking::BlockingExecutor;
 use buck2_execute::execute::blocking::IoRequest;
 use buck2_execute::execute::clean_output_paths::cleanup_path;
+use buck2_execute::execute::clean_output_paths::CleanOutputPaths;
 use buck2_execute::materialize::http::http_client;
 use buck2_execute::materialize::http::http_download;
+use buck2_execute::materialize::materializer::CasDownloadInfo;
 use buck2_execute::output_size::OutputSize;
 use buck2_execute::re::manager::ReConnectionManager;
 use chrono::Duration;
@@ -52,8 +55,10 @@ use remote_execution::TCode;
 use remote_execution::TDigest;
 use tracing::instrument;
 
+use crate::materializers::deferred::verify::verify_artifact;
 use crate::materializers::deferred::ArtifactMaterializationMethod;
 use crate::materializers::deferred::ArtifactMaterializationStage;
+use crate::materializers::deferred::ArtifactMetadata;
 use crate::materializers::deferred::ArtifactTree;
 use crate::materializers::deferred::LowPriorityMaterializerCommand;
 use crate::materializers::deferred::MaterializationMethodToProto;
@@ -64,6 +69,9 @@ use crate::materializers::deferred::Version;
 use crate::materializers::deferred::WriteFile;
 use crate::materializers::io::materialize_files;
 use crate::materializers::io::MaterializeTreeStructure;
+use crate::materializers::local_cas::LocalCas;
+use crate::materializers::verified_download::download_files;
+use crate::materializers::verified_download::DownloadVerificationStat;
 
 pub(super) struct DefaultIoHandler {
     pub(super) fs: ProjectRoot,
@@ -72,11 +80,18 @@ pub(super) struct DefaultIoHandler {
     pub(super) re_client_manager: Arc<ReConnectionManager>,
     /// Executor for blocking IO operations
     pub(super) io_executor: Arc<dyn BlockingExecutor>,
+    /// Where to look for files before downloading them, if configured.
+    pub(super) local_cas: Option<Arc<LocalCas>>,
+    /// Whether to check the digests of files downloaded from the CAS.
+    pub(super) verify_downloads: bool,
+    /// The lazy outputs state dir, if they are enabled. See `lazy.rs`.
+    pub(super) lazy_outputs: Option<ProjectRelativePathBuf>,
 }
 
 struct MaterializationStat {
     file_count: u64,
     total_bytes: u64,
+    verification: DownloadVerificationStat,
 }
 
 #[async_trait]
@@ -96,6 +111,20 @@ pub(super) trait IoHandler: Sized + Sync + Send + 'static {
         command_sender: MaterializerSender<Self>,
     ) -> BoxFuture<'static, Result<(), SharedError>>;
 
+    /// Delete paths that were already removed from the materializer's tree.
+    fn clean_untracked_paths(
+        self: &Arc<Self>,
+        paths: Vec<ProjectRelativePathBuf>,
+    ) -> BoxFuture<'static, anyhow::Result<()>>;
+
+    /// Check that materialized artifacts still match their metadata, reading file contents only
+    /// if `check_digests` is set. Returns the ones that don't.
+    fn verify_artifacts(
+        self: &Arc<Self>,
+        artifacts: Vec<(ProjectRelativePathBuf, ArtifactMetadata, Version)>,
+        check_digests: bool,
+    ) -> BoxFuture<'static, Vec<(ProjectRelativePathBuf, Version, anyhow::Error)>>;
+
     async fn materialize_entry(
         self: &Arc<Self>,
         path: ProjectRelativePathBuf,
@@ -113,6 +142,53 @@ pub(super) trait IoHandler: Sized + Sync + Send + 'static {
 }
 
 impl DefaultIoHandler {
+    async fn download_from_re(
+        &self,
+        files: Vec<(ProjectRelativePathBuf, FileMetadata)>,
+        info: &Arc<CasDownloadInfo>,
+    ) -> Result<(), MaterializeEntryError> {
+        let re_files = files
+            .iter()
+            .map(|(name, f)| {
+                let name = name.to_string();
+                let digest = maybe_tombstone_digest(f.digest.data())?.to_re();
+
+                tracing::trace!(name = %name, digest = %digest, "push download");
+
+                Ok(NamedDigestWithPermissions {
+                    named_digest: NamedDigest {
+                        name,
+                        digest,
+                        ..Default::default()
+                    },
+                    is_executable: f.is_executable,
+                    ..Default::default()
+                })
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        let connection = self.re_client_manager.get_re_connection();
+        let re_client = connection.get_client();
+
+        re_client
+            .materialize_files(re_files, info.re_use_case)
+            .await
+            .map_err(|e| match e.downcast_ref::<REClientError>() {
+                Some(e) if e.code == TCode::NOT_FOUND => MaterializeEntryError::NotFound {
+                    info: info.dupe(),
+                    debug: Arc::from(e.message.as_str()),
+                },
+                _ => MaterializeEntryError::Error(e.context({
+                    format!(
+                        "Error materializing files declared by action: {}",
+                        info.origin
+                    )
+                })),
+            })?;
+
+        Ok(())
+    }
+
     /// Materializes an `entry` at `path`, using the materialization `method`
     #[instrument(level = "debug", skip(self, stat), fields(path = %path, method = %method, entry = %entry))]
     async fn materialize_entry_span(
@@ -140,56 +216,52 @@ impl DefaultIoHandler {
 
                     while let Some((entry_path, entry)) = walk.next() {
                         if let DirectoryEntry::Leaf(ActionDirectoryMember::File(f)) = entry {
-                            let name = path.join_normalized(entry_path.get())?.to_string();
-                            let digest = maybe_tombstone_digest(f.digest.data())?.to_re();
-
-                            tracing::trace!(name = %name, digest = %digest, "push download");
-
-                            files.push(NamedDigestWithPermissions {
-                                named_digest: NamedDigest {
-                                    name,
-                                    digest,
-                                    ..Default::default()
-                                },
-                                is_executable: f.is_executable,
-                                ..Default::default()
-                            });
+                            files.push((path.join_normalized(entry_path.get())?, f.dupe()));
                         }
                     }
                 }
                 stat.file_count = files.len().try_into().unwrap_or_default();
-                stat.total_bytes = files
-                    .iter()
-                    .map(|x| u64::try_from(x.named_digest.digest.size_in_bytes).unwrap_or_default())
-                    .sum();
-
-                let connection = self.re_client_manager.get_re_connection();
-                let re_client = connection.get_client();
-
-                re_client
-                    .materialize_files(files, info.re_use_case)
-                    .await
-                    .map_err(|e| match e.downcast_ref::<REClientError>() {
-                        Some(e) if e.code == TCode::NOT_FOUND => MaterializeEntryError::NotFound {
-                            info: info.dupe(),
-                            debug: Arc::from(e.message.as_str()),
-                        },
-                        _ => MaterializeEntryError::Error(e.context({
-                            format!(
-                                "Error materializing files declared by action: {}",
-                                info.origin
-                            )
-                        })),
-                    })?;
+                stat.total_bytes = files.iter().map(|(_, f)| f.digest.size()).sum();
+
+                if let Some(local_cas) = &self.local_cas {
+                    files = self
+                        .io_executor
+                        .execute_io_inline(|| local_cas.materialize_many(&self.fs, files))
+                        .await?;
+                    if files.is_empty() {
+                        return Ok(());
+                    }
+                }
+
+                download_files(
+                    &self.fs,
+                    self.digest_config,
+                    self.io_executor.as_ref(),
+                    self.verify_downloads,
+                    &files,
+                    &mut stat.verification,
+                    |files| self.download_from_re(files, info),
+                )
+                .await?;
+
+                if let Some(local_cas) = &self.local_cas {
+                    self.io_executor
+                        .execute_io_inline(|| {
+                            local_cas.insert_many(&self.fs, &files);
+                            Ok(())
+                        })
+                        .await?;
+                }
             }
             ArtifactMaterializationMethod::HttpDownload { info } => {
                 async {
                     let downloaded = http_download(
-                        &http_client()?,
+                        &http_client(info.config.dupe())?,
+                        self.io_executor.as_ref(),
                         &self.fs,
                         self.digest_config,
                         &path,
-                        &info.url,
+                        &info.urls,
                         &info.checksum,
                         info.metadata.is_executable,
                     )
@@ -292,6 +364,45 @@ impl IoHandler for DefaultIoHandler {
             .boxed()
     }
 
+    fn clean_untracked_paths(
+        self: &Arc<Self>,
+        paths: Vec<ProjectRelativePathBuf>,
+    ) -> BoxFuture<'static, anyhow::Result<()>> {
+        self.io_executor
+            .execute_io(Box::new(CleanOutputPaths { paths }))
+    }
+
+    fn verify_artifacts(
+        self: &Arc<Self>,
+        artifacts: Vec<(ProjectRelativePathBuf, ArtifactMetadata, Version)>,
+        check_digests: bool,
+    ) -> BoxFuture<'static, Vec<(ProjectRelativePathBuf, Version, anyhow::Error)>> {
+        let this = self.dupe();
+        async move {
+            let digest_config = check_digests.then_some(this.digest_config);
+            let res = this
+                .io_executor
+                .execute_io_inline(|| {
+                    let mut failures = Vec::new();
+                    for (path, metadata, version) in artifacts {
+                        if let Err(e) = verify_artifact(&this.fs, &path, &metadata, digest_config) {
+                            failures.push((path, version, e));
+                        }
+                    }
+                    Ok(failures)
+                })
+                .await;
+            match res {
+                Ok(failures) => failures,
+                Err(e) => {
+                    tracing::warn!("Failed to verify materialized artifacts: {:#}", e);
+                    Vec::new()
+                }
+            }
+        }
+        .boxed()
+    }
+
     /// Materializes an `entry` at `path`, using the materialization `method`
     #[instrument(level = "debug", skip(self), fields(path = %path, method = %method, entry = %entry))]
     async fn materialize_entry(
@@ -315,6 +426,7 @@ impl IoHandler for DefaultIoHandler {
                 let mut stat = MaterializationStat {
                     file_count: 0,
                     total_bytes: 0,
+                    verification: DownloadVerificationStat::default(),
                 };
                 let res = self
                     .materialize_entry_span(path, method.dupe(), entry, &mut stat)
@@ -331,6 +443,8 @@ impl IoHandler for DefaultIoHandler {
                         success: error.is_none(),
                         error,
                         method: Some(method.to_proto() as i32),
+                        verified_file_count: stat.verification.verified_file_count,
+                        corrupt_file_count: stat.verification.corrupt_file_count,
                     },
                 )
             })
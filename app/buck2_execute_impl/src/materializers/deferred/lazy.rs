@@ -0,0 +1,596 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+//! The parts of lazy outputs that don't depend on FUSE: what the filesystem serves, and the cache
+//! of the blobs it fetched. The filesystem itself is in `fuse.rs`.
+
+// The filesystem is only built on Linux.
+#![cfg_attr(not(target_os = "linux"), allow(dead_code))]
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use anyhow::Context as _;
+use async_trait::async_trait;
+use buck2_common::executor_config::RemoteExecutorUseCase;
+use buck2_common::file_ops::FileMetadata;
+use buck2_core::directory::DirectoryEntry;
+use buck2_core::fs::fs_util;
+use buck2_core::fs::paths::file_name::FileName;
+use buck2_core::fs::paths::file_name::FileNameBuf;
+use buck2_core::fs::paths::forward_rel_path::ForwardRelativePath;
+use buck2_core::fs::project::ProjectRoot;
+use buck2_core::fs::project_rel_path::ProjectRelativePath;
+use buck2_core::fs::project_rel_path::ProjectRelativePathBuf;
+use buck2_execute::digest::CasDigestToReExt;
+use buck2_execute::directory::ActionDirectory;
+use buck2_execute::directory::ActionDirectoryMember;
+use buck2_execute::execute::blocking::BlockingExecutor;
+use buck2_execute::materialize::materializer::CasDownloadInfo;
+use buck2_execute::re::manager::ReConnectionManager;
+use chrono::DateTime;
+use chrono::Utc;
+use dupe::Dupe;
+use parking_lot::Mutex;
+use remote_execution::NamedDigest;
+use remote_execution::NamedDigestWithPermissions;
+use tokio::sync::oneshot;
+use tokio::sync::OnceCell;
+
+use crate::materializers::deferred::file_tree::DataTree;
+use crate::materializers::deferred::io_handler::DefaultIoHandler;
+use crate::materializers::deferred::ArtifactMaterializationData;
+use crate::materializers::deferred::ArtifactMaterializationMethod;
+use crate::materializers::deferred::ArtifactMaterializationStage;
+use crate::materializers::deferred::ArtifactTree;
+use crate::materializers::deferred::GcPolicy;
+use crate::materializers::deferred::MaterializerCommand;
+use crate::materializers::deferred::MaterializerSender;
+use crate::materializers::deferred::Processing;
+
+/// Where the outputs that aren't lazy are, given the lazy outputs state dir.
+pub(crate) fn backing_dir(dir: &ProjectRelativePath) -> ProjectRelativePathBuf {
+    dir.join(ForwardRelativePath::unchecked_new("gen"))
+}
+
+/// Where the blobs of lazy outputs are cached, given the lazy outputs state dir.
+pub(crate) fn blobs_dir(dir: &ProjectRelativePath) -> ProjectRelativePathBuf {
+    dir.join(ForwardRelativePath::unchecked_new("blobs"))
+}
+
+#[derive(Copy, Clone, Dupe, Debug, PartialEq, Eq)]
+pub(crate) enum LazyKind {
+    Dir,
+    File,
+    Symlink,
+}
+
+/// An entry of the lazy outputs filesystem that isn't on disk.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum LazyEntry {
+    /// A directory that contains artifacts served lazily, and its children that are or contain
+    /// such artifacts. Outputs can be created in it.
+    Parent(Vec<(FileNameBuf, LazyKind)>),
+    /// A directory in an artifact, and its children.
+    Dir(Vec<(FileNameBuf, LazyKind)>),
+    /// A file in an artifact, fetched from the CAS when it's read.
+    File(FileMetadata, RemoteExecutorUseCase),
+    Symlink(PathBuf),
+}
+
+impl LazyEntry {
+    fn new(
+        entry: DirectoryEntry<&dyn ActionDirectory, &ActionDirectoryMember>,
+        info: &CasDownloadInfo,
+    ) -> Self {
+        match entry {
+            DirectoryEntry::Dir(d) => Self::Dir(
+                d.entries()
+                    .map(|(name, child)| (name.to_owned(), member_kind(child)))
+                    .collect(),
+            ),
+            DirectoryEntry::Leaf(ActionDirectoryMember::File(meta)) => {
+                Self::File(meta.dupe(), info.re_use_case)
+            }
+            DirectoryEntry::Leaf(ActionDirectoryMember::Symlink(s)) => {
+                Self::Symlink(PathBuf::from(s.target().as_str()))
+            }
+            DirectoryEntry::Leaf(ActionDirectoryMember::ExternalSymlink(s)) => {
+                Self::Symlink(s.to_path_buf())
+            }
+        }
+    }
+
+    pub(crate) fn kind(&self) -> LazyKind {
+        match self {
+            Self::Parent(..) | Self::Dir(..) => LazyKind::Dir,
+            Self::File(..) => LazyKind::File,
+            Self::Symlink(..) => LazyKind::Symlink,
+        }
+    }
+
+    pub(crate) fn children(&self) -> Option<&[(FileNameBuf, LazyKind)]> {
+        match self {
+            Self::Parent(children) | Self::Dir(children) => Some(children),
+            Self::File(..) | Self::Symlink(..) => None,
+        }
+    }
+}
+
+fn member_kind<D: ?Sized>(entry: DirectoryEntry<&D, &ActionDirectoryMember>) -> LazyKind {
+    match entry {
+        DirectoryEntry::Dir(..) => LazyKind::Dir,
+        DirectoryEntry::Leaf(ActionDirectoryMember::File(..)) => LazyKind::File,
+        DirectoryEntry::Leaf(..) => LazyKind::Symlink,
+    }
+}
+
+impl ArtifactMaterializationData {
+    /// The entry at `path` in this artifact, if it's served lazily: it was declared as a CAS
+    /// download, and nothing is materializing it or cleaning its path.
+    fn lazy_entry<'a>(&self, path: impl Iterator<Item = &'a FileName>) -> Option<LazyEntry> {
+        let (entry, info) = match (&self.stage, &self.processing) {
+            (ArtifactMaterializationStage::Declared { entry, method }, Processing::Done(..)) => {
+                match &**method {
+                    ArtifactMaterializationMethod::CasDownload { info } => (entry, info),
+                    _ => return None,
+                }
+            }
+            _ => return None,
+        };
+
+        let mut entry = entry.as_ref().map_dir(|d| d as &dyn ActionDirectory);
+        for name in path {
+            entry = match entry {
+                DirectoryEntry::Dir(d) => d.get(name)?,
+                DirectoryEntry::Leaf(..) => return None,
+            };
+        }
+        Some(LazyEntry::new(entry, info))
+    }
+}
+
+impl ArtifactTree {
+    /// The entry the lazy outputs filesystem serves at `path`, if any: what's in the artifacts
+    /// that are served lazily, and the directories that contain them.
+    pub(super) fn lazy_entry(&self, path: &ProjectRelativePath) -> Option<LazyEntry> {
+        let mut path_iter = path.iter();
+        if let Some(data) = self.prefix_get(&mut path_iter) {
+            return data.lazy_entry(path_iter);
+        }
+
+        let children = self
+            .get_subtree(&mut path.iter())
+            .ok()??
+            .children()?
+            .iter()
+            .filter_map(|(name, child)| Some((name.clone(), lazy_kind(child)?)))
+            .collect::<Vec<_>>();
+        if children.is_empty() {
+            return None;
+        }
+        Some(LazyEntry::Parent(children))
+    }
+}
+
+/// The kind of `tree` in the lazy outputs filesystem, if it's or contains artifacts served lazily.
+fn lazy_kind(tree: &ArtifactTree) -> Option<LazyKind> {
+    match tree {
+        DataTree::Data(data) => Some(data.lazy_entry(std::iter::empty())?.kind()),
+        DataTree::Tree(children) => children
+            .values()
+            .any(|child| lazy_kind(child).is_some())
+            .then_some(LazyKind::Dir),
+    }
+}
+
+/// Looks up the entries the lazy outputs filesystem serves. This is the deferred materializer,
+/// except in tests.
+pub(crate) trait LazyEntries: Send + Sync + 'static {
+    /// Blocks until the entry is found, so this must not be called from an async context.
+    fn get(&self, path: ProjectRelativePathBuf) -> anyhow::Result<Option<LazyEntry>>;
+}
+
+impl LazyEntries for MaterializerSender<DefaultIoHandler> {
+    fn get(&self, path: ProjectRelativePathBuf) -> anyhow::Result<Option<LazyEntry>> {
+        let (sender, recv) = oneshot::channel();
+        self.send(MaterializerCommand::GetLazyEntry(path, sender))?;
+        recv.blocking_recv()
+            .context("Recv'ing lazy entry from command thread.")
+    }
+}
+
+/// Fetches blobs from the CAS. This is the RE client, except in tests.
+#[async_trait]
+pub(crate) trait BlobFetcher: Send + Sync + 'static {
+    /// Write the blob described by `meta` at `path`.
+    async fn fetch(
+        &self,
+        path: &ProjectRelativePath,
+        meta: &FileMetadata,
+        re_use_case: RemoteExecutorUseCase,
+    ) -> anyhow::Result<()>;
+}
+
+#[async_trait]
+impl BlobFetcher for ReConnectionManager {
+    async fn fetch(
+        &self,
+        path: &ProjectRelativePath,
+        meta: &FileMetadata,
+        re_use_case: RemoteExecutorUseCase,
+    ) -> anyhow::Result<()> {
+        self.get_re_connection()
+            .get_client()
+            .materialize_files(
+                vec![NamedDigestWithPermissions {
+                    named_digest: NamedDigest {
+                        name: path.to_string(),
+                        digest: meta.digest.to_re(),
+                        ..Default::default()
+                    },
+                    is_executable: meta.is_executable,
+                    ..Default::default()
+                }],
+                re_use_case,
+            )
+            .await
+    }
+}
+
+/// The blobs of the lazy files that were read, named `{digest}_{size}`. Opening a blob sets its
+/// mtime, which is what GC takes as its last access time, since blobs aren't tracked by the
+/// materializer.
+pub(crate) struct LazyBlobCache {
+    fs: ProjectRoot,
+    dir: ProjectRelativePathBuf,
+    fetcher: Arc<dyn BlobFetcher>,
+    io_executor: Arc<dyn BlockingExecutor>,
+    /// Blobs being fetched, so that concurrent reads of a blob only fetch it once.
+    fetching: Mutex<HashMap<String, Arc<OnceCell<()>>>>,
+}
+
+impl LazyBlobCache {
+    pub(crate) fn new(
+        fs: ProjectRoot,
+        dir: ProjectRelativePathBuf,
+        fetcher: Arc<dyn BlobFetcher>,
+        io_executor: Arc<dyn BlockingExecutor>,
+    ) -> anyhow::Result<Self> {
+        // Blobs that a previous daemon was fetching are incomplete.
+        let tmp_dir = fs.resolve(&dir.join(ForwardRelativePath::unchecked_new("tmp")));
+        fs_util::remove_all(&tmp_dir)?;
+        fs_util::create_dir_all(&tmp_dir)?;
+
+        Ok(Self {
+            fs,
+            dir,
+            fetcher,
+            io_executor,
+            fetching: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub(crate) fn blob_name(meta: &FileMetadata) -> String {
+        format!("{}_{}", meta.digest.raw_digest(), meta.digest.size())
+    }
+
+    /// Open the blob of `meta`, fetching it first if it isn't in the cache.
+    pub(crate) async fn open(
+        &self,
+        meta: &FileMetadata,
+        re_use_case: RemoteExecutorUseCase,
+    ) -> anyhow::Result<File> {
+        static NEXT_TMP: AtomicU64 = AtomicU64::new(0);
+
+        let name = Self::blob_name(meta);
+        let path = self.dir.join(ForwardRelativePath::unchecked_new(&name));
+
+        let fetching = self.fetching.lock().entry(name.clone()).or_default().dupe();
+        let fetched = fetching
+            .get_or_try_init(|| async {
+                let cached = self
+                    .io_executor
+                    .execute_io_inline(|| fs_util::try_exists(self.fs.resolve(&path)))
+                    .await?;
+                if cached {
+                    return Ok(());
+                }
+
+                // Fetch to a temporary path and rename, so that the cache never has partial blobs.
+                let tmp = self.dir.join(ForwardRelativePath::unchecked_new(&format!(
+                    "tmp/{}.{}",
+                    name,
+                    NEXT_TMP.fetch_add(1, Ordering::Relaxed)
+                )));
+                self.fetcher
+                    .fetch(&tmp, meta, re_use_case)
+                    .await
+                    .with_context(|| format!("Error fetching `{}`", name))?;
+                self.io_executor
+                    .execute_io_inline(|| {
+                        fs_util::rename(self.fs.resolve(&tmp), self.fs.resolve(&path))
+                    })
+                    .await
+            })
+            .await
+            .map(|_| ());
+        self.fetching.lock().remove(&name);
+        fetched?;
+
+        self.io_executor
+            .execute_io_inline(|| {
+                let path = self.fs.resolve(&path);
+                let file = File::open(&path).with_context(|| format!("open({})", path))?;
+                touch(&file).with_context(|| format!("Error touching `{}`", path))?;
+                Ok(file)
+            })
+            .await
+    }
+
+    /// Evict the blobs that don't fit `policy`, least recently opened first. Blobs that are open
+    /// can still be read after they are evicted.
+    pub(crate) async fn gc(&self, policy: GcPolicy) -> anyhow::Result<()> {
+        self.io_executor
+            .execute_io_inline(|| self.gc_blocking(policy, Utc::now()))
+            .await
+    }
+
+    fn gc_blocking(&self, policy: GcPolicy, now: DateTime<Utc>) -> anyhow::Result<()> {
+        let mut blobs = Vec::new();
+        let mut total_size = 0;
+        for entry in fs_util::read_dir(self.fs.resolve(&self.dir))? {
+            let entry = entry?;
+            let meta = entry.metadata()?;
+            // Skip the temporary dir.
+            if !meta.is_file() {
+                continue;
+            }
+            let last_access_time = DateTime::<Utc>::from(meta.modified()?);
+            total_size += meta.len();
+            blobs.push((last_access_time, meta.len(), entry.path()));
+        }
+        blobs.sort();
+
+        let mut evicted = 0;
+        for (last_access_time, size, path) in blobs {
+            let too_old = policy
+                .max_age
+                .map_or(false, |max_age| last_access_time < now - max_age);
+            let too_big = policy
+                .max_size
+                .map_or(false, |max_size| total_size > max_size);
+            if !too_old && !too_big {
+                break;
+            }
+            fs_util::remove_file(&path)?;
+            total_size -= size;
+            evicted += 1;
+        }
+
+        if evicted > 0 {
+            tracing::info!("Materializer GC: evicted {} lazy output blobs", evicted);
+        }
+        Ok(())
+    }
+}
+
+/// Set the mtime of `file` to now.
+#[cfg(unix)]
+fn touch(file: &File) -> std::io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    // SAFETY: `futimens` only reads the fd, and a null `times` means now.
+    if unsafe { libc::futimens(file.as_raw_fd(), std::ptr::null()) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Lazy outputs are only supported on Linux, so there are no blobs to touch elsewhere.
+#[cfg(not(unix))]
+fn touch(_file: &File) -> std::io::Result<()> {
+    Ok(())
+}
+
+/// The lazy outputs filesystem, mounted over `buck-out/v2/gen` until this is dropped. See
+/// `fuse.rs`.
+pub(super) struct LazyOutputs {
+    #[cfg(target_os = "linux")]
+    _mount: crate::materializers::fuse::LazyOutputsMount,
+}
+
+impl LazyOutputs {
+    #[cfg(target_os = "linux")]
+    pub(super) fn mount(
+        fs: &ProjectRoot,
+        gen: ProjectRelativePathBuf,
+        dir: &ProjectRelativePath,
+        entries: Arc<dyn LazyEntries>,
+        blobs: Arc<LazyBlobCache>,
+    ) -> anyhow::Result<Self> {
+        Ok(Self {
+            _mount: crate::materializers::fuse::mount(fs, gen, dir, entries, blobs)?,
+        })
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub(super) fn mount(
+        _fs: &ProjectRoot,
+        _gen: ProjectRelativePathBuf,
+        _dir: &ProjectRelativePath,
+        _entries: Arc<dyn LazyEntries>,
+        _blobs: Arc<LazyBlobCache>,
+    ) -> anyhow::Result<Self> {
+        Err(anyhow::anyhow!("Lazy outputs are only supported on Linux"))
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::time::Duration;
+    use std::time::SystemTime;
+
+    use buck2_common::file_ops::TrackedFileDigest;
+    use buck2_core::fs::project::ProjectRootTemp;
+    use buck2_execute::digest_config::DigestConfig;
+    use buck2_execute::execute::blocking::testing::DummyBlockingExecutor;
+
+    use super::*;
+
+    /// Serves blobs from memory, and counts fetches.
+    pub(crate) struct TestFetcher {
+        pub(crate) fs: ProjectRoot,
+        pub(crate) contents: HashMap<String, &'static str>,
+        pub(crate) fetches: AtomicU64,
+    }
+
+    #[async_trait]
+    impl BlobFetcher for TestFetcher {
+        async fn fetch(
+            &self,
+            path: &ProjectRelativePath,
+            meta: &FileMetadata,
+            _re_use_case: RemoteExecutorUseCase,
+        ) -> anyhow::Result<()> {
+            self.fetches.fetch_add(1, Ordering::Relaxed);
+            let contents = self
+                .contents
+                .get(&LazyBlobCache::blob_name(meta))
+                .context("Not found")?;
+            fs_util::write(self.fs.resolve(path), contents)
+        }
+    }
+
+    fn file(contents: &str) -> FileMetadata {
+        FileMetadata {
+            digest: TrackedFileDigest::from_content(
+                contents.as_bytes(),
+                DigestConfig::testing_default().cas_digest_config(),
+            ),
+            is_executable: false,
+        }
+    }
+
+    fn blob_cache(
+        temp: &ProjectRootTemp,
+        contents: &[&'static str],
+    ) -> anyhow::Result<(LazyBlobCache, Arc<TestFetcher>)> {
+        let fs = temp.path().dupe();
+        let fetcher = Arc::new(TestFetcher {
+            fs: fs.dupe(),
+            contents: contents
+                .iter()
+                .map(|c| (LazyBlobCache::blob_name(&file(c)), *c))
+                .collect(),
+            fetches: AtomicU64::new(0),
+        });
+        let blobs = LazyBlobCache::new(
+            fs.dupe(),
+            ProjectRelativePathBuf::unchecked_new("blobs".to_owned()),
+            fetcher.dupe(),
+            Arc::new(DummyBlockingExecutor { fs }),
+        )?;
+        Ok((blobs, fetcher))
+    }
+
+    fn blob_path(
+        temp: &ProjectRootTemp,
+        contents: &str,
+    ) -> buck2_core::fs::paths::abs_norm_path::AbsNormPathBuf {
+        temp.path()
+            .resolve(&ProjectRelativePathBuf::unchecked_new(format!(
+                "blobs/{}",
+                LazyBlobCache::blob_name(&file(contents))
+            )))
+    }
+
+    #[tokio::test]
+    async fn test_open() -> anyhow::Result<()> {
+        let temp = ProjectRootTemp::new()?;
+        let (blobs, fetcher) = blob_cache(&temp, &["hello"])?;
+        let re_use_case = RemoteExecutorUseCase::buck2_default();
+
+        blobs.open(&file("hello"), re_use_case).await?;
+        blobs.open(&file("hello"), re_use_case).await?;
+        assert_eq!(1, fetcher.fetches.load(Ordering::Relaxed));
+        assert_eq!("hello", fs_util::read_to_string(blob_path(&temp, "hello"))?);
+
+        // Nothing is left in the cache when a fetch fails.
+        assert!(blobs.open(&file("missing"), re_use_case).await.is_err());
+        assert!(!fs_util::try_exists(blob_path(&temp, "missing"))?);
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_gc() -> anyhow::Result<()> {
+        let temp = ProjectRootTemp::new()?;
+        let (blobs, _fetcher) = blob_cache(&temp, &["old", "recent"])?;
+        let re_use_case = RemoteExecutorUseCase::buck2_default();
+
+        blobs.open(&file("old"), re_use_case).await?;
+        let old = File::options().write(true).open(blob_path(&temp, "old"))?;
+        filetime_set(&old, SystemTime::now() - Duration::from_secs(3600))?;
+        blobs.open(&file("recent"), re_use_case).await?;
+
+        let exists = |contents| fs_util::try_exists(blob_path(&temp, contents)).unwrap();
+
+        blobs.gc_blocking(
+            GcPolicy {
+                max_size: None,
+                max_age: Some(chrono::Duration::hours(2)),
+            },
+            Utc::now(),
+        )?;
+        assert!(exists("old"));
+
+        // The least recently opened blob goes first.
+        blobs.gc_blocking(
+            GcPolicy {
+                max_size: Some(6),
+                max_age: None,
+            },
+            Utc::now(),
+        )?;
+        assert!(!exists("old"));
+        assert!(exists("recent"));
+
+        blobs.gc_blocking(
+            GcPolicy {
+                max_size: None,
+                max_age: Some(chrono::Duration::zero()),
+            },
+            Utc::now() + chrono::Duration::seconds(1),
+        )?;
+        assert!(!exists("recent"));
+        Ok(())
+    }
+
+    /// Set the mtime of `file`.
+    #[cfg(unix)]
+    fn filetime_set(file: &File, time: SystemTime) -> std::io::Result<()> {
+        use std::os::unix::io::AsRawFd;
+
+        let since_epoch = time.duration_since(SystemTime::UNIX_EPOCH).unwrap();
+        let time = libc::timespec {
+            tv_sec: since_epoch.as_secs() as libc::time_t,
+            tv_nsec: since_epoch.subsec_nanos() as _,
+        };
+        // SAFETY: `times` outlives the call.
+        if unsafe { libc::futimens(file.as_raw_fd(), [time, time].as_ptr()) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
@@ -10,13 +10,17 @@
 mod clean_stale;
 mod extension;
 mod file_tree;
+mod gc;
 mod io_handler;
+pub(crate) mod lazy;
 mod subscriptions;
+mod verify;
 
 #[cfg(test)]
 mod tests;
 
 use std::borrow::Cow;
+use std::collections::HashMap;
 use std::collections::VecDeque;
 use std::pin::Pin;
 use std::sync::atomic::AtomicUsize;
@@ -37,6 +41,7 @@ use buck2_common::result::ToUnsharedResultExt;
 use buck2_core::directory::unordered_entry_walk;
 use buck2_core::directory::DirectoryEntry;
 use buck2_core::env_helper::EnvHelper;
+use buck2_core::fs::paths::forward_rel_path::ForwardRelativePath;
 use buck2_core::fs::paths::RelativePathBuf;
 use buck2_core::fs::project::ProjectRoot;
 use buck2_core::fs::project_rel_path::ProjectRelativePath;
@@ -95,11 +100,19 @@ use tracing::instrument;
 
 use crate::materializers::deferred::extension::ExtensionCommand;
 use crate::materializers::deferred::file_tree::FileTree;
+pub use crate::materializers::deferred::gc::GcConfiguration;
+pub use crate::materializers::deferred::gc::GcPolicy;
 use crate::materializers::deferred::io_handler::DefaultIoHandler;
 use crate::materializers::deferred::io_handler::IoHandler;
+use crate::materializers::deferred::lazy::blobs_dir;
+use crate::materializers::deferred::lazy::LazyBlobCache;
+use crate::materializers::deferred::lazy::LazyEntry;
+use crate::materializers::deferred::lazy::LazyOutputs;
 use crate::materializers::deferred::subscriptions::MaterializerSubscriptionOperation;
 use crate::materializers::deferred::subscriptions::MaterializerSubscriptions;
+pub use crate::materializers::deferred::verify::VerifierConfiguration;
 use crate::materializers::immediate;
+use crate::materializers::local_cas::LocalCas;
 use crate::materializers::sqlite::MaterializerState;
 use crate::materializers::sqlite::MaterializerStateSqliteDb;
 
@@ -146,6 +159,10 @@ pub struct DeferredMaterializer {
 
     /// Tracked for logging purposes.
     materializer_state_info: buck2_data::MaterializerStateInfo,
+
+    /// The lazy outputs filesystem, if configured. Unmounts when dropped.
+    #[allocative(skip)]
+    _lazy_outputs: Option<LazyOutputs>,
 }
 
 impl Drop for DeferredMaterializer {
@@ -159,6 +176,15 @@ pub struct DeferredMaterializerConfigs {
     pub materialize_final_artifacts: bool,
     pub defer_write_actions: bool,
     pub ttl_refresh: TtlRefreshConfiguration,
+    pub gc: GcConfiguration,
+    pub verifier: VerifierConfiguration,
+    pub local_cas: Option<Arc<LocalCas>>,
+    /// Whether to check the digests of files downloaded from the CAS, and download them again
+    /// if they don't match.
+    pub verify_downloads: bool,
+    /// Where to keep the state of lazy outputs, if the RE outputs that aren't materialized should
+    /// be served from the CAS when read. See `lazy.rs`.
+    pub lazy_outputs: Option<ProjectRelativePathBuf>,
 }
 
 pub struct TtlRefreshConfiguration {
@@ -258,6 +284,17 @@ struct DeferredMaterializerCommandProcessor<T: 'static> {
     ttl_refresh_history: Vec<TtlRefreshHistoryEntry>,
     /// The current ttl_refresh instance, if any exists.
     ttl_refresh_instance: Option<oneshot::Receiver<(DateTime<Utc>, anyhow::Result<()>)>>,
+    /// What to evict from buck-out. See `gc.rs`.
+    gc_policy: GcPolicy,
+    /// The current GC instance, if any exists. Resolves when its deletions are done.
+    gc_instance: Option<oneshot::Receiver<()>>,
+    /// The blobs fetched for lazy outputs, which GC trims too.
+    lazy_blobs: Option<Arc<LazyBlobCache>>,
+    /// Whether the background verifier is checking artifacts. See `verify.rs`.
+    verification_in_progress: bool,
+    /// The modified artifacts the background verifier could not repair, and their version when
+    /// they were reported, so that they are only reported once.
+    verification_reported: HashMap<ProjectRelativePathBuf, Version>,
 }
 
 struct TtlRefreshHistoryEntry {
@@ -320,6 +357,14 @@ enum MaterializerCommand<T: 'static> {
         oneshot::Sender<Vec<Result<ProjectRelativePathBuf, ArtifactNotMaterializedReason>>>,
     ),
 
+    /// Takes a list of paths, and sends whether each of them is, or is in, a declared artifact
+    /// through the oneshot. See `Materializer::has_declared_artifacts` for more information.
+    HasDeclaredArtifacts(Vec<ProjectRelativePathBuf>, oneshot::Sender<Vec<bool>>),
+
+    /// Takes a path, and sends what the lazy outputs filesystem serves there through the oneshot.
+    /// See `lazy.rs`.
+    GetLazyEntry(ProjectRelativePathBuf, oneshot::Sender<Option<LazyEntry>>),
+
     /// Declares that a set of artifacts already exist
     DeclareExisting(
         Vec<(ProjectRelativePathBuf, ArtifactValue)>,
@@ -367,6 +412,12 @@ impl<T> std::fmt::Debug for MaterializerCommand<T> {
             MaterializerCommand::GetMaterializedFilePaths(paths, _) => {
                 write!(f, "GetMaterializedFilePaths({:?}, _)", paths,)
             }
+            MaterializerCommand::HasDeclaredArtifacts(paths, _) => {
+                write!(f, "HasDeclaredArtifacts({:?}, _)", paths,)
+            }
+            MaterializerCommand::GetLazyEntry(path, _) => {
+                write!(f, "GetLazyEntry({:?}, _)", path)
+            }
             MaterializerCommand::DeclareExisting(paths, current_span, trace_id) => {
                 write!(
                     f,
@@ -410,6 +461,12 @@ enum LowPriorityMaterializerCommand {
         version: Version,
         result: Result<(), SharedMaterializingError>,
     },
+
+    /// [Verifier task -> Command thread]
+    /// Notifies the command thread of the artifacts that don't match what's on disk.
+    VerificationFinished {
+        failures: Vec<(ProjectRelativePathBuf, Version, anyhow::Error)>,
+    },
 }
 
 /// Tree that stores materialization data for each artifact. Used internally by
@@ -555,9 +612,34 @@ enum ArtifactMaterializationStage {
         /// Should not be deleted without invalidating DICE nodes, which currently
         /// means killing the daemon.
         active: bool,
+        /// For active artifacts, how to materialize them again if they get modified on disk.
+        rematerialize: Option<Rematerialize>,
     },
 }
 
+/// The entry and method an artifact was declared with. DICE holds on to the entry of active
+/// artifacts anyway, so keeping it is cheap.
+type Rematerialize = (
+    ActionDirectoryEntry<ActionSharedDirectory>,
+    Arc<ArtifactMaterializationMethod>,
+);
+
+/// Artifacts that are downloaded or written can be materialized again in place, unlike local
+/// copies whose sources may have changed too.
+fn rematerialize_method(
+    entry: &ActionDirectoryEntry<ActionSharedDirectory>,
+    method: &Arc<ArtifactMaterializationMethod>,
+) -> Option<Rematerialize> {
+    match &**method {
+        ArtifactMaterializationMethod::CasDownload { .. }
+        | ArtifactMaterializationMethod::HttpDownload { .. }
+        | ArtifactMaterializationMethod::Write(..) => Some((entry.dupe(), method.dupe())),
+        ArtifactMaterializationMethod::LocalCopy(..) => None,
+        #[cfg(test)]
+        ArtifactMaterializationMethod::Test => None,
+    }
+}
+
 /// Different ways to materialize the files of an artifact. Some artifacts need
 /// to be fetched from the CAS, others copied locally.
 #[derive(Debug, Display)]
@@ -839,6 +921,19 @@ impl Materializer for DeferredMaterializer {
         Ok(recv.await?)
     }
 
+    async fn has_declared_artifacts(
+        &self,
+        paths: Vec<ProjectRelativePathBuf>,
+    ) -> anyhow::Result<Option<Vec<bool>>> {
+        if paths.is_empty() {
+            return Ok(Some(Vec::new()));
+        }
+        let (sender, recv) = oneshot::channel();
+        self.command_sender
+            .send(MaterializerCommand::HasDeclaredArtifacts(paths, sender))?;
+        Ok(Some(recv.await?))
+    }
+
     fn as_deferred_materializer_extension(&self) -> Option<&dyn DeferredMaterializerExtensions> {
         Some(self as _)
     }
@@ -895,6 +990,7 @@ impl DeferredMaterializer {
                             metadata,
                             last_access_time,
                             active: false,
+                            rematerialize: None,
                         },
                         processing: Processing::Done(Version(0)),
                     }),
@@ -902,6 +998,17 @@ impl DeferredMaterializer {
             }
         }
 
+        let lazy_blobs = match &configs.lazy_outputs {
+            Some(dir) => Some(Arc::new(LazyBlobCache::new(
+                fs.dupe(),
+                blobs_dir(dir),
+                re_client_manager.dupe(),
+                io_executor.dupe(),
+            )?)),
+            None => None,
+        };
+        let gen = buck_out_path.join(ForwardRelativePath::unchecked_new("gen"));
+
         let command_processor = DeferredMaterializerCommandProcessor {
             io: Arc::new(DefaultIoHandler {
                 fs: fs.dupe(),
@@ -909,6 +1016,9 @@ impl DeferredMaterializer {
                 buck_out_path,
                 re_client_manager,
                 io_executor: io_executor.dupe(),
+                local_cas: configs.local_cas,
+                verify_downloads: configs.verify_downloads,
+                lazy_outputs: configs.lazy_outputs.clone(),
             }),
             digest_config,
             sqlite_db,
@@ -921,6 +1031,11 @@ impl DeferredMaterializer {
             subscriptions: MaterializerSubscriptions::new(),
             ttl_refresh_history: Vec::new(),
             ttl_refresh_instance: None,
+            gc_policy: configs.gc.policy,
+            gc_instance: None,
+            lazy_blobs: lazy_blobs.dupe(),
+            verification_in_progress: false,
+            verification_reported: HashMap::new(),
         };
 
         let command_thread = std::thread::Builder::new()
@@ -932,11 +1047,27 @@ impl DeferredMaterializer {
                         .build()
                         .unwrap();
 
-                    rt.block_on(command_processor.run(command_receiver, configs.ttl_refresh));
+                    rt.block_on(command_processor.run(
+                        command_receiver,
+                        configs.ttl_refresh,
+                        configs.gc,
+                        configs.verifier,
+                    ));
                 }
             })
             .context("Cannot start materializer thread")?;
 
+        let lazy_outputs = match (&configs.lazy_outputs, lazy_blobs) {
+            (Some(dir), Some(blobs)) => Some(LazyOutputs::mount(
+                &fs,
+                gen,
+                dir,
+                Arc::new(command_sender.dupe()),
+                blobs,
+            )?),
+            _ => None,
+        };
+
         Ok(Self {
             command_thread,
             command_sender,
@@ -946,6 +1077,7 @@ impl DeferredMaterializer {
             io_executor,
             digest_config,
             materializer_state_info,
+            _lazy_outputs: lazy_outputs,
         })
     }
 }
@@ -984,12 +1116,16 @@ struct CommandStream<T: 'static> {
     high_priority: UnboundedReceiver<MaterializerCommand<T>>,
     low_priority: UnboundedReceiver<LowPriorityMaterializerCommand>,
     refresh_ttl_ticker: Option<Interval>,
+    gc_ticker: Option<Interval>,
+    verify_ticker: Option<Interval>,
 }
 
 enum Op<T: 'static> {
     Command(MaterializerCommand<T>),
     LowPriorityCommand(LowPriorityMaterializerCommand),
     RefreshTtls,
+    Gc,
+    Verify,
 }
 
 impl<T: 'static> Stream for CommandStream<T> {
@@ -1012,6 +1148,18 @@ impl<T: 'static> Stream for CommandStream<T> {
             }
         }
 
+        if let Some(ticker) = this.gc_ticker.as_mut() {
+            if let Poll::Ready(..) = ticker.poll_tick(cx) {
+                return Poll::Ready(Some(Op::Gc));
+            }
+        }
+
+        if let Some(ticker) = this.verify_ticker.as_mut() {
+            if let Poll::Ready(..) = ticker.poll_tick(cx) {
+                return Poll::Ready(Some(Op::Verify));
+            }
+        }
+
         // We can never be done because we never drop the senders, so let's not bother.
 
         Poll::Pending
@@ -1026,6 +1174,8 @@ impl<T: IoHandler> DeferredMaterializerCommandProcessor<T> {
         mut self,
         commands: MaterializerReceiver<T>,
         ttl_refresh: TtlRefreshConfiguration,
+        gc: GcConfiguration,
+        verifier: VerifierConfiguration,
     ) {
         let MaterializerReceiver {
             high_priority,
@@ -1042,15 +1192,39 @@ impl<T: IoHandler> DeferredMaterializerCommandProcessor<T> {
             None
         };
 
+        let gc_ticker = if gc.policy.is_enabled() {
+            Some(tokio::time::interval_at(
+                tokio::time::Instant::now() + gc.frequency,
+                gc.frequency,
+            ))
+        } else {
+            None
+        };
+
+        let verify_ticker = if verifier.enabled {
+            Some(tokio::time::interval_at(
+                tokio::time::Instant::now() + verifier.frequency,
+                verifier.frequency,
+            ))
+        } else {
+            None
+        };
+
         let mut stream = CommandStream {
             high_priority,
             low_priority,
             refresh_ttl_ticker,
+            gc_ticker,
+            verify_ticker,
         };
 
+        // Used to only run GC and the verifier when the materializer is idle.
+        let mut last_command_time = tokio::time::Instant::now();
+
         while let Some(op) = stream.next().await {
             match op {
                 Op::Command(command) => {
+                    last_command_time = tokio::time::Instant::now();
                     self.log_buffer.push(format!("{:?}", command));
                     self.process_one_command(command);
                     counters.ack_received();
@@ -1097,6 +1271,18 @@ impl<T: IoHandler> DeferredMaterializerCommandProcessor<T> {
                         }
                     }
                 }
+                Op::Gc => {
+                    if last_command_time.elapsed() >= gc.idle_time && counters.queue_size() == 0 {
+                        self.run_gc();
+                    }
+                }
+                Op::Verify => {
+                    if last_command_time.elapsed() >= verifier.idle_time
+                        && counters.queue_size() == 0
+                    {
+                        self.start_verification();
+                    }
+                }
             }
         }
     }
@@ -1109,6 +1295,15 @@ impl<T: IoHandler> DeferredMaterializerCommandProcessor<T> {
                     paths.into_map(|p| self.tree.file_contents_path(p, self.digest_config));
                 result_sender.send(result).ok();
             }
+            // Entry point for `has_declared_artifacts` calls
+            MaterializerCommand::HasDeclaredArtifacts(paths, result_sender) => {
+                let result = paths.into_map(|p| self.tree.prefix_get(&mut p.iter()).is_some());
+                result_sender.send(result).ok();
+            }
+            // Entry point for the lazy outputs filesystem
+            MaterializerCommand::GetLazyEntry(path, result_sender) => {
+                result_sender.send(self.tree.lazy_entry(&path)).ok();
+            }
             MaterializerCommand::DeclareExisting(artifacts, ..) => {
                 for (path, artifact) in artifacts {
                     self.declare_existing(&path, artifact);
@@ -1176,6 +1371,9 @@ impl<T: IoHandler> DeferredMaterializerCommandProcessor<T> {
             } => {
                 self.tree.cleanup_finished(path, version, result);
             }
+            LowPriorityMaterializerCommand::VerificationFinished { failures } => {
+                self.verification_finished(failures);
+            }
         }
     }
 
@@ -1264,6 +1462,7 @@ impl<T: IoHandler> DeferredMaterializerCommandProcessor<T> {
                     metadata,
                     last_access_time: Utc::now(),
                     active: true,
+                    rematerialize: None,
                 },
                 processing: Processing::Done(self.version_tracker.next()),
             }),
@@ -1307,10 +1506,12 @@ impl<T: IoHandler> DeferredMaterializerCommandProcessor<T> {
                             "already materialized, updating deps only",
                         );
                         let deps = value.deps().duped();
+                        let method = Arc::from(method);
                         data.stage = ArtifactMaterializationStage::Materialized {
                             metadata: metadata.dupe(),
                             last_access_time: *last_access_time,
                             active: true,
+                            rematerialize: rematerialize_method(value.entry(), &method),
                         };
                         data.deps = deps;
 
@@ -1322,6 +1523,16 @@ impl<T: IoHandler> DeferredMaterializerCommandProcessor<T> {
         }
 
         // We don't have a matching artifact. Declare it.
+        self.declare_unchecked(path, value, Arc::from(method))
+    }
+
+    /// Declare an artifact, replacing whatever is at `path` even if it matches.
+    fn declare_unchecked(
+        &mut self,
+        path: &ProjectRelativePath,
+        value: ArtifactValue,
+        method: Arc<ArtifactMaterializationMethod>,
+    ) {
         let version = self.version_tracker.next();
 
         tracing::trace!(
@@ -1339,8 +1550,6 @@ impl<T: IoHandler> DeferredMaterializerCommandProcessor<T> {
             .tree
             .invalidate_paths_and_collect_futures(vec![path.to_owned()], self.sqlite_db.as_mut());
 
-        let method = Arc::from(method);
-
         // Dispatch Write actions eagerly if possible. We can do this if no cleanup is required. We
         // also check that there are no deps, though for writes there should never be deps.
 
@@ -1684,10 +1893,7 @@ impl<T: IoHandler> DeferredMaterializerCommandProcessor<T> {
                             tracing::debug!("artifact is already materialized");
                             None
                         }
-                        ArtifactMaterializationStage::Declared {
-                            entry,
-                            method: _method,
-                        } => {
+                        ArtifactMaterializationStage::Declared { entry, method } => {
                             let metadata = ArtifactMetadata::new(entry);
                             // NOTE: We only insert this artifact if there isn't an in-progress cleanup
                             // future on this path.
@@ -1705,6 +1911,7 @@ impl<T: IoHandler> DeferredMaterializerCommandProcessor<T> {
                                 metadata,
                                 last_access_time: timestamp,
                                 active: true,
+                                rematerialize: rematerialize_method(entry, method),
                             })
                         }
                     };
@@ -11,10 +11,15 @@ use std::borrow::Cow;
 use std::collections::HashMap;
 use std::collections::HashSet;
 
+use buck2_common::executor_config::RemoteExecutorUseCase;
 use buck2_common::file_ops::FileMetadata;
+use buck2_core::fs::fs_util;
+use buck2_core::fs::project::ProjectRootTemp;
 use buck2_execute::digest_config::DigestConfig;
 use buck2_execute::directory::insert_file;
+use buck2_execute::directory::insert_symlink;
 use buck2_execute::directory::ActionDirectoryBuilder;
+use buck2_execute::directory::Symlink;
 use buck2_execute::materialize::materializer::DeferredMaterializerSubscription;
 use dupe::Dupe;
 
@@ -86,6 +91,325 @@ fn test_remove_path() {
     assert_eq!(removed_subtree.get("a/b/c/e"), Some(&"a/b/c/e".to_owned()));
 }
 
+#[test]
+fn test_find_gc_candidates() {
+    fn insert(tree: &mut ArtifactTree, now: DateTime<Utc>, path: &str, size: usize, age: i64) {
+        let download = Arc::new(ArtifactMaterializationMethod::CasDownload {
+            info: Arc::new(CasDownloadInfo::new_declared(
+                RemoteExecutorUseCase::buck2_default(),
+            )),
+        });
+        let meta = FileMetadata {
+            digest: TrackedFileDigest::from_content(
+                &vec![0; size],
+                DigestConfig::testing_default().cas_digest_config(),
+            ),
+            is_executable: false,
+        };
+        let entry = ActionDirectoryEntry::Leaf(ActionDirectoryMember::File(meta));
+        tree.insert(
+            ProjectRelativePath::unchecked_new(path)
+                .iter()
+                .map(|f| f.to_owned()),
+            Box::new(ArtifactMaterializationData {
+                deps: None,
+                stage: ArtifactMaterializationStage::Materialized {
+                    metadata: ArtifactMetadata::new(&entry),
+                    last_access_time: now - Duration::hours(age),
+                    active: path.starts_with("in_use"),
+                    // Artifacts in use are only evicted if they can be downloaded again.
+                    rematerialize: match path {
+                        "in_use/downloaded" => rematerialize_method(&entry, &download),
+                        _ => None,
+                    },
+                },
+                processing: Processing::Done(Version(0)),
+            }),
+        );
+    }
+
+    let now = Utc::now();
+    let mut tree = ArtifactTree::new();
+    insert(&mut tree, now, "a/old", 10, 48);
+    insert(&mut tree, now, "b", 20, 5);
+    insert(&mut tree, now, "a/recent", 30, 1);
+    insert(&mut tree, now, "in_use/built", 40, 100);
+    insert(&mut tree, now, "in_use/downloaded", 50, 2);
+
+    let evicted = |max_size: Option<u64>, max_age: Option<i64>| {
+        let policy = GcPolicy {
+            max_size,
+            max_age: max_age.map(Duration::hours),
+        };
+        super::gc::find_gc_candidates(&tree, policy, now)
+            .into_iter()
+            .map(|c| c.path.to_string())
+            .collect::<Vec<_>>()
+    };
+
+    assert_eq!(Vec::<String>::new(), evicted(None, None));
+    assert_eq!(vec!["a/old"], evicted(None, Some(24)));
+    assert_eq!(vec!["a/old", "b"], evicted(Some(125), None));
+    assert_eq!(
+        vec!["a/old", "b", "in_use/downloaded", "a/recent"],
+        evicted(Some(10), None)
+    );
+    assert_eq!(vec!["a/old"], evicted(Some(145), Some(24)));
+    assert_eq!(
+        vec!["a/old", "b", "in_use/downloaded"],
+        evicted(None, Some(1))
+    );
+}
+
+#[test]
+fn test_lazy_entry() -> anyhow::Result<()> {
+    use buck2_core::fs::paths::file_name::FileNameBuf;
+
+    use super::lazy::LazyKind;
+
+    let digest_config = DigestConfig::testing_default();
+    let re_use_case = RemoteExecutorUseCase::buck2_default();
+    let file = FileMetadata::empty(digest_config.cas_digest_config());
+
+    let mut builder = ActionDirectoryBuilder::empty();
+    insert_file(
+        &mut builder,
+        ProjectRelativePath::unchecked_new("d/f"),
+        file.dupe(),
+    )?;
+    insert_symlink(
+        &mut builder,
+        ProjectRelativePath::unchecked_new("link"),
+        Arc::new(Symlink::new(RelativePathBuf::from("d/f"))),
+    )?;
+    let entry = DirectoryEntry::Dir(
+        builder
+            .fingerprint(digest_config.as_directory_serializer())
+            .shared(&*buck2_execute::directory::INTERNER),
+    );
+
+    let mut tree = ArtifactTree::new();
+    let mut insert = |path: &str, stage| {
+        tree.insert(
+            ProjectRelativePath::unchecked_new(path)
+                .iter()
+                .map(|f| f.to_owned()),
+            Box::new(ArtifactMaterializationData {
+                deps: None,
+                stage,
+                processing: Processing::Done(Version(0)),
+            }),
+        )
+    };
+    insert(
+        "out/lazy",
+        ArtifactMaterializationStage::Declared {
+            entry: entry.dupe(),
+            method: Arc::new(ArtifactMaterializationMethod::CasDownload {
+                info: Arc::new(CasDownloadInfo::new_declared(re_use_case)),
+            }),
+        },
+    );
+    insert(
+        "other/materialized",
+        ArtifactMaterializationStage::Materialized {
+            metadata: ArtifactMetadata::new(&entry),
+            last_access_time: Utc::now(),
+            active: true,
+            rematerialize: None,
+        },
+    );
+
+    let lazy_entry = |path: &str| tree.lazy_entry(ProjectRelativePath::unchecked_new(path));
+    let dir = |children: &[(&str, LazyKind)]| {
+        children
+            .iter()
+            .map(|(name, kind)| (FileNameBuf::unchecked_new(*name), *kind))
+            .collect::<Vec<_>>()
+    };
+
+    assert_eq!(
+        Some(LazyEntry::Parent(dir(&[("out", LazyKind::Dir)]))),
+        lazy_entry("")
+    );
+    assert_eq!(
+        Some(LazyEntry::Parent(dir(&[("lazy", LazyKind::Dir)]))),
+        lazy_entry("out")
+    );
+    assert_eq!(
+        Some(LazyEntry::Dir(dir(&[
+            ("d", LazyKind::Dir),
+            ("link", LazyKind::Symlink)
+        ]))),
+        lazy_entry("out/lazy")
+    );
+    assert_eq!(
+        Some(LazyEntry::File(file, re_use_case)),
+        lazy_entry("out/lazy/d/f")
+    );
+    assert_eq!(
+        Some(LazyEntry::Symlink("d/f".into())),
+        lazy_entry("out/lazy/link")
+    );
+    assert_eq!(None, lazy_entry("out/lazy/d/f/g"));
+    assert_eq!(None, lazy_entry("out/missing"));
+    // Materialized artifacts are on disk, and so are the directories that only contain them.
+    assert_eq!(None, lazy_entry("other"));
+    assert_eq!(None, lazy_entry("other/materialized"));
+    Ok(())
+}
+
+#[test]
+fn test_verify_artifact() -> anyhow::Result<()> {
+    let digest_config = DigestConfig::testing_default();
+    let file = |content: &str| {
+        ArtifactMetadata(DirectoryEntry::Leaf(ActionDirectoryMember::File(
+            FileMetadata {
+                digest: TrackedFileDigest::from_content(
+                    content.as_bytes(),
+                    digest_config.cas_digest_config(),
+                ),
+                is_executable: false,
+            },
+        )))
+    };
+
+    let temp = ProjectRootTemp::new()?;
+    temp.write_file("out/file", "hello");
+    let path = ProjectRelativePath::unchecked_new("out/file");
+    let verify = |metadata: &ArtifactMetadata, check_digests: bool| {
+        super::verify::verify_artifact(
+            temp.path(),
+            path,
+            metadata,
+            check_digests.then_some(digest_config),
+        )
+    };
+
+    verify(&file("hello"), true)?;
+    // Same size, different contents: only caught when checking digests.
+    verify(&file("hellO"), false)?;
+    assert!(verify(&file("hellO"), true).is_err());
+    assert!(verify(&file("hi"), false).is_err());
+
+    let symlink = ArtifactMetadata(DirectoryEntry::Leaf(ActionDirectoryMember::Symlink(
+        Arc::new(Symlink::new("file".into())),
+    )));
+    assert!(verify(&symlink, false).is_err());
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn test_verify_artifact_with_fifo() -> anyhow::Result<()> {
+    let digest_config = DigestConfig::testing_default();
+    let temp = ProjectRootTemp::new()?;
+    temp.write_file("out/dir/a", "hello");
+    let path = ProjectRelativePath::unchecked_new("out/dir");
+
+    let entry =
+        crate::materializers::io::build_entry_from_disk(temp.path().resolve(path), digest_config)?
+            .context("Expected an entry")?;
+    let metadata = ArtifactMetadata::new(&entry.map_dir(|d| {
+        d.fingerprint(digest_config.as_directory_serializer())
+            .shared(&*buck2_execute::directory::INTERNER)
+    }));
+
+    let fifo = temp
+        .path()
+        .resolve(ProjectRelativePath::unchecked_new("out/dir/fifo"));
+    {
+        use std::os::unix::ffi::OsStrExt;
+
+        let fifo = std::ffi::CString::new(fifo.as_path().as_os_str().as_bytes())?;
+        if unsafe { libc::mkfifo(fifo.as_ptr(), 0o600) } != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+    }
+
+    // A FIFO is an error rather than a panic, so the artifact gets reported as modified.
+    let err = super::verify::verify_artifact(temp.path(), path, &metadata, Some(digest_config))
+        .unwrap_err();
+    assert!(
+        err.is::<crate::materializers::io::UnknownFileTypeError>(),
+        "{:#}",
+        err
+    );
+
+    // Same for an artifact that is itself a FIFO.
+    let err = super::verify::verify_artifact(
+        temp.path(),
+        ProjectRelativePath::unchecked_new("out/dir/fifo"),
+        &metadata,
+        Some(digest_config),
+    )
+    .unwrap_err();
+    assert!(
+        format!("{:#}", err).contains("found a special file"),
+        "{:#}",
+        err
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_verify_directory_artifact() -> anyhow::Result<()> {
+    let digest_config = DigestConfig::testing_default();
+    let temp = ProjectRootTemp::new()?;
+    temp.write_file("out/dir/a", "hello");
+    temp.write_file("out/dir/sub/b", "world");
+    let path = ProjectRelativePath::unchecked_new("out/dir");
+
+    let entry =
+        crate::materializers::io::build_entry_from_disk(temp.path().resolve(path), digest_config)?
+            .context("Expected an entry")?;
+    let metadata = ArtifactMetadata::new(&entry.map_dir(|d| {
+        d.fingerprint(digest_config.as_directory_serializer())
+            .shared(&*buck2_execute::directory::INTERNER)
+    }));
+    let verify = |check_digests: bool| {
+        super::verify::verify_artifact(
+            temp.path(),
+            path,
+            &metadata,
+            check_digests.then_some(digest_config),
+        )
+    };
+
+    verify(false)?;
+    verify(true)?;
+
+    // Same size, different contents: only caught when checking digests.
+    temp.write_file("out/dir/sub/b", "World");
+    verify(false)?;
+    assert!(verify(true).is_err());
+    temp.write_file("out/dir/sub/b", "world!");
+    assert!(verify(false).is_err());
+    temp.write_file("out/dir/sub/b", "world");
+    verify(true)?;
+
+    // An extra empty file doesn't change the size.
+    temp.write_file("out/dir/sub/extra", "");
+    verify(false)?;
+    assert!(verify(true).is_err());
+    fs_util::remove_file(
+        temp.path()
+            .resolve(ProjectRelativePath::unchecked_new("out/dir/sub/extra")),
+    )?;
+    verify(true)?;
+
+    fs_util::remove_file(
+        temp.path()
+            .resolve(ProjectRelativePath::unchecked_new("out/dir/a")),
+    )?;
+    assert!(verify(false).is_err());
+    assert!(verify(true).is_err());
+
+    Ok(())
+}
+
 mod state_machine {
     use std::path::Path;
 
@@ -96,6 +420,7 @@ mod state_machine {
     use tokio::time::Duration as TokioDuration;
 
     use super::*;
+    use crate::materializers::deferred::verify::RepairOutcome;
 
     #[derive(Debug, Eq, PartialEq)]
     enum Op {
@@ -146,6 +471,24 @@ mod state_machine {
             futures::future::ready(Ok(())).boxed()
         }
 
+        fn clean_untracked_paths(
+            self: &Arc<Self>,
+            paths: Vec<ProjectRelativePathBuf>,
+        ) -> BoxFuture<'static, anyhow::Result<()>> {
+            self.log
+                .lock()
+                .extend(paths.into_iter().map(|path| (Op::Clean, path)));
+            futures::future::ready(Ok(())).boxed()
+        }
+
+        fn verify_artifacts(
+            self: &Arc<Self>,
+            _artifacts: Vec<(ProjectRelativePathBuf, ArtifactMetadata, Version)>,
+            _check_digests: bool,
+        ) -> BoxFuture<'static, Vec<(ProjectRelativePathBuf, Version, anyhow::Error)>> {
+            futures::future::ready(Vec::new()).boxed()
+        }
+
         async fn materialize_entry(
             self: &Arc<Self>,
             path: ProjectRelativePathBuf,
@@ -231,6 +574,11 @@ mod state_machine {
                 subscriptions: MaterializerSubscriptions::new(),
                 ttl_refresh_history: Default::default(),
                 ttl_refresh_instance: Default::default(),
+                gc_policy: Default::default(),
+                gc_instance: Default::default(),
+                lazy_blobs: None,
+                verification_in_progress: false,
+                verification_reported: Default::default(),
             },
             command_receiver,
         )
@@ -287,6 +635,142 @@ mod state_machine {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_repair_artifacts() -> anyhow::Result<()> {
+        let digest_config = DigestConfig::testing_default();
+        let (mut dm, _) = make_processor(digest_config, Default::default());
+
+        let value = ArtifactValue::file(digest_config.empty_file());
+        let mut insert = |path: &ProjectRelativePath,
+                          active: bool,
+                          method: Arc<ArtifactMaterializationMethod>| {
+            dm.tree.insert(
+                path.iter().map(|f| f.to_owned()),
+                Box::new(ArtifactMaterializationData {
+                    deps: None,
+                    stage: ArtifactMaterializationStage::Materialized {
+                        metadata: ArtifactMetadata::new(value.entry()),
+                        last_access_time: Utc::now(),
+                        active,
+                        rematerialize: rematerialize_method(value.entry(), &method),
+                    },
+                    processing: Processing::Done(Version(0)),
+                }),
+            );
+        };
+
+        // Left by a previous daemon.
+        let stale = make_path("foo/stale");
+        insert(&stale, false, Arc::new(ArtifactMaterializationMethod::Test));
+        // Written by this daemon.
+        let written = make_path("foo/written");
+        insert(
+            &written,
+            true,
+            Arc::new(ArtifactMaterializationMethod::Write(Arc::new(WriteFile {
+                compressed_data: Box::new([]),
+                decompressed_size: 0,
+                is_executable: false,
+            }))),
+        );
+        // Built by this daemon.
+        let built = make_path("foo/built");
+        insert(&built, true, Arc::new(ArtifactMaterializationMethod::Test));
+
+        let (fut, outcomes) = dm.repair_artifacts(vec![
+            stale.clone(),
+            written.clone(),
+            built.clone(),
+            make_path("foo/unknown"),
+        ]);
+        fut.await?;
+
+        let outcomes: Vec<_> = outcomes
+            .into_iter()
+            .map(|(path, outcome)| (path, outcome.to_string()))
+            .collect();
+        assert_eq!(
+            outcomes,
+            &[
+                (stale.clone(), RepairOutcome::Repaired.to_string()),
+                (written.clone(), RepairOutcome::Rematerialized.to_string()),
+                (built.clone(), RepairOutcome::InUse.to_string()),
+            ]
+        );
+
+        // Stale artifacts are forgotten and deleted.
+        assert!(dm.tree.prefix_get(&mut stale.iter()).is_none());
+        assert!(dm.io.take_log().contains(&(Op::Clean, stale.clone())));
+        // Written artifacts are declared again, and the others are left alone.
+        assert!(matches!(
+            dm.tree
+                .prefix_get(&mut written.iter())
+                .map(|data| &data.stage),
+            Some(ArtifactMaterializationStage::Declared { .. })
+        ));
+        assert!(matches!(
+            dm.tree
+                .prefix_get(&mut built.iter())
+                .map(|data| &data.stage),
+            Some(ArtifactMaterializationStage::Materialized { .. })
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_redeclare_evicted() -> anyhow::Result<()> {
+        let digest_config = DigestConfig::testing_default();
+        let (mut dm, _) = make_processor(digest_config, Default::default());
+
+        let value = ArtifactValue::file(digest_config.empty_file());
+        let download = Arc::new(ArtifactMaterializationMethod::CasDownload {
+            info: Arc::new(CasDownloadInfo::new_declared(
+                RemoteExecutorUseCase::buck2_default(),
+            )),
+        });
+        let mut insert = |path: &ProjectRelativePath, active: bool| {
+            dm.tree.insert(
+                path.iter().map(|f| f.to_owned()),
+                Box::new(ArtifactMaterializationData {
+                    deps: None,
+                    stage: ArtifactMaterializationStage::Materialized {
+                        metadata: ArtifactMetadata::new(value.entry()),
+                        last_access_time: Utc::now(),
+                        active,
+                        rematerialize: rematerialize_method(value.entry(), &download),
+                    },
+                    processing: Processing::Done(Version(0)),
+                }),
+            );
+        };
+
+        let stale = make_path("foo/stale");
+        insert(&stale, false);
+        let downloaded = make_path("foo/downloaded");
+        insert(&downloaded, true);
+
+        let to_delete = dm.redeclare_evicted(vec![stale.clone(), downloaded.clone()]);
+
+        // Stale artifacts are left for GC to forget and delete.
+        assert_eq!(to_delete, &[stale.clone()]);
+        // Artifacts in use are deleted, and downloaded again when they are materialized.
+        assert_eq!(dm.io.take_log(), &[(Op::Clean, downloaded.clone())]);
+        assert!(matches!(
+            dm.tree
+                .prefix_get(&mut downloaded.iter())
+                .map(|data| &data.stage),
+            Some(ArtifactMaterializationStage::Declared { .. })
+        ));
+        let _ignore = dm
+            .materialize_artifact(&downloaded, EventDispatcher::null())
+            .context("Expected a future")?
+            .await;
+        assert_eq!(dm.io.take_log(), &[(Op::Materialize, downloaded.clone())]);
+
+        Ok(())
+    }
+
     fn make_artifact_value_with_symlink_dep(
         target_path: &ProjectRelativePathBuf,
         target_from_symlink: &RelativePathBuf,
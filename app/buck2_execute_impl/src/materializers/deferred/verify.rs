@@ -0,0 +1,382 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+//! Detect and repair materialized artifacts that were modified on disk.
+//!
+//! Tools occasionally write into buck-out, and the materializer would then keep serving the
+//! modified files. `audit deferred-materializer fsck --repair` and the background verifier (when
+//! `buck2.materializer_verify_frequency_seconds` is set) check materialized artifacts against
+//! their metadata and repair the ones that don't match by deleting them and forgetting about
+//! them, so that the next build that needs them rebuilds or re-downloads them.
+//!
+//! Artifacts declared by the running daemon can't be repaired this way, since DICE assumes they
+//! exist. Those that were downloaded or written are declared again instead, so that they get
+//! materialized again the next time they're needed. The others (i.e. outputs of local actions and
+//! copies) are only reported: restarting the daemon (`buck2 kill`) makes them repairable.
+
+use std::collections::HashMap;
+
+use buck2_common::file_ops::FileDigest;
+use buck2_core::directory::DirectoryEntry;
+use buck2_core::directory::FingerprintedDirectory;
+use buck2_core::fs::fs_util;
+use buck2_core::fs::paths::abs_norm_path::AbsNormPath;
+use buck2_core::fs::paths::file_name::FileName;
+use buck2_core::fs::project::ProjectRoot;
+use buck2_core::fs::project_rel_path::ProjectRelativePath;
+use buck2_core::fs::project_rel_path::ProjectRelativePathBuf;
+use buck2_execute::artifact_value::ArtifactValue;
+use buck2_execute::digest_config::DigestConfig;
+use buck2_execute::directory::ActionDirectoryMember;
+use derive_more::Display;
+use dupe::Dupe;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use thiserror::Error;
+
+use crate::materializers::deferred::io_handler::IoHandler;
+use crate::materializers::deferred::join_all_existing_futs;
+use crate::materializers::deferred::ActionDirectoryFingerprint;
+use crate::materializers::deferred::ArtifactMaterializationStage;
+use crate::materializers::deferred::ArtifactMetadata;
+use crate::materializers::deferred::DeferredMaterializerCommandProcessor;
+use crate::materializers::deferred::LowPriorityMaterializerCommand;
+use crate::materializers::deferred::Version;
+use crate::materializers::io::build_entry_from_disk;
+
+pub struct VerifierConfiguration {
+    pub enabled: bool,
+    /// How often to verify materialized artifacts.
+    pub frequency: std::time::Duration,
+    /// How long the materializer must have gone without receiving a command for the verifier
+    /// to run.
+    pub idle_time: std::time::Duration,
+}
+
+#[derive(Debug, Error)]
+pub(super) enum VerifyArtifactError {
+    #[error("Expected a {expected}, found a {actual}")]
+    WrongType {
+        expected: &'static str,
+        actual: &'static str,
+    },
+    #[error("Expected {expected} bytes, found {actual}")]
+    SizeMismatch { expected: u64, actual: u64 },
+    #[error("Expected digest {expected}, found {actual}")]
+    DigestMismatch {
+        expected: FileDigest,
+        actual: FileDigest,
+    },
+    #[error("Expected the file to be executable: {expected}, found: {actual}")]
+    ExecutableMismatch { expected: bool, actual: bool },
+    #[error("Expected the symlink to point to `{expected}`, found `{actual}`")]
+    SymlinkTargetMismatch { expected: String, actual: String },
+    #[error("Expected the directory to contain {expected} bytes of files, found {actual}")]
+    DirectorySizeMismatch { expected: u64, actual: u64 },
+    #[error("Expected directory digest {expected}, found {actual}")]
+    DirectoryDigestMismatch {
+        expected: ActionDirectoryFingerprint,
+        actual: ActionDirectoryFingerprint,
+    },
+}
+
+#[derive(Debug, Display)]
+pub(super) enum RepairOutcome {
+    #[display(fmt = "Repaired: deleted it so that it gets rebuilt")]
+    Repaired,
+    #[display(fmt = "Repaired: it will be materialized again when needed")]
+    Rematerialized,
+    #[display(fmt = "Not repaired: in use by the daemon; run `buck2 kill` and repair again")]
+    InUse,
+}
+
+fn file_type_name(file_type: std::fs::FileType) -> &'static str {
+    if file_type.is_dir() {
+        "directory"
+    } else if file_type.is_symlink() {
+        "symlink"
+    } else if file_type.is_file() {
+        "file"
+    } else {
+        "special file"
+    }
+}
+
+/// The total size of the files in the directory at `path`, which is what `DirectoryMetadata`
+/// records.
+fn files_size(path: &AbsNormPath) -> anyhow::Result<u64> {
+    let mut size = 0;
+    for entry in fs_util::read_dir(path)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            size += files_size(&path.join(FileName::new(&entry.file_name())?))?;
+        } else if file_type.is_file() {
+            size += entry.metadata()?.len();
+        }
+    }
+    Ok(size)
+}
+
+/// Check that the artifact at `path` matches `metadata`. Checking file digests means reading
+/// every file, so it's only done when `digest_config` is passed.
+///
+/// The materializer only keeps the digest and size of directories, so without digests, a
+/// directory is checked by adding up the size of its files. With digests, it's rebuilt from disk,
+/// which catches any change, including extra or missing entries.
+pub(super) fn verify_artifact(
+    fs: &ProjectRoot,
+    path: &ProjectRelativePath,
+    metadata: &ArtifactMetadata,
+    digest_config: Option<DigestConfig>,
+) -> anyhow::Result<()> {
+    let abs_path = fs.resolve(path);
+    let file_type = fs_util::symlink_metadata(&abs_path)?.file_type();
+
+    let expect_type = |expected: &'static str, ok: bool| {
+        if ok {
+            Ok(())
+        } else {
+            Err(VerifyArtifactError::WrongType {
+                expected,
+                actual: file_type_name(file_type),
+            })
+        }
+    };
+
+    match &metadata.0 {
+        DirectoryEntry::Dir(dir) => {
+            expect_type("directory", file_type.is_dir())?;
+            match digest_config {
+                Some(digest_config) => {
+                    let actual = match build_entry_from_disk(abs_path.clone(), digest_config)? {
+                        Some(DirectoryEntry::Dir(builder)) => builder
+                            .fingerprint(digest_config.as_directory_serializer())
+                            .fingerprint()
+                            .dupe(),
+                        _ => return Err(anyhow::anyhow!("`{}` disappeared", abs_path)),
+                    };
+                    if actual != dir.fingerprint {
+                        return Err(VerifyArtifactError::DirectoryDigestMismatch {
+                            expected: dir.fingerprint.dupe(),
+                            actual,
+                        }
+                        .into());
+                    }
+                }
+                None => {
+                    let actual = files_size(&abs_path)?;
+                    if actual != dir.total_size {
+                        return Err(VerifyArtifactError::DirectorySizeMismatch {
+                            expected: dir.total_size,
+                            actual,
+                        }
+                        .into());
+                    }
+                }
+            }
+        }
+        DirectoryEntry::Leaf(ActionDirectoryMember::File(file)) => {
+            expect_type("file", file_type.is_file())?;
+            let meta = fs_util::symlink_metadata(&abs_path)?;
+            if meta.len() != file.digest.size() {
+                return Err(VerifyArtifactError::SizeMismatch {
+                    expected: file.digest.size(),
+                    actual: meta.len(),
+                }
+                .into());
+            }
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+
+                let is_executable = meta.permissions().mode() & 0o100 != 0;
+                if is_executable != file.is_executable {
+                    return Err(VerifyArtifactError::ExecutableMismatch {
+                        expected: file.is_executable,
+                        actual: is_executable,
+                    }
+                    .into());
+                }
+            }
+            if let Some(digest_config) = digest_config {
+                let actual = FileDigest::from_file_disk(
+                    abs_path.as_path(),
+                    digest_config.cas_digest_config(),
+                )?;
+                if &actual != file.digest.data() {
+                    return Err(VerifyArtifactError::DigestMismatch {
+                        expected: file.digest.data().dupe(),
+                        actual,
+                    }
+                    .into());
+                }
+            }
+        }
+        DirectoryEntry::Leaf(ActionDirectoryMember::Symlink(symlink)) => {
+            expect_type("symlink", file_type.is_symlink())?;
+            let actual = fs_util::read_link(&abs_path)?;
+            let expected = symlink.target().as_str();
+            if actual.to_str() != Some(expected) {
+                return Err(VerifyArtifactError::SymlinkTargetMismatch {
+                    expected: expected.to_owned(),
+                    actual: actual.to_string_lossy().into_owned(),
+                }
+                .into());
+            }
+        }
+        DirectoryEntry::Leaf(ActionDirectoryMember::ExternalSymlink(..)) => {
+            expect_type("symlink", file_type.is_symlink())?;
+        }
+    }
+
+    Ok(())
+}
+
+impl<T: IoHandler> DeferredMaterializerCommandProcessor<T> {
+    /// Forget about the materialized artifacts at `paths`, which don't match what's on disk, and
+    /// delete them, unless the running daemon uses them. Returns a future that resolves when
+    /// they have been deleted, and the outcome for each path.
+    pub(super) fn repair_artifacts(
+        &mut self,
+        paths: Vec<ProjectRelativePathBuf>,
+    ) -> (
+        BoxFuture<'static, anyhow::Result<()>>,
+        Vec<(ProjectRelativePathBuf, RepairOutcome)>,
+    ) {
+        let mut outcomes = Vec::with_capacity(paths.len());
+        let mut to_delete = Vec::new();
+        let mut to_redeclare = Vec::new();
+        for path in paths {
+            let data = match self.tree.prefix_get(&mut path.iter()) {
+                Some(data) => data,
+                None => continue,
+            };
+            match &data.stage {
+                ArtifactMaterializationStage::Materialized { active: false, .. } => {
+                    to_delete.push(path.clone());
+                    outcomes.push((path, RepairOutcome::Repaired));
+                }
+                ArtifactMaterializationStage::Materialized {
+                    rematerialize: Some((entry, method)),
+                    ..
+                } => {
+                    let value = ArtifactValue::new(entry.dupe(), data.deps.dupe());
+                    to_redeclare.push((path.clone(), value, method.dupe()));
+                    outcomes.push((path, RepairOutcome::Rematerialized));
+                }
+                ArtifactMaterializationStage::Materialized { .. } => {
+                    outcomes.push((path, RepairOutcome::InUse));
+                }
+                // It was re-declared since, so there's nothing to repair anymore.
+                ArtifactMaterializationStage::Declared { .. } => {}
+            }
+        }
+
+        // Declaring replaces what's on disk, which gets deleted before the artifact is
+        // materialized again.
+        for (path, value, method) in to_redeclare {
+            self.declare_unchecked(&path, value, method);
+        }
+
+        if to_delete.is_empty() {
+            return (futures::future::ready(Ok(())).boxed(), outcomes);
+        }
+
+        let existing_futs = self
+            .tree
+            .invalidate_paths_and_collect_futures(to_delete.clone(), self.sqlite_db.as_mut());
+        let io = self.io.dupe();
+        let fut = async move {
+            join_all_existing_futs(existing_futs).await?;
+            io.clean_untracked_paths(to_delete).await
+        }
+        .boxed();
+
+        (fut, outcomes)
+    }
+
+    /// Check all materialized artifacts in the background (without reading file contents), unless
+    /// a check is already running. The results come back as `VerificationFinished`.
+    pub(super) fn start_verification(&mut self) {
+        if self.verification_in_progress {
+            return;
+        }
+
+        let artifacts: Vec<_> = self
+            .tree
+            .iter_with_paths()
+            .filter_map(|(path, data)| match &data.stage {
+                ArtifactMaterializationStage::Materialized { metadata, .. } => Some((
+                    ProjectRelativePathBuf::from(path),
+                    metadata.dupe(),
+                    data.processing.current_version(),
+                )),
+                ArtifactMaterializationStage::Declared { .. } => None,
+            })
+            .collect();
+
+        self.verification_in_progress = true;
+        let io = self.io.dupe();
+        let command_sender = self.command_sender.dupe();
+        self.rt.spawn(async move {
+            let failures = io.verify_artifacts(artifacts, false).await;
+            let _ignored = command_sender.send_low_priority(
+                LowPriorityMaterializerCommand::VerificationFinished { failures },
+            );
+        });
+    }
+
+    pub(super) fn verification_finished(
+        &mut self,
+        failures: Vec<(ProjectRelativePathBuf, Version, anyhow::Error)>,
+    ) {
+        self.verification_in_progress = false;
+
+        // Forget the reports about artifacts that changed since, so they get reported again if
+        // they're modified again.
+        let tree = &self.tree;
+        self.verification_reported.retain(|path, version| {
+            match tree.prefix_get(&mut path.iter()) {
+                Some(data) => data.processing.current_version() == *version,
+                None => false,
+            }
+        });
+
+        let mut to_repair = Vec::new();
+        let mut versions = HashMap::new();
+        for (path, version, error) in failures {
+            // Ignore artifacts that changed since we checked them.
+            match self.tree.prefix_get(&mut path.iter()) {
+                Some(data) if data.processing.current_version() == version => {}
+                _ => continue,
+            }
+            tracing::debug!("Materialized artifact `{}` was modified: {:#}", path, error);
+            versions.insert(path.clone(), version);
+            to_repair.push(path);
+        }
+
+        let (fut, outcomes) = self.repair_artifacts(to_repair);
+        for (path, outcome) in outcomes {
+            if let RepairOutcome::InUse = outcome {
+                // Those can't be repaired until the daemon restarts, so report them once.
+                let version = versions[&path];
+                if self.verification_reported.insert(path.clone(), version) == Some(version) {
+                    continue;
+                }
+            }
+            tracing::warn!("Materialized artifact `{}` was modified. {}", path, outcome);
+        }
+        self.rt.spawn(async move {
+            if let Err(e) = fut.await {
+                tracing::warn!("Failed to delete modified artifacts: {:#}", e);
+            }
+        });
+    }
+}
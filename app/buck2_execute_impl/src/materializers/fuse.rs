@@ -0,0 +1,1459 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+//! Lazy outputs, using FUSE. Selected with `buck2.materializations = fuse`, Linux only.
+//!
+//! The deferred materializer is used as usual, but a filesystem is mounted over `buck-out/v2/gen`
+//! so that the RE outputs it declared but didn't materialize are there anyway, as regular files
+//! whose contents are fetched from the CAS when they are read. Fetched blobs are kept in a cache
+//! that the materializer's GC trims.
+//!
+//! Everything else is passed through to a backing directory, which holds what would otherwise be
+//! in `buck-out/v2/gen`. Outputs can be created, changed and removed there as usual, but lazy
+//! outputs are read-only: the materializer replaces them when it materializes or invalidates
+//! them.
+//!
+//! The backing directory is kept across daemons, and moved back to `buck-out/v2/gen` when a daemon
+//! that doesn't use lazy outputs starts.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::ffi::OsStr;
+use std::ffi::OsString;
+use std::fs::DirBuilder;
+use std::fs::File;
+use std::fs::Metadata;
+use std::fs::OpenOptions;
+use std::fs::Permissions;
+use std::hash::Hash;
+use std::hash::Hasher;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::ffi::OsStringExt;
+use std::os::unix::fs::DirBuilderExt;
+use std::os::unix::fs::FileExt;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::process::Command;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use anyhow::Context as _;
+use buck2_common::executor_config::RemoteExecutorUseCase;
+use buck2_common::file_ops::FileMetadata;
+use buck2_core::fs::fs_util;
+use buck2_core::fs::paths::abs_norm_path::AbsNormPath;
+use buck2_core::fs::paths::abs_norm_path::AbsNormPathBuf;
+use buck2_core::fs::paths::file_name::FileName;
+use buck2_core::fs::paths::forward_rel_path::ForwardRelativePath;
+use buck2_core::fs::paths::forward_rel_path::ForwardRelativePathBuf;
+use buck2_core::fs::project::ProjectRoot;
+use buck2_core::fs::project_rel_path::ProjectRelativePath;
+use buck2_core::fs::project_rel_path::ProjectRelativePathBuf;
+use dupe::Dupe;
+use fuser::FileAttr;
+use fuser::FileType;
+use fuser::Filesystem;
+use fuser::MountOption;
+use fuser::ReplyAttr;
+use fuser::ReplyCreate;
+use fuser::ReplyData;
+use fuser::ReplyDirectory;
+use fuser::ReplyEmpty;
+use fuser::ReplyEntry;
+use fuser::ReplyOpen;
+use fuser::ReplyWrite;
+use fuser::Request;
+use fuser::TimeOrNow;
+use parking_lot::Mutex;
+use tokio::sync::OnceCell;
+
+use crate::materializers::deferred::lazy::backing_dir;
+use crate::materializers::deferred::lazy::LazyBlobCache;
+use crate::materializers::deferred::lazy::LazyEntries;
+use crate::materializers::deferred::lazy::LazyEntry;
+use crate::materializers::deferred::lazy::LazyKind;
+
+const ROOT_INO: u64 = fuser::FUSE_ROOT_ID;
+
+/// Lazy outputs turn into regular ones when they are materialized or invalidated, so the kernel
+/// can't cache anything.
+const TTL: Duration = Duration::ZERO;
+
+/// The errno a request fails with.
+type Errno = i32;
+
+fn errno(e: io::Error) -> Errno {
+    e.raw_os_error().unwrap_or(libc::EIO)
+}
+
+/// The inodes the kernel knows about, and their paths. An inode is kept until the kernel forgets
+/// it, even if its path was removed, since it may still be open.
+struct Inodes {
+    next_ino: u64,
+    /// The path of each inode, if it wasn't removed, and the lookups of it the kernel holds.
+    by_ino: HashMap<u64, (Option<ForwardRelativePathBuf>, u64)>,
+    by_path: HashMap<ForwardRelativePathBuf, u64>,
+}
+
+impl Inodes {
+    fn new() -> Self {
+        Self {
+            next_ino: ROOT_INO,
+            by_ino: HashMap::from([(ROOT_INO, (Some(ForwardRelativePathBuf::empty()), 1))]),
+            by_path: HashMap::from([(ForwardRelativePathBuf::empty(), ROOT_INO)]),
+        }
+    }
+
+    fn path(&self, ino: u64) -> Result<ForwardRelativePathBuf, Errno> {
+        match self.by_ino.get(&ino) {
+            Some((Some(path), _)) => Ok(path.clone()),
+            _ => Err(libc::ENOENT),
+        }
+    }
+
+    /// The inode of `path`, if the kernel knows about it.
+    fn get(&self, path: &ForwardRelativePath) -> Option<u64> {
+        self.by_path.get(path).copied()
+    }
+
+    /// The kernel looked up `path`.
+    fn lookup(&mut self, path: ForwardRelativePathBuf) -> u64 {
+        let ino = match self.by_path.get(&path) {
+            Some(ino) => *ino,
+            None => {
+                self.next_ino += 1;
+                let ino = self.next_ino;
+                self.by_path.insert(path.clone(), ino);
+                self.by_ino.insert(ino, (Some(path), 0));
+                ino
+            }
+        };
+        if let Some((_, lookups)) = self.by_ino.get_mut(&ino) {
+            *lookups += 1;
+        }
+        ino
+    }
+
+    /// The kernel dropped `nlookup` references to `ino`.
+    fn forget(&mut self, ino: u64, nlookup: u64) {
+        if ino == ROOT_INO {
+            return;
+        }
+        let path = match self.by_ino.get_mut(&ino) {
+            Some((path, lookups)) => {
+                *lookups = lookups.saturating_sub(nlookup);
+                if *lookups > 0 {
+                    return;
+                }
+                path.take()
+            }
+            None => return,
+        };
+        self.by_ino.remove(&ino);
+        if let Some(path) = path {
+            self.by_path.remove(&path);
+        }
+    }
+
+    /// `path` and everything under it were removed.
+    fn unlink(&mut self, path: &ForwardRelativePath) {
+        self.by_path.retain(|p, ino| {
+            if !p.starts_with(path) {
+                return true;
+            }
+            if let Some((p, _)) = self.by_ino.get_mut(&*ino) {
+                *p = None;
+            }
+            false
+        });
+    }
+
+    /// `from` and everything under it were moved to `to`.
+    fn rename(&mut self, from: &ForwardRelativePath, to: &ForwardRelativePath) {
+        self.unlink(to);
+        let moved = self
+            .by_path
+            .iter()
+            .filter(|(p, _)| p.starts_with(from))
+            .map(|(p, ino)| (p.clone(), *ino))
+            .collect::<Vec<_>>();
+        for (old, ino) in moved {
+            // `starts_with` checked this.
+            let new = to.join(old.strip_prefix(from).unwrap());
+            self.by_path.remove(&old);
+            self.by_path.insert(new.clone(), ino);
+            if let Some((p, _)) = self.by_ino.get_mut(&ino) {
+                *p = Some(new);
+            }
+        }
+    }
+}
+
+/// What's at a path of the filesystem.
+enum Entry {
+    Backing(Metadata),
+    Lazy(LazyEntry),
+}
+
+enum OpenFile {
+    Backing(File),
+    Lazy {
+        meta: FileMetadata,
+        re_use_case: RemoteExecutorUseCase,
+        /// Opened on the first read.
+        blob: OnceCell<File>,
+    },
+}
+
+fn lazy_file_type(kind: LazyKind) -> FileType {
+    match kind {
+        LazyKind::Dir => FileType::Directory,
+        LazyKind::File => FileType::RegularFile,
+        LazyKind::Symlink => FileType::Symlink,
+    }
+}
+
+fn backing_file_type(file_type: std::fs::FileType) -> FileType {
+    use std::os::unix::fs::FileTypeExt;
+
+    if file_type.is_dir() {
+        FileType::Directory
+    } else if file_type.is_symlink() {
+        FileType::Symlink
+    } else if file_type.is_fifo() {
+        FileType::NamedPipe
+    } else if file_type.is_socket() {
+        FileType::Socket
+    } else if file_type.is_char_device() {
+        FileType::CharDevice
+    } else if file_type.is_block_device() {
+        FileType::BlockDevice
+    } else {
+        FileType::RegularFile
+    }
+}
+
+fn time(secs: i64, nsecs: i64) -> SystemTime {
+    match (u64::try_from(secs), u32::try_from(nsecs)) {
+        (Ok(secs), Ok(nsecs)) => UNIX_EPOCH + Duration::new(secs, nsecs),
+        _ => UNIX_EPOCH,
+    }
+}
+
+fn open_options(flags: i32) -> OpenOptions {
+    let mut options = OpenOptions::new();
+    match flags & libc::O_ACCMODE {
+        libc::O_RDONLY => options.read(true),
+        libc::O_WRONLY => options.write(true),
+        _ => options.read(true).write(true),
+    };
+    options
+        .append(flags & libc::O_APPEND != 0)
+        .truncate(flags & libc::O_TRUNC != 0);
+    options
+}
+
+/// Read up to `size` bytes at `offset`.
+fn read_at(file: &File, offset: u64, size: u32) -> io::Result<Vec<u8>> {
+    let mut data = vec![0; size as usize];
+    let mut len = 0;
+    while len < data.len() {
+        match file.read_at(&mut data[len..], offset + len as u64) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    data.truncate(len);
+    Ok(data)
+}
+
+fn set_times(path: &Path, atime: Option<TimeOrNow>, mtime: Option<TimeOrNow>) -> io::Result<()> {
+    fn timespec(time: Option<TimeOrNow>) -> libc::timespec {
+        let (secs, nsecs) = match time {
+            None => (0, libc::UTIME_OMIT),
+            Some(TimeOrNow::Now) => (0, libc::UTIME_NOW),
+            Some(TimeOrNow::SpecificTime(time)) => {
+                let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+                (since_epoch.as_secs(), since_epoch.subsec_nanos().into())
+            }
+        };
+        libc::timespec {
+            tv_sec: secs as libc::time_t,
+            tv_nsec: nsecs as _,
+        }
+    }
+
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let times = [timespec(atime), timespec(mtime)];
+    // SAFETY: `path` and `times` outlive the call.
+    if unsafe {
+        libc::utimensat(
+            libc::AT_FDCWD,
+            path.as_ptr(),
+            times.as_ptr(),
+            libc::AT_SYMLINK_NOFOLLOW,
+        )
+    } != 0
+    {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// The entries of a directory that follow `offset`, with the offset of each. `offset` is the
+/// offset of the last entry the kernel got, so offsets are 1-based.
+fn entries_after(
+    entries: Vec<(u64, FileType, OsString)>,
+    offset: i64,
+) -> impl Iterator<Item = (u64, i64, FileType, OsString)> {
+    entries
+        .into_iter()
+        .enumerate()
+        .skip(usize::try_from(offset).unwrap_or_default())
+        .map(|(i, (ino, kind, name))| (ino, (i + 1) as i64, kind, name))
+}
+
+/// The inode number listed for a directory entry the kernel doesn't know about. The kernel only
+/// uses it for display, and looks the entry up before using it.
+fn unknown_ino(path: &ForwardRelativePath) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    path.hash(&mut hasher);
+    hasher.finish() | (1 << 63)
+}
+
+/// The filesystem: the backing directory, with the lazy outputs of the materializer on top.
+struct LazyFs {
+    backing: AbsNormPathBuf,
+    /// Where this is mounted, to look up lazy outputs.
+    gen: ProjectRelativePathBuf,
+    entries: Arc<dyn LazyEntries>,
+    blobs: Arc<LazyBlobCache>,
+    /// The owner of lazy outputs.
+    uid: u32,
+    gid: u32,
+    inodes: Mutex<Inodes>,
+    files: Mutex<HashMap<u64, Arc<OpenFile>>>,
+    next_fh: AtomicU64,
+}
+
+impl LazyFs {
+    fn backing_path(&self, path: &ForwardRelativePath) -> AbsNormPathBuf {
+        self.backing.join(path)
+    }
+
+    fn child(&self, parent: u64, name: &OsStr) -> Result<ForwardRelativePathBuf, Errno> {
+        let name = name
+            .to_str()
+            .and_then(|name| FileName::new(name).ok())
+            .ok_or(libc::EINVAL)?;
+        Ok(self.inodes.lock().path(parent)?.join(name))
+    }
+
+    fn lazy_entry(&self, path: &ForwardRelativePath) -> Result<Option<LazyEntry>, Errno> {
+        self.entries.get(self.gen.join(path)).map_err(|e| {
+            tracing::warn!("Error looking up lazy output `{}`: {:#}", path, e);
+            libc::EIO
+        })
+    }
+
+    fn backing_metadata(&self, path: &ForwardRelativePath) -> Result<Option<Metadata>, Errno> {
+        match std::fs::symlink_metadata(self.backing_path(path)) {
+            Ok(meta) => Ok(Some(meta)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(errno(e)),
+        }
+    }
+
+    fn entry(&self, path: &ForwardRelativePath) -> Result<Entry, Errno> {
+        if let Some(meta) = self.backing_metadata(path)? {
+            return Ok(Entry::Backing(meta));
+        }
+        match self.lazy_entry(path)? {
+            Some(entry) => Ok(Entry::Lazy(entry)),
+            None => Err(libc::ENOENT),
+        }
+    }
+
+    fn attr(&self, ino: u64, entry: &Entry) -> FileAttr {
+        match entry {
+            Entry::Backing(meta) => FileAttr {
+                ino,
+                size: meta.size(),
+                blocks: meta.blocks(),
+                atime: time(meta.atime(), meta.atime_nsec()),
+                mtime: time(meta.mtime(), meta.mtime_nsec()),
+                ctime: time(meta.ctime(), meta.ctime_nsec()),
+                crtime: UNIX_EPOCH,
+                kind: backing_file_type(meta.file_type()),
+                perm: (meta.mode() & 0o7777) as u16,
+                nlink: meta.nlink() as u32,
+                uid: meta.uid(),
+                gid: meta.gid(),
+                rdev: meta.rdev() as u32,
+                blksize: meta.blksize() as u32,
+                flags: 0,
+            },
+            Entry::Lazy(entry) => {
+                let (size, perm) = match entry {
+                    LazyEntry::Parent(..) | LazyEntry::Dir(..) => (0, 0o755),
+                    LazyEntry::File(meta, _) if meta.is_executable => (meta.digest.size(), 0o755),
+                    LazyEntry::File(meta, _) => (meta.digest.size(), 0o644),
+                    LazyEntry::Symlink(target) => (target.as_os_str().len() as u64, 0o777),
+                };
+                let kind = lazy_file_type(entry.kind());
+                FileAttr {
+                    ino,
+                    size,
+                    blocks: (size + 511) / 512,
+                    atime: UNIX_EPOCH,
+                    mtime: UNIX_EPOCH,
+                    ctime: UNIX_EPOCH,
+                    crtime: UNIX_EPOCH,
+                    kind,
+                    perm,
+                    nlink: if kind == FileType::Directory { 2 } else { 1 },
+                    uid: self.uid,
+                    gid: self.gid,
+                    rdev: 0,
+                    blksize: 4096,
+                    flags: 0,
+                }
+            }
+        }
+    }
+
+    fn lookup(&self, path: ForwardRelativePathBuf) -> Result<FileAttr, Errno> {
+        let entry = self.entry(&path)?;
+        let ino = self.inodes.lock().lookup(path);
+        Ok(self.attr(ino, &entry))
+    }
+
+    fn getattr(&self, ino: u64) -> Result<FileAttr, Errno> {
+        let path = self.inodes.lock().path(ino)?;
+        Ok(self.attr(ino, &self.entry(&path)?))
+    }
+
+    /// The backing directory to create the children of `path` in. The directories that contain
+    /// lazy outputs are created on demand.
+    fn backing_dir(&self, path: &ForwardRelativePath) -> Result<AbsNormPathBuf, Errno> {
+        let dir = self.backing_path(path);
+        match self.backing_metadata(path)? {
+            Some(meta) if meta.is_dir() => return Ok(dir),
+            Some(..) => return Err(libc::ENOTDIR),
+            None => {}
+        }
+        match self.lazy_entry(path)? {
+            Some(LazyEntry::Parent(..)) => {
+                std::fs::create_dir_all(&dir).map_err(errno)?;
+                Ok(dir)
+            }
+            Some(LazyEntry::Dir(..)) => Err(libc::EROFS),
+            Some(..) => Err(libc::ENOTDIR),
+            None => Err(libc::ENOENT),
+        }
+    }
+
+    /// Create the entry `name` in `parent` with `create`.
+    fn create_with<T>(
+        &self,
+        parent: u64,
+        name: &OsStr,
+        create: impl FnOnce(&AbsNormPath) -> io::Result<T>,
+    ) -> Result<(FileAttr, T), Errno> {
+        let path = self.child(parent, name)?;
+        let dir = self.backing_dir(path.parent().unwrap_or(ForwardRelativePath::empty()))?;
+        if self.lazy_entry(&path)?.is_some() {
+            return Err(libc::EEXIST);
+        }
+        // `child` checked this is a file name.
+        let res = create(&dir.join(path.file_name().unwrap())).map_err(errno)?;
+        Ok((self.lookup(path)?, res))
+    }
+
+    fn setattr(
+        &self,
+        ino: u64,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        atime: Option<TimeOrNow>,
+        mtime: Option<TimeOrNow>,
+        fh: Option<u64>,
+    ) -> Result<FileAttr, Errno> {
+        let path = self.inodes.lock().path(ino)?;
+        let meta = match self.entry(&path)? {
+            Entry::Backing(meta) => meta,
+            Entry::Lazy(..) => return Err(libc::EROFS),
+        };
+        let backing = self.backing_path(&path);
+
+        // Outputs are owned by the daemon's user.
+        if uid.map_or(false, |uid| uid != meta.uid()) || gid.map_or(false, |gid| gid != meta.gid())
+        {
+            return Err(libc::EPERM);
+        }
+        if let Some(mode) = mode {
+            std::fs::set_permissions(&backing, Permissions::from_mode(mode & 0o7777))
+                .map_err(errno)?;
+        }
+        if let Some(size) = size {
+            let file = fh.and_then(|fh| self.files.lock().get(&fh).map(|f| f.dupe()));
+            match file.as_deref() {
+                Some(OpenFile::Backing(file)) => file.set_len(size),
+                _ => OpenOptions::new()
+                    .write(true)
+                    .open(&backing)
+                    .and_then(|file| file.set_len(size)),
+            }
+            .map_err(errno)?;
+        }
+        if atime.is_some() || mtime.is_some() {
+            set_times(backing.as_path(), atime, mtime).map_err(errno)?;
+        }
+        self.getattr(ino)
+    }
+
+    fn readlink(&self, ino: u64) -> Result<Vec<u8>, Errno> {
+        let path = self.inodes.lock().path(ino)?;
+        let target = match self.entry(&path)? {
+            Entry::Backing(..) => std::fs::read_link(self.backing_path(&path)).map_err(errno)?,
+            Entry::Lazy(LazyEntry::Symlink(target)) => target,
+            Entry::Lazy(..) => return Err(libc::EINVAL),
+        };
+        Ok(target.into_os_string().into_vec())
+    }
+
+    fn unlink(&self, parent: u64, name: &OsStr) -> Result<(), Errno> {
+        let path = self.child(parent, name)?;
+        match self.entry(&path)? {
+            Entry::Backing(..) => {
+                std::fs::remove_file(self.backing_path(&path)).map_err(errno)?;
+            }
+            Entry::Lazy(..) => return Err(libc::EROFS),
+        }
+        self.inodes.lock().unlink(&path);
+        Ok(())
+    }
+
+    fn rmdir(&self, parent: u64, name: &OsStr) -> Result<(), Errno> {
+        let path = self.child(parent, name)?;
+        match (self.backing_metadata(&path)?, self.lazy_entry(&path)?) {
+            (_, Some(LazyEntry::Parent(..))) => return Err(libc::ENOTEMPTY),
+            (Some(..), _) => {
+                std::fs::remove_dir(self.backing_path(&path)).map_err(errno)?;
+            }
+            (None, Some(..)) => return Err(libc::EROFS),
+            (None, None) => return Err(libc::ENOENT),
+        }
+        self.inodes.lock().unlink(&path);
+        Ok(())
+    }
+
+    fn rename(
+        &self,
+        parent: u64,
+        name: &OsStr,
+        new_parent: u64,
+        new_name: &OsStr,
+        flags: u32,
+    ) -> Result<(), Errno> {
+        // `RENAME_EXCHANGE` and `RENAME_NOREPLACE` aren't supported.
+        if flags != 0 {
+            return Err(libc::EINVAL);
+        }
+        let from = self.child(parent, name)?;
+        let to = self.child(new_parent, new_name)?;
+        // Directories that contain lazy outputs can't be moved either, since the outputs stay
+        // where they were declared.
+        if self.lazy_entry(&from)?.is_some() || self.lazy_entry(&to)?.is_some() {
+            return Err(libc::EROFS);
+        }
+        if self.backing_metadata(&from)?.is_none() {
+            return Err(libc::ENOENT);
+        }
+        let dir = self.backing_dir(to.parent().unwrap_or(ForwardRelativePath::empty()))?;
+        std::fs::rename(self.backing_path(&from), dir.join(to.file_name().unwrap()))
+            .map_err(errno)?;
+        self.inodes.lock().rename(&from, &to);
+        Ok(())
+    }
+
+    fn link(&self, ino: u64, new_parent: u64, new_name: &OsStr) -> Result<FileAttr, Errno> {
+        let path = self.inodes.lock().path(ino)?;
+        if let Entry::Lazy(..) = self.entry(&path)? {
+            // There is nothing on disk to link to, so let the caller copy it instead.
+            return Err(libc::EXDEV);
+        }
+        let src = self.backing_path(&path);
+        let (attr, ()) =
+            self.create_with(new_parent, new_name, |dest| std::fs::hard_link(&src, dest))?;
+        Ok(attr)
+    }
+
+    fn add_file(&self, file: OpenFile) -> u64 {
+        let fh = self.next_fh.fetch_add(1, Ordering::Relaxed);
+        self.files.lock().insert(fh, Arc::new(file));
+        fh
+    }
+
+    fn file(&self, fh: u64) -> Result<Arc<OpenFile>, Errno> {
+        self.files
+            .lock()
+            .get(&fh)
+            .map(|f| f.dupe())
+            .ok_or(libc::EBADF)
+    }
+
+    fn open(&self, ino: u64, flags: i32) -> Result<u64, Errno> {
+        let path = self.inodes.lock().path(ino)?;
+        let file = match self.entry(&path)? {
+            Entry::Backing(..) => OpenFile::Backing(
+                open_options(flags)
+                    .open(self.backing_path(&path))
+                    .map_err(errno)?,
+            ),
+            Entry::Lazy(LazyEntry::File(meta, re_use_case)) => {
+                if flags & libc::O_ACCMODE != libc::O_RDONLY || flags & libc::O_TRUNC != 0 {
+                    return Err(libc::EROFS);
+                }
+                OpenFile::Lazy {
+                    meta,
+                    re_use_case,
+                    blob: OnceCell::new(),
+                }
+            }
+            Entry::Lazy(..) => return Err(libc::EISDIR),
+        };
+        Ok(self.add_file(file))
+    }
+
+    fn create(
+        &self,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        flags: i32,
+    ) -> Result<(FileAttr, u64), Errno> {
+        let (attr, file) = self.create_with(parent, name, |path| {
+            let mut options = open_options(flags);
+            // Files can be created for reading only, but `OpenOptions` needs to write to do it.
+            options.write(true).mode(mode & !umask);
+            if flags & libc::O_EXCL != 0 {
+                options.create_new(true);
+            } else {
+                options.create(true);
+            }
+            options.open(path)
+        })?;
+        Ok((attr, self.add_file(OpenFile::Backing(file))))
+    }
+
+    async fn read_lazy(
+        &self,
+        meta: &FileMetadata,
+        re_use_case: RemoteExecutorUseCase,
+        blob: &OnceCell<File>,
+        offset: u64,
+        size: u32,
+    ) -> anyhow::Result<Vec<u8>> {
+        let blob = blob
+            .get_or_try_init(|| self.blobs.open(meta, re_use_case))
+            .await?;
+        Ok(read_at(blob, offset, size)?)
+    }
+
+    /// The entries of directory `ino`: those of its backing directory, and its lazy outputs.
+    fn readdir(&self, ino: u64) -> Result<Vec<(u64, FileType, OsString)>, Errno> {
+        let path = self.inodes.lock().path(ino)?;
+
+        let lazy = self.lazy_entry(&path)?;
+        let backing = self.backing_metadata(&path)?;
+        match (&backing, &lazy) {
+            (Some(meta), _) if !meta.is_dir() => return Err(libc::ENOTDIR),
+            (None, Some(entry)) if entry.kind() != LazyKind::Dir => return Err(libc::ENOTDIR),
+            (None, None) => return Err(libc::ENOENT),
+            _ => {}
+        }
+
+        let mut children = BTreeMap::new();
+        for (name, kind) in lazy.as_ref().and_then(|e| e.children()).unwrap_or_default() {
+            children.insert(OsString::from(name.as_str()), lazy_file_type(*kind));
+        }
+        if backing.is_some() {
+            for entry in std::fs::read_dir(self.backing_path(&path)).map_err(errno)? {
+                let entry = entry.map_err(errno)?;
+                let kind = backing_file_type(entry.file_type().map_err(errno)?);
+                children.insert(entry.file_name(), kind);
+            }
+        }
+
+        let inodes = self.inodes.lock();
+        let ino_of =
+            |path: &ForwardRelativePath| inodes.get(path).unwrap_or_else(|| unknown_ino(path));
+        let mut entries = vec![
+            (ino, FileType::Directory, OsString::from(".")),
+            (
+                path.parent().map_or(ROOT_INO, ino_of),
+                FileType::Directory,
+                OsString::from(".."),
+            ),
+        ];
+        for (name, kind) in children {
+            let ino = match name.to_str().and_then(|name| FileName::new(name).ok()) {
+                Some(name) => ino_of(&path.join(name)),
+                None => unknown_ino(&path),
+            };
+            entries.push((ino, kind, name));
+        }
+        Ok(entries)
+    }
+}
+
+/// The FUSE side of [`LazyFs`]. Requests are answered on the FUSE thread, except for reads of lazy
+/// files, which are answered from tokio tasks so that fetching a file doesn't block requests for
+/// other files.
+struct LazyFilesystem {
+    fs: Arc<LazyFs>,
+    rt: tokio::runtime::Handle,
+}
+
+impl Filesystem for LazyFilesystem {
+    fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        match self
+            .fs
+            .child(parent, name)
+            .and_then(|path| self.fs.lookup(path))
+        {
+            Ok(attr) => reply.entry(&TTL, &attr, 0),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn forget(&mut self, _req: &Request<'_>, ino: u64, nlookup: u64) {
+        self.fs.inodes.lock().forget(ino, nlookup);
+    }
+
+    fn getattr(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyAttr) {
+        match self.fs.getattr(ino) {
+            Ok(attr) => reply.attr(&TTL, &attr),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn setattr(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        atime: Option<TimeOrNow>,
+        mtime: Option<TimeOrNow>,
+        _ctime: Option<SystemTime>,
+        fh: Option<u64>,
+        _crtime: Option<SystemTime>,
+        _chgtime: Option<SystemTime>,
+        _bkuptime: Option<SystemTime>,
+        _flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        match self.fs.setattr(ino, mode, uid, gid, size, atime, mtime, fh) {
+            Ok(attr) => reply.attr(&TTL, &attr),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn readlink(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyData) {
+        match self.fs.readlink(ino) {
+            Ok(target) => reply.data(&target),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn mkdir(
+        &mut self,
+        _req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        reply: ReplyEntry,
+    ) {
+        match self.fs.create_with(parent, name, |path| {
+            DirBuilder::new().mode(mode & !umask).create(path)
+        }) {
+            Ok((attr, ())) => reply.entry(&TTL, &attr, 0),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn unlink(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        match self.fs.unlink(parent, name) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn rmdir(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        match self.fs.rmdir(parent, name) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn symlink(
+        &mut self,
+        _req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        link: &Path,
+        reply: ReplyEntry,
+    ) {
+        match self
+            .fs
+            .create_with(parent, name, |path| std::os::unix::fs::symlink(link, path))
+        {
+            Ok((attr, ())) => reply.entry(&TTL, &attr, 0),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn rename(
+        &mut self,
+        _req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        flags: u32,
+        reply: ReplyEmpty,
+    ) {
+        match self.fs.rename(parent, name, newparent, newname, flags) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn link(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        newparent: u64,
+        newname: &OsStr,
+        reply: ReplyEntry,
+    ) {
+        match self.fs.link(ino, newparent, newname) {
+            Ok(attr) => reply.entry(&TTL, &attr, 0),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn open(&mut self, _req: &Request<'_>, ino: u64, flags: i32, reply: ReplyOpen) {
+        match self.fs.open(ino, flags) {
+            Ok(fh) => reply.opened(fh, 0),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        let file = match self.fs.file(fh) {
+            Ok(file) => file,
+            Err(e) => return reply.error(e),
+        };
+        let offset = match u64::try_from(offset) {
+            Ok(offset) => offset,
+            Err(..) => return reply.error(libc::EINVAL),
+        };
+
+        match &*file {
+            OpenFile::Backing(f) => match read_at(f, offset, size) {
+                Ok(data) => reply.data(&data),
+                Err(e) => reply.error(errno(e)),
+            },
+            OpenFile::Lazy { .. } => {
+                let fs = self.fs.dupe();
+                self.rt.spawn(async move {
+                    let (meta, re_use_case, blob) = match &*file {
+                        OpenFile::Lazy {
+                            meta,
+                            re_use_case,
+                            blob,
+                        } => (meta, *re_use_case, blob),
+                        OpenFile::Backing(..) => unreachable!(),
+                    };
+                    match fs.read_lazy(meta, re_use_case, blob, offset, size).await {
+                        Ok(data) => reply.data(&data),
+                        Err(e) => {
+                            tracing::warn!(
+                                "Error reading lazy output `{}`: {:#}",
+                                LazyBlobCache::blob_name(meta),
+                                e
+                            );
+                            reply.error(libc::EIO);
+                        }
+                    }
+                });
+            }
+        }
+    }
+
+    fn write(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        fh: u64,
+        offset: i64,
+        data: &[u8],
+        _write_flags: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyWrite,
+    ) {
+        let res = self.fs.file(fh).and_then(|file| match &*file {
+            OpenFile::Backing(f) => {
+                let offset = u64::try_from(offset).map_err(|_| libc::EINVAL)?;
+                f.write_all_at(data, offset).map_err(errno)
+            }
+            OpenFile::Lazy { .. } => Err(libc::EBADF),
+        });
+        match res {
+            Ok(()) => reply.written(data.len() as u32),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn fsync(&mut self, _req: &Request<'_>, _ino: u64, fh: u64, datasync: bool, reply: ReplyEmpty) {
+        let res = self.fs.file(fh).and_then(|file| match &*file {
+            OpenFile::Backing(f) if datasync => f.sync_data().map_err(errno),
+            OpenFile::Backing(f) => f.sync_all().map_err(errno),
+            OpenFile::Lazy { .. } => Ok(()),
+        });
+        match res {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn release(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        fh: u64,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        _flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        self.fs.files.lock().remove(&fh);
+        reply.ok();
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        let entries = match self.fs.readdir(ino) {
+            Ok(entries) => entries,
+            Err(e) => return reply.error(e),
+        };
+        for (ino, offset, kind, name) in entries_after(entries, offset) {
+            if reply.add(ino, offset, kind, name) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+
+    fn create(
+        &mut self,
+        _req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        flags: i32,
+        reply: ReplyCreate,
+    ) {
+        match self.fs.create(parent, name, mode, umask, flags) {
+            Ok((attr, fh)) => reply.created(&TTL, &attr, 0, fh, 0),
+            Err(e) => reply.error(e),
+        }
+    }
+}
+
+/// Unmount the filesystem that a daemon which didn't exit cleanly left at `path`. Only one daemon
+/// uses a buck-out, so anything mounted there is stale.
+fn unmount_stale(path: &AbsNormPath) -> anyhow::Result<()> {
+    let stale = match std::fs::metadata(path) {
+        // What a mount whose FUSE server is gone returns.
+        Err(e) => e.raw_os_error() == Some(libc::ENOTCONN),
+        Ok(meta) => match path.parent() {
+            Some(parent) => meta.dev() != fs_util::metadata(parent)?.dev(),
+            None => false,
+        },
+    };
+    if !stale {
+        return Ok(());
+    }
+
+    tracing::warn!("Unmounting stale FUSE mount at `{}`", path);
+    let mut errors = Vec::new();
+    for fusermount in ["fusermount", "fusermount3"] {
+        match Command::new(fusermount)
+            .args(["-u", "-z"])
+            .arg(path.as_path())
+            .status()
+        {
+            Ok(status) if status.success() => return Ok(()),
+            Ok(status) => errors.push(format!("`{}` exited with {}", fusermount, status)),
+            Err(e) => errors.push(format!("`{}`: {}", fusermount, e)),
+        }
+    }
+    Err(anyhow::anyhow!(
+        "Failed to unmount stale FUSE mount at `{}`: {}",
+        path,
+        errors.join(", ")
+    ))
+}
+
+fn is_empty_dir(path: &AbsNormPath) -> anyhow::Result<bool> {
+    Ok(fs_util::read_dir(path)?.next().is_none())
+}
+
+/// The mounted filesystem. Unmounts when dropped.
+pub(crate) struct LazyOutputsMount {
+    _session: Mutex<fuser::BackgroundSession>,
+}
+
+/// Mount the lazy outputs filesystem over `gen`, with its state in `dir`.
+pub(crate) fn mount(
+    fs: &ProjectRoot,
+    gen: ProjectRelativePathBuf,
+    dir: &ProjectRelativePath,
+    entries: Arc<dyn LazyEntries>,
+    blobs: Arc<LazyBlobCache>,
+) -> anyhow::Result<LazyOutputsMount> {
+    let gen_path = fs.resolve(&gen);
+    let backing = fs.resolve(&backing_dir(dir));
+
+    unmount_stale(&gen_path)?;
+    fs_util::create_dir_all(&gen_path)?;
+    if is_empty_dir(&gen_path)? {
+        fs_util::create_dir_all(&backing)?;
+    } else {
+        // The outputs of a daemon that didn't use lazy outputs.
+        fs_util::remove_all(&backing)?;
+        fs_util::create_dir_all(fs.resolve(dir))?;
+        fs_util::rename(&gen_path, &backing)?;
+        fs_util::create_dir_all(&gen_path)?;
+    }
+
+    let lazy_fs = LazyFs {
+        backing,
+        gen,
+        entries,
+        blobs,
+        // SAFETY: These can't fail.
+        uid: unsafe { libc::getuid() },
+        gid: unsafe { libc::getgid() },
+        inodes: Mutex::new(Inodes::new()),
+        files: Mutex::new(HashMap::new()),
+        next_fh: AtomicU64::new(0),
+    };
+    let session = fuser::spawn_mount2(
+        LazyFilesystem {
+            fs: Arc::new(lazy_fs),
+            rt: tokio::runtime::Handle::current(),
+        },
+        &gen_path,
+        &[
+            MountOption::FSName("buck2-lazy".to_owned()),
+            MountOption::DefaultPermissions,
+        ],
+    )
+    .with_context(|| format!("Failed to mount FUSE at `{}`", gen_path))?;
+
+    Ok(LazyOutputsMount {
+        _session: Mutex::new(session),
+    })
+}
+
+/// Move the outputs of a daemon that used lazy outputs back to `gen`, for a daemon that doesn't.
+/// The lazy outputs themselves were never on disk, so they are gone.
+pub fn restore_lazy_outputs(
+    fs: &ProjectRoot,
+    gen: &ProjectRelativePath,
+    dir: &ProjectRelativePath,
+) -> anyhow::Result<()> {
+    let dir_path = fs.resolve(dir);
+    if !fs_util::try_exists(&dir_path)? {
+        return Ok(());
+    }
+
+    let gen_path = fs.resolve(gen);
+    let backing = fs.resolve(&backing_dir(dir));
+    unmount_stale(&gen_path)?;
+    if fs_util::try_exists(&backing)?
+        && (!fs_util::try_exists(&gen_path)? || is_empty_dir(&gen_path)?)
+    {
+        fs_util::remove_all(&gen_path)?;
+        fs_util::rename(&backing, &gen_path)?;
+    }
+    fs_util::remove_all(&dir_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use buck2_common::file_ops::TrackedFileDigest;
+    use buck2_core::fs::paths::file_name::FileNameBuf;
+    use buck2_core::fs::project::ProjectRootTemp;
+    use buck2_execute::digest_config::DigestConfig;
+    use buck2_execute::execute::blocking::testing::DummyBlockingExecutor;
+
+    use super::*;
+    use crate::materializers::deferred::lazy::tests::TestFetcher;
+
+    struct TestEntries(HashMap<ProjectRelativePathBuf, LazyEntry>);
+
+    impl LazyEntries for TestEntries {
+        fn get(&self, path: ProjectRelativePathBuf) -> anyhow::Result<Option<LazyEntry>> {
+            Ok(self.0.get(&path).cloned())
+        }
+    }
+
+    fn file(contents: &str) -> FileMetadata {
+        FileMetadata {
+            digest: TrackedFileDigest::from_content(
+                contents.as_bytes(),
+                DigestConfig::testing_default().cas_digest_config(),
+            ),
+            is_executable: false,
+        }
+    }
+
+    fn children(children: &[(&str, LazyKind)]) -> Vec<(FileNameBuf, LazyKind)> {
+        children
+            .iter()
+            .map(|(name, kind)| (FileNameBuf::unchecked_new(*name), *kind))
+            .collect()
+    }
+
+    /// A filesystem with `gen/out/foo` backed by `foo`, and the lazy outputs `gen/out/lazy` (a
+    /// directory with a file and a symlink) and `gen/out/bar`.
+    fn lazy_fs(temp: &ProjectRootTemp) -> anyhow::Result<(LazyFs, Arc<TestFetcher>)> {
+        let fs = temp.path().dupe();
+        let re_use_case = RemoteExecutorUseCase::buck2_default();
+        let entries = HashMap::from([
+            (
+                "gen",
+                LazyEntry::Parent(children(&[("out", LazyKind::Dir)])),
+            ),
+            (
+                "gen/out",
+                LazyEntry::Parent(children(&[
+                    ("lazy", LazyKind::Dir),
+                    ("bar", LazyKind::File),
+                ])),
+            ),
+            (
+                "gen/out/lazy",
+                LazyEntry::Dir(children(&[
+                    ("file", LazyKind::File),
+                    ("link", LazyKind::Symlink),
+                ])),
+            ),
+            (
+                "gen/out/lazy/file",
+                LazyEntry::File(file("hello world"), re_use_case),
+            ),
+            (
+                "gen/out/lazy/link",
+                LazyEntry::Symlink(PathBuf::from("file")),
+            ),
+            ("gen/out/bar", LazyEntry::File(file("bar"), re_use_case)),
+        ]);
+
+        let fetcher = Arc::new(TestFetcher {
+            fs: fs.dupe(),
+            contents: HashMap::from([(
+                LazyBlobCache::blob_name(&file("hello world")),
+                "hello world",
+            )]),
+            fetches: AtomicU64::new(0),
+        });
+        let blobs = LazyBlobCache::new(
+            fs.dupe(),
+            ProjectRelativePathBuf::unchecked_new("blobs".to_owned()),
+            fetcher.dupe(),
+            Arc::new(DummyBlockingExecutor { fs: fs.dupe() }),
+        )?;
+
+        temp.write_file("backing/out/foo", "foo");
+        let lazy_fs = LazyFs {
+            backing: fs.resolve(ProjectRelativePath::unchecked_new("backing")),
+            gen: ProjectRelativePathBuf::unchecked_new("gen".to_owned()),
+            entries: Arc::new(TestEntries(
+                entries
+                    .into_iter()
+                    .map(|(p, e)| (ProjectRelativePathBuf::unchecked_new(p.to_owned()), e))
+                    .collect(),
+            )),
+            blobs: Arc::new(blobs),
+            uid: 0,
+            gid: 0,
+            inodes: Mutex::new(Inodes::new()),
+            files: Mutex::new(HashMap::new()),
+            next_fh: AtomicU64::new(0),
+        };
+        Ok((lazy_fs, fetcher))
+    }
+
+    /// Look up `path` like the kernel does.
+    fn lookup(fs: &LazyFs, path: &str) -> Result<FileAttr, Errno> {
+        let mut attr = fs.getattr(ROOT_INO)?;
+        for name in path.split('/') {
+            attr = fs.lookup(fs.child(attr.ino, OsStr::new(name))?)?;
+        }
+        Ok(attr)
+    }
+
+    fn names(fs: &LazyFs, ino: u64) -> Vec<(String, FileType)> {
+        entries_after(fs.readdir(ino).unwrap(), 2)
+            .map(|(_, _, kind, name)| (name.into_string().unwrap(), kind))
+            .collect()
+    }
+
+    #[test]
+    fn test_inodes() {
+        let path = |p: &str| ForwardRelativePathBuf::unchecked_new(p.to_owned());
+        let mut inodes = Inodes::new();
+
+        let dir = inodes.lookup(path("dir"));
+        let file = inodes.lookup(path("dir/file"));
+        assert_eq!(file, inodes.lookup(path("dir/file")));
+
+        inodes.rename(&path("dir"), &path("new"));
+        assert_eq!(Ok(path("new/file")), inodes.path(file));
+        assert_eq!(Some(dir), inodes.get(&path("new")));
+        assert_eq!(None, inodes.get(&path("dir")));
+
+        inodes.unlink(&path("new"));
+        assert_eq!(Err(libc::ENOENT), inodes.path(file));
+        assert_eq!(None, inodes.get(&path("new/file")));
+
+        // Removed inodes are kept until they are forgotten.
+        inodes.forget(file, 1);
+        assert!(inodes.by_ino.contains_key(&file));
+        inodes.forget(file, 1);
+        assert!(!inodes.by_ino.contains_key(&file));
+
+        inodes.forget(ROOT_INO, 1);
+        assert_eq!(Ok(ForwardRelativePathBuf::empty()), inodes.path(ROOT_INO));
+    }
+
+    #[test]
+    fn test_entries() -> anyhow::Result<()> {
+        let temp = ProjectRootTemp::new()?;
+        let (fs, _fetcher) = lazy_fs(&temp)?;
+
+        assert_eq!(FileType::RegularFile, lookup(&fs, "out/foo").unwrap().kind);
+        let file = lookup(&fs, "out/lazy/file").unwrap();
+        assert_eq!(
+            (FileType::RegularFile, 11, 0o644),
+            (file.kind, file.size, file.perm)
+        );
+        assert_eq!(FileType::Directory, lookup(&fs, "out/lazy").unwrap().kind);
+        assert_eq!(Err(libc::ENOENT), lookup(&fs, "out/missing").map(|_| ()));
+        assert_eq!(Err(libc::ENOENT), lookup(&fs, "buck2-default").map(|_| ()));
+
+        let link = lookup(&fs, "out/lazy/link").unwrap();
+        assert_eq!(Ok(b"file".to_vec()), fs.readlink(link.ino));
+
+        // The backing directory and the lazy outputs are merged.
+        let out = lookup(&fs, "out").unwrap();
+        assert_eq!(
+            vec![
+                ("bar".to_owned(), FileType::RegularFile),
+                ("foo".to_owned(), FileType::RegularFile),
+                ("lazy".to_owned(), FileType::Directory),
+            ],
+            names(&fs, out.ino)
+        );
+        let lazy = lookup(&fs, "out/lazy").unwrap();
+        assert_eq!(
+            vec![
+                ("file".to_owned(), FileType::RegularFile),
+                ("link".to_owned(), FileType::Symlink),
+            ],
+            names(&fs, lazy.ino)
+        );
+        assert_eq!(Err(libc::ENOTDIR), fs.readdir(file.ino).map(|_| ()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_lazy_outputs_are_read_only() -> anyhow::Result<()> {
+        let temp = ProjectRootTemp::new()?;
+        let (fs, _fetcher) = lazy_fs(&temp)?;
+        let out = lookup(&fs, "out").unwrap().ino;
+        let lazy = lookup(&fs, "out/lazy").unwrap().ino;
+        let file = lookup(&fs, "out/lazy/file").unwrap().ino;
+
+        assert_eq!(Err(libc::EROFS), fs.unlink(out, OsStr::new("bar")));
+        assert_eq!(Err(libc::EROFS), fs.rmdir(out, OsStr::new("lazy")));
+        assert_eq!(Err(libc::ENOTEMPTY), fs.rmdir(ROOT_INO, OsStr::new("out")));
+        assert_eq!(
+            Err(libc::EROFS),
+            fs.rename(out, OsStr::new("bar"), out, OsStr::new("baz"), 0)
+        );
+        assert_eq!(
+            Err(libc::EROFS),
+            fs.rename(out, OsStr::new("foo"), out, OsStr::new("bar"), 0)
+        );
+        assert_eq!(
+            Err(libc::EEXIST),
+            fs.create(out, OsStr::new("bar"), 0o644, 0, libc::O_WRONLY)
+                .map(|_| ())
+        );
+        assert_eq!(
+            Err(libc::EROFS),
+            fs.create(lazy, OsStr::new("new"), 0o644, 0, libc::O_WRONLY)
+                .map(|_| ())
+        );
+        assert_eq!(Err(libc::EROFS), fs.open(file, libc::O_RDWR).map(|_| ()));
+        assert_eq!(
+            Err(libc::EROFS),
+            fs.setattr(file, None, None, None, Some(0), None, None, None)
+                .map(|_| ())
+        );
+        assert_eq!(
+            Err(libc::EXDEV),
+            fs.link(file, out, OsStr::new("hardlink")).map(|_| ())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_create_next_to_lazy_outputs() -> anyhow::Result<()> {
+        let temp = ProjectRootTemp::new()?;
+        let (fs, _fetcher) = lazy_fs(&temp)?;
+        fs_util::remove_all(
+            temp.path()
+                .resolve(ProjectRelativePath::unchecked_new("backing/out")),
+        )?;
+
+        // The directories that contain lazy outputs are created in the backing directory.
+        let out = lookup(&fs, "out").unwrap().ino;
+        let (attr, fh) = fs.create(out, OsStr::new("new"), 0o644, 0, libc::O_WRONLY)?;
+        match &*fs.file(fh).unwrap() {
+            OpenFile::Backing(f) => f.write_all_at(b"new", 0)?,
+            OpenFile::Lazy { .. } => panic!("Not a backing file"),
+        }
+        assert_eq!(
+            "new",
+            fs_util::read_to_string(
+                temp.path()
+                    .resolve(ProjectRelativePath::unchecked_new("backing/out/new"))
+            )?
+        );
+        assert_eq!(Ok(attr.ino), lookup(&fs, "out/new").map(|a| a.ino));
+
+        fs.rename(out, OsStr::new("new"), ROOT_INO, OsStr::new("moved"), 0)
+            .unwrap();
+        assert_eq!(Ok(attr.ino), lookup(&fs, "moved").map(|a| a.ino));
+        fs.unlink(ROOT_INO, OsStr::new("moved")).unwrap();
+        assert_eq!(Err(libc::ENOENT), lookup(&fs, "moved").map(|_| ()));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_read_lazy() -> anyhow::Result<()> {
+        let temp = ProjectRootTemp::new()?;
+        let (fs, fetcher) = lazy_fs(&temp)?;
+
+        let read = |path: &str, offset, size| {
+            let ino = lookup(&fs, path).unwrap().ino;
+            let file = fs.file(fs.open(ino, libc::O_RDONLY).unwrap()).unwrap();
+            let fs = &fs;
+            async move {
+                match &*file {
+                    OpenFile::Lazy {
+                        meta,
+                        re_use_case,
+                        blob,
+                    } => fs.read_lazy(meta, *re_use_case, blob, offset, size).await,
+                    OpenFile::Backing(..) => panic!("Not a lazy file"),
+                }
+            }
+        };
+
+        assert_eq!(b"world".to_vec(), read("out/lazy/file", 6, 100).await?);
+        assert_eq!(b"hello".to_vec(), read("out/lazy/file", 0, 5).await?);
+        assert_eq!(1, fetcher.fetches.load(Ordering::Relaxed));
+        assert!(read("out/bar", 0, 5).await.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_restore_lazy_outputs() -> anyhow::Result<()> {
+        let temp = ProjectRootTemp::new()?;
+        let fs = temp.path();
+        let gen = ProjectRelativePath::unchecked_new("buck-out/gen");
+        let dir = ProjectRelativePath::unchecked_new("buck-out/lazy_outputs");
+        temp.write_file("buck-out/lazy_outputs/gen/out", "out");
+
+        // Outputs left by a daemon that didn't use lazy outputs are kept over the backing
+        // directory.
+        temp.write_file("buck-out/gen/new", "new");
+        restore_lazy_outputs(fs, gen, dir)?;
+        assert!(fs_util::try_exists(
+            fs.resolve(gen).join(ForwardRelativePath::new("new")?)
+        )?);
+        assert!(!fs_util::try_exists(fs.resolve(dir))?);
+
+        temp.write_file("buck-out/lazy_outputs/gen/out", "out");
+        fs_util::remove_all(fs.resolve(gen))?;
+        restore_lazy_outputs(fs, gen, dir)?;
+        assert_eq!(
+            "out",
+            fs_util::read_to_string(fs.resolve(gen).join(ForwardRelativePath::new("out")?))?
+        );
+        Ok(())
+    }
+}
@@ -47,6 +47,9 @@ use remote_execution::NamedDigestWithPermissions;
 
 use crate::materializers::io::materialize_files;
 use crate::materializers::io::MaterializeTreeStructure;
+use crate::materializers::local_cas::LocalCas;
+use crate::materializers::verified_download::download_files;
+use crate::materializers::verified_download::DownloadVerificationStat;
 
 /// Materializer that materializes everything immediately on declare.
 #[derive(Allocative)]
@@ -55,6 +58,8 @@ pub struct ImmediateMaterializer {
     digest_config: DigestConfig,
     re_client_manager: Arc<ReConnectionManager>,
     io_executor: Arc<dyn BlockingExecutor>,
+    local_cas: Option<Arc<LocalCas>>,
+    verify_downloads: bool,
 }
 
 impl ImmediateMaterializer {
@@ -69,8 +74,23 @@ impl ImmediateMaterializer {
             digest_config,
             re_client_manager,
             io_executor,
+            local_cas: None,
+            verify_downloads: false,
         }
     }
+
+    /// Look for files in `local_cas` before downloading them, and add downloaded files to it.
+    pub fn with_local_cas(mut self, local_cas: Option<Arc<LocalCas>>) -> Self {
+        self.local_cas = local_cas;
+        self
+    }
+
+    /// Check the digests of files downloaded from the CAS, and download them again if they
+    /// don't match.
+    pub fn with_verify_downloads(mut self, verify_downloads: bool) -> Self {
+        self.verify_downloads = verify_downloads;
+        self
+    }
 }
 
 #[async_trait]
@@ -154,22 +174,53 @@ impl Materializer for ImmediateMaterializer {
             let mut walk = unordered_entry_walk(value.entry().as_ref());
             while let Some((entry_path, entry)) = walk.next() {
                 if let DirectoryEntry::Leaf(ActionDirectoryMember::File(m)) = entry {
-                    files.push(NamedDigestWithPermissions {
-                        named_digest: NamedDigest {
-                            digest: m.digest.to_re(),
-                            name: path.join_normalized(entry_path.get())?.to_string(),
-                            ..Default::default()
-                        },
-                        is_executable: m.is_executable,
-                        ..Default::default()
-                    });
+                    files.push((path.join_normalized(entry_path.get())?, m.dupe()));
                 }
             }
         }
 
+        if let Some(local_cas) = &self.local_cas {
+            files = self
+                .io_executor
+                .execute_io_inline(|| local_cas.materialize_many(&self.fs, files))
+                .await?;
+            if files.is_empty() {
+                return Ok(());
+            }
+        }
+
         let re_conn = self.re_client_manager.get_re_connection();
         let re_client = re_conn.get_client();
-        critical_section(|| re_client.materialize_files(files, info.re_use_case)).await?;
+        download_files(
+            &self.fs,
+            self.digest_config,
+            self.io_executor.as_ref(),
+            self.verify_downloads,
+            &files,
+            &mut DownloadVerificationStat::default(),
+            |files| {
+                let re_files = files.map(|(name, m)| NamedDigestWithPermissions {
+                    named_digest: NamedDigest {
+                        digest: m.digest.to_re(),
+                        name: name.to_string(),
+                        ..Default::default()
+                    },
+                    is_executable: m.is_executable,
+                    ..Default::default()
+                });
+                critical_section(|| re_client.materialize_files(re_files, info.re_use_case))
+            },
+        )
+        .await?;
+
+        if let Some(local_cas) = &self.local_cas {
+            self.io_executor
+                .execute_io_inline(|| {
+                    local_cas.insert_many(&self.fs, &files);
+                    Ok(())
+                })
+                .await?;
+        }
         Ok(())
     }
 
@@ -185,11 +236,12 @@ impl Materializer for ImmediateMaterializer {
             .await?;
 
         http_download(
-            &http_client()?,
+            &http_client(info.config.dupe())?,
+            self.io_executor.as_ref(),
             &self.fs,
             self.digest_config,
             &path,
-            &info.url,
+            &info.urls,
             &info.checksum,
             info.metadata.is_executable,
         )
@@ -9,17 +9,29 @@
 
 use std::collections::HashMap;
 
+use anyhow::Context as _;
+use buck2_common::file_ops::FileDigest;
+use buck2_common::file_ops::FileMetadata;
+use buck2_common::file_ops::TrackedFileDigest;
 use buck2_core::directory::DirectoryEntry;
 use buck2_core::fs::fs_util;
 use buck2_core::fs::paths::abs_norm_path::AbsNormPath;
 use buck2_core::fs::paths::abs_norm_path::AbsNormPathBuf;
+use buck2_core::fs::paths::file_name::FileNameBuf;
 use buck2_core::fs::project::ProjectRoot;
 use buck2_core::fs::project_rel_path::ProjectRelativePathBuf;
+use buck2_execute::digest_config::DigestConfig;
+use buck2_execute::directory::new_symlink;
 use buck2_execute::directory::ActionDirectory;
+use buck2_execute::directory::ActionDirectoryBuilder;
 use buck2_execute::directory::ActionDirectoryEntry;
 use buck2_execute::directory::ActionDirectoryMember;
 use buck2_execute::directory::ActionSharedDirectory;
 use buck2_execute::execute::blocking::IoRequest;
+use faccess::PathExt;
+use thiserror::Error;
+
+use crate::materializers::local_cas::clone_or_copy;
 
 pub struct MaterializeTreeStructure {
     pub path: ProjectRelativePathBuf,
@@ -76,8 +88,8 @@ where
 
 /// Materializes the files of an the entry rooted at `dest`.
 ///
-/// Files are copied from `src`. In other words, if a file would be
-/// materialized at `dest/p`, then it's copied from `src/p`.
+/// Files are copied from `src`, using reflinks where supported. In other words, if a file would
+/// be materialized at `dest/p`, then it's copied from `src/p`.
 pub(crate) fn materialize_files<P, D>(
     entry: DirectoryEntry<&D, &ActionDirectoryMember>,
     src: P,
@@ -146,7 +158,7 @@ where
         DirectoryEntry::Leaf(ActionDirectoryMember::File(_)) => {
             if let Some(src) = file_src(dest) {
                 if fs_util::symlink_metadata(&dest).is_err() {
-                    fs_util::copy(src, dest)?;
+                    clone_or_copy(&src, dest)?;
                 }
             }
             Ok(())
@@ -165,3 +177,87 @@ where
         }
     }
 }
+
+#[derive(Debug, Error)]
+#[error("`{0}` is not a file, a directory or a symlink")]
+pub(crate) struct UnknownFileTypeError(pub(crate) AbsNormPathBuf);
+
+/// Build the entry at `path` from what is on disk, hashing every file. Returns `None` if there is
+/// nothing at `path`, and an `UnknownFileTypeError` if there is anything else than files,
+/// directories and symlinks (e.g. a FIFO or a socket).
+pub(crate) fn build_entry_from_disk(
+    mut path: AbsNormPathBuf,
+    digest_config: DigestConfig,
+) -> anyhow::Result<Option<ActionDirectoryEntry<ActionDirectoryBuilder>>> {
+    fn build_dir_from_disk(
+        disk_path: &mut AbsNormPathBuf,
+        digest_config: DigestConfig,
+    ) -> anyhow::Result<ActionDirectoryBuilder> {
+        let mut builder = ActionDirectoryBuilder::empty();
+
+        for file in fs_util::read_dir(&disk_path)? {
+            let file = file?;
+            let filetype = file.file_type()?;
+            let filename = file.file_name();
+
+            let filename = filename
+                .to_str()
+                .context("Filename is not UTF-8")
+                .and_then(|f| FileNameBuf::try_from(f.to_owned()))
+                .with_context(|| format!("Invalid filename: {}", disk_path.display()))?;
+
+            disk_path.push(&filename);
+
+            if filetype.is_dir() {
+                let dir = build_dir_from_disk(disk_path, digest_config)?;
+                builder.insert(filename, DirectoryEntry::Dir(dir))?;
+            } else if filetype.is_symlink() {
+                builder.insert(
+                    filename,
+                    DirectoryEntry::Leaf(new_symlink(fs_util::read_link(&disk_path)?)?),
+                )?;
+            } else if filetype.is_file() {
+                let metadata = FileMetadata {
+                    digest: TrackedFileDigest::new(
+                        FileDigest::from_file(&disk_path, digest_config.cas_digest_config())?,
+                        digest_config.cas_digest_config(),
+                    ),
+                    is_executable: file.path().executable(),
+                };
+                builder.insert(
+                    filename,
+                    DirectoryEntry::Leaf(ActionDirectoryMember::File(metadata)),
+                )?;
+            } else {
+                return Err(UnknownFileTypeError(disk_path.clone()).into());
+            }
+            disk_path.pop();
+        }
+
+        Ok(builder)
+    }
+
+    // Get file metadata. If the file is missing, ignore it.
+    let m = match std::fs::symlink_metadata(&path) {
+        Ok(m) => m,
+        Err(ref err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+
+    let value = if m.file_type().is_symlink() {
+        DirectoryEntry::Leaf(new_symlink(fs_util::read_link(&path)?)?)
+    } else if m.is_file() {
+        DirectoryEntry::Leaf(ActionDirectoryMember::File(FileMetadata {
+            digest: TrackedFileDigest::new(
+                FileDigest::from_file(&path, digest_config.cas_digest_config())?,
+                digest_config.cas_digest_config(),
+            ),
+            is_executable: path.executable(),
+        }))
+    } else if m.is_dir() {
+        DirectoryEntry::Dir(build_dir_from_disk(&mut path, digest_config)?)
+    } else {
+        return Err(UnknownFileTypeError(path).into());
+    };
+    Ok(Some(value))
+}
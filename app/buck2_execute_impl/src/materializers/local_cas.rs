@@ -0,0 +1,639 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+//! A content-addressed store of file contents on local disk, which can be shared by several
+//! daemons (e.g. across checkouts and isolation dirs), configured via `buck2.local_cas_dir`.
+//!
+//! The materializers look files up there before downloading them, and add the files they
+//! download. Files are linked out of the store with reflinks when the filesystem supports them.
+//! Otherwise, if `buck2.local_cas_hardlinks` is set, they are hardlinked, or copied if the
+//! output is on another filesystem. Otherwise, they are copied.
+//!
+//! Objects are hashed once, when they are inserted. They are read-only, so the outputs hardlinked
+//! to them are read-only too, and their mtime is never changed afterwards: it is recorded in a
+//! stamp file next to the object, in `stamps`. Modifying an object through an output hardlinked
+//! to it requires making it writable and changes its mtime, so objects are checked against their
+//! stamps after being hardlinked, and discarded if they were modified.
+//!
+//! If `buck2.local_cas_max_bytes` is set, the least recently used objects are deleted when the
+//! store grows past that size. Uses are recorded as the mtime of the stamps, so that they don't
+//! change the outputs hardlinked to the objects.
+
+use std::fs::Metadata;
+use std::io;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::time::SystemTime;
+
+use allocative::Allocative;
+use anyhow::Context;
+use buck2_common::file_ops::FileDigest;
+use buck2_common::file_ops::FileMetadata;
+use buck2_core::fs::fs_util;
+use buck2_core::fs::paths::abs_norm_path::AbsNormPath;
+use buck2_core::fs::paths::abs_norm_path::AbsNormPathBuf;
+use buck2_core::fs::paths::forward_rel_path::ForwardRelativePath;
+use buck2_core::fs::project::ProjectRoot;
+use buck2_core::fs::project_rel_path::ProjectRelativePathBuf;
+use buck2_execute::digest_config::DigestConfig;
+
+pub struct LocalCasConfig {
+    /// Where to store objects. Must be on the same filesystem as buck-out for reflinks and
+    /// hardlinks to work.
+    pub root: AbsNormPathBuf,
+    /// Whether to fall back to hardlinks when reflinks aren't supported.
+    pub hardlinks: bool,
+    /// How large the store may grow before objects are evicted from it.
+    pub max_bytes: Option<u64>,
+    pub digest_config: DigestConfig,
+}
+
+#[derive(Allocative)]
+pub struct LocalCas {
+    root: AbsNormPathBuf,
+    hardlinks: bool,
+    max_bytes: Option<u64>,
+    digest_config: DigestConfig,
+    /// How many bytes were inserted since the store was last trimmed.
+    #[allocative(skip)]
+    inserted_bytes: AtomicU64,
+}
+
+impl LocalCas {
+    pub fn new(config: LocalCasConfig) -> anyhow::Result<Self> {
+        fs_util::create_dir_all(
+            config
+                .root
+                .join(ForwardRelativePath::unchecked_new(TMP_DIR)),
+        )?;
+        fs_util::create_dir_all(
+            config
+                .root
+                .join(ForwardRelativePath::unchecked_new(STAMPS_DIR)),
+        )?;
+        Ok(Self {
+            root: config.root,
+            hardlinks: config.hardlinks,
+            max_bytes: config.max_bytes,
+            digest_config: config.digest_config,
+            // Start at the threshold, so that the store is trimmed on the first insert after the
+            // daemon starts.
+            inserted_bytes: AtomicU64::new(config.max_bytes.map_or(0, gc_threshold)),
+        })
+    }
+
+    /// The path of an object relative to the root. Executable and non-executable files are stored
+    /// separately, because hardlinks share permissions.
+    fn object_name(meta: &FileMetadata) -> String {
+        let digest = meta.digest.raw_digest().to_string();
+        let mut name = format!("{}/{}_{}", &digest[..2], digest, meta.digest.size());
+        if meta.is_executable {
+            name.push_str(".x");
+        }
+        name
+    }
+
+    fn object_path(&self, meta: &FileMetadata) -> AbsNormPathBuf {
+        self.root
+            .join(ForwardRelativePath::unchecked_new(&Self::object_name(meta)))
+    }
+
+    fn stamp_path(&self, object_name: &str) -> AbsNormPathBuf {
+        self.root
+            .join(ForwardRelativePath::unchecked_new(STAMPS_DIR))
+            .join(ForwardRelativePath::unchecked_new(object_name))
+    }
+
+    /// Whether `object` is still what was inserted, according to its stamp.
+    fn is_intact(&self, object: &AbsNormPath, stamp: &AbsNormPath) -> anyhow::Result<bool> {
+        let object_meta = fs_util::symlink_metadata(object)?;
+        if !object_meta.permissions().readonly() {
+            return Ok(false);
+        }
+        Ok(fs_util::read_to_string_opt(stamp)?.as_deref() == Some(&*mtime_stamp(&object_meta)))
+    }
+
+    /// Materialize the file described by `meta` at `dest`, if the store has it. Returns whether
+    /// it did.
+    pub fn materialize(&self, meta: &FileMetadata, dest: &AbsNormPath) -> anyhow::Result<bool> {
+        let object_name = Self::object_name(meta);
+        let object = self.object_path(meta);
+        let stamp = self.stamp_path(&object_name);
+        let object_meta = match fs_util::symlink_metadata_if_exists(&object)? {
+            Some(object_meta) => object_meta,
+            None => return Ok(false),
+        };
+        if object_meta.len() != meta.digest.size() {
+            return self.discard(&object, "size");
+        }
+
+        let res: anyhow::Result<()> = try {
+            if clone_file(&object, dest).is_ok() {
+                set_writable(dest, meta.is_executable)?;
+            } else if self.hardlinks {
+                match fs_util::hard_link(&object, dest) {
+                    Ok(()) => {}
+                    // Outputs may be on another filesystem, e.g. when lazy outputs are mounted
+                    // over buck-out.
+                    Err(e) if is_cross_device(&e) => {
+                        fs_util::copy(&object, dest)?;
+                        set_writable(dest, meta.is_executable)?;
+                    }
+                    Err(e) => Err(e)?,
+                }
+                // Check the object once it's linked, so that it can't be modified in between.
+                // Modifying it from now on modifies this output too, like any other output.
+                if !self.is_intact(&object, &stamp)? {
+                    fs_util::remove_file(dest)?;
+                    return self.discard(&object, "contents");
+                }
+            } else {
+                fs_util::copy(&object, dest)?;
+                set_writable(dest, meta.is_executable)?;
+            }
+        };
+        if let Err(e) = res {
+            // Another daemon may have evicted the object in the meantime.
+            if fs_util::try_exists(&object)? {
+                return Err(e);
+            }
+            // It may have been linked before being checked.
+            let _ignored = fs_util::remove_file(dest);
+            return Ok(false);
+        }
+
+        // Record that the object was used, for eviction.
+        touch(&stamp);
+        Ok(true)
+    }
+
+    /// Delete an object that doesn't match its name.
+    fn discard(&self, object: &AbsNormPath, what: &str) -> anyhow::Result<bool> {
+        tracing::warn!(
+            "Object `{}` in the local CAS has the wrong {}, deleting it",
+            object.display(),
+            what
+        );
+        fs_util::remove_file(object)?;
+        Ok(false)
+    }
+
+    /// Add the file at `src`, whose metadata is `meta`, to the store. The file is always copied,
+    /// since `src` might be modified later.
+    pub fn insert(&self, src: &AbsNormPath, meta: &FileMetadata) -> anyhow::Result<()> {
+        static NEXT_TMP: AtomicU64 = AtomicU64::new(0);
+
+        let object_name = Self::object_name(meta);
+        let object = self.object_path(meta);
+        if fs_util::try_exists(&object)? {
+            return Ok(());
+        }
+
+        // Write to a temporary path and rename, so that other daemons never see partial objects.
+        let tmp_path = || {
+            let tmp_name = format!(
+                "{}/{}.{}",
+                TMP_DIR,
+                std::process::id(),
+                NEXT_TMP.fetch_add(1, Ordering::Relaxed)
+            );
+            self.root
+                .join(ForwardRelativePath::unchecked_new(&tmp_name))
+        };
+        let tmp = tmp_path();
+        let tmp_stamp = tmp_path();
+        let res: anyhow::Result<()> = try {
+            clone_or_copy(src, &tmp)?;
+            // This is the only time the object is hashed: `src` may have been modified since it
+            // was materialized.
+            self.check_digest(&tmp, meta)
+                .with_context(|| format!("Inserting `{}`", src.display()))?;
+            set_read_only(&tmp, meta.is_executable)?;
+            // Renaming preserves the mtime.
+            fs_util::write(&tmp_stamp, mtime_stamp(&fs_util::symlink_metadata(&tmp)?))?;
+
+            let stamp = self.stamp_path(&object_name);
+            for path in [&object, &stamp] {
+                if let Some(parent) = path.parent() {
+                    fs_util::create_dir_all(parent)?;
+                }
+            }
+            // If another daemon inserts the same object concurrently, the object and stamp may
+            // come from different inserts. The object is then discarded on its next use.
+            fs_util::rename(&tmp_stamp, &stamp)?;
+            fs_util::rename(&tmp, &object)?;
+        };
+        if res.is_err() {
+            // Don't leave partial copies behind.
+            let _ignored = fs_util::remove_file(&tmp);
+            let _ignored = fs_util::remove_file(&tmp_stamp);
+        }
+        res
+    }
+
+    fn check_digest(&self, path: &AbsNormPath, meta: &FileMetadata) -> anyhow::Result<()> {
+        let digest =
+            FileDigest::from_file_disk(path.as_path(), self.digest_config.cas_digest_config())?;
+        if &digest != meta.digest.data() {
+            return Err(anyhow::anyhow!(
+                "Expected digest `{}`, got `{}`",
+                meta.digest,
+                digest
+            ));
+        }
+        Ok(())
+    }
+
+    /// Materialize the `files` that the store has, and return the others.
+    pub fn materialize_many(
+        &self,
+        fs: &ProjectRoot,
+        files: Vec<(ProjectRelativePathBuf, FileMetadata)>,
+    ) -> anyhow::Result<Vec<(ProjectRelativePathBuf, FileMetadata)>> {
+        let mut missing = Vec::new();
+        for (path, meta) in files {
+            if !self.materialize(&meta, &fs.resolve(&path))? {
+                missing.push((path, meta));
+            }
+        }
+        Ok(missing)
+    }
+
+    /// Add `files`, which were just materialized, to the store. This is best-effort, so errors
+    /// are only logged.
+    pub fn insert_many(&self, fs: &ProjectRoot, files: &[(ProjectRelativePathBuf, FileMetadata)]) {
+        for (path, meta) in files {
+            if let Err(e) = self.insert(&fs.resolve(path), meta) {
+                tracing::warn!("Failed to add `{}` to the local CAS: {:#}", path, e);
+            }
+        }
+
+        if let Some(max_bytes) = self.max_bytes {
+            let inserted: u64 = files.iter().map(|(_, meta)| meta.digest.size()).sum();
+            let total = self.inserted_bytes.fetch_add(inserted, Ordering::Relaxed) + inserted;
+            if total >= gc_threshold(max_bytes) {
+                self.inserted_bytes.store(0, Ordering::Relaxed);
+                if let Err(e) = self.gc(max_bytes) {
+                    tracing::warn!("Failed to trim the local CAS: {:#}", e);
+                }
+            }
+        }
+    }
+
+    /// Delete the least recently used objects until the store is no larger than `max_bytes`.
+    fn gc(&self, max_bytes: u64) -> anyhow::Result<()> {
+        let mut objects = Vec::new();
+        let mut total = 0;
+        for dir in fs_util::read_dir(&self.root)? {
+            let dir = dir?;
+            if dir.file_name() == TMP_DIR
+                || dir.file_name() == STAMPS_DIR
+                || !dir.file_type()?.is_dir()
+            {
+                continue;
+            }
+            for object in fs_util::read_dir(AbsNormPath::new(&dir.path())?)? {
+                let object = object?;
+                let meta = object.metadata()?;
+                let object_name = format!(
+                    "{}/{}",
+                    dir.file_name().to_string_lossy(),
+                    object.file_name().to_string_lossy()
+                );
+                let stamp = self.stamp_path(&object_name);
+                // Objects without a stamp are discarded on their next use anyway.
+                let last_use = match fs_util::symlink_metadata_if_exists(&stamp)? {
+                    Some(stamp_meta) => stamp_meta.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+                    None => SystemTime::UNIX_EPOCH,
+                };
+                total += meta.len();
+                objects.push((last_use, meta.len(), object.path(), stamp));
+            }
+        }
+
+        objects.sort_by_key(|(last_use, ..)| *last_use);
+        for (_, size, path, stamp) in objects {
+            if total <= max_bytes {
+                break;
+            }
+            // Objects can be evicted by several daemons at once.
+            if fs_util::remove_file(&path).is_ok() {
+                total -= size;
+                let _ignored = fs_util::remove_file(&stamp);
+            }
+        }
+        Ok(())
+    }
+}
+
+const TMP_DIR: &str = "tmp";
+const STAMPS_DIR: &str = "stamps";
+
+/// The mtime of an object, as recorded in its stamp.
+fn mtime_stamp(meta: &Metadata) -> String {
+    let mtime = meta
+        .modified()
+        .ok()
+        .and_then(|mtime| mtime.duration_since(SystemTime::UNIX_EPOCH).ok())
+        .unwrap_or_default();
+    mtime.as_nanos().to_string()
+}
+
+fn is_cross_device(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<io::Error>()
+        .and_then(|e| e.raw_os_error())
+        .map_or(false, |errno| errno == libc::EXDEV)
+}
+
+/// Trim the store whenever a tenth of its maximum size was inserted, to avoid listing it on every
+/// insert.
+fn gc_threshold(max_bytes: u64) -> u64 {
+    max_bytes / 10
+}
+
+/// Set the modification time of `path` to now. This records when the object of a stamp was last
+/// used.
+fn touch(path: &AbsNormPath) {
+    #[cfg(unix)]
+    {
+        use std::ffi::CString;
+        use std::os::unix::ffi::OsStrExt;
+
+        if let Ok(path) = CString::new(path.as_path().as_os_str().as_bytes()) {
+            // SAFETY: `path` is a valid C string, and a null `times` means now.
+            unsafe {
+                libc::utimensat(libc::AT_FDCWD, path.as_ptr(), std::ptr::null(), 0);
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _unused = path;
+    }
+}
+
+/// Create `dest` as a reflink of `src`, i.e. a copy that shares its blocks until either is
+/// modified.
+#[cfg(target_os = "linux")]
+fn clone_file(src: &AbsNormPath, dest: &AbsNormPath) -> anyhow::Result<()> {
+    use std::fs::File;
+    use std::os::unix::io::AsRawFd;
+
+    // From linux/fs.h.
+    const FICLONE: libc::c_ulong = 0x40049409;
+
+    let src_file = File::open(src)?;
+    let dest_file = File::create(dest)?;
+    // SAFETY: both file descriptors are valid for the duration of the call.
+    let res = unsafe { libc::ioctl(dest_file.as_raw_fd(), FICLONE as _, src_file.as_raw_fd()) };
+    if res != 0 {
+        let err = std::io::Error::last_os_error();
+        drop(dest_file);
+        fs_util::remove_file(dest)?;
+        return Err(anyhow::Error::new(err).context(format!(
+            "reflink(src={}, dest={})",
+            src.display(),
+            dest.display()
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn clone_file(_src: &AbsNormPath, _dest: &AbsNormPath) -> anyhow::Result<()> {
+    Err(anyhow::anyhow!(
+        "Reflinks are not supported on this platform"
+    ))
+}
+
+/// Copy `src` to `dest`, using a reflink when the filesystem supports it.
+pub(crate) fn clone_or_copy(src: &AbsNormPath, dest: &AbsNormPath) -> anyhow::Result<()> {
+    if clone_file(src, dest).is_ok() {
+        // Reflinks don't carry permissions, unlike copies.
+        return fs_util::set_permissions(dest, fs_util::metadata(src)?.permissions());
+    }
+    fs_util::copy(src, dest)?;
+    Ok(())
+}
+
+#[cfg(unix)]
+fn set_mode(path: &AbsNormPath, mode: u32) -> anyhow::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    fs_util::set_permissions(path, std::fs::Permissions::from_mode(mode))
+}
+
+fn set_writable(path: &AbsNormPath, is_executable: bool) -> anyhow::Result<()> {
+    #[cfg(unix)]
+    {
+        set_mode(path, if is_executable { 0o755 } else { 0o644 })
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _unused = is_executable;
+        let mut perms = fs_util::metadata(path)?.permissions();
+        perms.set_readonly(false);
+        fs_util::set_permissions(path, perms)
+    }
+}
+
+fn set_read_only(path: &AbsNormPath, is_executable: bool) -> anyhow::Result<()> {
+    #[cfg(unix)]
+    {
+        set_mode(path, if is_executable { 0o555 } else { 0o444 })
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _unused = is_executable;
+        let mut perms = fs_util::metadata(path)?.permissions();
+        perms.set_readonly(true);
+        fs_util::set_permissions(path, perms)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use buck2_common::file_ops::TrackedFileDigest;
+    use buck2_core::fs::project::ProjectRootTemp;
+    use buck2_core::fs::project_rel_path::ProjectRelativePath;
+
+    use super::*;
+
+    fn local_cas(fs: &ProjectRoot, max_bytes: Option<u64>) -> anyhow::Result<LocalCas> {
+        LocalCas::new(LocalCasConfig {
+            root: fs.resolve(ProjectRelativePath::unchecked_new("cas")),
+            hardlinks: true,
+            max_bytes,
+            digest_config: DigestConfig::testing_default(),
+        })
+    }
+
+    fn file_metadata(content: &str) -> FileMetadata {
+        FileMetadata {
+            digest: TrackedFileDigest::from_content(
+                content.as_bytes(),
+                DigestConfig::testing_default().cas_digest_config(),
+            ),
+            is_executable: false,
+        }
+    }
+
+    #[test]
+    fn test_insert_and_materialize() -> anyhow::Result<()> {
+        let temp = ProjectRootTemp::new()?;
+        let fs = temp.path();
+        let cas = local_cas(fs, None)?;
+
+        let meta = file_metadata("hello");
+        let src = ProjectRelativePath::unchecked_new("out/src").to_owned();
+        let dest = ProjectRelativePath::unchecked_new("out/dest").to_owned();
+        temp.write_file("out/src", "hello");
+
+        let files = vec![(dest.clone(), meta.clone())];
+        assert_eq!(files, cas.materialize_many(fs, files.clone())?);
+
+        cas.insert_many(fs, &[(src, meta)]);
+        assert_eq!(Vec::<(_, _)>::new(), cas.materialize_many(fs, files)?);
+        assert_eq!("hello", fs_util::read_to_string(fs.resolve(&dest))?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_materialize_discards_corrupt_object() -> anyhow::Result<()> {
+        let temp = ProjectRootTemp::new()?;
+        let fs = temp.path();
+        let cas = local_cas(fs, None)?;
+
+        let meta = file_metadata("hello");
+        temp.write_file("out/src", "hello");
+        cas.insert(
+            &fs.resolve(ProjectRelativePath::unchecked_new("out/src")),
+            &meta,
+        )?;
+
+        // Same size, different contents, and read-only again: only the mtime changed.
+        let object = cas.object_path(&meta);
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        set_writable(&object, false)?;
+        fs_util::write(&object, "world")?;
+        set_read_only(&object, false)?;
+
+        let dest = fs.resolve(ProjectRelativePath::unchecked_new("out/dest"));
+        if clone_file(&object, &dest).is_ok() {
+            // Reflinks never share writes, so the store doesn't check objects it clones.
+            return Ok(());
+        }
+        assert!(!cas.materialize(&meta, &dest)?);
+        assert!(!fs_util::try_exists(&object)?);
+        assert!(!fs_util::try_exists(&dest)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_materialize_keeps_object_mtime() -> anyhow::Result<()> {
+        let temp = ProjectRootTemp::new()?;
+        let fs = temp.path();
+        let cas = local_cas(fs, None)?;
+
+        let meta = file_metadata("hello");
+        temp.write_file("out/src", "hello");
+        cas.insert(
+            &fs.resolve(ProjectRelativePath::unchecked_new("out/src")),
+            &meta,
+        )?;
+        let object = cas.object_path(&meta);
+        let mtime = fs_util::symlink_metadata(&object)?.modified()?;
+
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        assert!(cas.materialize(
+            &meta,
+            &fs.resolve(ProjectRelativePath::unchecked_new("out/dest"))
+        )?);
+        assert_eq!(mtime, fs_util::symlink_metadata(&object)?.modified()?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_insert_checks_digest() -> anyhow::Result<()> {
+        let temp = ProjectRootTemp::new()?;
+        let fs = temp.path();
+        let cas = local_cas(fs, None)?;
+
+        // Modified since it was materialized.
+        let meta = file_metadata("hello");
+        temp.write_file("out/src", "world");
+        assert!(
+            cas.insert(
+                &fs.resolve(ProjectRelativePath::unchecked_new("out/src")),
+                &meta,
+            )
+            .is_err()
+        );
+        assert!(!fs_util::try_exists(cas.object_path(&meta))?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_insert_failure_removes_tmp() -> anyhow::Result<()> {
+        let temp = ProjectRootTemp::new()?;
+        let fs = temp.path();
+        let cas = local_cas(fs, None)?;
+
+        assert!(
+            cas.insert(
+                &fs.resolve(ProjectRelativePath::unchecked_new("out/missing")),
+                &file_metadata("hello"),
+            )
+            .is_err()
+        );
+        assert_eq!(
+            0,
+            fs_util::read_dir(fs.resolve(ProjectRelativePath::unchecked_new("cas/tmp")))?.count()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_gc() -> anyhow::Result<()> {
+        let temp = ProjectRootTemp::new()?;
+        let fs = temp.path();
+        let cas = local_cas(fs, Some(12))?;
+
+        let mut files = Vec::new();
+        for (name, content) in [("a", "aaaaa"), ("b", "bbbbb"), ("c", "ccccc")] {
+            let path = ProjectRelativePath::unchecked_new("out")
+                .join(ForwardRelativePath::unchecked_new(name));
+            temp.write_file(path.as_str(), content);
+            files.push((path, file_metadata(content)));
+        }
+
+        cas.insert_many(fs, &files[..2]);
+        // Use `a`, so that `b` is the least recently used object.
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        assert!(cas.materialize(
+            &files[0].1,
+            &fs.resolve(ProjectRelativePath::unchecked_new("out/a2"))
+        )?);
+        cas.insert_many(fs, &files[2..]);
+
+        assert!(fs_util::try_exists(cas.object_path(&files[0].1))?);
+        assert!(!fs_util::try_exists(cas.object_path(&files[1].1))?);
+        assert!(fs_util::try_exists(cas.object_path(&files[2].1))?);
+
+        Ok(())
+    }
+}
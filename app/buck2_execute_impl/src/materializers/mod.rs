@@ -11,6 +11,10 @@
 pub mod eden;
 
 pub mod deferred;
+#[cfg(target_os = "linux")]
+pub mod fuse;
 pub mod immediate;
 pub mod io;
+pub mod local_cas;
 pub mod sqlite;
+pub(crate) mod verified_download;
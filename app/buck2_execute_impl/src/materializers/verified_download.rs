@@ -0,0 +1,206 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+//! Verification of files downloaded from the CAS, enabled by `buck2.materializer_verify_downloads`.
+//!
+//! The RE client writes the files it downloads itself, so they are hashed right after they have
+//! been written. Files that don't match their digest (e.g. because a proxy corrupted them) are
+//! deleted and downloaded again, and the materialization fails if they're still corrupt after a
+//! few attempts, rather than leaving bad files in buck-out.
+
+use std::future::Future;
+
+use buck2_common::file_ops::FileDigest;
+use buck2_common::file_ops::FileMetadata;
+use buck2_core::fs::fs_util;
+use buck2_core::fs::project::ProjectRoot;
+use buck2_core::fs::project_rel_path::ProjectRelativePathBuf;
+use buck2_execute::digest_config::DigestConfig;
+use buck2_execute::execute::blocking::BlockingExecutor;
+use dupe::Dupe;
+use thiserror::Error;
+
+/// How many times to download a file that keeps being corrupt before giving up.
+const MAX_DOWNLOAD_ATTEMPTS: u64 = 3;
+
+#[derive(Debug, Error)]
+#[error(
+    "Downloaded file `{path}` has digest `{actual}`, expected `{expected}` (after {attempts} attempts)"
+)]
+struct CorruptDownloadError {
+    path: ProjectRelativePathBuf,
+    expected: FileDigest,
+    actual: FileDigest,
+    attempts: u64,
+}
+
+#[derive(Default)]
+pub(crate) struct DownloadVerificationStat {
+    /// How many files were hashed after being downloaded, counting retries.
+    pub(crate) verified_file_count: u64,
+    /// How many of those didn't match their digest.
+    pub(crate) corrupt_file_count: u64,
+}
+
+/// Hash the `files` that were just downloaded, delete the ones that don't match their digest, and
+/// return them along with the digest they actually had.
+fn find_corrupt_files(
+    fs: &ProjectRoot,
+    digest_config: DigestConfig,
+    files: &[(ProjectRelativePathBuf, FileMetadata)],
+) -> anyhow::Result<Vec<(ProjectRelativePathBuf, FileMetadata, FileDigest)>> {
+    let mut corrupt = Vec::new();
+    for (path, meta) in files {
+        let abs_path = fs.resolve(path);
+        let actual =
+            FileDigest::from_file_disk(abs_path.as_path(), digest_config.cas_digest_config())?;
+        if &actual != meta.digest.data() {
+            fs_util::remove_file(&abs_path)?;
+            corrupt.push((path.clone(), meta.clone(), actual));
+        }
+    }
+    Ok(corrupt)
+}
+
+/// Download `files` using `download`. When `verify` is set, hash them once they have been written
+/// and download the ones that are corrupt again.
+pub(crate) async fn download_files<E, F, Fut>(
+    fs: &ProjectRoot,
+    digest_config: DigestConfig,
+    io_executor: &dyn BlockingExecutor,
+    verify: bool,
+    files: &[(ProjectRelativePathBuf, FileMetadata)],
+    stat: &mut DownloadVerificationStat,
+    download: F,
+) -> Result<(), E>
+where
+    E: From<anyhow::Error>,
+    F: Fn(Vec<(ProjectRelativePathBuf, FileMetadata)>) -> Fut,
+    Fut: Future<Output = Result<(), E>>,
+{
+    let mut to_download = files.to_vec();
+    for attempt in 1.. {
+        download(to_download.clone()).await?;
+        if !verify {
+            break;
+        }
+
+        let corrupt = io_executor
+            .execute_io_inline(|| find_corrupt_files(fs, digest_config, &to_download))
+            .await?;
+        stat.verified_file_count += to_download.len() as u64;
+        stat.corrupt_file_count += corrupt.len() as u64;
+
+        if let Some((path, meta, actual)) = corrupt.first() {
+            if attempt == MAX_DOWNLOAD_ATTEMPTS {
+                return Err(anyhow::Error::new(CorruptDownloadError {
+                    path: path.clone(),
+                    expected: meta.digest.data().dupe(),
+                    actual: actual.dupe(),
+                    attempts: attempt,
+                })
+                .into());
+            }
+            tracing::warn!(
+                "{} downloaded file(s) did not match their digest (e.g. `{}`), downloading again",
+                corrupt.len(),
+                path
+            );
+        } else {
+            break;
+        }
+
+        to_download = corrupt
+            .into_iter()
+            .map(|(path, meta, _)| (path, meta))
+            .collect();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use buck2_common::file_ops::TrackedFileDigest;
+    use buck2_core::fs::project::ProjectRootTemp;
+    use buck2_core::fs::project_rel_path::ProjectRelativePath;
+    use buck2_execute::execute::blocking::testing::DummyBlockingExecutor;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_download_files_retries_corrupt_files() -> anyhow::Result<()> {
+        let temp = ProjectRootTemp::new()?;
+        let fs = temp.path();
+        let io_executor = DummyBlockingExecutor { fs: fs.dupe() };
+        let digest_config = DigestConfig::testing_default();
+
+        let meta = FileMetadata {
+            digest: TrackedFileDigest::from_content(b"hello", digest_config.cas_digest_config()),
+            is_executable: false,
+        };
+        let path = ProjectRelativePath::unchecked_new("out/file").to_owned();
+        let files = vec![(path.clone(), meta)];
+
+        // The first download is corrupt, the next ones are fine.
+        let downloads = Cell::new(0);
+        let download = |files: Vec<(ProjectRelativePathBuf, FileMetadata)>| {
+            let content = if downloads.get() == 0 {
+                "hellx"
+            } else {
+                "hello"
+            };
+            downloads.set(downloads.get() + 1);
+            for (path, _) in files {
+                temp.write_file(path.as_str(), content);
+            }
+            futures::future::ready(anyhow::Ok(()))
+        };
+
+        let mut stat = DownloadVerificationStat::default();
+        download_files(
+            fs,
+            digest_config,
+            &io_executor,
+            true,
+            &files,
+            &mut stat,
+            download,
+        )
+        .await?;
+        assert_eq!(2, downloads.get());
+        assert_eq!(2, stat.verified_file_count);
+        assert_eq!(1, stat.corrupt_file_count);
+        assert_eq!("hello", fs_util::read_to_string(fs.resolve(&path))?);
+
+        // Files that are always corrupt fail the download.
+        let download = |files: Vec<(ProjectRelativePathBuf, FileMetadata)>| {
+            for (path, _) in files {
+                temp.write_file(path.as_str(), "hellx");
+            }
+            futures::future::ready(anyhow::Ok(()))
+        };
+        let mut stat = DownloadVerificationStat::default();
+        let res = download_files(
+            fs,
+            digest_config,
+            &io_executor,
+            true,
+            &files,
+            &mut stat,
+            download,
+        )
+        .await;
+        assert!(res.is_err());
+        assert_eq!(MAX_DOWNLOAD_ATTEMPTS, stat.corrupt_file_count);
+
+        Ok(())
+    }
+}
@@ -121,6 +121,7 @@ impl StatusDecoder for MiniperfStatusDecoder {
                                 cpu_instructions_kernel: Some(
                                     counters.kernel_instructions.adjusted_count(),
                                 ),
+                                ..Default::default()
                             });
 
                     if let Err(e) = execution_stats.as_ref() {
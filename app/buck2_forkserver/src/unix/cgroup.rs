@@ -0,0 +1,353 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+//! Run each command in its own cgroup (v2), so that we can limit the memory and CPU it uses, and
+//! report what it used once it exits, including whatever processes it spawned.
+//!
+//! Controllers can only be enabled for the children of a cgroup that has no processes of its own,
+//! so the daemon moves itself out of its cgroup before it starts the forkserver:
+//!
+//! ```text
+//! <cgroup the daemon was started in>
+//!     buck2-daemon    the daemon and the forkserver
+//!     buck2-actions   controllers are enabled for its children
+//!         <command>   one per command
+//! ```
+
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::Command;
+use std::process::ExitStatus;
+use std::time::Duration;
+
+use anyhow::Context as _;
+use async_trait::async_trait;
+use buck2_core::fs::fs_util;
+use buck2_core::fs::paths::abs_norm_path::AbsNormPathBuf;
+use buck2_core::fs::paths::forward_rel_path::ForwardRelativePath;
+use rand::distributions::Alphanumeric;
+use rand::distributions::DistString;
+use thiserror::Error;
+
+use crate::run::status_decoder::DecodedStatus;
+use crate::run::status_decoder::StatusDecoder;
+
+const CGROUP_MOUNT: &str = "/sys/fs/cgroup";
+
+/// The controllers we enable for commands, if available.
+const CONTROLLERS: &[&str] = &["cpu", "memory", "io"];
+
+/// The period used in `cpu.max`, in microseconds.
+const CPU_PERIOD_US: u64 = 100_000;
+
+#[derive(Debug, Error)]
+enum CgroupError {
+    #[error("cgroup v2 is not mounted at `{}`", CGROUP_MOUNT)]
+    NotMounted,
+    #[error("The process is not in a cgroup v2 hierarchy: `{0}`")]
+    NotInCgroup(String),
+}
+
+/// Move the daemon into its own cgroup, and create the cgroup commands will run in. Returns the
+/// latter, which should be given to the forkserver. This only works if the cgroup the daemon was
+/// started in is delegated to the user, and has no other processes.
+pub fn setup_action_cgroups() -> anyhow::Result<AbsNormPathBuf> {
+    if !Path::new(CGROUP_MOUNT).join("cgroup.controllers").exists() {
+        return Err(CgroupError::NotMounted.into());
+    }
+
+    let proc_cgroup = fs_util::read_to_string("/proc/self/cgroup")?;
+    let current = parse_proc_cgroup(&proc_cgroup)
+        .ok_or_else(|| CgroupError::NotInCgroup(proc_cgroup.clone()))?;
+    let current =
+        AbsNormPathBuf::try_from(Path::new(CGROUP_MOUNT).join(current.trim_start_matches('/')))?;
+
+    let daemon = current.join(ForwardRelativePath::unchecked_new("buck2-daemon"));
+    fs_util::create_dir_if_not_exists(&daemon)?;
+    fs_util::write(daemon.join(procs()), std::process::id().to_string())
+        .context("Error moving the daemon to its own cgroup")?;
+
+    let available = fs_util::read_to_string(current.join(controllers()))?;
+    let enable = CONTROLLERS
+        .iter()
+        .filter(|c| available.split_whitespace().any(|a| a == **c))
+        .map(|c| format!("+{}", c))
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    fs_util::write(current.join(subtree_control()), &enable)
+        .context("Error enabling controllers: does the cgroup have other processes?")?;
+
+    let actions = current.join(ForwardRelativePath::unchecked_new("buck2-actions"));
+    fs_util::create_dir_if_not_exists(&actions)?;
+    fs_util::write(actions.join(subtree_control()), &enable)?;
+
+    Ok(actions)
+}
+
+/// The cgroup under which the forkserver creates one cgroup per command.
+pub(crate) struct ActionCgroups {
+    root: AbsNormPathBuf,
+}
+
+impl ActionCgroups {
+    pub(crate) fn new(root: AbsNormPathBuf) -> Self {
+        Self { root }
+    }
+
+    /// Create a cgroup for a command, with these limits.
+    pub(crate) fn create(
+        &self,
+        limits: Option<&buck2_forkserver_proto::ResourceLimits>,
+    ) -> anyhow::Result<ActionCgroup> {
+        let name = Alphanumeric.sample_string(&mut rand::thread_rng(), 16);
+        let path = self.root.join(ForwardRelativePath::unchecked_new(&name));
+        fs_util::create_dir(&path)?;
+
+        let cgroup = ActionCgroup {
+            procs: OpenOptions::new()
+                .write(true)
+                .open(path.join(procs()))
+                .with_context(|| format!("Error opening `{}`", path.join(procs())))?,
+            path,
+        };
+
+        if let Some(limits) = limits {
+            if let Some(memory_max_bytes) = limits.memory_max_bytes {
+                cgroup.write("memory.max", &memory_max_bytes.to_string())?;
+            }
+            if let Some(cpus) = limits.cpus {
+                let quota = u64::from(cpus) * CPU_PERIOD_US;
+                cgroup.write("cpu.max", &format!("{} {}", quota, CPU_PERIOD_US))?;
+            }
+        }
+
+        Ok(cgroup)
+    }
+}
+
+pub(crate) struct ActionCgroup {
+    path: AbsNormPathBuf,
+    /// Opened ahead of time so that the child only has to write to it.
+    procs: File,
+}
+
+impl ActionCgroup {
+    /// Make `cmd` enter this cgroup between fork and exec. This must be the first `pre_exec` of
+    /// `cmd`: after entering the sandbox, we no longer have the permission to write to the cgroup.
+    pub(crate) fn apply(&self, cmd: &mut Command) {
+        let fd = self.procs.as_raw_fd();
+        unsafe {
+            cmd.pre_exec(move || {
+                // Writing 0 moves the writing process.
+                if libc::write(fd, b"0".as_ptr() as *const libc::c_void, 1) < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+    }
+
+    fn write(&self, file: &str, value: &str) -> anyhow::Result<()> {
+        fs_util::write(
+            self.path.join(ForwardRelativePath::unchecked_new(file)),
+            value,
+        )
+        .with_context(|| format!("Error setting `{}` to `{}`", file, value))
+    }
+
+    fn read(&self, file: &str) -> Option<String> {
+        fs_util::read_to_string(self.path.join(ForwardRelativePath::unchecked_new(file))).ok()
+    }
+
+    /// What the processes in this cgroup used so far. Stats whose controller is not enabled are
+    /// missing.
+    fn stats(&self) -> buck2_data::CommandExecutionStats {
+        let mut stats = buck2_data::CommandExecutionStats::default();
+
+        // `memory.peak` was added in Linux 5.19.
+        stats.memory_peak_bytes = self
+            .read("memory.peak")
+            .and_then(|peak| peak.trim().parse().ok());
+
+        if let Some(cpu_stat) = self.read("cpu.stat") {
+            let cpu_stat = parse_flat_keyed(&cpu_stat);
+            let get = |key| cpu_stat.iter().find(|(k, _)| *k == key).map(|(_, v)| *v);
+            stats.cpu_user_us = get("user_usec");
+            stats.cpu_system_us = get("system_usec");
+        }
+
+        if let Some(io_stat) = self.read("io.stat") {
+            let (read, write) = parse_io_stat(&io_stat);
+            stats.io_read_bytes = Some(read);
+            stats.io_write_bytes = Some(write);
+        }
+
+        stats
+    }
+
+    /// Kill whatever is left in this cgroup and remove it.
+    async fn remove(self) {
+        // `cgroup.kill` was added in Linux 5.14. Without it, the cgroup can't be removed if the
+        // command left processes behind.
+        let _ignored = self.write("cgroup.kill", "1");
+
+        // Killed processes take a moment to leave the cgroup.
+        for _ in 0..10 {
+            match fs_util::remove_dir(&self.path) {
+                Ok(()) => return,
+                Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+            }
+        }
+
+        tracing::debug!("Error removing cgroup `{}`", self.path);
+    }
+}
+
+/// Adds the resource usage of the command's cgroup to the status.
+pub(crate) struct CgroupStatusDecoder<D> {
+    inner: D,
+    cgroup: Option<ActionCgroup>,
+}
+
+impl<D> CgroupStatusDecoder<D> {
+    pub(crate) fn new(inner: D, cgroup: Option<ActionCgroup>) -> Self {
+        Self { inner, cgroup }
+    }
+}
+
+#[async_trait]
+impl<D> StatusDecoder for CgroupStatusDecoder<D>
+where
+    D: StatusDecoder + Send,
+{
+    async fn decode_status(self, status: ExitStatus) -> anyhow::Result<DecodedStatus> {
+        let decoded = self.inner.decode_status(status).await;
+
+        let cgroup = match self.cgroup {
+            Some(cgroup) => cgroup,
+            None => return decoded,
+        };
+
+        let stats = cgroup.stats();
+        cgroup.remove().await;
+
+        match decoded? {
+            DecodedStatus::Status {
+                exit_code,
+                execution_stats,
+            } => {
+                let execution_stats = match execution_stats {
+                    Some(counters) => buck2_data::CommandExecutionStats {
+                        cpu_instructions_user: counters.cpu_instructions_user,
+                        cpu_instructions_kernel: counters.cpu_instructions_kernel,
+                        ..stats
+                    },
+                    None => stats,
+                };
+                Ok(DecodedStatus::Status {
+                    exit_code,
+                    execution_stats: Some(execution_stats),
+                })
+            }
+            DecodedStatus::SpawnFailed(e) => Ok(DecodedStatus::SpawnFailed(e)),
+        }
+    }
+
+    async fn cancel(self) -> anyhow::Result<()> {
+        if let Some(cgroup) = self.cgroup {
+            cgroup.remove().await;
+        }
+        self.inner.cancel().await
+    }
+}
+
+fn procs() -> &'static ForwardRelativePath {
+    ForwardRelativePath::unchecked_new("cgroup.procs")
+}
+
+fn controllers() -> &'static ForwardRelativePath {
+    ForwardRelativePath::unchecked_new("cgroup.controllers")
+}
+
+fn subtree_control() -> &'static ForwardRelativePath {
+    ForwardRelativePath::unchecked_new("cgroup.subtree_control")
+}
+
+/// The path of the cgroup v2 hierarchy in `/proc/self/cgroup`.
+fn parse_proc_cgroup(proc_cgroup: &str) -> Option<&str> {
+    proc_cgroup.lines().find_map(|l| l.strip_prefix("0::"))
+}
+
+/// Parse a file made of `key value` lines, like `cpu.stat`.
+fn parse_flat_keyed(contents: &str) -> Vec<(&str, u64)> {
+    contents
+        .lines()
+        .filter_map(|l| {
+            let (key, value) = l.split_once(' ')?;
+            Some((key, value.trim().parse().ok()?))
+        })
+        .collect()
+}
+
+/// Sum the bytes read and written on all devices in `io.stat`, whose lines look like
+/// `8:0 rbytes=1 wbytes=2 rios=3 wios=4 dbytes=0 dios=0`.
+fn parse_io_stat(io_stat: &str) -> (u64, u64) {
+    let mut read = 0;
+    let mut write = 0;
+    for field in io_stat.lines().flat_map(|l| l.split_whitespace().skip(1)) {
+        let (key, value) = match field.split_once('=') {
+            Some((key, value)) => (key, value.parse::<u64>().unwrap_or_default()),
+            None => continue,
+        };
+        match key {
+            "rbytes" => read += value,
+            "wbytes" => write += value,
+            _ => {}
+        }
+    }
+    (read, write)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_proc_cgroup() {
+        assert_eq!(
+            Some("/user.slice/user-1000.slice/session-1.scope"),
+            parse_proc_cgroup("0::/user.slice/user-1000.slice/session-1.scope\n")
+        );
+        assert_eq!(None, parse_proc_cgroup("1:name=systemd:/\n"));
+    }
+
+    #[test]
+    fn test_parse_flat_keyed() {
+        assert_eq!(
+            vec![("usage_usec", 30), ("user_usec", 20), ("system_usec", 10)],
+            parse_flat_keyed("usage_usec 30\nuser_usec 20\nsystem_usec 10\n")
+        );
+    }
+
+    #[test]
+    fn test_parse_io_stat() {
+        assert_eq!(
+            (5, 7),
+            parse_io_stat(
+                "8:0 rbytes=1 wbytes=2 rios=1 wios=1 dbytes=0 dios=0\n\
+                 259:0 rbytes=4 wbytes=5 rios=1 wios=1 dbytes=0 dios=0\n"
+            )
+        );
+    }
+}
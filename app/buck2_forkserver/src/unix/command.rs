@@ -25,6 +25,7 @@ pub async fn run_forkserver(
     fd: RawFd,
     log_reload_handle: Arc<dyn LogConfigurationReloadHandle>,
     state_dir: AbsNormPathBuf,
+    cgroup_dir: Option<AbsNormPathBuf>,
 ) -> anyhow::Result<()> {
     // SAFETY: At worst, we just read (or close) the wrong FD.
     let io = UnixStream::from_std(unsafe { StdUnixStream::from_raw_fd(fd) })
@@ -35,7 +36,7 @@ pub async fn run_forkserver(
         DuplexChannel::new(read, write)
     };
 
-    let service = UnixForkserverService::new(log_reload_handle, &state_dir)
+    let service = UnixForkserverService::new(log_reload_handle, &state_dir, cgroup_dir)
         .context("Failed to create UnixForkserverService")?;
 
     let router = tonic::transport::Server::builder()
@@ -7,9 +7,13 @@
  * of this source tree.
  */
 
+mod cgroup;
 mod command;
 mod launch;
+#[cfg(target_os = "linux")]
+mod sandbox;
 mod service;
 
+pub use cgroup::setup_action_cgroups;
 pub use command::run_forkserver;
 pub use launch::launch_forkserver;
@@ -0,0 +1,436 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+//! Run commands in a sandbox made of Linux namespaces: the command gets its own user and mount
+//! namespace (and optionally network namespace), and a fresh tmpfs as its root into which only
+//! the paths it was declared to use are bind-mounted.
+//!
+//! Commands that don't run in a sandbox can still be cut off from the network with a
+//! `NetworkNamespace`.
+//!
+//! Everything is computed before forking so that the child only has to issue syscalls.
+
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::ffi::CString;
+use std::ffi::OsStr;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Command;
+
+use anyhow::Context as _;
+use buck2_core::fs::paths::abs_norm_path::AbsNormPath;
+
+/// Host paths that every sandboxed command can read, if they exist.
+const SYSTEM_PATHS: &[&str] = &[
+    "/bin", "/etc", "/lib", "/lib32", "/lib64", "/nix", "/opt", "/sbin", "/usr",
+];
+
+/// Host paths that are mounted with their submounts. Like other read-only paths, they are only
+/// remounted read-only at the top: devices like `/dev/null` are still writable, and so are the
+/// filesystems mounted under them, like `/dev/shm`.
+const DEVICE_PATHS: &[&str] = &["/dev", "/proc"];
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum MountKind {
+    ReadOnly,
+    Writable,
+}
+
+struct SandboxMount {
+    source: CString,
+    target: CString,
+    /// Flags to remount the bind mount with, if it must be made read-only.
+    remount_flags: Option<libc::c_ulong>,
+}
+
+pub(crate) struct Sandbox {
+    root: CString,
+    unshare_flags: libc::c_int,
+    uid_map: Vec<u8>,
+    gid_map: Vec<u8>,
+    /// Directories to create in the sandbox root, parents first.
+    dirs: Vec<CString>,
+    /// Empty files to create in the sandbox root to bind-mount files onto.
+    files: Vec<CString>,
+    mounts: Vec<SandboxMount>,
+    cwd: CString,
+}
+
+impl Sandbox {
+    /// Prepare a sandbox rooted at `root`, which must be an existing empty directory. Paths that
+    /// do not exist on the host are skipped, the command will get an `ENOENT` accessing them.
+    pub(crate) fn new(
+        root: &AbsNormPath,
+        config: &buck2_forkserver_proto::SandboxConfig,
+        cwd: Option<&OsStr>,
+        extra_readonly_paths: &[&Path],
+    ) -> anyhow::Result<Self> {
+        let mut paths = BTreeMap::new();
+
+        for path in SYSTEM_PATHS.iter().chain(DEVICE_PATHS) {
+            paths.insert(PathBuf::from(path), MountKind::ReadOnly);
+        }
+        for path in config
+            .readonly_paths
+            .iter()
+            .map(|p| Path::new(OsStr::from_bytes(p)))
+            .chain(extra_readonly_paths.iter().copied())
+        {
+            paths.entry(path.to_owned()).or_insert(MountKind::ReadOnly);
+        }
+        for path in config
+            .writable_paths
+            .iter()
+            .map(|p| Path::new(OsStr::from_bytes(p)))
+        {
+            paths.insert(path.to_owned(), MountKind::Writable);
+        }
+
+        let mut dirs = BTreeSet::new();
+        let mut files = BTreeSet::new();
+        let mut mounts = Vec::with_capacity(paths.len());
+
+        // `paths` is sorted, so parents are mounted before their children, which would otherwise
+        // be shadowed.
+        for (path, kind) in paths {
+            if !path.is_absolute() {
+                return Err(anyhow::anyhow!(
+                    "Sandbox path is not absolute: `{}`",
+                    path.display()
+                ));
+            }
+
+            let metadata = match std::fs::metadata(&path) {
+                Ok(m) => m,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => {
+                    return Err(e).with_context(|| format!("Error accessing `{}`", path.display()));
+                }
+            };
+
+            for ancestor in path.ancestors().skip(1) {
+                dirs.insert(ancestor.to_owned());
+            }
+            if metadata.is_dir() {
+                dirs.insert(path.clone());
+            } else {
+                files.insert(path.clone());
+            }
+
+            let remount_flags = match kind {
+                MountKind::ReadOnly => Some(
+                    libc::MS_REMOUNT | libc::MS_BIND | libc::MS_RDONLY | locked_mount_flags(&path)?,
+                ),
+                MountKind::Writable => None,
+            };
+
+            mounts.push(SandboxMount {
+                source: to_cstring(path.as_os_str())?,
+                target: rooted(root, &path)?,
+                remount_flags,
+            });
+        }
+
+        let cwd = match cwd {
+            Some(cwd) => {
+                let cwd = Path::new(cwd);
+                for ancestor in cwd.ancestors() {
+                    dirs.insert(ancestor.to_owned());
+                }
+                cwd.as_os_str()
+            }
+            None => OsStr::new("/"),
+        };
+        dirs.insert(PathBuf::from("/tmp"));
+
+        // Creating `/` is a no-op, the tmpfs is mounted there.
+        dirs.remove(Path::new("/"));
+
+        let mut unshare_flags = libc::CLONE_NEWUSER | libc::CLONE_NEWNS;
+        if config.isolate_network {
+            unshare_flags |= libc::CLONE_NEWNET;
+        }
+
+        let (uid_map, gid_map) = id_maps();
+
+        Ok(Self {
+            root: to_cstring(root.as_path().as_os_str())?,
+            unshare_flags,
+            uid_map,
+            gid_map,
+            dirs: dirs
+                .iter()
+                .map(|d| rooted(root, d))
+                .collect::<anyhow::Result<_>>()?,
+            files: files
+                .iter()
+                .map(|f| rooted(root, f))
+                .collect::<anyhow::Result<_>>()?,
+            mounts,
+            cwd: to_cstring(cwd)?,
+        })
+    }
+
+    /// Make `cmd` enter the sandbox between fork and exec.
+    pub(crate) fn apply(self, cmd: &mut Command) {
+        unsafe {
+            cmd.pre_exec(move || self.enter());
+        }
+    }
+
+    /// Runs in the forked child: only syscalls on data computed in `new`.
+    fn enter(&self) -> io::Result<()> {
+        unsafe {
+            enter_user_namespace(self.unshare_flags, &self.uid_map, &self.gid_map)?;
+
+            // Don't let our mounts propagate back to the host.
+            check(libc::mount(
+                std::ptr::null(),
+                b"/\0".as_ptr() as *const libc::c_char,
+                std::ptr::null(),
+                libc::MS_REC | libc::MS_PRIVATE,
+                std::ptr::null(),
+            ))?;
+
+            check(libc::mount(
+                b"tmpfs\0".as_ptr() as *const libc::c_char,
+                self.root.as_ptr(),
+                b"tmpfs\0".as_ptr() as *const libc::c_char,
+                libc::MS_NOSUID | libc::MS_NODEV,
+                std::ptr::null(),
+            ))?;
+
+            for dir in &self.dirs {
+                if libc::mkdir(dir.as_ptr(), 0o755) != 0 {
+                    let e = io::Error::last_os_error();
+                    if e.kind() != io::ErrorKind::AlreadyExists {
+                        return Err(e);
+                    }
+                }
+            }
+
+            for file in &self.files {
+                let fd = check(libc::open(
+                    file.as_ptr(),
+                    libc::O_CREAT | libc::O_WRONLY | libc::O_CLOEXEC,
+                    0o644,
+                ))?;
+                libc::close(fd);
+            }
+
+            for mount in &self.mounts {
+                check(libc::mount(
+                    mount.source.as_ptr(),
+                    mount.target.as_ptr(),
+                    std::ptr::null(),
+                    libc::MS_BIND | libc::MS_REC,
+                    std::ptr::null(),
+                ))?;
+
+                if let Some(flags) = mount.remount_flags {
+                    check(libc::mount(
+                        std::ptr::null(),
+                        mount.target.as_ptr(),
+                        std::ptr::null(),
+                        flags,
+                        std::ptr::null(),
+                    ))?;
+                }
+            }
+
+            check(libc::chroot(self.root.as_ptr()))?;
+            check(libc::chdir(self.cwd.as_ptr()))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The flags of the mount containing `path` that we are not allowed to clear when remounting it
+/// from a user namespace.
+fn locked_mount_flags(path: &Path) -> anyhow::Result<libc::c_ulong> {
+    let c_path = to_cstring(path.as_os_str())?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error())
+            .with_context(|| format!("Error calling statvfs on `{}`", path.display()));
+    }
+
+    let mut flags = 0;
+    for (st, ms) in [
+        (libc::ST_NOSUID, libc::MS_NOSUID),
+        (libc::ST_NODEV, libc::MS_NODEV),
+        (libc::ST_NOEXEC, libc::MS_NOEXEC),
+        (libc::ST_NOATIME, libc::MS_NOATIME),
+        (libc::ST_NODIRATIME, libc::MS_NODIRATIME),
+        (libc::ST_RELATIME, libc::MS_RELATIME),
+    ] {
+        if stat.f_flag & st != 0 {
+            flags |= ms;
+        }
+    }
+    Ok(flags)
+}
+
+fn rooted(root: &AbsNormPath, path: &Path) -> anyhow::Result<CString> {
+    let relative = path.strip_prefix("/").unwrap_or(path);
+    to_cstring(root.as_path().join(relative).as_os_str())
+}
+
+/// An empty network namespace (with only a loopback interface, which is down) for a command that
+/// otherwise runs on the host as usual.
+pub(crate) struct NetworkNamespace {
+    uid_map: Vec<u8>,
+    gid_map: Vec<u8>,
+}
+
+impl NetworkNamespace {
+    pub(crate) fn new() -> Self {
+        let (uid_map, gid_map) = id_maps();
+        Self { uid_map, gid_map }
+    }
+
+    /// Make `cmd` enter the namespace between fork and exec.
+    pub(crate) fn apply(self, cmd: &mut Command) {
+        unsafe {
+            cmd.pre_exec(move || {
+                enter_user_namespace(
+                    libc::CLONE_NEWUSER | libc::CLONE_NEWNET,
+                    &self.uid_map,
+                    &self.gid_map,
+                )
+            });
+        }
+    }
+}
+
+/// Map our own ids into the namespace so that outputs are owned by the user as usual.
+fn id_maps() -> (Vec<u8>, Vec<u8>) {
+    let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+    (
+        format!("{} {} 1", uid, uid).into_bytes(),
+        format!("{} {} 1", gid, gid).into_bytes(),
+    )
+}
+
+/// Runs in the forked child. Unprivileged users can only create other namespaces along with a
+/// user namespace.
+unsafe fn enter_user_namespace(
+    unshare_flags: libc::c_int,
+    uid_map: &[u8],
+    gid_map: &[u8],
+) -> io::Result<()> {
+    check(libc::unshare(unshare_flags))?;
+
+    // We must give up `setgroups` to be allowed to write a gid map as an unprivileged user.
+    write_file(b"/proc/self/setgroups\0", b"deny")?;
+    write_file(b"/proc/self/uid_map\0", uid_map)?;
+    write_file(b"/proc/self/gid_map\0", gid_map)?;
+    Ok(())
+}
+
+fn to_cstring(s: &OsStr) -> anyhow::Result<CString> {
+    CString::new(s.as_bytes()).with_context(|| format!("Invalid path: `{}`", s.to_string_lossy()))
+}
+
+fn check(ret: libc::c_int) -> io::Result<libc::c_int> {
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret)
+    }
+}
+
+unsafe fn write_file(path: &[u8], data: &[u8]) -> io::Result<()> {
+    let fd = check(libc::open(
+        path.as_ptr() as *const libc::c_char,
+        libc::O_WRONLY | libc::O_CLOEXEC,
+    ))?;
+    let res = libc::write(fd, data.as_ptr() as *const libc::c_void, data.len());
+    libc::close(fd);
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use buck2_core::fs::paths::abs_norm_path::AbsNormPathBuf;
+
+    use super::*;
+
+    #[test]
+    fn test_sandbox_layout() -> anyhow::Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let project = tempdir.path().join("project");
+        std::fs::create_dir_all(project.join("src"))?;
+        std::fs::write(project.join("src/lib.h"), "")?;
+        std::fs::create_dir_all(project.join("buck-out/out"))?;
+
+        let root = AbsNormPathBuf::try_from(tempdir.path().join("root"))?;
+        let config = buck2_forkserver_proto::SandboxConfig {
+            readonly_paths: vec![
+                project.join("src/lib.h").as_os_str().as_bytes().to_vec(),
+                project.join("missing").as_os_str().as_bytes().to_vec(),
+            ],
+            writable_paths: vec![project.join("buck-out/out").as_os_str().as_bytes().to_vec()],
+            isolate_network: true,
+        };
+
+        let sandbox = Sandbox::new(&root, &config, Some(project.as_os_str()), &[])?;
+
+        let in_root = |p: &Path| rooted(&root, p).unwrap();
+
+        assert!(sandbox.unshare_flags & libc::CLONE_NEWNET != 0);
+        assert!(sandbox.files.contains(&in_root(&project.join("src/lib.h"))));
+        assert!(sandbox.dirs.contains(&in_root(&project.join("src"))));
+        assert!(
+            sandbox
+                .dirs
+                .contains(&in_root(&project.join("buck-out/out")))
+        );
+        assert_eq!(sandbox.cwd, to_cstring(project.as_os_str())?);
+
+        // Missing paths are not mounted.
+        assert!(
+            !sandbox
+                .mounts
+                .iter()
+                .any(|m| m.source == to_cstring(project.join("missing").as_os_str()).unwrap())
+        );
+
+        let lib = sandbox
+            .mounts
+            .iter()
+            .find(|m| m.source == to_cstring(project.join("src/lib.h").as_os_str()).unwrap())
+            .unwrap();
+        assert!(lib.remount_flags.unwrap() & libc::MS_RDONLY != 0);
+
+        let out = sandbox
+            .mounts
+            .iter()
+            .find(|m| m.source == to_cstring(project.join("buck-out/out").as_os_str()).unwrap())
+            .unwrap();
+        assert_eq!(out.remount_flags, None);
+
+        let proc = sandbox
+            .mounts
+            .iter()
+            .find(|m| m.source == to_cstring(OsStr::new("/proc")).unwrap())
+            .unwrap();
+        assert!(proc.remount_flags.unwrap() & libc::MS_RDONLY != 0);
+
+        Ok(())
+    }
+}
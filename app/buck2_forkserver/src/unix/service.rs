@@ -39,6 +39,8 @@ use tonic::Response;
 use tonic::Status;
 use tonic::Streaming;
 
+use super::cgroup::ActionCgroups;
+use super::cgroup::CgroupStatusDecoder;
 use crate::convert::encode_event_stream;
 use crate::run::prepare_command;
 use crate::run::status_decoder::DefaultStatusDecoder;
@@ -57,18 +59,32 @@ pub struct UnixForkserverService {
 
     /// State for Miniperf.
     miniperf: Option<MiniperfContainer>,
+
+    /// The directory sandboxed commands use as their root. Each command mounts its own tmpfs on
+    /// it in its own mount namespace, so it can be shared.
+    #[cfg_attr(not(target_os = "linux"), allow(unused))]
+    sandbox_root: AbsNormPathBuf,
+
+    /// Commands run in their own cgroup under this one, if we were given one.
+    cgroups: Option<ActionCgroups>,
 }
 
 impl UnixForkserverService {
     pub fn new(
         log_reload_handle: Arc<dyn LogConfigurationReloadHandle>,
         state_dir: &AbsNormPath,
+        cgroup_dir: Option<AbsNormPathBuf>,
     ) -> anyhow::Result<Self> {
         let miniperf = MiniperfContainer::new(state_dir)?;
 
+        let sandbox_root = state_dir.join(ForwardRelativePath::unchecked_new("sandbox"));
+        fs_util::create_dir_all(&sandbox_root)?;
+
         Ok(Self {
             log_reload_handle,
             miniperf,
+            sandbox_root,
+            cgroups: cgroup_dir.map(ActionCgroups::new),
         })
     }
 }
@@ -109,6 +125,9 @@ impl Forkserver for UnixForkserverService {
                 cwd,
                 timeout,
                 enable_miniperf,
+                sandbox,
+                resource_limits,
+                block_network,
             } = msg;
 
             let exe = OsStr::from_bytes(&exe);
@@ -153,6 +172,65 @@ impl Forkserver for UnixForkserverService {
                 }
             }
 
+            // This must come before the sandbox, see `ActionCgroup::apply`.
+            let cgroup = match &self.cgroups {
+                Some(cgroups) => Some(
+                    cgroups
+                        .create(resource_limits.as_ref())
+                        .context("Error creating cgroup")?,
+                ),
+                None => None,
+            };
+            if let Some(cgroup) = &cgroup {
+                cgroup.apply(&mut cmd);
+            }
+
+            if let Some(sandbox) = sandbox {
+                #[cfg(target_os = "linux")]
+                {
+                    let mut sandbox = sandbox;
+                    sandbox.isolate_network |= block_network;
+                    let mut extra_readonly_paths = vec![];
+                    if let (Some(miniperf), Some(_)) = (&self.miniperf, &miniperf_output) {
+                        extra_readonly_paths.push(miniperf.miniperf.as_path());
+                        sandbox.writable_paths.push(
+                            miniperf
+                                .output_dir
+                                .as_path()
+                                .as_os_str()
+                                .as_bytes()
+                                .to_vec(),
+                        );
+                    }
+                    crate::unix::sandbox::Sandbox::new(
+                        &self.sandbox_root,
+                        &sandbox,
+                        cwd,
+                        &extra_readonly_paths,
+                    )
+                    .context("Error preparing sandbox")?
+                    .apply(&mut cmd);
+                }
+
+                #[cfg(not(target_os = "linux"))]
+                {
+                    let _unused = sandbox;
+                    return Err(anyhow::anyhow!("Sandboxing is only supported on Linux"));
+                }
+            } else if block_network {
+                #[cfg(target_os = "linux")]
+                {
+                    crate::unix::sandbox::NetworkNamespace::new().apply(&mut cmd);
+                }
+
+                #[cfg(not(target_os = "linux"))]
+                {
+                    return Err(anyhow::anyhow!(
+                        "Blocking network access is only supported on Linux"
+                    ));
+                }
+            }
+
             let mut cmd = prepare_command(cmd);
             let child = cmd.spawn();
 
@@ -164,14 +242,14 @@ impl Forkserver for UnixForkserverService {
                 Some(out) => stream_command_events(
                     child,
                     cancellation,
-                    MiniperfStatusDecoder::new(out),
+                    CgroupStatusDecoder::new(MiniperfStatusDecoder::new(out), cgroup),
                     DefaultKillProcess,
                 )?
                 .left_stream(),
                 None => stream_command_events(
                     child,
                     cancellation,
-                    DefaultStatusDecoder,
+                    CgroupStatusDecoder::new(DefaultStatusDecoder, cgroup),
                     DefaultKillProcess,
                 )?
                 .right_stream(),
@@ -281,6 +281,13 @@ impl CoercedAttr {
         ctx: &dyn AttrConfigurationContext,
         select_entries: &'a [(TargetLabel, CoercedAttr)],
     ) -> anyhow::Result<Option<&'a CoercedAttr>> {
+        Ok(Self::select_the_most_specific_entry(ctx, select_entries)?.map(|(_k, v)| v))
+    }
+
+    fn select_the_most_specific_entry<'a>(
+        ctx: &dyn AttrConfigurationContext,
+        select_entries: &'a [(TargetLabel, CoercedAttr)],
+    ) -> anyhow::Result<Option<(&'a TargetLabel, &'a CoercedAttr)>> {
         let mut matching: Option<(&TargetLabel, &ConfigSettingData, &CoercedAttr)> = None;
         for (k, v) in select_entries {
             matching = match (ctx.matches(k), matching) {
@@ -301,7 +308,39 @@ impl CoercedAttr {
                 }
             }
         }
-        Ok(matching.map(|(_k, _conf, v)| v))
+        Ok(matching.map(|(k, _conf, v)| (k, v)))
+    }
+
+    /// Appends the keys of the `select()` branches that are taken in the provided context to
+    /// `keys`, in the order they are encountered. The default branch is reported as `DEFAULT`.
+    pub fn selected_keys(
+        &self,
+        ctx: &dyn AttrConfigurationContext,
+        keys: &mut Vec<String>,
+    ) -> anyhow::Result<()> {
+        match self {
+            CoercedAttr::Literal(_) => Ok(()),
+            CoercedAttr::Selector(box CoercedSelector { entries, default }) => {
+                if let Some((k, v)) = Self::select_the_most_specific_entry(ctx, entries)? {
+                    keys.push(k.to_string());
+                    return v.selected_keys(ctx, keys);
+                }
+                let default = default.as_ref().ok_or_else(|| {
+                    SelectError::MissingDefault(
+                        ctx.cfg().cfg().dupe(),
+                        entries.iter().map(|(k, _)| k).duped().collect(),
+                    )
+                })?;
+                keys.push("DEFAULT".to_owned());
+                default.selected_keys(ctx, keys)
+            }
+            CoercedAttr::Concat(items) => {
+                for item in &**items {
+                    item.selected_keys(ctx, keys)?;
+                }
+                Ok(())
+            }
+        }
     }
 
     /// Returns the "configured" representation of the attribute in the provided context.
@@ -370,11 +409,14 @@ impl CoercedAttr {
 mod tests {
 
     use buck2_core::target::label::TargetLabel;
+    use buck2_util::arc_str::ArcSlice;
+    use buck2_util::arc_str::ArcStr;
     use dupe::Dupe;
 
     use crate::attrs::attr_type::attr_literal::AttrLiteral;
     use crate::attrs::coerced_attr::CoercedAttr;
     use crate::attrs::coerced_attr::CoercedSelector;
+    use crate::attrs::testing::configuration_ctx;
 
     #[test]
     fn test_check_all_keys_unique_small() {
@@ -412,4 +454,74 @@ mod tests {
         long[10].0 = long[0].0.dupe();
         assert!(CoercedSelector::check_all_keys_unique(&long).is_err());
     }
+
+    fn literal(s: &str) -> CoercedAttr {
+        CoercedAttr::Literal(AttrLiteral::String(ArcStr::from(s)))
+    }
+
+    /// A `select()` with a branch on `root//other:config`, which matches in
+    /// `configuration_ctx()`, and one on `root//other:nomatch`, which does not.
+    fn select(
+        matching: Option<CoercedAttr>,
+        other: CoercedAttr,
+        default: Option<CoercedAttr>,
+    ) -> CoercedAttr {
+        let mut entries = vec![(TargetLabel::testing_parse("root//other:nomatch"), other)];
+        if let Some(matching) = matching {
+            entries.push((TargetLabel::testing_parse("root//other:config"), matching));
+        }
+        CoercedAttr::Selector(Box::new(
+            CoercedSelector::new(ArcSlice::from_iter(entries), default).unwrap(),
+        ))
+    }
+
+    fn selected_keys(attr: &CoercedAttr) -> anyhow::Result<Vec<String>> {
+        let mut keys = Vec::new();
+        attr.selected_keys(&configuration_ctx(), &mut keys)?;
+        Ok(keys)
+    }
+
+    #[test]
+    fn test_selected_keys_literal() -> anyhow::Result<()> {
+        assert_eq!(Vec::<String>::new(), selected_keys(&literal("a"))?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_selected_keys_default() -> anyhow::Result<()> {
+        let attr = select(None, literal("a"), Some(literal("b")));
+        assert_eq!(vec!["DEFAULT"], selected_keys(&attr)?);
+
+        let attr = select(Some(literal("a")), literal("b"), Some(literal("c")));
+        assert_eq!(vec!["root//other:config"], selected_keys(&attr)?);
+
+        // No branch matches and there is no default.
+        let attr = select(None, literal("a"), None);
+        assert!(selected_keys(&attr).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_selected_keys_nested() -> anyhow::Result<()> {
+        let inner = select(None, literal("a"), Some(literal("b")));
+        let attr = select(Some(inner), literal("c"), None);
+        assert_eq!(vec!["root//other:config", "DEFAULT"], selected_keys(&attr)?);
+
+        // Branches that are not taken are not reported, even if they contain selects.
+        let inner = select(Some(literal("a")), literal("b"), None);
+        let attr = select(None, inner, Some(literal("c")));
+        assert_eq!(vec!["DEFAULT"], selected_keys(&attr)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_selected_keys_concat() -> anyhow::Result<()> {
+        let attr = CoercedAttr::Concat(Box::new([
+            select(Some(literal("a")), literal("b"), None),
+            literal("c"),
+            select(None, literal("d"), Some(literal("e"))),
+        ]));
+        assert_eq!(vec!["root//other:config", "DEFAULT"], selected_keys(&attr)?);
+        Ok(())
+    }
 }
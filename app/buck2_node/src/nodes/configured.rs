@@ -541,6 +541,21 @@ impl ConfiguredTargetNode {
         })
     }
 
+    /// For every attribute that uses `select()`, the keys of the branches taken in this
+    /// node's configuration.
+    pub fn resolved_selects(&self) -> anyhow::Result<Vec<(&str, Vec<String>)>> {
+        let ctx = self.attr_configuration_context();
+        let mut resolved = Vec::new();
+        for a in self.0.target_node.attrs(AttrInspectOptions::All) {
+            let mut keys = Vec::new();
+            a.value.selected_keys(&ctx, &mut keys)?;
+            if !keys.is_empty() {
+                resolved.push((a.name, keys));
+            }
+        }
+        Ok(resolved)
+    }
+
     pub fn call_stack(&self) -> Option<String> {
         match &self.0.target_node {
             TargetNodeOrForward::TargetNode(n) => n.call_stack(),
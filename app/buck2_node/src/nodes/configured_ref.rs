@@ -144,7 +144,7 @@ impl QueryTarget for ConfiguredGraphNodeRef {
         )
     }
 
-    fn inputs_for_each<E, F: FnMut(CellPath) -> Result<(), E>>(
+    fn inputs_for_each<E: From<anyhow::Error>, F: FnMut(CellPath) -> Result<(), E>>(
         &self,
         mut func: F,
     ) -> Result<(), E> {
@@ -86,7 +86,10 @@ pub trait QueryTarget: LabeledNode + Dupe + Send + Sync + 'static {
     type Attr: ?Sized + Debug;
 
     /// Returns the input files for this node.
-    fn inputs_for_each<E, F: FnMut(CellPath) -> Result<(), E>>(&self, func: F) -> Result<(), E>;
+    fn inputs_for_each<E: From<anyhow::Error>, F: FnMut(CellPath) -> Result<(), E>>(
+        &self,
+        func: F,
+    ) -> Result<(), E>;
 
     fn rule_type(&self) -> Cow<str>;
 
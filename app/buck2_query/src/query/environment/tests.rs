@@ -56,7 +56,10 @@ impl LabeledNode for TestTarget {
 impl QueryTarget for TestTarget {
     type Attr = TestTargetAttr;
 
-    fn inputs_for_each<E, F: FnMut(CellPath) -> Result<(), E>>(&self, _func: F) -> Result<(), E> {
+    fn inputs_for_each<E: From<anyhow::Error>, F: FnMut(CellPath) -> Result<(), E>>(
+        &self,
+        _func: F,
+    ) -> Result<(), E> {
         unimplemented!()
     }
 
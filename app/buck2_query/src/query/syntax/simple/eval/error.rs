@@ -46,6 +46,8 @@ pub enum QueryError {
     ArgNotYetSupported(String, String),
     #[error("Invalid traversal depth `{0}`")]
     InvalidDepth(i32),
+    #[error("Traversal depth `{0}` is too large")]
+    DepthTooLarge(u64),
     #[error("File literal `{1}` not within the project root `{}`", .0)]
     FileLiteralNotInProject(ProjectRoot, String),
     #[error("query function {0} not available in this context")]
@@ -55,7 +55,10 @@ impl LabeledNode for Target {
 impl QueryTarget for Target {
     type Attr = TargetAttr;
 
-    fn inputs_for_each<E, F: FnMut(CellPath) -> Result<(), E>>(&self, _func: F) -> Result<(), E> {
+    fn inputs_for_each<E: From<anyhow::Error>, F: FnMut(CellPath) -> Result<(), E>>(
+        &self,
+        _func: F,
+    ) -> Result<(), E> {
         unimplemented!()
     }
 
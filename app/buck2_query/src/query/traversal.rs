@@ -404,7 +404,7 @@ mod tests {
             unimplemented!()
         }
 
-        fn inputs_for_each<E, F: FnMut(CellPath) -> Result<(), E>>(
+        fn inputs_for_each<E: From<anyhow::Error>, F: FnMut(CellPath) -> Result<(), E>>(
             &self,
             _func: F,
         ) -> Result<(), E> {
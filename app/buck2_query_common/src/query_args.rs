@@ -58,6 +58,12 @@ pub struct CommonAttributeArgs {
     /// When using in automation, please specify the regular expression to match the attribute
     /// precisely, for example `--output-attribute '^headers$'` to make it easier to track
     /// which special attributes are used.
+    ///
+    /// A few derived attributes are computed on request and only output when passed by their
+    /// exact name: `buck.resolved_selects` (the `select()` keys taken for each attribute, cquery
+    /// only), `buck.absolute_inputs` (input files as absolute paths), `buck.target_call_stack`
+    /// (as with `--target-call-stacks`) and `buck.target_hash` (the hash of the target node,
+    /// without its deps or files). They are not supported with the dot output formats.
     #[clap(
          short = 'a',
          long,
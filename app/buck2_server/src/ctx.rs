@@ -20,6 +20,10 @@ use anyhow::Context;
 use async_trait::async_trait;
 use buck2_build_api::actions::build_listener::BuildSignalSender;
 use buck2_build_api::actions::build_listener::SetBuildSignals;
+use buck2_build_api::actions::execute::shared_outputs::HasSharedOutputs;
+use buck2_build_api::actions::execute::shared_outputs::SharedOutputs;
+use buck2_build_api::actions::execute::shared_outputs::SharedOutputsHistory;
+use buck2_build_api::actions::impls::run_action_knobs::DeterminismCheck;
 use buck2_build_api::actions::impls::run_action_knobs::HasRunActionKnobs;
 use buck2_build_api::actions::impls::run_action_knobs::RunActionKnobs;
 use buck2_build_api::build::HasCreateUnhashedSymlinkLock;
@@ -71,12 +75,18 @@ use buck2_execute::execute::dice_data::set_fallback_executor_config;
 use buck2_execute::execute::dice_data::SetCommandExecutor;
 use buck2_execute::execute::dice_data::SetReClient;
 use buck2_execute::knobs::ExecutorGlobalKnobs;
+use buck2_execute::materialize::http::DownloadConfig;
+use buck2_execute::materialize::http::HasDownloadConfig;
 use buck2_execute::materialize::materializer::Materializer;
 use buck2_execute::materialize::materializer::SetMaterializer;
 use buck2_execute::re::client::RemoteExecutionClient;
 use buck2_execute::re::manager::ReConnectionHandle;
 use buck2_execute::re::manager::ReConnectionManager;
 use buck2_execute::re::manager::ReConnectionObserver;
+use buck2_execute_impl::executors::action_timings::ActionTimings;
+use buck2_execute_impl::executors::memory_estimates::MemoryEstimates;
+use buck2_execute_impl::executors::stale_outputs::StaleOutputs;
+use buck2_execute_impl::executors::worker::WorkerPool;
 use buck2_execute_impl::low_pass_filter::LowPassFilter;
 use buck2_forkserver::client::ForkserverClient;
 use buck2_interpreter::dice::starlark_debug::SetStarlarkDebugger;
@@ -104,6 +114,8 @@ use dupe::Dupe;
 use gazebo::prelude::SliceExt;
 use host_sharing::HostSharingBroker;
 use host_sharing::HostSharingStrategy;
+use sysinfo::System;
+use sysinfo::SystemExt;
 use tokio::sync::Mutex;
 use tracing::warn;
 
@@ -147,6 +159,16 @@ pub struct BaseServerCommandContext {
     pub materializer: Arc<dyn Materializer>,
     /// Forkserver connection, if any was started
     pub forkserver: Option<ForkserverClient>,
+    /// Persistent workers, if enabled
+    pub worker_pool: Option<Arc<WorkerPool>>,
+    /// Learnt memory usage of local actions, if memory aware scheduling is enabled
+    pub memory_estimates: Option<Arc<MemoryEstimates>>,
+    /// Timings of actions raced by the hybrid executor, used when it is adaptive
+    pub action_timings: Arc<ActionTimings>,
+    /// Undeclared outputs of local actions
+    pub stale_outputs: Arc<StaleOutputs>,
+    /// Configuration-independent outputs produced by previous commands
+    pub shared_outputs_history: Arc<SharedOutputsHistory>,
     /// The event dispatcher for this command context.
     pub events: EventDispatcher,
     /// Removes this command from the set of active commands when dropped.
@@ -346,6 +368,15 @@ impl ServerCommandContext {
 
         if let Some(build_options) = self.build_options.as_ref() {
             run_action_knobs.eager_dep_files = build_options.eager_dep_files;
+            run_action_knobs.check_determinism = if !build_options.check_determinism {
+                DeterminismCheck::Disabled
+            } else if build_options.check_determinism_categories.is_empty() {
+                DeterminismCheck::All
+            } else {
+                DeterminismCheck::Categories(Arc::new(
+                    build_options.check_determinism_categories.clone(),
+                ))
+            };
         }
 
         let concurrency = self
@@ -362,6 +393,15 @@ impl ServerCommandContext {
         let build_signals = self.build_signals.dupe();
 
         let forkserver = self.base_context.forkserver.dupe();
+        let worker_pool = self.base_context.worker_pool.dupe();
+        let memory_estimates = self.base_context.memory_estimates.dupe();
+        let action_timings = self.base_context.action_timings.dupe();
+        let stale_outputs = self
+            .build_options
+            .as_ref()
+            .map_or(false, |opts| opts.check_stale_outputs)
+            .then(|| self.base_context.stale_outputs.dupe());
+        let shared_outputs_history = self.base_context.shared_outputs_history.dupe();
 
         let upload_all_actions = self
             .build_options
@@ -382,6 +422,11 @@ impl ServerCommandContext {
             re_connection,
             build_signals,
             forkserver,
+            worker_pool,
+            memory_estimates,
+            action_timings,
+            stale_outputs,
+            shared_outputs_history,
             upload_all_actions,
             no_remote_cache,
             create_unhashed_symlink_lock,
@@ -471,6 +516,11 @@ struct DiceCommandDataProvider {
     re_connection: Arc<ReConnectionHandle>,
     build_signals: BuildSignalSender,
     forkserver: Option<ForkserverClient>,
+    worker_pool: Option<Arc<WorkerPool>>,
+    memory_estimates: Option<Arc<MemoryEstimates>>,
+    action_timings: Arc<ActionTimings>,
+    stale_outputs: Option<Arc<StaleOutputs>>,
+    shared_outputs_history: Arc<SharedOutputsHistory>,
     upload_all_actions: bool,
     run_action_knobs: RunActionKnobs,
     no_remote_cache: bool,
@@ -508,11 +558,34 @@ impl DiceDataProvider for DiceCommandDataProvider {
             .unwrap_or_else(RolloutPercentage::never)
             .roll();
 
-        let executor_global_knobs = ExecutorGlobalKnobs { enable_miniperf };
+        let allow_unenforced_network_block = root_config
+            .parse("buck2", "allow_unenforced_network_block")?
+            .unwrap_or(false);
+
+        let executor_global_knobs = ExecutorGlobalKnobs {
+            enable_miniperf,
+            allow_unenforced_network_block,
+        };
+
+        let download_config = self
+            .blocking_executor
+            .execute_io_inline(|| DownloadConfig::from_config(root_config))
+            .await?;
 
-        let host_sharing_broker =
+        let mut host_sharing_broker =
             HostSharingBroker::new(HostSharingStrategy::SmallerTasksFirst, concurrency);
 
+        if root_config
+            .parse::<bool>("build", "memory_aware_scheduling")?
+            .unwrap_or(false)
+        {
+            let memory_budget = match root_config.parse::<u64>("build", "local_memory_budget_mb")? {
+                Some(budget_mb) => budget_mb * 1024 * 1024,
+                None => default_local_memory_budget(),
+            };
+            host_sharing_broker = host_sharing_broker.with_memory_budget(memory_budget);
+        }
+
         // We use the job count for the low pass filter too. The low pass filter prevents sending
         // RE-eligile tasks to local if their concurrency is higher than our threshold. While it
         // doesn't *have* to be the same as the concurrency we give the actual executor, it's a
@@ -552,6 +625,10 @@ impl DiceDataProvider for DiceCommandDataProvider {
             executor_global_knobs,
             self.upload_all_actions,
             self.forkserver.dupe(),
+            self.worker_pool.dupe(),
+            self.memory_estimates.dupe(),
+            self.action_timings.dupe(),
+            self.stale_outputs.dupe(),
             self.no_remote_cache,
             ctx.global_data()
                 .get_io_provider()
@@ -562,6 +639,10 @@ impl DiceDataProvider for DiceCommandDataProvider {
         data.set_materializer(self.materializer.dupe());
         data.set_build_signals(self.build_signals.dupe());
         data.set_run_action_knobs(self.run_action_knobs.dupe());
+        data.set_download_config(Arc::new(download_config));
+        data.set_shared_outputs(Arc::new(SharedOutputs::new(
+            self.shared_outputs_history.dupe(),
+        )));
         data.set_create_unhashed_symlink_lock(self.create_unhashed_symlink_lock.dupe());
         data.set_starlark_debugger_handle(self.starlark_debugger.clone().map(|v| Box::new(v) as _));
         data.spawner = Arc::new(BuckSpawner::default());
@@ -576,6 +657,55 @@ impl DiceDataProvider for DiceCommandDataProvider {
     }
 }
 
+/// By default, local actions may use the memory that is available when the command starts, i.e.
+/// not used by the daemon and everything else that is running, and not more than the cgroups the
+/// daemon runs in allow.
+fn default_local_memory_budget() -> u64 {
+    let mut system = System::new();
+    system.refresh_memory();
+    let available = system.available_memory();
+    match cgroup_available_memory() {
+        Some(cgroup_available) => available.min(cgroup_available),
+        None => available,
+    }
+}
+
+/// How much more memory the cgroup (v2) hierarchy of the daemon allows it to use, if it is
+/// limited: the smallest `memory.max - memory.current` of the cgroup and its ancestors.
+#[cfg(target_os = "linux")]
+fn cgroup_available_memory() -> Option<u64> {
+    use std::path::Path;
+
+    let read = |path: &Path| std::fs::read_to_string(path).ok();
+
+    let cgroup = read(Path::new("/proc/self/cgroup"))?;
+    let cgroup = cgroup.lines().find_map(|line| line.strip_prefix("0::"))?;
+
+    let root = Path::new("/sys/fs/cgroup");
+    let mut dir = root.join(cgroup.trim_start_matches('/'));
+    let mut available: Option<u64> = None;
+    while dir.starts_with(root) {
+        // `memory.max` is `max` when there is no limit.
+        if let Some(max) = read(&dir.join("memory.max")).and_then(|m| m.trim().parse::<u64>().ok())
+        {
+            let current = read(&dir.join("memory.current"))
+                .and_then(|c| c.trim().parse::<u64>().ok())
+                .unwrap_or(0);
+            let dir_available = max.saturating_sub(current);
+            available = Some(available.map_or(dir_available, |a| a.min(dir_available)));
+        }
+        if !dir.pop() {
+            break;
+        }
+    }
+    available
+}
+
+#[cfg(not(target_os = "linux"))]
+fn cgroup_available_memory() -> Option<u64> {
+    None
+}
+
 fn create_cycle_detector() -> Arc<dyn UserCycleDetector> {
     Arc::new(StackedDiceCycleDetector {
         inner: vec![
@@ -34,10 +34,14 @@ use buck2_execute::execute::request::ExecutorPreference;
 use buck2_execute::knobs::ExecutorGlobalKnobs;
 use buck2_execute::materialize::materializer::Materializer;
 use buck2_execute::re::manager::ReConnectionHandle;
+use buck2_execute_impl::executors::action_timings::ActionTimings;
 use buck2_execute_impl::executors::caching::CachingExecutor;
 use buck2_execute_impl::executors::hybrid::HybridExecutor;
 use buck2_execute_impl::executors::local::LocalExecutor;
+use buck2_execute_impl::executors::memory_estimates::MemoryEstimates;
 use buck2_execute_impl::executors::re::ReExecutor;
+use buck2_execute_impl::executors::stale_outputs::StaleOutputs;
+use buck2_execute_impl::executors::worker::WorkerPool;
 use buck2_execute_impl::low_pass_filter::LowPassFilter;
 use buck2_forkserver::client::ForkserverClient;
 use dupe::Dupe;
@@ -71,6 +75,10 @@ pub struct CommandExecutorFactory {
     pub executor_global_knobs: ExecutorGlobalKnobs,
     pub upload_all_actions: bool,
     pub forkserver: Option<ForkserverClient>,
+    pub worker_pool: Option<Arc<WorkerPool>>,
+    pub memory_estimates: Option<Arc<MemoryEstimates>>,
+    pub action_timings: Arc<ActionTimings>,
+    pub stale_outputs: Option<Arc<StaleOutputs>>,
     pub no_remote_cache: bool,
     project_root: ProjectRoot,
 }
@@ -86,6 +94,10 @@ impl CommandExecutorFactory {
         executor_global_knobs: ExecutorGlobalKnobs,
         upload_all_actions: bool,
         forkserver: Option<ForkserverClient>,
+        worker_pool: Option<Arc<WorkerPool>>,
+        memory_estimates: Option<Arc<MemoryEstimates>>,
+        action_timings: Arc<ActionTimings>,
+        stale_outputs: Option<Arc<StaleOutputs>>,
         no_remote_cache: bool,
         project_root: ProjectRoot,
     ) -> Self {
@@ -99,6 +111,10 @@ impl CommandExecutorFactory {
             executor_global_knobs,
             upload_all_actions,
             forkserver,
+            worker_pool,
+            memory_estimates,
+            action_timings,
+            stale_outputs,
             no_remote_cache,
             project_root,
         }
@@ -111,7 +127,7 @@ impl HasCommandExecutor for CommandExecutorFactory {
         artifact_fs: &ArtifactFs,
         executor_config: &CommandExecutorConfig,
     ) -> anyhow::Result<CommandExecutorResponse> {
-        let local_executor_new = |_options| {
+        let local_executor_new = |options: &LocalExecutorOptions| {
             LocalExecutor::new(
                 artifact_fs.clone(),
                 self.materializer.dupe(),
@@ -121,6 +137,11 @@ impl HasCommandExecutor for CommandExecutorFactory {
                 self.forkserver.dupe(),
                 self.executor_global_knobs.dupe(),
             )
+            .with_sandbox(options.sandbox)
+            .with_worker_pool(self.worker_pool.dupe())
+            .with_memory_estimates(self.memory_estimates.dupe())
+            .with_stale_outputs(self.stale_outputs.dupe())
+            .with_block_network(options.block_network)
         };
 
         if !buck2_core::is_open_source() && !cfg!(fbcode_build) {
@@ -137,7 +158,7 @@ impl HasCommandExecutor for CommandExecutorFactory {
             }
 
             return Ok(CommandExecutorResponse {
-                executor: Arc::new(local_executor_new(&LocalExecutorOptions {})),
+                executor: Arc::new(local_executor_new(&LocalExecutorOptions::default())),
                 platform: Default::default(),
             });
         }
@@ -198,6 +219,7 @@ impl HasCommandExecutor for CommandExecutorFactory {
                         level: *level,
                         executor_preference: self.strategy.hybrid_preference(),
                         low_pass_filter: self.low_pass_filter.dupe(),
+                        action_timings: Some(self.action_timings.dupe()),
                     })),
                     _ => None,
                 };
@@ -295,11 +317,11 @@ impl ExecutionStrategyExt for ExecutionStrategy {
 /// This is used when execution platforms are not configured.
 pub fn get_default_executor_config(host_platform: HostPlatformOverride) -> CommandExecutorConfig {
     let executor = if buck2_core::is_open_source() {
-        Executor::Local(LocalExecutorOptions {})
+        Executor::Local(LocalExecutorOptions::default())
     } else {
         Executor::RemoteEnabled {
             executor: RemoteEnabledExecutor::Hybrid {
-                local: LocalExecutorOptions {},
+                local: LocalExecutorOptions::default(),
                 remote: RemoteExecutorOptions::default(),
                 level: HybridExecutionLevel::Limited,
             },
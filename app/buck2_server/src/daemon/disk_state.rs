@@ -12,6 +12,7 @@ use std::sync::Arc;
 
 use allocative::Allocative;
 use anyhow::Context;
+use buck2_build_api::actions::impls::dep_files::initialize_dep_files_state;
 use buck2_common::invocation_paths::InvocationPaths;
 use buck2_common::legacy_configs::LegacyBuckConfig;
 use buck2_core::fs::fs_util;
@@ -22,6 +23,8 @@ use buck2_core::rollout_percentage::RolloutPercentage;
 use buck2_execute::digest_config::DigestConfig;
 use buck2_execute::execute::blocking::BlockingExecutor;
 use buck2_execute::materialize::materializer::MaterializationMethod;
+use buck2_execute_impl::executors::action_timings::ActionTimings;
+use buck2_execute_impl::executors::stale_outputs::StaleOutputs;
 use buck2_execute_impl::materializers::deferred::DeferredMaterializerConfigs;
 use buck2_execute_impl::materializers::sqlite::MaterializerState;
 use buck2_execute_impl::materializers::sqlite::MaterializerStateSqliteDb;
@@ -32,7 +35,9 @@ use crate::daemon::server::BuckdServerInitPreferences;
 #[derive(Allocative)]
 pub struct DiskStateOptions {
     pub sqlite_materializer_state: bool,
-    // In future, this will include the config for dep files on disk
+    pub sqlite_dep_files_state: bool,
+    pub sqlite_action_timings: bool,
+    pub sqlite_stale_outputs: bool,
 }
 
 impl DiskStateOptions {
@@ -43,13 +48,34 @@ impl DiskStateOptions {
         let sqlite_materializer_state = matches!(
             // We can only enable materializer state on sqlite if you use deferred materializer
             materialization_method,
-            MaterializationMethod::Deferred | MaterializationMethod::DeferredSkipFinalArtifacts
+            MaterializationMethod::Deferred
+                | MaterializationMethod::DeferredSkipFinalArtifacts
+                | MaterializationMethod::Fuse
         ) && root_config
             .parse::<RolloutPercentage>("buck2", "sqlite_materializer_state")?
             .unwrap_or_else(RolloutPercentage::never)
             .roll();
+        // Dep files only hit if the materializer still knows about the outputs they match, so
+        // there is no point in persisting them if the materializer state isn't persisted too.
+        let sqlite_dep_files_state = sqlite_materializer_state
+            && root_config
+                .parse::<bool>("buck2", "sqlite_dep_files_state")?
+                .unwrap_or(true);
+        // Action timings don't refer to anything in buck-out, so they can't go stale when the
+        // materializer state is lost.
+        let sqlite_action_timings = root_config
+            .parse::<bool>("buck2", "sqlite_action_timings")?
+            .unwrap_or(true);
+        // Stale outputs are checked on disk before being reported, so losing the materializer
+        // state doesn't make them wrong either.
+        let sqlite_stale_outputs = root_config
+            .parse::<bool>("buck2", "sqlite_stale_outputs")?
+            .unwrap_or(true);
         Ok(Self {
             sqlite_materializer_state,
+            sqlite_dep_files_state,
+            sqlite_action_timings,
+            sqlite_stale_outputs,
         })
     }
 }
@@ -116,6 +142,92 @@ pub(crate) async fn maybe_initialize_materializer_sqlite_db(
     Ok((Some(db), materializer_state))
 }
 
+pub(crate) async fn maybe_initialize_dep_files_sqlite_db(
+    options: &DiskStateOptions,
+    paths: &InvocationPaths,
+    io_executor: Arc<dyn BlockingExecutor>,
+    root_config: &LegacyBuckConfig,
+    fs: ProjectRoot,
+    digest_config: DigestConfig,
+) -> anyhow::Result<()> {
+    if !options.sqlite_dep_files_state {
+        // Like the materializer state, delete the dep files state when it's disabled, so that it
+        // can't go stale.
+        return io_executor
+            .execute_io_inline(|| fs.remove_path_recursive(&paths.dep_files_state_path()))
+            .await;
+    }
+
+    let mut versions = HashMap::from([(
+        "digest_config".to_owned(),
+        format!("{:?}", digest_config.cas_digest_config()),
+    )]);
+    if let Some(buckconfig_version) =
+        root_config.parse("buck2", "sqlite_dep_files_state_version")?
+    {
+        versions.insert("buckconfig_version".to_owned(), buckconfig_version);
+    }
+    if let Some(hostname) = buck2_events::metadata::collect().get("hostname") {
+        versions.insert("hostname".to_owned(), hostname.to_owned());
+    }
+
+    io_executor
+        .execute_io_inline(|| initialize_dep_files_state(paths.dep_files_state_path(), versions))
+        .await
+}
+
+pub(crate) async fn initialize_action_timings(
+    options: &DiskStateOptions,
+    paths: &InvocationPaths,
+    io_executor: Arc<dyn BlockingExecutor>,
+    root_config: &LegacyBuckConfig,
+    fs: ProjectRoot,
+) -> anyhow::Result<ActionTimings> {
+    if !options.sqlite_action_timings {
+        io_executor
+            .execute_io_inline(|| fs.remove_path_recursive(&paths.action_timings_path()))
+            .await?;
+        return Ok(ActionTimings::new());
+    }
+
+    let mut versions = HashMap::new();
+    if let Some(buckconfig_version) = root_config.parse("buck2", "sqlite_action_timings_version")? {
+        versions.insert("buckconfig_version".to_owned(), buckconfig_version);
+    }
+    // Timings measured on one host say nothing about how fast another one is.
+    if let Some(hostname) = buck2_events::metadata::collect().get("hostname") {
+        versions.insert("hostname".to_owned(), hostname.to_owned());
+    }
+
+    io_executor
+        .execute_io_inline(|| ActionTimings::initialize(paths.action_timings_path(), versions))
+        .await
+}
+
+pub(crate) async fn initialize_stale_outputs(
+    options: &DiskStateOptions,
+    paths: &InvocationPaths,
+    io_executor: Arc<dyn BlockingExecutor>,
+    root_config: &LegacyBuckConfig,
+    fs: ProjectRoot,
+) -> anyhow::Result<StaleOutputs> {
+    if !options.sqlite_stale_outputs {
+        io_executor
+            .execute_io_inline(|| fs.remove_path_recursive(&paths.stale_outputs_path()))
+            .await?;
+        return Ok(StaleOutputs::new());
+    }
+
+    let mut versions = HashMap::new();
+    if let Some(buckconfig_version) = root_config.parse("buck2", "sqlite_stale_outputs_version")? {
+        versions.insert("buckconfig_version".to_owned(), buckconfig_version);
+    }
+
+    io_executor
+        .execute_io_inline(|| StaleOutputs::initialize(paths.stale_outputs_path(), versions))
+        .await
+}
+
 // Once we start storing disk state in the cache directory, we need to make sure
 // buck2 always deletes the cache directory if the cache is disabled.
 // Otherwise, buck-out state can diverge from the state of on-disk cache when
@@ -27,11 +27,27 @@ pub async fn maybe_launch_forkserver(
         return Ok(None);
     }
 
+    let mut args = vec!["forkserver".to_owned()];
+
+    if root_config
+        .parse::<bool>("buck2", "resource_control")?
+        .unwrap_or(false)
+    {
+        match buck2_forkserver::unix::setup_action_cgroups() {
+            Ok(cgroup_dir) => {
+                args.push("--cgroup-dir".to_owned());
+                args.push(cgroup_dir.to_string());
+            }
+            Err(e) => tracing::warn!(
+                "Resource control is unavailable, actions will run without limits: {:#}",
+                e
+            ),
+        }
+    }
+
     let exe = std::env::current_exe().context("Cannot access current_exe")?;
-    Some(
-        buck2_forkserver::unix::launch_forkserver(exe, &["forkserver"], forkserver_state_dir).await,
-    )
-    .transpose()
+    Some(buck2_forkserver::unix::launch_forkserver(exe, &args, forkserver_state_dir).await)
+        .transpose()
 }
 
 #[cfg(not(unix))]
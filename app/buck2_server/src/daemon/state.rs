@@ -16,6 +16,7 @@ use std::time::Instant;
 use allocative::Allocative;
 use anyhow::Context;
 use buck2_build_api::actions::build_listener::CriticalPathBackendName;
+use buck2_build_api::actions::execute::shared_outputs::SharedOutputsHistory;
 use buck2_cli_proto::unstable_dice_dump_request::DiceDumpFormat;
 use buck2_common::cas_digest::DigestAlgorithm;
 use buck2_common::cas_digest::DigestAlgorithmKind;
@@ -27,6 +28,8 @@ use buck2_common::result::SharedResult;
 use buck2_common::result::ToSharedResultExt;
 use buck2_core::cells::name::CellName;
 use buck2_core::facebook_only;
+use buck2_core::fs::paths::abs_norm_path::AbsNormPathBuf;
+use buck2_core::fs::paths::forward_rel_path::ForwardRelativePath;
 use buck2_core::fs::project::ProjectRoot;
 use buck2_core::fs::project_rel_path::ProjectRelativePathBuf;
 use buck2_core::quiet_soft_error;
@@ -42,10 +45,19 @@ use buck2_execute::execute::blocking::BuckBlockingExecutor;
 use buck2_execute::materialize::materializer::MaterializationMethod;
 use buck2_execute::materialize::materializer::Materializer;
 use buck2_execute::re::manager::ReConnectionManager;
+use buck2_execute_impl::executors::action_timings::ActionTimings;
+use buck2_execute_impl::executors::memory_estimates::MemoryEstimates;
+use buck2_execute_impl::executors::stale_outputs::StaleOutputs;
+use buck2_execute_impl::executors::worker::WorkerPool;
 use buck2_execute_impl::materializers::deferred::DeferredMaterializer;
 use buck2_execute_impl::materializers::deferred::DeferredMaterializerConfigs;
+use buck2_execute_impl::materializers::deferred::GcConfiguration;
+use buck2_execute_impl::materializers::deferred::GcPolicy;
 use buck2_execute_impl::materializers::deferred::TtlRefreshConfiguration;
+use buck2_execute_impl::materializers::deferred::VerifierConfiguration;
 use buck2_execute_impl::materializers::immediate::ImmediateMaterializer;
+use buck2_execute_impl::materializers::local_cas::LocalCas;
+use buck2_execute_impl::materializers::local_cas::LocalCasConfig;
 use buck2_execute_impl::materializers::sqlite::MaterializerState;
 use buck2_execute_impl::materializers::sqlite::MaterializerStateIdentity;
 use buck2_execute_impl::materializers::sqlite::MaterializerStateSqliteDb;
@@ -67,6 +79,9 @@ use crate::active_commands::ActiveCommandDropGuard;
 use crate::ctx::BaseServerCommandContext;
 use crate::daemon::check_working_dir;
 use crate::daemon::disk_state::delete_unknown_disk_state;
+use crate::daemon::disk_state::initialize_action_timings;
+use crate::daemon::disk_state::initialize_stale_outputs;
+use crate::daemon::disk_state::maybe_initialize_dep_files_sqlite_db;
 use crate::daemon::disk_state::maybe_initialize_materializer_sqlite_db;
 use crate::daemon::disk_state::DiskStateOptions;
 use crate::daemon::forkserver::maybe_launch_forkserver;
@@ -119,6 +134,24 @@ pub struct DaemonStateData {
 
     pub(crate) forkserver: Option<ForkserverClient>,
 
+    /// Persistent workers live as long as the daemon so that they can be reused across commands.
+    pub(crate) worker_pool: Option<Arc<WorkerPool>>,
+
+    /// The peak memory of local actions, learnt across commands, when memory aware scheduling is
+    /// enabled.
+    pub(crate) memory_estimates: Option<Arc<MemoryEstimates>>,
+
+    /// How long actions took on the executor that won when they were raced, used by the adaptive
+    /// hybrid executor.
+    pub(crate) action_timings: Arc<ActionTimings>,
+
+    /// Undeclared outputs of local actions, used when commands check for stale outputs.
+    pub(crate) stale_outputs: Arc<StaleOutputs>,
+
+    /// Configuration-independent outputs produced by previous commands, so that other
+    /// configurations don't replace them with different ones.
+    pub(crate) shared_outputs_history: Arc<SharedOutputsHistory>,
+
     #[allocative(skip)]
     pub scribe_sink: Option<Arc<dyn EventSink>>,
 
@@ -286,6 +319,47 @@ impl DaemonState {
                 .unwrap_or_else(RolloutPercentage::never)
                 .roll();
 
+            // Materializer GC is off unless at least one limit is set.
+            let gc_max_size_mb: Option<u64> =
+                root_config.parse("buck2", "materializer_gc_max_size_mb")?;
+            let gc_max_age_seconds: Option<i64> =
+                root_config.parse("buck2", "materializer_gc_max_age_seconds")?;
+            let gc_frequency = root_config
+                .parse("buck2", "materializer_gc_frequency_seconds")?
+                .unwrap_or(600);
+            let gc_idle_time = root_config
+                .parse("buck2", "materializer_gc_idle_seconds")?
+                .unwrap_or(60);
+
+            // The background verifier is off unless a frequency is set.
+            let verify_frequency: Option<u64> =
+                root_config.parse("buck2", "materializer_verify_frequency_seconds")?;
+            let verify_idle_time = root_config
+                .parse("buck2", "materializer_verify_idle_seconds")?
+                .unwrap_or(60);
+
+            // The local CAS is off unless a directory is set.
+            let local_cas = match root_config.get("buck2", "local_cas_dir") {
+                Some(dir) => {
+                    let hardlinks = root_config
+                        .parse("buck2", "local_cas_hardlinks")?
+                        .unwrap_or(false);
+                    let max_bytes = root_config.parse("buck2", "local_cas_max_bytes")?;
+                    Some(Arc::new(LocalCas::new(LocalCasConfig {
+                        root: AbsNormPathBuf::new(dir.into())
+                            .context("`buck2.local_cas_dir` must be an absolute path")?,
+                        hardlinks,
+                        max_bytes,
+                        digest_config,
+                    })?))
+                }
+                None => None,
+            };
+
+            let verify_downloads = root_config
+                .parse("buck2", "materializer_verify_downloads")?
+                .unwrap_or(false);
+
             DeferredMaterializerConfigs {
                 materialize_final_artifacts: matches!(
                     materialization_method,
@@ -297,6 +371,23 @@ impl DaemonState {
                     min_ttl: chrono::Duration::seconds(ttl_refresh_min_ttl),
                     enabled: ttl_refresh_enabled,
                 },
+                gc: GcConfiguration {
+                    policy: GcPolicy {
+                        max_size: gc_max_size_mb.map(|mb| mb * 1024 * 1024),
+                        max_age: gc_max_age_seconds.map(chrono::Duration::seconds),
+                    },
+                    frequency: std::time::Duration::from_secs(gc_frequency),
+                    idle_time: std::time::Duration::from_secs(gc_idle_time),
+                },
+                verifier: VerifierConfiguration {
+                    enabled: verify_frequency.is_some(),
+                    frequency: std::time::Duration::from_secs(verify_frequency.unwrap_or(0)),
+                    idle_time: std::time::Duration::from_secs(verify_idle_time),
+                },
+                local_cas,
+                verify_downloads,
+                lazy_outputs: matches!(materialization_method, MaterializationMethod::Fuse)
+                    .then(|| paths.lazy_outputs_dir()),
             }
         };
 
@@ -320,13 +411,46 @@ impl DaemonState {
                 blocking_executor.dupe() as Arc<dyn BlockingExecutor>,
                 root_config,
                 &deferred_materializer_configs,
-                fs,
+                fs.dupe(),
                 digest_config,
                 &init_ctx,
             ),
         )
         .await?;
 
+        // Initialized after deleting unknown disk state, which would otherwise race with it.
+        maybe_initialize_dep_files_sqlite_db(
+            &disk_state_options,
+            paths,
+            blocking_executor.dupe() as Arc<dyn BlockingExecutor>,
+            root_config,
+            fs.dupe(),
+            digest_config,
+        )
+        .await?;
+
+        let action_timings = Arc::new(
+            initialize_action_timings(
+                &disk_state_options,
+                paths,
+                blocking_executor.dupe() as Arc<dyn BlockingExecutor>,
+                root_config,
+                fs.dupe(),
+            )
+            .await?,
+        );
+
+        let stale_outputs = Arc::new(
+            initialize_stale_outputs(
+                &disk_state_options,
+                paths,
+                blocking_executor.dupe() as Arc<dyn BlockingExecutor>,
+                root_config,
+                fs,
+            )
+            .await?,
+        );
+
         let materializer_state_identity = materializer_db.as_ref().map(|d| d.identity().clone());
 
         let re_client_manager = Arc::new(ReConnectionManager::new(
@@ -337,6 +461,21 @@ impl DaemonState {
             Some(paths.re_logs_dir().to_string()),
             paths.buck_out_dir().to_string(),
         ));
+        // A daemon that used lazy outputs left the outputs that weren't lazy in their backing
+        // directory.
+        #[cfg(target_os = "linux")]
+        {
+            if !matches!(materialization_method, MaterializationMethod::Fuse) {
+                buck2_execute_impl::materializers::fuse::restore_lazy_outputs(
+                    io.project_root(),
+                    &paths
+                        .buck_out_dir()
+                        .join(ForwardRelativePath::unchecked_new("gen")),
+                    &paths.lazy_outputs_dir(),
+                )?;
+            }
+        }
+
         let materializer = Self::create_materializer(
             fb,
             io.project_root().dupe(),
@@ -355,6 +494,34 @@ impl DaemonState {
         let forkserver =
             maybe_launch_forkserver(root_config, &paths.forkserver_state_dir()).await?;
 
+        let worker_pool = if root_config
+            .parse::<bool>("build", "persistent_workers")?
+            .unwrap_or(true)
+        {
+            let max_workers = root_config
+                .parse("build", "persistent_workers_max_per_key")?
+                .unwrap_or(4);
+            let idle_timeout = root_config
+                .parse("build", "persistent_workers_idle_timeout_seconds")?
+                .unwrap_or(600);
+            Some(Arc::new(WorkerPool::new(
+                paths.worker_logs_dir(),
+                max_workers,
+                Duration::from_secs(idle_timeout),
+            )?))
+        } else {
+            None
+        };
+
+        let memory_estimates = if root_config
+            .parse::<bool>("build", "memory_aware_scheduling")?
+            .unwrap_or(false)
+        {
+            Some(Arc::new(MemoryEstimates::new()))
+        } else {
+            None
+        };
+
         let dice = init_ctx
             .construct_dice(io.dupe(), digest_config, root_config)
             .await?;
@@ -443,6 +610,11 @@ impl DaemonState {
             blocking_executor,
             materializer,
             forkserver,
+            worker_pool,
+            memory_estimates,
+            action_timings,
+            stale_outputs,
+            shared_outputs_history: Arc::new(SharedOutputsHistory::default()),
             scribe_sink,
             hash_all_commands,
             disk_state_options,
@@ -467,24 +639,24 @@ impl DaemonState {
         materializer_state: Option<MaterializerState>,
     ) -> anyhow::Result<Arc<dyn Materializer>> {
         match materialization_method {
-            MaterializationMethod::Immediate => Ok(Arc::new(ImmediateMaterializer::new(
+            MaterializationMethod::Immediate => Ok(Arc::new(
+                ImmediateMaterializer::new(fs, digest_config, re_client_manager, blocking_executor)
+                    .with_local_cas(deferred_materializer_configs.local_cas)
+                    .with_verify_downloads(deferred_materializer_configs.verify_downloads),
+            )),
+            // Lazy outputs are served by the deferred materializer, see `fuse.rs`.
+            MaterializationMethod::Deferred
+            | MaterializationMethod::DeferredSkipFinalArtifacts
+            | MaterializationMethod::Fuse => Ok(Arc::new(DeferredMaterializer::new(
                 fs,
                 digest_config,
+                buck_out_path,
                 re_client_manager,
                 blocking_executor,
-            ))),
-            MaterializationMethod::Deferred | MaterializationMethod::DeferredSkipFinalArtifacts => {
-                Ok(Arc::new(DeferredMaterializer::new(
-                    fs,
-                    digest_config,
-                    buck_out_path,
-                    re_client_manager,
-                    blocking_executor,
-                    deferred_materializer_configs,
-                    materializer_db,
-                    materializer_state,
-                )?))
-            }
+                deferred_materializer_configs,
+                materializer_db,
+                materializer_state,
+            )?)),
             MaterializationMethod::Eden => {
                 #[cfg(any(fbcode_build, cargo_internal_build))]
                 {
@@ -602,6 +774,18 @@ impl DaemonState {
                 "sqlite-materializer-state:{}",
                 data.disk_state_options.sqlite_materializer_state
             ),
+            format!(
+                "sqlite-dep-files-state:{}",
+                data.disk_state_options.sqlite_dep_files_state
+            ),
+            format!(
+                "sqlite-action-timings:{}",
+                data.disk_state_options.sqlite_action_timings
+            ),
+            format!(
+                "sqlite-stale-outputs:{}",
+                data.disk_state_options.sqlite_stale_outputs
+            ),
         ];
 
         dispatcher.instant_event(buck2_data::TagEvent { tags });
@@ -624,6 +808,11 @@ impl DaemonState {
             file_watcher: data.file_watcher.dupe(),
             events: dispatcher,
             forkserver: data.forkserver.dupe(),
+            worker_pool: data.worker_pool.dupe(),
+            memory_estimates: data.memory_estimates.dupe(),
+            action_timings: data.action_timings.dupe(),
+            stale_outputs: data.stale_outputs.dupe(),
+            shared_outputs_history: data.shared_outputs_history.dupe(),
             hash_all_commands: data.hash_all_commands,
             _drop_guard: drop_guard,
             daemon_start_time: data.start_time,
@@ -0,0 +1,161 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+use anyhow::Context;
+use buck2_build_api::build::ProviderArtifacts;
+use buck2_core::fs::artifact_path_resolver::ArtifactFs;
+use buck2_core::fs::project_rel_path::ProjectRelativePath;
+use buck2_core::fs::project_rel_path::ProjectRelativePathBuf;
+use buck2_core::provider::label::NonDefaultProvidersName;
+use buck2_core::provider::label::ProviderName;
+use buck2_core::provider::label::ProvidersName;
+use globset::Glob;
+use globset::GlobSet;
+use globset::GlobSetBuilder;
+
+/// The filters passed to `buck2 build --materialize-only`, selecting which final artifacts get
+/// materialized. Each filter is either a sub-target, like `[compile_commands]`, which selects
+/// all the outputs of that sub-target of the requested targets, or a glob on the project-relative
+/// output path, like `*.so`.
+pub(crate) struct MaterializeOnlyFilter {
+    sub_targets: Vec<ProvidersName>,
+    globs: GlobSet,
+}
+
+impl MaterializeOnlyFilter {
+    /// Returns `None` if there are no filters, i.e. if materialization isn't restricted.
+    pub(crate) fn new(filters: &[String]) -> anyhow::Result<Option<Self>> {
+        if filters.is_empty() {
+            return Ok(None);
+        }
+
+        let mut sub_targets = Vec::new();
+        let mut globs = GlobSetBuilder::new();
+        for filter in filters {
+            if let Some(names) = filter.strip_prefix('[').and_then(|f| f.strip_suffix(']')) {
+                let names = names
+                    .split("][")
+                    .map(|n| ProviderName::new(n.to_owned()))
+                    .collect::<anyhow::Result<Vec<_>>>()
+                    .with_context(|| {
+                        format!("Invalid `--materialize-only` sub-target `{}`", filter)
+                    })?;
+                sub_targets.push(ProvidersName::NonDefault(Box::new(
+                    NonDefaultProvidersName::Named(names.into_boxed_slice()),
+                )));
+            } else {
+                globs.add(
+                    Glob::new(filter).with_context(|| {
+                        format!("Invalid `--materialize-only` glob `{}`", filter)
+                    })?,
+                );
+            }
+        }
+
+        Ok(Some(Self {
+            sub_targets,
+            globs: globs.build()?,
+        }))
+    }
+
+    fn matches(&self, name: &ProvidersName, path: &ProjectRelativePath) -> bool {
+        self.sub_targets.contains(name) || self.globs.is_match(path.as_str())
+    }
+
+    /// The sub-targets that have to be built on top of the providers label with `name` for their
+    /// outputs to be materialized. Those are all the filtered sub-targets when the base target was
+    /// requested, and none otherwise: a requested sub-target is matched by its own name.
+    pub(crate) fn sub_targets_to_build<'a>(
+        &'a self,
+        name: &ProvidersName,
+    ) -> impl Iterator<Item = &'a ProvidersName> + 'a {
+        let is_default = matches!(name, ProvidersName::Default);
+        self.sub_targets.iter().filter(move |_| is_default)
+    }
+
+    /// The paths of the `outputs` of the providers label with `name` that match the filters.
+    pub(crate) fn paths_to_materialize(
+        &self,
+        name: &ProvidersName,
+        outputs: &[ProviderArtifacts],
+        artifact_fs: &ArtifactFs,
+    ) -> anyhow::Result<Vec<ProjectRelativePathBuf>> {
+        let mut paths = Vec::new();
+        for output in outputs {
+            for (artifact, _) in output.values.iter() {
+                let path = artifact.get_path().resolve(artifact_fs)?;
+                if self.matches(name, &path) {
+                    paths.push(path);
+                }
+            }
+        }
+        Ok(paths)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matches() -> anyhow::Result<()> {
+        let filter =
+            MaterializeOnlyFilter::new(&["*.so".to_owned(), "[compile_commands]".to_owned()])?
+                .unwrap();
+
+        let default = ProvidersName::Default;
+        let compile_commands = ProvidersName::NonDefault(Box::new(NonDefaultProvidersName::Named(
+            vec![ProviderName::new("compile_commands".to_owned())?].into_boxed_slice(),
+        )));
+        let so = ProjectRelativePath::unchecked_new("buck-out/v2/gen/root/abc/foo/libfoo.so");
+        let json = ProjectRelativePath::unchecked_new("buck-out/v2/gen/root/abc/foo/out.json");
+
+        assert!(filter.matches(&default, so));
+        assert!(!filter.matches(&default, json));
+        assert!(filter.matches(&compile_commands, json));
+
+        assert!(MaterializeOnlyFilter::new(&[])?.is_none());
+        assert!(MaterializeOnlyFilter::new(&["[".to_owned()]).is_err());
+        assert!(MaterializeOnlyFilter::new(&["[a b]".to_owned()]).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sub_targets_to_build() -> anyhow::Result<()> {
+        let filter =
+            MaterializeOnlyFilter::new(&["*.so".to_owned(), "[compile_commands]".to_owned()])?
+                .unwrap();
+
+        let compile_commands = ProvidersName::NonDefault(Box::new(NonDefaultProvidersName::Named(
+            vec![ProviderName::new("compile_commands".to_owned())?].into_boxed_slice(),
+        )));
+
+        // Only the base target was requested: the sub-target has to be built to find its outputs.
+        assert_eq!(
+            filter
+                .sub_targets_to_build(&ProvidersName::Default)
+                .collect::<Vec<_>>(),
+            vec![&compile_commands]
+        );
+        // The sub-target itself was requested, its outputs already match.
+        assert_eq!(filter.sub_targets_to_build(&compile_commands).count(), 0);
+
+        let nested = MaterializeOnlyFilter::new(&["[a][b]".to_owned()])?.unwrap();
+        assert_eq!(
+            nested
+                .sub_targets_to_build(&ProvidersName::Default)
+                .map(|n| n.to_string())
+                .collect::<Vec<_>>(),
+            vec!["[a][b]".to_owned()]
+        );
+
+        Ok(())
+    }
+}
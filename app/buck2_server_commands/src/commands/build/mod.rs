@@ -21,6 +21,7 @@ use buck2_build_api::build::HasCreateUnhashedSymlinkLock;
 use buck2_build_api::build::MaterializationContext;
 use buck2_build_api::build::ProvidersToBuild;
 use buck2_build_api::calculation::Calculation;
+use buck2_build_api::interpreter::rule_defs::provider::collection::FrozenProviderCollectionValue;
 use buck2_build_api::query::cquery::evaluator::universe_from_literals;
 use buck2_build_api::query::dice::get_dice_query_delegate;
 use buck2_cli_proto::build_request::build_providers::Action as BuildProviderAction;
@@ -33,7 +34,9 @@ use buck2_common::dice::file_ops::HasFileOps;
 use buck2_common::legacy_configs::dice::HasLegacyConfigs;
 use buck2_common::pattern::resolve::resolve_target_patterns;
 use buck2_common::pattern::resolve::ResolvedPattern;
+use buck2_core::fs::artifact_path_resolver::ArtifactFs;
 use buck2_core::fs::fs_util;
+use buck2_core::fs::project_rel_path::ProjectRelativePathBuf;
 use buck2_core::package::PackageLabel;
 use buck2_core::pattern::pattern_type::ConfiguredProvidersPatternExtra;
 use buck2_core::pattern::pattern_type::ProvidersPatternExtra;
@@ -62,6 +65,7 @@ use futures::stream::TryStreamExt;
 use gazebo::prelude::*;
 use itertools::Itertools;
 
+use crate::commands::build::materialize_only::MaterializeOnlyFilter;
 use crate::commands::build::results::build_report::BuildReportCollector;
 use crate::commands::build::results::providers::ProvidersPrinter;
 use crate::commands::build::results::result_report::ResultReporter;
@@ -70,6 +74,7 @@ use crate::commands::build::results::BuildOwner;
 use crate::commands::build::results::BuildResultCollector;
 use crate::commands::build::unhashed_outputs::create_unhashed_outputs;
 
+mod materialize_only;
 mod results;
 mod unhashed_outputs;
 
@@ -222,25 +227,61 @@ async fn build(
         Materializations::from_i32(request.final_artifact_materializations)
             .with_context(|| "Invalid final_artifact_materializations")
             .unwrap();
-    let materialization_context =
-        ConvertMaterializationContext::from(final_artifact_materializations);
+    let materialize_only = MaterializeOnlyFilter::new(&request.materialize_only)?;
+    // When filtering, nothing is materialized while building, and the matching outputs are
+    // materialized once they are all known.
+    let materialization_context = if materialize_only.is_some() {
+        MaterializationContext::Skip
+    } else {
+        ConvertMaterializationContext::from(final_artifact_materializations)
+    };
 
     let mut provider_artifacts = Vec::new();
+    let mut paths_to_materialize = Vec::new();
     for (k, v) in build_targets(
         &ctx,
         resolved_pattern,
         target_resolution_config,
-        build_providers,
+        build_providers.dupe(),
         &materialization_context,
     )
     .await?
     {
         result_collectors.collect_result(&BuildOwner::Target(&k), &v);
-        let mut outputs = v.outputs.into_iter().filter_map(|output| match output {
-            Ok(output) => Some(output),
-            _ => None,
-        });
-        provider_artifacts.extend(&mut outputs);
+        let outputs: Vec<_> = v
+            .outputs
+            .into_iter()
+            .filter_map(|output| match output {
+                Ok(output) => Some(output),
+                _ => None,
+            })
+            .collect();
+        if let Some(materialize_only) = &materialize_only {
+            paths_to_materialize.extend(materialize_only.paths_to_materialize(
+                k.name(),
+                &outputs,
+                &artifact_fs,
+            )?);
+            paths_to_materialize.extend(
+                sub_target_paths_to_materialize(
+                    &ctx,
+                    materialize_only,
+                    &k,
+                    &v.providers,
+                    &build_providers,
+                    &artifact_fs,
+                )
+                .await?,
+            );
+        }
+        provider_artifacts.extend(outputs);
+    }
+
+    if !paths_to_materialize.is_empty() {
+        ctx.per_transaction_data()
+            .get_materializer()
+            .ensure_materialized(paths_to_materialize)
+            .await?;
     }
 
     if should_create_unhashed_links.unwrap_or(false) {
@@ -304,6 +345,43 @@ async fn build(
     })
 }
 
+/// The outputs of the sub-targets of `label` selected by `--materialize-only` that match it. The
+/// sub-targets the target doesn't have are ignored.
+async fn sub_target_paths_to_materialize(
+    ctx: &DiceComputations,
+    materialize_only: &MaterializeOnlyFilter,
+    label: &ConfiguredProvidersLabel,
+    providers: &FrozenProviderCollectionValue,
+    build_providers: &BuildProviders,
+    artifact_fs: &ArtifactFs,
+) -> anyhow::Result<Vec<ProjectRelativePathBuf>> {
+    let providers_to_build = build_providers_to_providers_to_build(build_providers);
+    let mut paths = Vec::new();
+    for name in materialize_only.sub_targets_to_build(label.name()) {
+        let sub_target = ConfiguredProvidersLabel::new(label.target().dupe(), name.clone());
+        if providers.lookup_inner(&sub_target).is_err() {
+            continue;
+        }
+        let result = build::build_configured_label(
+            ctx,
+            &MaterializationContext::Skip,
+            &sub_target,
+            &providers_to_build,
+            true,
+        )
+        .await?;
+        if let Some(result) = result {
+            let outputs = result
+                .outputs
+                .into_iter()
+                .filter_map(|output| output.ok())
+                .collect::<Vec<_>>();
+            paths.extend(materialize_only.paths_to_materialize(name, &outputs, artifact_fs)?);
+        }
+    }
+    Ok(paths)
+}
+
 async fn build_targets(
     ctx: &DiceComputations,
     spec: ResolvedPattern<ConfiguredProvidersPatternExtra>,
@@ -75,6 +75,7 @@ async fn aquery(
 
     let output_configuration = QueryResultPrinter::from_request_options(
         &cell_resolver,
+        server_ctx.project_root(),
         &request.output_attributes,
         request.unstable_output_format,
     )?;
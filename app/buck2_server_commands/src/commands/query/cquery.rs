@@ -15,15 +15,24 @@ use buck2_build_api::analysis::calculation::RuleAnalysisCalculation;
 use buck2_build_api::interpreter::rule_defs::provider::collection::FrozenProviderCollectionValue;
 use buck2_build_api::query::cquery::environment::CqueryOwnerBehavior;
 use buck2_build_api::query::cquery::evaluator::get_cquery_evaluator;
+use buck2_build_api::query::cquery::starlark_output::format_nodes_with_starlark_fn;
 use buck2_cli_proto::CqueryRequest;
 use buck2_cli_proto::CqueryResponse;
+use buck2_cli_proto::QueryOutputFormat;
 use buck2_common::dice::cells::HasCellResolver;
+use buck2_core::bzl::ImportPath;
+use buck2_core::cells::build_file_cell::BuildFileCell;
+use buck2_core::cells::CellResolver;
+use buck2_core::fs::project_rel_path::ProjectRelativePath;
 use buck2_core::provider::label::ConfiguredProvidersLabel;
 use buck2_core::provider::label::ProvidersName;
 use buck2_core::truncate::truncate;
+use buck2_interpreter::parse_import::parse_import_with_config;
+use buck2_interpreter::parse_import::ParseImportOptions;
 use buck2_node::nodes::configured::ConfiguredTargetNode;
 use buck2_query::query::compatibility::MaybeCompatible;
 use buck2_query::query::syntax::simple::eval::values::QueryEvaluationResult;
+use buck2_query::query::syntax::simple::eval::values::QueryEvaluationValue;
 use buck2_server_ctx::ctx::ServerCommandContextTrait;
 use buck2_server_ctx::partial_result_dispatcher::PartialResultDispatcher;
 use buck2_server_ctx::pattern::target_platform_from_client_context;
@@ -36,6 +45,7 @@ use dupe::Dupe;
 use crate::commands::query::printer::ProviderLookUp;
 use crate::commands::query::printer::QueryResultPrinter;
 use crate::commands::query::printer::ShouldPrintProviders;
+use crate::commands::query::QueryCommandError;
 
 pub async fn cquery_command(
     ctx: Box<dyn ServerCommandContextTrait>,
@@ -93,6 +103,7 @@ async fn cquery(
     let cell_resolver = ctx.get_cell_resolver().await?;
     let output_configuration = QueryResultPrinter::from_request_options(
         &cell_resolver,
+        server_ctx.project_root(),
         &request.output_attributes,
         request.unstable_output_format,
     )?;
@@ -105,6 +116,7 @@ async fn cquery(
         target_call_stacks,
         show_providers,
         correct_owner,
+        output_starlark_fn,
         ..
     } = request;
     // The request will always have a universe value, an empty one indicates the user didn't provide a universe.
@@ -150,6 +162,24 @@ async fn cquery(
         ShouldPrintProviders::No
     };
 
+    if !output_starlark_fn.is_empty() {
+        if !request.output_attributes.is_empty()
+            || request.unstable_output_format != QueryOutputFormat::Default as i32
+            || *target_call_stacks
+            || *show_providers
+        {
+            return Err(QueryCommandError::StarlarkFnOutputConflict.into());
+        }
+        let (path, name) =
+            parse_starlark_fn(&cell_resolver, server_ctx.working_dir(), output_starlark_fn)?;
+        let result = print_with_starlark_fn(&mut stdout, &ctx, &path, &name, query_result).await;
+        let error_messages = match result {
+            Ok(_) => vec![],
+            Err(e) => vec![format!("{:#}", e)],
+        };
+        return Ok(CqueryResponse { error_messages });
+    }
+
     let result = match query_result {
         QueryEvaluationResult::Single(targets) => {
             output_configuration
@@ -181,6 +211,55 @@ async fn cquery(
     Ok(CqueryResponse { error_messages })
 }
 
+/// Parses a `path/to/file.bzl:function` argument of `--output-starlark-fn`.
+fn parse_starlark_fn(
+    cell_resolver: &CellResolver,
+    working_dir: &ProjectRelativePath,
+    value: &str,
+) -> anyhow::Result<(ImportPath, String)> {
+    let current_cell = cell_resolver.get_cell_path(working_dir)?;
+    let cell_alias_resolver = cell_resolver
+        .get(current_cell.cell())?
+        .cell_alias_resolver();
+
+    let (path, name) = value
+        .rsplit_once(':')
+        .ok_or_else(|| QueryCommandError::StarlarkFnFormat(value.to_owned()))?;
+
+    const OPTS: ParseImportOptions = ParseImportOptions {
+        allow_missing_at_symbol: true,
+        allow_relative_imports: true,
+    };
+    let path = parse_import_with_config(cell_alias_resolver, &current_cell, path, &OPTS)?;
+    let build_file_cell = BuildFileCell::new(path.cell());
+    Ok((ImportPath::new(path, build_file_cell)?, name.to_owned()))
+}
+
+async fn print_with_starlark_fn(
+    mut stdout: impl Write,
+    ctx: &DiceComputations,
+    path: &ImportPath,
+    name: &str,
+    query_result: QueryEvaluationResult<ConfiguredTargetNode>,
+) -> anyhow::Result<()> {
+    let targets = match query_result {
+        QueryEvaluationResult::Single(value) => value,
+        QueryEvaluationResult::Multiple(results) => results.merged()?,
+    };
+    let targets = match targets {
+        QueryEvaluationValue::TargetSet(targets) => targets,
+        QueryEvaluationValue::FileSet(_) => {
+            return Err(QueryCommandError::StarlarkFnFileSet.into());
+        }
+    };
+    for line in
+        format_nodes_with_starlark_fn(ctx, path, name, targets.into_iter().collect()).await?
+    {
+        writeln!(stdout, "{}", line)?;
+    }
+    Ok(())
+}
+
 #[async_trait]
 impl ProviderLookUp<ConfiguredTargetNode> for DiceComputations {
     async fn lookup(
@@ -0,0 +1,100 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+//! Attributes that are not stored on the node but computed when requested with
+//! `--output-attribute`. They are only output when requested by their exact name: a regex like
+//! `''` does not select them because some of them are expensive to compute.
+
+use buck2_build_api::query::aquery::environment::ActionQueryNode;
+use buck2_node::nodes::configured::ConfiguredTargetNode;
+use buck2_node::nodes::unconfigured::TargetNode;
+use buck2_query::query::environment::QueryTarget;
+
+use crate::target_hash::BuckTargetHash;
+use crate::target_hash::TargetHashes;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum DerivedAttr {
+    /// For each attribute using `select()`, the keys of the branches taken.
+    ResolvedSelects,
+    /// The input files of the target, as absolute paths.
+    AbsoluteInputs,
+    /// The `.bzl` call stack that defined the target, output under the same key and in the same
+    /// form as with `--target-call-stacks`.
+    CallStack,
+    /// The hash of the target node, as `buck2 targets --show-target-hash
+    /// --target-hash-recursive=false` with no file hashing.
+    TargetHash,
+}
+
+impl DerivedAttr {
+    const ALL: &'static [DerivedAttr] = &[
+        DerivedAttr::ResolvedSelects,
+        DerivedAttr::AbsoluteInputs,
+        DerivedAttr::CallStack,
+        DerivedAttr::TargetHash,
+    ];
+
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            DerivedAttr::ResolvedSelects => "buck.resolved_selects",
+            DerivedAttr::AbsoluteInputs => "buck.absolute_inputs",
+            DerivedAttr::CallStack => "buck.target_call_stack",
+            DerivedAttr::TargetHash => "buck.target_hash",
+        }
+    }
+
+    pub(crate) fn from_name(name: &str) -> Option<DerivedAttr> {
+        Self::ALL.iter().copied().find(|a| a.name() == name)
+    }
+}
+
+/// Node-specific computation of the derived attributes. The defaults are for nodes that have no
+/// such value, which are then output as `null`.
+pub trait QueryTargetDerivedAttrs: QueryTarget {
+    fn resolved_selects(&self) -> anyhow::Result<Option<Vec<(&str, Vec<String>)>>> {
+        Ok(None)
+    }
+
+    fn immediate_target_hash(&self) -> Option<BuckTargetHash> {
+        None
+    }
+}
+
+impl QueryTargetDerivedAttrs for TargetNode {
+    fn immediate_target_hash(&self) -> Option<BuckTargetHash> {
+        Some(TargetHashes::compute_immediate_one(self, true))
+    }
+}
+
+impl QueryTargetDerivedAttrs for ConfiguredTargetNode {
+    fn resolved_selects(&self) -> anyhow::Result<Option<Vec<(&str, Vec<String>)>>> {
+        Ok(Some(ConfiguredTargetNode::resolved_selects(self)?))
+    }
+
+    fn immediate_target_hash(&self) -> Option<BuckTargetHash> {
+        Some(TargetHashes::compute_immediate_one(self, true))
+    }
+}
+
+impl QueryTargetDerivedAttrs for ActionQueryNode {}
+
+#[cfg(test)]
+mod tests {
+    use super::DerivedAttr;
+
+    #[test]
+    fn test_from_name() {
+        for attr in DerivedAttr::ALL {
+            assert_eq!(Some(*attr), DerivedAttr::from_name(attr.name()));
+        }
+        assert_eq!(None, DerivedAttr::from_name("buck.type"));
+        assert_eq!(None, DerivedAttr::from_name("buck\\.target_hash"));
+    }
+}
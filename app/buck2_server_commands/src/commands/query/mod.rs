@@ -11,6 +11,7 @@ use thiserror::Error;
 
 pub mod aquery;
 pub mod cquery;
+pub mod derived_attrs;
 pub mod printer;
 pub mod uquery;
 
@@ -20,4 +21,16 @@ enum QueryCommandError {
         "query result was a set of files and one or more --output-attribute was requested, but files have not attributes"
     )]
     FileSetHasNoAttributes,
+    #[error("`--output-attribute {0}` is only supported with the default and JSON output formats")]
+    DerivedAttributeOutputFormat(&'static str),
+    #[error("`--streaming` is only supported with the default and JSON output formats")]
+    StreamingOutputFormat,
+    #[error("`--output-starlark-fn` must be of the form `path/to/file.bzl:function`, got `{0}`")]
+    StarlarkFnFormat(String),
+    #[error(
+        "`--output-starlark-fn` cannot be combined with other output formats, attributes, call stacks or providers"
+    )]
+    StarlarkFnOutputConflict,
+    #[error("query result was a set of files, but `--output-starlark-fn` only formats targets")]
+    StarlarkFnFileSet,
 }
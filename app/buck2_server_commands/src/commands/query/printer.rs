@@ -9,6 +9,7 @@
 
 #![allow(clippy::drop_non_drop)] // FIXME?
 
+use std::collections::BTreeMap;
 use std::fmt::Display;
 use std::fmt::Formatter;
 
@@ -16,6 +17,7 @@ use async_trait::async_trait;
 use buck2_build_api::interpreter::rule_defs::provider::collection::FrozenProviderCollectionValue;
 use buck2_cli_proto::QueryOutputFormat;
 use buck2_core::cells::CellResolver;
+use buck2_core::fs::project::ProjectRoot;
 use buck2_query::query::compatibility::MaybeCompatible;
 use buck2_query::query::environment::QueryTarget;
 use buck2_query::query::environment::QueryTargets;
@@ -27,6 +29,8 @@ use buck2_util::indent::indent;
 use dupe::Clone_;
 use dupe::Copy_;
 use dupe::Dupe_;
+use futures::Stream;
+use futures::StreamExt;
 use gazebo::variants::UnpackVariants;
 use indent_write::fmt::IndentWriter;
 use regex::RegexSet;
@@ -35,6 +39,8 @@ use serde::ser::SerializeSeq;
 use serde::Serialize;
 use serde::Serializer;
 
+use crate::commands::query::derived_attrs::DerivedAttr;
+use crate::commands::query::derived_attrs::QueryTargetDerivedAttrs;
 use crate::commands::query::QueryCommandError;
 use crate::dot::targets::DotTargetGraph;
 use crate::dot::Dot;
@@ -55,20 +61,36 @@ pub trait ProviderLookUp<T: QueryTarget>: Send + Sync {
 #[derive(Debug)]
 pub struct QueryResultPrinter<'a> {
     resolver: &'a CellResolver,
+    project_root: &'a ProjectRoot,
     attributes: Option<RegexSet>,
+    derived_attributes: Vec<DerivedAttr>,
     output_format: QueryOutputFormat,
 }
 
-struct TargetSetJsonPrinter<'a, T: QueryTarget> {
+/// The attributes requested with `--output-attribute`.
+struct RequestedAttributes<'a> {
+    attributes: &'a Option<RegexSet>,
+    derived_attributes: &'a [DerivedAttr],
+    resolver: &'a CellResolver,
+    project_root: &'a ProjectRoot,
+}
+
+impl<'a> RequestedAttributes<'a> {
+    fn is_some(&self) -> bool {
+        self.attributes.is_some() || !self.derived_attributes.is_empty()
+    }
+}
+
+struct TargetSetJsonPrinter<'a, T: QueryTargetDerivedAttrs> {
     value: Vec<PrintableQueryTarget<'a, T>>,
     is_complex: bool,
 }
 
-impl<'a, T: QueryTarget> TargetSetJsonPrinter<'a, T> {
+impl<'a, T: QueryTargetDerivedAttrs> TargetSetJsonPrinter<'a, T> {
     async fn new(
         target_call_stacks: bool,
         print_providers: ShouldPrintProviders<'a, T>,
-        attributes: &'a Option<RegexSet>,
+        attributes: &'a RequestedAttributes<'a>,
         targets: &'a TargetSet<T>,
     ) -> anyhow::Result<TargetSetJsonPrinter<'a, T>> {
         Ok(TargetSetJsonPrinter {
@@ -81,20 +103,20 @@ impl<'a, T: QueryTarget> TargetSetJsonPrinter<'a, T> {
     }
 }
 
-struct PrintableQueryTarget<'a, T: QueryTarget> {
+struct PrintableQueryTarget<'a, T: QueryTargetDerivedAttrs> {
     value: &'a T,
-    attributes: &'a Option<RegexSet>,
+    attributes: &'a RequestedAttributes<'a>,
     providers: Option<FrozenProviderCollectionValue>,
     target_call_stacks: bool,
 }
 
-impl<'a, T: QueryTarget> PrintableQueryTarget<'a, T> {
+impl<'a, T: QueryTargetDerivedAttrs> PrintableQueryTarget<'a, T> {
     fn label(&self) -> String {
         self.value.node_ref().to_string()
     }
 }
 
-impl<'a, T: QueryTarget> Display for PrintableQueryTarget<'a, T> {
+impl<'a, T: QueryTargetDerivedAttrs> Display for PrintableQueryTarget<'a, T> {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         write!(f, "{}", self.value.node_ref())?;
 
@@ -126,7 +148,44 @@ impl<'a, T: QueryTarget> Display for PrintableQueryTarget<'a, T> {
     }
 }
 
-impl<'a, T: QueryTarget> Serialize for PrintableQueryTarget<'a, T> {
+impl<'a, T: QueryTargetDerivedAttrs> PrintableQueryTarget<'a, T> {
+    fn serialize_derived_attr<M: SerializeMap>(
+        &self,
+        attr: DerivedAttr,
+        map: &mut M,
+    ) -> Result<(), M::Error> {
+        match attr {
+            DerivedAttr::ResolvedSelects => {
+                let resolved = self
+                    .value
+                    .resolved_selects()
+                    .map_err(serde::ser::Error::custom)?
+                    .map(|resolved| resolved.into_iter().collect::<BTreeMap<_, _>>());
+                map.serialize_entry(attr.name(), &resolved)
+            }
+            DerivedAttr::AbsoluteInputs => {
+                let mut inputs = Vec::new();
+                self.value
+                    .inputs_for_each(|cell_path| {
+                        let path = self.attributes.resolver.resolve_path(cell_path.as_ref())?;
+                        inputs.push(self.attributes.project_root.resolve(&path).to_string());
+                        anyhow::Ok(())
+                    })
+                    .map_err(serde::ser::Error::custom)?;
+                map.serialize_entry(attr.name(), &inputs)
+            }
+            // Already output below when `--target-call-stacks` is passed.
+            DerivedAttr::CallStack if self.target_call_stacks => Ok(()),
+            DerivedAttr::CallStack => map.serialize_entry(attr.name(), &self.value.call_stack()),
+            DerivedAttr::TargetHash => map.serialize_entry(
+                attr.name(),
+                &self.value.immediate_target_hash().map(|h| h.to_string()),
+            ),
+        }
+    }
+}
+
+impl<'a, T: QueryTargetDerivedAttrs> Serialize for PrintableQueryTarget<'a, T> {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: Serializer,
@@ -134,7 +193,7 @@ impl<'a, T: QueryTarget> Serialize for PrintableQueryTarget<'a, T> {
         let mut map = serializer.serialize_map(None)?;
 
         QueryTargets::for_all_attrs(self.value, |attr_name, attr_value| {
-            if let Some(attr_regex) = self.attributes {
+            if let Some(attr_regex) = self.attributes.attributes {
                 if attr_regex.is_match(attr_name) {
                     struct AttrValueSerialize<'a, T: QueryTarget> {
                         target: &'a T,
@@ -162,6 +221,10 @@ impl<'a, T: QueryTarget> Serialize for PrintableQueryTarget<'a, T> {
             Ok(())
         })?;
 
+        for attr in self.attributes.derived_attributes {
+            self.serialize_derived_attr(*attr, &mut map)?;
+        }
+
         if self.target_call_stacks {
             map.serialize_entry("buck.target_call_stack", &self.value.call_stack())?;
         }
@@ -174,7 +237,7 @@ impl<'a, T: QueryTarget> Serialize for PrintableQueryTarget<'a, T> {
     }
 }
 
-impl<'a, T: QueryTarget> Serialize for TargetSetJsonPrinter<'a, T> {
+impl<'a, T: QueryTargetDerivedAttrs> Serialize for TargetSetJsonPrinter<'a, T> {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: Serializer,
@@ -215,11 +278,13 @@ impl<'a> QueryResultPrinter<'a> {
     /// Utility for creating from the options in their protobuf form.
     pub fn from_request_options(
         resolver: &'a CellResolver,
+        project_root: &'a ProjectRoot,
         attributes: &[String],
         output_format: i32,
     ) -> anyhow::Result<Self> {
         Self::from_options(
             resolver,
+            project_root,
             attributes,
             QueryOutputFormat::from_i32(output_format)
                 .expect("cli should send a valid output_format enum"),
@@ -228,6 +293,7 @@ impl<'a> QueryResultPrinter<'a> {
 
     pub fn from_options(
         resolver: &'a CellResolver,
+        project_root: &'a ProjectRoot,
         attributes: &[String],
         output_format: QueryOutputFormat,
     ) -> anyhow::Result<Self> {
@@ -237,33 +303,72 @@ impl<'a> QueryResultPrinter<'a> {
             (v, _) => v,
         };
 
-        let attributes = if attributes.is_empty() {
+        // Derived attributes are only output when requested by their exact name, everything
+        // else is a regex.
+        let mut derived_attributes = Vec::new();
+        let mut regexes = Vec::new();
+        for attribute in attributes {
+            match DerivedAttr::from_name(attribute) {
+                Some(derived) => derived_attributes.push(derived),
+                None => regexes.push(attribute),
+            }
+        }
+
+        if !derived_attributes.is_empty()
+            && matches!(
+                output_format,
+                QueryOutputFormat::Dot | QueryOutputFormat::DotCompact
+            )
+        {
+            return Err(QueryCommandError::DerivedAttributeOutputFormat(
+                derived_attributes[0].name(),
+            )
+            .into());
+        }
+
+        let attributes = if regexes.is_empty() {
             None
         } else {
-            Some(RegexSet::new(attributes)?)
+            Some(RegexSet::new(regexes)?)
         };
 
         Ok(Self {
             resolver,
+            project_root,
             attributes,
+            derived_attributes,
             output_format,
         })
     }
 
-    pub async fn print_multi_output<'b, T: QueryTarget, W: std::io::Write>(
+    fn requested_attributes(&self) -> RequestedAttributes<'_> {
+        RequestedAttributes {
+            attributes: &self.attributes,
+            derived_attributes: &self.derived_attributes,
+            resolver: self.resolver,
+            project_root: self.project_root,
+        }
+    }
+
+    fn has_attributes(&self) -> bool {
+        self.requested_attributes().is_some()
+    }
+
+    pub async fn print_multi_output<'b, T: QueryTargetDerivedAttrs, W: std::io::Write>(
         &self,
         mut output: W,
         multi_result: MultiQueryResult<T>,
         target_call_stacks: bool,
         print_providers: ShouldPrintProviders<'b, T>,
     ) -> anyhow::Result<()> {
-        match (self.output_format, &self.attributes) {
+        match (self.output_format, self.has_attributes()) {
             // A multi-query only has interesting output with --json output. For non-json output it gets merged together.
             // TODO(cjhopman): buck1 does this really odd thing that a multi-query that requests any attributes
             // gets the entire result merged together rather than printed as a multi-query. We match that behavior, but
             // it really doesn't make sense and we should migrate off of that.
-            (QueryOutputFormat::Json, None) => {
+            (QueryOutputFormat::Json, false) => {
                 let multi_result = multi_result.0;
+                let attributes = self.requested_attributes();
                 let mut captured_error = Ok(());
 
                 let mut ser = serde_json::Serializer::pretty(&mut output);
@@ -276,7 +381,7 @@ impl<'a> QueryResultPrinter<'a> {
                                 &TargetSetJsonPrinter::new(
                                     target_call_stacks,
                                     print_providers,
-                                    &self.attributes,
+                                    &attributes,
                                     &targets,
                                 )
                                 .await?,
@@ -316,18 +421,19 @@ impl<'a> QueryResultPrinter<'a> {
         }
     }
 
-    pub async fn print_single_output<'b, T: QueryTarget, W: std::io::Write>(
+    pub async fn print_single_output<'b, T: QueryTargetDerivedAttrs, W: std::io::Write>(
         &self,
         mut output: W,
         result: QueryEvaluationValue<T>,
         call_stack: bool,
         print_providers: ShouldPrintProviders<'b, T>,
     ) -> anyhow::Result<()> {
+        let attributes = self.requested_attributes();
         match result {
             QueryEvaluationValue::TargetSet(targets) => match self.output_format {
                 QueryOutputFormat::Default => {
                     for target in
-                        printable_targets(&targets, print_providers, &self.attributes, call_stack)
+                        printable_targets(&targets, print_providers, &attributes, call_stack)
                             .await?
                     {
                         writeln!(&mut output, "{}", target)?;
@@ -335,14 +441,9 @@ impl<'a> QueryResultPrinter<'a> {
                 }
                 QueryOutputFormat::Json => {
                     let mut ser = serde_json::Serializer::pretty(&mut output);
-                    TargetSetJsonPrinter::new(
-                        call_stack,
-                        print_providers,
-                        &self.attributes,
-                        &targets,
-                    )
-                    .await?
-                    .serialize(&mut ser)?;
+                    TargetSetJsonPrinter::new(call_stack, print_providers, &attributes, &targets)
+                        .await?
+                        .serialize(&mut ser)?;
                     std::mem::drop(ser);
                     // need to add a newline to flush the output.
                     writeln!(&mut output)?
@@ -367,7 +468,7 @@ impl<'a> QueryResultPrinter<'a> {
                 }
             },
             QueryEvaluationValue::FileSet(files) => {
-                if self.attributes.is_some() {
+                if self.has_attributes() {
                     return Err(QueryCommandError::FileSetHasNoAttributes.into());
                 }
                 match self.output_format {
@@ -403,12 +504,78 @@ impl<'a> QueryResultPrinter<'a> {
 
         Ok(())
     }
+
+    /// Prints the targets as each batch of them is produced, rather than once the whole result
+    /// is known. The output is the same as printing the union of the batches with
+    /// `print_single_output`, but only the list and JSON formats are supported.
+    pub async fn print_streaming_output<T: QueryTargetDerivedAttrs, W: std::io::Write>(
+        &self,
+        mut output: W,
+        targets: impl Stream<Item = anyhow::Result<TargetSet<T>>>,
+        call_stack: bool,
+    ) -> anyhow::Result<()> {
+        let json = match self.output_format {
+            QueryOutputFormat::Default => false,
+            QueryOutputFormat::Json => true,
+            QueryOutputFormat::Dot | QueryOutputFormat::DotCompact => {
+                return Err(QueryCommandError::StreamingOutputFormat.into());
+            }
+        };
+        let attributes = self.requested_attributes();
+        // Matches the shape chosen by `TargetSetJsonPrinter`.
+        let is_complex = attributes.is_some() || call_stack;
+
+        if json {
+            write!(&mut output, "{}", if is_complex { "{" } else { "[" })?;
+        }
+
+        futures::pin_mut!(targets);
+        let mut empty = true;
+        let result: anyhow::Result<()> = try {
+            while let Some(batch) = targets.next().await {
+                let batch = batch?;
+                for target in
+                    printable_targets(&batch, ShouldPrintProviders::No, &attributes, call_stack)
+                        .await?
+                {
+                    if !json {
+                        writeln!(&mut output, "{}", target)?;
+                        continue;
+                    }
+                    // Reproduce `serde_json`'s pretty printing of the whole collection, one
+                    // element at a time.
+                    write!(&mut output, "{}\n  ", if empty { "" } else { "," })?;
+                    let label = serde_json::to_string(&target.label())?;
+                    if is_complex {
+                        let value = serde_json::to_string_pretty(&target)?;
+                        write!(&mut output, "{}: {}", label, value.replace('\n', "\n  "))?;
+                    } else {
+                        write!(&mut output, "{}", label)?;
+                    }
+                    empty = false;
+                }
+                output.flush()?;
+            }
+        };
+
+        // Close the JSON container even if the stream failed, so that what was printed so far
+        // is still valid JSON. The error is reported separately.
+        let closed: anyhow::Result<()> = try {
+            if json {
+                if !empty {
+                    writeln!(&mut output)?;
+                }
+                writeln!(&mut output, "{}", if is_complex { "}" } else { "]" })?;
+            }
+        };
+        result.and(closed)
+    }
 }
 
-async fn printable_targets<'a, T: QueryTarget>(
+async fn printable_targets<'a, T: QueryTargetDerivedAttrs>(
     targets: &'a TargetSet<T>,
     print_providers: ShouldPrintProviders<'a, T>,
-    attributes: &'a Option<RegexSet>,
+    attributes: &'a RequestedAttributes<'a>,
     target_call_stacks: bool,
 ) -> anyhow::Result<Vec<PrintableQueryTarget<'a, T>>> {
     futures::future::join_all(targets.iter().map(|t| {
@@ -431,3 +598,108 @@ async fn printable_targets<'a, T: QueryTarget>(
     .into_iter()
     .collect::<anyhow::Result<_>>()
 }
+
+#[cfg(test)]
+mod tests {
+    use buck2_cli_proto::QueryOutputFormat;
+    use buck2_core::cells::cell_root_path::CellRootPathBuf;
+    use buck2_core::cells::name::CellName;
+    use buck2_core::cells::testing::CellResolverExt;
+    use buck2_core::cells::CellResolver;
+    use buck2_core::configuration::data::ConfigurationData;
+    use buck2_core::fs::paths::abs_norm_path::AbsNormPathBuf;
+    use buck2_core::fs::project::ProjectRoot;
+    use buck2_core::fs::project_rel_path::ProjectRelativePathBuf;
+    use buck2_core::target::label::TargetLabel;
+    use buck2_node::nodes::configured::ConfiguredTargetNode;
+    use buck2_query::query::syntax::simple::eval::set::TargetSet;
+    use buck2_query::query::syntax::simple::eval::values::QueryEvaluationValue;
+
+    use super::QueryResultPrinter;
+    use super::ShouldPrintProviders;
+
+    fn batch(labels: &[&str]) -> TargetSet<ConfiguredTargetNode> {
+        labels
+            .iter()
+            .map(|label| {
+                ConfiguredTargetNode::testing_new(
+                    TargetLabel::testing_parse(label).configure(ConfigurationData::testing_new()),
+                    "foo_lib",
+                )
+            })
+            .collect()
+    }
+
+    /// Prints the batches with `print_streaming_output` and their union with
+    /// `print_single_output`.
+    async fn print_both(
+        printer: &QueryResultPrinter<'_>,
+        batches: &[&[&str]],
+        call_stack: bool,
+    ) -> anyhow::Result<(String, String)> {
+        let mut streamed = Vec::new();
+        printer
+            .print_streaming_output(
+                &mut streamed,
+                futures::stream::iter(batches.iter().map(|labels| Ok(batch(labels)))),
+                call_stack,
+            )
+            .await?;
+
+        let mut buffered = Vec::new();
+        printer
+            .print_single_output(
+                &mut buffered,
+                QueryEvaluationValue::TargetSet(batch(&batches.concat())),
+                call_stack,
+                ShouldPrintProviders::No,
+            )
+            .await?;
+
+        Ok((String::from_utf8(streamed)?, String::from_utf8(buffered)?))
+    }
+
+    #[tokio::test]
+    async fn test_streaming_output_matches_buffered_output() -> anyhow::Result<()> {
+        let resolver = CellResolver::of_names_and_paths(
+            CellName::testing_new("root"),
+            &[(
+                CellName::testing_new("root"),
+                CellRootPathBuf::new(ProjectRelativePathBuf::unchecked_new("".to_owned())),
+            )],
+        );
+        let project_root =
+            ProjectRoot::new_unchecked(AbsNormPathBuf::try_from(std::env::current_dir()?)?);
+
+        let batches: &[&[&str]] = &[&["root//a:x", "root//a:y"], &[], &["root//b:z"]];
+        for (format, attributes, call_stack) in [
+            (QueryOutputFormat::Default, &[][..], false),
+            (QueryOutputFormat::Default, &[][..], true),
+            (QueryOutputFormat::Json, &[][..], false),
+            (QueryOutputFormat::Json, &[][..], true),
+            (
+                QueryOutputFormat::Json,
+                &["buck.type".to_owned()][..],
+                false,
+            ),
+            (
+                QueryOutputFormat::Json,
+                &["buck.target_hash".to_owned(), "^name$".to_owned()][..],
+                false,
+            ),
+        ] {
+            let printer =
+                QueryResultPrinter::from_options(&resolver, &project_root, attributes, format)?;
+            for batches in [batches, &[][..]] {
+                let (streamed, buffered) = print_both(&printer, batches, call_stack).await?;
+                assert_eq!(
+                    buffered, streamed,
+                    "format: {:?}, attributes: {:?}, call stacks: {}",
+                    format, attributes, call_stack
+                );
+            }
+        }
+
+        Ok(())
+    }
+}
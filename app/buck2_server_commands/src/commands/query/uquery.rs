@@ -7,6 +7,7 @@
  * of this source tree.
  */
 
+use std::collections::HashSet;
 use std::io::Write;
 
 use anyhow::Context;
@@ -15,16 +16,27 @@ use buck2_build_api::query::uquery::evaluator::get_uquery_evaluator;
 use buck2_cli_proto::UqueryRequest;
 use buck2_cli_proto::UqueryResponse;
 use buck2_common::dice::cells::HasCellResolver;
+use buck2_core::pattern::pattern_type::TargetPatternExtra;
+use buck2_core::pattern::PackageSpec;
+use buck2_core::pattern::ParsedPattern;
+use buck2_node::nodes::unconfigured::TargetNode;
+use buck2_query::query::syntax::simple::eval::set::TargetSet;
 use buck2_query::query::syntax::simple::eval::values::QueryEvaluationResult;
 use buck2_server_ctx::ctx::ServerCommandContextTrait;
 use buck2_server_ctx::partial_result_dispatcher::PartialResultDispatcher;
 use buck2_server_ctx::pattern::target_platform_from_client_context;
 use buck2_server_ctx::template::run_server_command;
 use buck2_server_ctx::template::ServerCommandTemplate;
+use dice::DiceComputations;
 use dice::DiceTransaction;
+use dupe::Dupe;
+use futures::Stream;
+use futures::StreamExt;
 
 use crate::commands::query::printer::QueryResultPrinter;
 use crate::commands::query::printer::ShouldPrintProviders;
+use crate::commands::targets::streaming::load_targets;
+use crate::commands::targets::streaming::stream_packages;
 
 pub async fn uquery_command(
     ctx: Box<dyn ServerCommandContextTrait>,
@@ -74,6 +86,7 @@ async fn uquery(
     let cell_resolver = ctx.get_cell_resolver().await?;
     let output_configuration = QueryResultPrinter::from_request_options(
         &cell_resolver,
+        server_ctx.project_root(),
         &request.output_attributes,
         request.unstable_output_format,
     )?;
@@ -83,6 +96,7 @@ async fn uquery(
         query_args,
         context,
         target_call_stacks,
+        streaming,
         ..
     } = request;
 
@@ -96,6 +110,28 @@ async fn uquery(
         get_uquery_evaluator(&ctx, server_ctx.working_dir(), global_target_platform).await?;
     let evaluator = &evaluator;
 
+    // Only unions of target patterns can be printed as their packages are loaded, other queries
+    // are printed once evaluated as if `--streaming` wasn't passed.
+    let streamable_patterns = if *streaming && query_args.is_empty() {
+        evaluator.streamable_patterns(query)?
+    } else {
+        None
+    };
+    if let Some(patterns) = streamable_patterns {
+        let result = output_configuration
+            .print_streaming_output(
+                &mut stdout,
+                stream_targets(&ctx, patterns),
+                *target_call_stacks,
+            )
+            .await;
+        let error_messages = match result {
+            Ok(_) => vec![],
+            Err(e) => vec![format!("{:#}", e)],
+        };
+        return Ok(UqueryResponse { error_messages });
+    }
+
     let query_result = evaluator.eval_query(query, query_args).await?;
 
     let result = match query_result {
@@ -128,3 +164,48 @@ async fn uquery(
 
     Ok(UqueryResponse { error_messages })
 }
+
+/// How many packages `uquery --streaming` loads concurrently.
+const STREAMING_PACKAGES_IN_FLIGHT: usize = 1000;
+
+/// Loads the targets matching the patterns one package at a time. Targets matched by more than
+/// one pattern are only returned the first time.
+///
+/// `stream_packages` returns each package with explicitly listed targets once, before any package
+/// found by a recursive pattern, but overlapping recursive patterns can return the same package
+/// several times. So rather than every target, this remembers the packages output in full and the
+/// explicitly listed targets, whose number is bounded by the size of the query.
+fn stream_targets<'a>(
+    dice: &'a DiceComputations,
+    patterns: Vec<ParsedPattern<TargetPatternExtra>>,
+) -> impl Stream<Item = anyhow::Result<TargetSet<TargetNode>>> + 'a {
+    let mut seen_packages = HashSet::new();
+    let mut seen_targets = HashSet::new();
+    stream_packages(dice, patterns)
+        .map(move |x| async move {
+            let (package, spec) = x?;
+            let all = matches!(spec, PackageSpec::All);
+            let (_eval_result, targets) = load_targets(dice, package.dupe(), spec, true).await?;
+            anyhow::Ok((package, all, targets))
+        })
+        // Unlike `buck2 targets --streaming`, keep the packages in order so that the output is
+        // deterministic. Packages that finish early are held until the ones before them are
+        // printed, so bound how many can be waiting.
+        .buffered(STREAMING_PACKAGES_IN_FLIGHT)
+        .map(move |x| {
+            let (package, all, targets) = x?;
+            if all && !seen_packages.insert(package) {
+                return Ok(TargetSet::new());
+            }
+            Ok(targets
+                .into_iter()
+                .filter(|node| {
+                    if all {
+                        !seen_targets.contains(node.label())
+                    } else {
+                        seen_targets.insert(node.label().dupe())
+                    }
+                })
+                .collect())
+        })
+}
@@ -17,6 +17,7 @@ use buck2_build_api::calculation::load_patterns;
 use buck2_build_api::calculation::MissingTargetBehavior;
 use buck2_build_api::nodes::lookup::ConfiguredTargetNodeLookup;
 use buck2_build_api::nodes::lookup::TargetNodeLookup;
+use buck2_build_api::nodes::rdeps_index::ReverseDepsIndexCalculation;
 use buck2_cli_proto::targets_request;
 use buck2_cli_proto::targets_request::TargetHashFileMode;
 use buck2_cli_proto::targets_request::TargetHashGraphType;
@@ -30,6 +31,7 @@ use buck2_core::target::label::TargetLabel;
 use buck2_node::nodes::configured::ConfiguredTargetNode;
 use buck2_node::nodes::unconfigured::TargetNode;
 use buck2_server_ctx::ctx::ServerCommandContextTrait;
+use dice::DiceComputations;
 use dice::DiceTransaction;
 use dupe::Dupe;
 use dupe::OptionDupedExt;
@@ -82,6 +84,27 @@ impl TargetHashOptions {
     }
 }
 
+/// Resolves the patterns and returns patterns for the matched targets plus every target in the
+/// repository that transitively depends on them.
+pub(crate) async fn expand_to_rdeps(
+    dice: &DiceComputations,
+    parsed_patterns: Vec<ParsedPattern<TargetPatternExtra>>,
+) -> anyhow::Result<Vec<ParsedPattern<TargetPatternExtra>>> {
+    let results = load_patterns(dice, parsed_patterns, MissingTargetBehavior::Fail).await?;
+    let roots = results
+        .iter_loaded_targets()
+        .map(|node| anyhow::Ok(node?.label().dupe()))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let index = dice.get_reverse_deps_index().await?;
+    Ok(index
+        .transitive_rdeps(roots, None)
+        .into_iter()
+        .map(|label| {
+            ParsedPattern::Target(label.pkg(), label.name().to_owned(), TargetPatternExtra)
+        })
+        .collect())
+}
+
 pub(crate) async fn targets_batch(
     server_ctx: &dyn ServerCommandContextTrait,
     dice: DiceTransaction,
@@ -10,7 +10,7 @@
 mod default;
 pub(crate) mod fmt;
 mod resolve_alias;
-mod streaming;
+pub(crate) mod streaming;
 
 use std::fs::File;
 use std::io::BufWriter;
@@ -33,6 +33,7 @@ use buck2_server_ctx::template::run_server_command;
 use buck2_server_ctx::template::ServerCommandTemplate;
 use dice::DiceTransaction;
 
+use crate::commands::targets::default::expand_to_rdeps;
 use crate::commands::targets::default::targets_batch;
 use crate::commands::targets::default::TargetHashOptions;
 use crate::commands::targets::fmt::create_formatter;
@@ -204,6 +205,11 @@ async fn targets(
                 let target_platform =
                     target_platform_from_client_context(client_ctx, server_ctx, &dice).await?;
                 let fs = server_ctx.project_root();
+                let parsed_target_patterns = if other.rdeps {
+                    expand_to_rdeps(&dice, parsed_target_patterns).await?
+                } else {
+                    parsed_target_patterns
+                };
                 targets_batch(
                     server_ctx,
                     dice,
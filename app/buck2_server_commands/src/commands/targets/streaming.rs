@@ -211,7 +211,7 @@ pub(crate) async fn targets_streaming(
 }
 
 /// Given the patterns, separate into those which have an explicit package, and those which are recursive
-fn stream_packages<T: PatternType>(
+pub(crate) fn stream_packages<T: PatternType>(
     dice: &DiceComputations,
     patterns: Vec<ParsedPattern<T>>,
 ) -> impl Stream<Item = anyhow::Result<(PackageLabel, PackageSpec<T>)>> {
@@ -236,7 +236,7 @@ fn stream_packages<T: PatternType>(
         .chain(find_package_roots_stream(dice, recursive_paths).map(|x| Ok((x?, PackageSpec::All))))
 }
 
-async fn load_targets(
+pub(crate) async fn load_targets(
     dice: &DiceComputations,
     package: PackageLabel,
     spec: PackageSpec<TargetPatternExtra>,
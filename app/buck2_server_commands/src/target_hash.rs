@@ -421,7 +421,10 @@ impl TargetHashes {
         Ok(Self { target_mapping })
     }
 
-    pub fn compute_immediate_one(node: &TargetNode, use_fast_hash: bool) -> BuckTargetHash {
+    pub fn compute_immediate_one<T: TargetHashingTargetNode>(
+        node: &T,
+        use_fast_hash: bool,
+    ) -> BuckTargetHash {
         let mut hasher = TargetHashes::new_hasher(use_fast_hash);
         TargetHashes::hash_node(node, &mut *hasher);
         hasher.finish_u128()
@@ -18,6 +18,9 @@ use crate::NamedSemaphores;
 
 const SINGLE_RUN: usize = 1;
 
+/// Memory permits are in MiB, so that their count fits in a usize.
+const MEMORY_PERMIT_BYTES: u64 = 1024 * 1024;
+
 /// This class is intended to represent the resources required by each test. This is then used to
 /// map onto resources available on the machine where the tests are run on in order to not saturate the
 /// machine and adversely impact testrunning performance and reliability.
@@ -101,6 +104,13 @@ impl Default for HostSharingRequirements {
 pub struct HostSharingGuard {
     _run_guard: SharedSemaphoreReleaser,
     _name_guard: Option<SharedSemaphoreReleaser>,
+    _memory_guard: Option<SharedSemaphoreReleaser>,
+}
+
+/// The memory commands may use together, in MiB.
+struct MemoryBudget {
+    permits: SharedSemaphore,
+    num_permits: usize,
 }
 
 /// Used to ensure that host resources are properly reserved before executing a command spec.
@@ -108,6 +118,9 @@ pub struct HostSharingBroker {
     permits: SharedSemaphore,
     num_machine_permits: usize,
     named_semaphores: NamedSemaphores,
+    /// If set, commands that are expected to use memory also wait for it to be available, so that
+    /// we don't overcommit the host.
+    memory: Option<MemoryBudget>,
 }
 
 impl HostSharingBroker {
@@ -125,6 +138,14 @@ impl HostSharingBroker {
         }
     }
 
+    /// Like `requested_permits`, commands expected to use more memory than the budget are capped to
+    /// the budget. Returns `None` if there is no memory budget.
+    pub fn requested_memory_permits(&self, memory_estimate: u64) -> Option<usize> {
+        let memory = self.memory.as_ref()?;
+        let permits = memory_estimate.div_ceil(MEMORY_PERMIT_BYTES);
+        Some(usize::try_from(permits).map_or(memory.num_permits, |p| p.min(memory.num_permits)))
+    }
+
     pub fn new(host_sharing_strategy: HostSharingStrategy, num_machine_permits: usize) -> Self {
         let permits = match host_sharing_strategy {
             HostSharingStrategy::Fifo => SharedSemaphore::new(true, num_machine_permits),
@@ -137,17 +158,46 @@ impl HostSharingBroker {
             permits,
             num_machine_permits,
             named_semaphores: NamedSemaphores::new(),
+            memory: None,
         }
     }
 
+    /// Also schedule commands against this much memory, in bytes.
+    pub fn with_memory_budget(mut self, memory_budget: u64) -> Self {
+        let num_permits =
+            usize::try_from(memory_budget / MEMORY_PERMIT_BYTES).unwrap_or(usize::MAX);
+        self.memory = Some(MemoryBudget {
+            // Like `SmallerTasksFirst`: a command that needs a lot of memory shouldn't hold back
+            // the ones that fit in what is left.
+            permits: SharedSemaphore::new(false, num_permits),
+            num_permits,
+        });
+        self
+    }
+
     pub fn num_machine_permits(&self) -> usize {
         self.num_machine_permits
     }
 
+    /// Reserve the resources for a command. `memory_estimate` is how much memory the command is
+    /// expected to use, in bytes, if known.
     pub async fn acquire(
         &self,
         host_sharing_requirements: &HostSharingRequirements,
+        memory_estimate: Option<u64>,
     ) -> HostSharingGuard {
+        // Like the identifier semaphore below, wait for memory before taking any permits, so that
+        // commands waiting for memory don't hold back the others.
+        let _memory_guard = match (&self.memory, memory_estimate) {
+            (Some(memory), Some(memory_estimate)) => {
+                let permits = self
+                    .requested_memory_permits(memory_estimate)
+                    .unwrap_or_default();
+                Some(memory.permits.acquire(permits).await)
+            }
+            _ => None,
+        };
+
         match host_sharing_requirements {
             HostSharingRequirements::Shared(weight_class) => {
                 let permits = self.requested_permits(weight_class);
@@ -155,6 +205,7 @@ impl HostSharingBroker {
                 HostSharingGuard {
                     _run_guard,
                     _name_guard: None,
+                    _memory_guard,
                 }
             }
             HostSharingRequirements::ExclusiveAccess => {
@@ -162,6 +213,7 @@ impl HostSharingBroker {
                 HostSharingGuard {
                     _run_guard,
                     _name_guard: None,
+                    _memory_guard,
                 }
             }
             HostSharingRequirements::OnePerToken(identifier, weight_class) => {
@@ -176,6 +228,7 @@ impl HostSharingBroker {
                 HostSharingGuard {
                     _run_guard,
                     _name_guard,
+                    _memory_guard,
                 }
             }
         }
@@ -232,4 +285,25 @@ mod tests {
             10,
         );
     }
+
+    #[test]
+    fn test_requested_memory_permits() {
+        let broker = HostSharingBroker::new(HostSharingStrategy::SmallerTasksFirst, 10);
+        assert_eq!(None, broker.requested_memory_permits(MEMORY_PERMIT_BYTES));
+
+        let broker = broker.with_memory_budget(8 * MEMORY_PERMIT_BYTES);
+
+        // This rounds up.
+        assert_eq!(Some(1), broker.requested_memory_permits(1));
+        assert_eq!(
+            Some(3),
+            broker.requested_memory_permits(3 * MEMORY_PERMIT_BYTES)
+        );
+
+        // This is capped to the budget.
+        assert_eq!(
+            Some(8),
+            broker.requested_memory_permits(20 * MEMORY_PERMIT_BYTES)
+        );
+    }
 }